@@ -1,4 +1,5 @@
-use ultra_batch::{BatchFetcher, Cache, Fetcher};
+use std::hash::{BuildHasherDefault, Hasher};
+use ultra_batch::{BatchFetcher, BatchFetcherBuilder, Cache, Fetcher};
 
 struct FetchIdent;
 
@@ -20,6 +21,27 @@ fn main() {
     divan::main();
 }
 
+/// A fast, non-cryptographic hasher for integer keys, like the one used by
+/// `rustc`.
+#[derive(Default)]
+struct FxHasher(u64);
+
+impl Hasher for FxHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_u64(u64::from(*byte));
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.0 = (self.0.rotate_left(5) ^ value).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+}
+
 #[divan::bench(args = [250, 1000])]
 fn load_misses(bencher: divan::Bencher, size: u64) {
     let runtime = tokio::runtime::Runtime::new().unwrap();
@@ -79,6 +101,43 @@ fn load_hits(bencher: divan::Bencher, size: u64) {
     });
 }
 
+#[divan::bench(args = [250, 1000])]
+fn load_hits_read_optimized(bencher: divan::Bencher, size: u64) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _enter = runtime.enter();
+    let batch_fetcher = BatchFetcher::build(FetchIdent)
+        .read_optimized_cache()
+        .finish();
+    let handle = runtime.handle();
+
+    handle.block_on({
+        let batch_fetcher = batch_fetcher.clone();
+        async move {
+            // Pre-load all keys
+            batch_fetcher
+                .load_many(&(0..size).collect::<Vec<_>>())
+                .await
+                .unwrap();
+        }
+    });
+
+    bencher.counter(size).bench(|| {
+        let mut tasks = vec![];
+        for n in 0..size {
+            let batch_fetcher = batch_fetcher.clone();
+            let task = handle.spawn(async move { batch_fetcher.load(n).await.unwrap() });
+            tasks.push((n, task));
+        }
+
+        handle.block_on(async move {
+            for (n, task) in tasks {
+                let result = task.await.unwrap();
+                assert_eq!(result, n);
+            }
+        });
+    });
+}
+
 #[divan::bench(args = [250, 1000])]
 fn load_hits_and_misses(bencher: divan::Bencher, size: u64) {
     let runtime = tokio::runtime::Runtime::new().unwrap();
@@ -218,3 +277,41 @@ fn load_many_hits_and_misses(bencher: divan::Bencher, size: u64) {
         });
     });
 }
+
+/// Load keys that are all cached with a single `load_many` call, so the
+/// time is spent looking keys up in the cache rather than spawning tasks.
+fn bench_cached_lookups(
+    bencher: divan::Bencher,
+    size: u64,
+    build: impl FnOnce(BatchFetcherBuilder<FetchIdent>) -> BatchFetcherBuilder<FetchIdent>,
+) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _enter = runtime.enter();
+    let batch_fetcher = build(BatchFetcher::build(FetchIdent)).finish();
+    let handle = runtime.handle();
+
+    let keys = (0..size).collect::<Vec<_>>();
+    handle.block_on(batch_fetcher.load_many(&keys)).unwrap();
+
+    bencher.counter(size).bench(|| {
+        let results = handle.block_on(batch_fetcher.load_many(&keys)).unwrap();
+        assert_eq!(results, keys);
+    });
+}
+
+#[divan::bench(args = [250, 1000])]
+fn cached_lookups(bencher: divan::Bencher, size: u64) {
+    bench_cached_lookups(bencher, size, |builder| builder);
+}
+
+#[divan::bench(args = [250, 1000])]
+fn cached_lookups_read_optimized(bencher: divan::Bencher, size: u64) {
+    bench_cached_lookups(bencher, size, |builder| builder.read_optimized_cache());
+}
+
+#[divan::bench(args = [250, 1000])]
+fn cached_lookups_cache_hasher(bencher: divan::Bencher, size: u64) {
+    bench_cached_lookups(bencher, size, |builder| {
+        builder.cache_hasher(BuildHasherDefault::<FxHasher>::default())
+    });
+}
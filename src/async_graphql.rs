@@ -0,0 +1,82 @@
+//! Helpers for using [`BatchFetcher`]s with [`async-graphql`](::async_graphql).
+//! Requires the `async-graphql` feature.
+//!
+//! [`BatchFetcher`]s (or a [`LoaderRegistry`] containing them) can be added
+//! as data to each request, then [`ContextExt::batch_fetcher`] can be used
+//! to get a [`BatchFetcher`] from within a resolver. Since [`LoadError`](crate::LoadError)
+//! implements `Display`, it can be returned from resolvers with `?`.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ultra_batch::{BatchFetcher, Fetcher, Cache, LoaderRegistry};
+//! # use ultra_batch::async_graphql::ContextExt;
+//! # use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Schema};
+//! # struct FetchUserNames;
+//! # impl Fetcher for FetchUserNames {
+//! #     type Key = u64;
+//! #     type Value = String;
+//! #     type Error = anyhow::Error;
+//! #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, String>) -> anyhow::Result<()> {
+//! #         for key in keys {
+//! #             values.insert(*key, format!("User {key}"));
+//! #         }
+//! #         Ok(())
+//! #     }
+//! # }
+//! struct Query;
+//!
+//! #[Object]
+//! impl Query {
+//!     async fn user_name(&self, ctx: &Context<'_>, id: u64) -> async_graphql::Result<String> {
+//!         let user_name = ctx.batch_fetcher::<FetchUserNames>()?.load(id).await?;
+//!         Ok(user_name)
+//!     }
+//! }
+//!
+//! # #[tokio::main] async fn main() -> anyhow::Result<()> {
+//! let schema = Schema::new(Query, EmptyMutation, EmptySubscription);
+//!
+//! // For each request:
+//! let mut loaders = LoaderRegistry::new();
+//! loaders.insert(BatchFetcher::build(FetchUserNames).finish());
+//! let request = async_graphql::Request::new("{ userName(id: 1) }").data(loaders);
+//! let response = schema.execute(request).await;
+//! # assert!(response.errors.is_empty());
+//! # Ok(()) }
+//! ```
+
+use crate::{BatchFetcher, Fetcher, LoaderRegistry};
+use ::async_graphql::Context;
+
+/// Extends [`async_graphql::Context`](::async_graphql::Context) with methods
+/// for getting [`BatchFetcher`]s.
+pub trait ContextExt {
+    /// Get the [`BatchFetcher`] for the [`Fetcher`] type `F`. The
+    /// `BatchFetcher` can either be added directly as data, or can be
+    /// contained in a [`LoaderRegistry`] added as data. Returns an error if
+    /// neither contains a `BatchFetcher` for `F`.
+    fn batch_fetcher<F>(&self) -> ::async_graphql::Result<&BatchFetcher<F>>
+    where
+        F: Fetcher + Send + Sync + 'static;
+}
+
+impl ContextExt for Context<'_> {
+    fn batch_fetcher<F>(&self) -> ::async_graphql::Result<&BatchFetcher<F>>
+    where
+        F: Fetcher + Send + Sync + 'static,
+    {
+        if let Some(batch_fetcher) = self.data_opt::<BatchFetcher<F>>() {
+            return Ok(batch_fetcher);
+        }
+
+        self.data_opt::<LoaderRegistry>()
+            .and_then(|registry| registry.get::<F>())
+            .ok_or_else(|| {
+                ::async_graphql::Error::new(format!(
+                    "BatchFetcher for `{}` does not exist",
+                    std::any::type_name::<F>()
+                ))
+            })
+    }
+}
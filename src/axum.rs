@@ -0,0 +1,171 @@
+//! Helpers for using [`BatchFetcher`]s with [`axum`](https://docs.rs/axum).
+//! Requires the `axum` feature.
+//!
+//! Add a [`LoaderLayer`] to a router to create a fresh [`LoaderRegistry`]
+//! from a [`LoaderFactory`] for each request. Handlers can then use the
+//! [`Loader`] extractor to get a single [`BatchFetcher`], or the [`Loaders`]
+//! extractor to get the whole registry.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ultra_batch::{Fetcher, Cache, LoaderFactory};
+//! # use ultra_batch::axum::{Loader, LoaderLayer};
+//! # use axum::{extract::Path, routing::get, Router};
+//! # #[derive(Clone)] struct FetchUserNames;
+//! # impl Fetcher for FetchUserNames {
+//! #     type Key = u64;
+//! #     type Value = String;
+//! #     type Error = anyhow::Error;
+//! #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, String>) -> anyhow::Result<()> {
+//! #         for key in keys {
+//! #             values.insert(*key, format!("User {key}"));
+//! #         }
+//! #         Ok(())
+//! #     }
+//! # }
+//! async fn get_user_name(
+//!     Loader(user_names): Loader<FetchUserNames>,
+//!     Path(user_id): Path<u64>,
+//! ) -> String {
+//!     user_names.load(user_id).await.unwrap_or_default()
+//! }
+//!
+//! let factory = LoaderFactory::new().fetcher(|| FetchUserNames);
+//! let app: Router = Router::new()
+//!     .route("/users/{user_id}/name", get(get_user_name))
+//!     .layer(LoaderLayer::new(factory));
+//! ```
+
+use crate::{BatchFetcher, Fetcher, LoaderFactory, LoaderRegistry};
+use axum_core::extract::FromRequestParts;
+use axum_core::response::{IntoResponse, Response};
+use http::request::Parts;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// A [`Layer`] that creates a new [`LoaderRegistry`] for each request using a
+/// [`LoaderFactory`], and adds it to the request's extensions. The registry
+/// can be accessed with the [`Loader`] and [`Loaders`] extractors.
+#[derive(Debug, Clone)]
+pub struct LoaderLayer {
+    factory: Arc<LoaderFactory>,
+}
+
+impl LoaderLayer {
+    /// Create a new `LoaderLayer` that uses `factory` to create the loaders
+    /// for each request.
+    pub fn new(factory: LoaderFactory) -> Self {
+        LoaderLayer {
+            factory: Arc::new(factory),
+        }
+    }
+}
+
+impl<S> Layer<S> for LoaderLayer {
+    type Service = LoaderService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LoaderService {
+            inner,
+            factory: self.factory.clone(),
+        }
+    }
+}
+
+/// The [`Service`] returned by [`LoaderLayer`].
+#[derive(Debug, Clone)]
+pub struct LoaderService<S> {
+    inner: S,
+    factory: Arc<LoaderFactory>,
+}
+
+impl<S, B> Service<http::Request<B>> for LoaderService<S>
+where
+    S: Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        let loaders = Arc::new(self.factory.create());
+        request.extensions_mut().insert(loaders);
+        self.inner.call(request)
+    }
+}
+
+/// Extracts the [`LoaderRegistry`] created by [`LoaderLayer`] for the
+/// current request.
+#[derive(Debug, Clone)]
+pub struct Loaders(pub Arc<LoaderRegistry>);
+
+impl<S> FromRequestParts<S> for Loaders
+where
+    S: Send + Sync,
+{
+    type Rejection = MissingLoader;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let loaders = parts
+            .extensions
+            .get::<Arc<LoaderRegistry>>()
+            .ok_or(MissingLoader {
+                type_name: std::any::type_name::<LoaderRegistry>(),
+            })?;
+        Ok(Loaders(loaders.clone()))
+    }
+}
+
+/// Extracts the [`BatchFetcher`] for the [`Fetcher`] type `F` from the
+/// [`LoaderRegistry`] created by [`LoaderLayer`] for the current request.
+pub struct Loader<F>(pub BatchFetcher<F>)
+where
+    F: Fetcher + Send + Sync + 'static;
+
+impl<F, S> FromRequestParts<S> for Loader<F>
+where
+    F: Fetcher + Send + Sync + 'static,
+    S: Send + Sync,
+{
+    type Rejection = MissingLoader;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let batch_fetcher = parts
+            .extensions
+            .get::<Arc<LoaderRegistry>>()
+            .and_then(|loaders| loaders.get::<F>())
+            .ok_or(MissingLoader {
+                type_name: std::any::type_name::<BatchFetcher<F>>(),
+            })?;
+        Ok(Loader(batch_fetcher.clone()))
+    }
+}
+
+/// Rejection returned by the [`Loader`] and [`Loaders`] extractors if the
+/// request doesn't have the expected loader, such as when [`LoaderLayer`]
+/// wasn't added to the router. Responds with a 500 Internal Server Error.
+#[derive(Debug)]
+pub struct MissingLoader {
+    type_name: &'static str,
+}
+
+impl std::fmt::Display for MissingLoader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "missing loader `{}` for request", self.type_name)
+    }
+}
+
+impl std::error::Error for MissingLoader {}
+
+impl IntoResponse for MissingLoader {
+    fn into_response(self) -> Response {
+        (http::StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{BatchExecutor, BatchExecutorBuilder, Executor};
+use std::fmt::Display;
+use std::future::Future;
+
+/// Creates a context for each batch executed by a [`BatchExecutor`], such
+/// as a database connection or a transaction, and finalizes it once the
+/// batch is done. Pass a `BatchContext` to [`BatchExecutor::build_with_context`]
+/// along with a [`ContextExecutor`], which receives the context for each
+/// batch.
+///
+/// If opening or finishing the context fails, then every caller waiting on
+/// the batch receives an [`ExecuteError::ExecutorError`](crate::ExecuteError::ExecutorError).
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchContext, BatchExecutor, ContextExecutor};
+/// # struct User;
+/// # struct DbPool;
+/// # struct DbConnection;
+/// # impl DbPool {
+/// #     async fn acquire(&self) -> anyhow::Result<DbConnection> { Ok(DbConnection) }
+/// # }
+/// # impl DbConnection {
+/// #     async fn insert_users(&mut self, users: &[User]) -> anyhow::Result<Vec<u64>> { Ok(vec![]) }
+/// # }
+/// struct AcquireConnection {
+///     db_pool: DbPool,
+/// }
+///
+/// impl BatchContext for AcquireConnection {
+///     type Context = DbConnection;
+///     type Error = anyhow::Error;
+///
+///     async fn open(&self) -> anyhow::Result<DbConnection> {
+///         self.db_pool.acquire().await
+///     }
+///
+///     async fn finish(&self, _db_conn: DbConnection, _succeeded: bool) -> anyhow::Result<()> {
+///         // The connection is returned to the pool when it's dropped
+///         Ok(())
+///     }
+/// }
+///
+/// struct UserInserter;
+///
+/// impl ContextExecutor for UserInserter {
+///     type Value = User;
+///     type Result = u64;
+///     type Error = anyhow::Error;
+///     type Context = DbConnection;
+///
+///     async fn execute(
+///         &self,
+///         db_conn: &mut DbConnection,
+///         values: Vec<User>,
+///     ) -> anyhow::Result<Vec<u64>> {
+///         db_conn.insert_users(&values).await
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// # let db_pool = DbPool;
+/// let batch_inserter =
+///     BatchExecutor::build_with_context(UserInserter, AcquireConnection { db_pool }).finish();
+/// # Ok(())
+/// # }
+/// ```
+pub trait BatchContext {
+    /// The context passed to the executor for a single batch.
+    type Context: Send;
+
+    /// The error indicating that opening or finishing a context failed.
+    type Error: Display + Send;
+
+    /// Create the context for a new batch.
+    fn open(&self) -> impl Future<Output = Result<Self::Context, Self::Error>> + Send;
+
+    /// Finalize the context after the batch was executed. `succeeded` is
+    /// `true` if executing the batch succeeded, such as to decide whether to
+    /// commit or roll back a transaction. If executing the batch failed,
+    /// errors from finishing the context are logged, and callers will
+    /// receive the original error from the executor.
+    fn finish(
+        &self,
+        context: Self::Context,
+        succeeded: bool,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// A variant of [`Executor`] that receives a context created for each
+/// batch by a [`BatchContext`]. Use [`BatchExecutor::build_with_context`]
+/// to create a [`BatchExecutor`] from a `ContextExecutor`.
+pub trait ContextExecutor {
+    /// The input value provided by the caller to do something.
+    type Value: Send;
+
+    /// The output value returned by the executor back to the caller for each
+    /// input value.
+    type Result: Send;
+
+    /// The error indicating that executing a batch failed.
+    type Error: Display + Send;
+
+    /// The context used while executing a single batch.
+    type Context: Send;
+
+    /// Execute the operation for each value in the batch using the batch's
+    /// context. The returned results follow the same rules as
+    /// [`Executor::execute`].
+    fn execute(
+        &self,
+        context: &mut Self::Context,
+        values: Vec<Self::Value>,
+    ) -> impl Future<Output = Result<Vec<Self::Result>, Self::Error>> + Send;
+}
+
+/// Adapts a [`ContextExecutor`] and a [`BatchContext`] into an [`Executor`],
+/// opening a new context for each call to [`Executor::execute`]. Created
+/// with [`BatchExecutor::build_with_context`] or [`WithContext::new`].
+///
+/// Note that each call to the [`Executor`] gets a separate context, so
+/// setting [`max_batch_size`](crate::BatchExecutorBuilder::max_batch_size)
+/// will open a context for each part of a large batch.
+#[derive(Debug, Clone)]
+pub struct WithContext<E, C> {
+    executor: E,
+    context: C,
+}
+
+impl<E, C> WithContext<E, C>
+where
+    E: ContextExecutor,
+    C: BatchContext<Context = E::Context>,
+    E::Error: From<C::Error>,
+{
+    /// Wrap a [`ContextExecutor`] so it can be used with a [`BatchExecutor`],
+    /// using `context` to create the context for each batch.
+    pub fn new(executor: E, context: C) -> Self {
+        WithContext { executor, context }
+    }
+
+    /// Get a reference to the wrapped [`ContextExecutor`].
+    pub fn get_ref(&self) -> &E {
+        &self.executor
+    }
+}
+
+impl<E, C> Executor for WithContext<E, C>
+where
+    E: ContextExecutor + Sync,
+    C: BatchContext<Context = E::Context> + Sync,
+    E::Error: From<C::Error>,
+{
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+
+    async fn execute(&self, values: Vec<Self::Value>) -> Result<Vec<Self::Result>, Self::Error> {
+        execute_with_context(&self.executor, &self.context, values).await
+    }
+}
+
+/// Execute a batch with `executor` in a new context opened by `context`,
+/// finishing the context once the batch is done.
+pub(crate) async fn execute_with_context<E, C>(
+    executor: &E,
+    context: &C,
+    values: Vec<E::Value>,
+) -> Result<Vec<E::Result>, E::Error>
+where
+    E: ContextExecutor + Sync,
+    C: BatchContext<Context = E::Context> + Sync,
+    E::Error: From<C::Error>,
+{
+    let mut batch_context = context.open().await?;
+
+    let results = match executor.execute(&mut batch_context, values).await {
+        Ok(results) => results,
+        Err(error) => {
+            if let Err(finish_error) = context.finish(batch_context, false).await {
+                tracing::warn!("error while finishing batch context: {finish_error}");
+            }
+            return Err(error);
+        }
+    };
+
+    context.finish(batch_context, true).await?;
+    Ok(results)
+}
+
+impl<E, C> BatchExecutor<WithContext<E, C>>
+where
+    E: ContextExecutor + Send + Sync + 'static,
+    C: BatchContext<Context = E::Context> + Send + Sync + 'static,
+    E::Error: From<C::Error>,
+{
+    /// Create a new `BatchExecutor` that uses the given [`ContextExecutor`]
+    /// to execute values, opening a new context with `context` for each
+    /// batch. Returns a [`BatchExecutorBuilder`], which can be used to
+    /// customize the `BatchExecutor`. See [`BatchContext`] for an example.
+    pub fn build_with_context(executor: E, context: C) -> BatchExecutorBuilder<WithContext<E, C>> {
+        BatchExecutor::build(WithContext::new(executor, context))
+    }
+}
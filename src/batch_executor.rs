@@ -1,10 +1,29 @@
-use crate::Executor;
+use crate::context::CallerContext;
+use crate::memoized::MemoizeLayer;
+use crate::metrics::record_queue_depth;
+use crate::runtime::{
+    default_spawner, default_timer, BatchDriver, DelayTimer, InFlightBatches, Instant, TaskHandle,
+};
+use crate::write_through::WriteThroughLayer;
+use crate::{
+    BatchFetcher, BatchMetrics, BatchScheduler, CompletedBatch, DefaultBatchScheduler,
+    DispatchedBatch, ExecutorLayer, FinishedBatch, FnExecutor, LocalFetcher, Memoized,
+    PendingBatch, Schedule, Spawner, Timer, TryExecutor, WriteThrough,
+};
+use futures_util::future::Either;
+use futures_util::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::hash::Hash;
+use std::time::Duration;
 use std::{borrow::Cow, sync::Arc};
+use tracing::Instrument;
 
-/// Batches calls to an [`Executor`], such as for bulk inserting, updating,
+/// Batches calls to an [`Executor`](crate::Executor), such as for bulk inserting, updating,
 /// or deleting records in a datastore. `BatchExecutor`s are asynchronous
 /// and designed to be passed and shared between threads or tasks. Cloning
-/// a `BatchExecutor` is shallow and will use the same underlying [`Executor`].
+/// a `BatchExecutor` is shallow and will use the same underlying [`Executor`](crate::Executor).
 ///
 /// `BatchExecutor` is designed primarily for bulk database operations-- for
 /// example, inserting lots of records, where a single query to insert
@@ -12,8 +31,9 @@ use std::{borrow::Cow, sync::Arc};
 /// be used for fetching data or any other bulk operation as well.
 ///
 /// Unlike [`BatchFetcher`](crate::BatchFetcher), `BatchExecutor` has no
-/// concepts of keys, values, deduplication, or caching; each executed value
-/// is passed directly to the underlying [`Executor`]. As such, it could also
+/// concepts of keys, values, or caching, and doesn't deduplicate values
+/// by default (see [`BatchExecutorBuilder::deduplicate_values`]); each
+/// executed value is passed directly to the underlying [`Executor`](crate::Executor). As such, it could also
 /// be suitable for writing a custom caching layer in situations where
 /// [`BatchFetcher`](crate::BatchFetcher) is not suitable.
 ///
@@ -25,28 +45,33 @@ use std::{borrow::Cow, sync::Arc};
 ///
 /// ## Execution semantics
 ///
-/// If the underlying [`Executor`] returns an error during the batch execution,
+/// If the underlying [`Executor`](crate::Executor) returns an error during the batch execution,
 /// then all pending [`execute`](BatchExecutor::execute) and [`execute_many`](BatchExecutor::execute_many)
 /// requests will fail. The same values can be resubmitted to retry.
 ///
-/// If the underlying [`Executor`] succeeds but does not return a `Vec` that
+/// A `BatchExecutor` can also use a [`TryExecutor`], which returns a result
+/// or error for each value. If the [`TryExecutor`] returns an error for a
+/// value, then only the request that submitted that value will fail.
+///
+/// If the underlying [`Executor`](crate::Executor) succeeds but does not return a `Vec` that
 /// contains results for all values, then calls to [`execute`](BatchExecutor::execute)
 /// may return `None`. Calls to [`execute_many`](BatchExecutor::execute_many)
-/// may return a `Vec` containing less output values than input values.
+/// may return a `Vec` containing less output values than input values. To match
+/// results to values by a key instead of by position, see [`KeyedExecutor`](crate::KeyedExecutor).
 pub struct BatchExecutor<E>
 where
-    E: Executor,
+    E: TryExecutor,
 {
     label: Cow<'static, str>,
-    _execute_task: Arc<tokio::task::JoinHandle<()>>,
-    execute_request_tx: tokio::sync::mpsc::Sender<ExecuteRequest<E::Value, E::Result>>,
+    execute_task: Arc<TaskHandle<ExecuteMessage<E::Value, E::Result>>>,
+    pending_values_limit: Option<Arc<PendingValuesLimit>>,
 }
 
 impl<E> BatchExecutor<E>
 where
-    E: Executor + Send + Sync + 'static,
+    E: TryExecutor + Send + Sync + 'static,
 {
-    /// Create a new `BatchExecutor` athat uses the given [`Executor`] to
+    /// Create a new `BatchExecutor` athat uses the given [`Executor`](crate::Executor) to
     /// execute values. Returns a [`BatchExecutorBuilder`], which can be
     /// used to customize the `BatchExecutor`. Call [`.finish()`](BatchExecutorBuilder::finish)
     /// to create the `BatchExecutor`.
@@ -106,14 +131,25 @@ where
     pub fn build(executor: E) -> BatchExecutorBuilder<E> {
         BatchExecutorBuilder {
             executor,
-            delay_duration: tokio::time::Duration::from_millis(10),
+            delay_duration: Duration::from_millis(10),
+            max_wait_duration: None,
             eager_batch_size: Some(100),
+            scheduler: None,
+            metrics: None,
+            slow_batch_threshold: None,
+            prepare: None,
+            max_batch_size: None,
+            max_concurrent_batches: 1,
+            max_concurrent_chunks: 1,
+            max_pending_values: None,
+            spawner: None,
+            timer: None,
             label: "unlabeled-batch-executor".into(),
         }
     }
 
-    /// Submit a value to be executed by the [`Executor`]. Returns the
-    /// result value returned by the [`Executor`] for this given item. See
+    /// Submit a value to be executed by the [`Executor`](crate::Executor). Returns the
+    /// result value returned by the [`Executor`](crate::Executor) for this given item. See
     /// the type-level docs for [`BatchExecutor`](#execution-semantics) for
     /// detailed execution semantics.
     #[tracing::instrument(skip_all, fields(batch_executor = %self.label))]
@@ -122,10 +158,39 @@ where
         Ok(values.pop())
     }
 
-    /// Submit multiple values to be executed by the [`Executor`]. Returns a
-    /// `Vec` containg values for each result returned by the [`Executor`]
+    /// Submit a value to be executed by the [`Executor`](crate::Executor)
+    /// without waiting for its result. Returns as soon as the value is
+    /// queued, and the value is batched the same way as a call to
+    /// [`execute`](BatchExecutor::execute). This is useful for
+    /// fire-and-forget workloads like recording events, where the caller
+    /// doesn't care about the result for each value. The result, or any
+    /// error from the executor, is discarded.
+    ///
+    /// If [`max_pending_values`](BatchExecutorBuilder::max_pending_values)
+    /// is set, this waits for room before queueing the value, and the value
+    /// counts towards the limit until its batch has finished executing.
+    ///
+    /// Values that were submitted are still executed after the last clone
+    /// of the `BatchExecutor` is dropped.
+    #[tracing::instrument(skip_all, fields(batch_executor = %self.label))]
+    pub async fn submit(&self, value: E::Value) -> Result<(), ExecuteError> {
+        // The batch holds onto the room for the value, since nothing waits
+        // for its result here
+        let pending_values_permit = match &self.pending_values_limit {
+            Some(pending_values_limit) => Some(pending_values_limit.acquire(1).await),
+            None => None,
+        };
+
+        // Drop the receiver, since nothing is waiting for the result
+        let (result_tx, _) = tokio::sync::oneshot::channel();
+        self.send_values(vec![value], result_tx, pending_values_permit)
+            .await
+    }
+
+    /// Submit multiple values to be executed by the [`Executor`](crate::Executor). Returns a
+    /// `Vec` containg values for each result returned by the [`Executor`](crate::Executor)
     /// for each given input value (but note that the returned `Vec` may
-    /// not have values for all inputs if the [`Executor`] did not return
+    /// not have values for all inputs if the [`Executor`](crate::Executor) did not return
     /// enough results). See the type-level docs for [`BatchExecutor`](#execution-semantics)
     /// for detailed execution semantics.
     #[tracing::instrument(skip_all, fields(batch_executor = %self.label, num_values = values.len()))]
@@ -137,19 +202,144 @@ where
         Ok(results)
     }
 
+    /// Submit a value to be executed like [`execute`](BatchExecutor::execute),
+    /// but block the current thread until the result is returned. This lets
+    /// synchronous code (such as a CLI tool) share a `BatchExecutor` with
+    /// async code. Requires the `blocking` feature.
+    ///
+    /// This must not be called from within an async context, since blocking
+    /// the thread that runs the `BatchExecutor`'s background tasks would
+    /// deadlock. Outside of a runtime, the default [`TokioRuntime`](crate::TokioRuntime)
+    /// runs the background tasks on its own thread.
+    #[cfg(feature = "blocking")]
+    pub fn blocking_execute(&self, value: E::Value) -> Result<Option<E::Result>, ExecuteError> {
+        futures_executor::block_on(self.execute(value))
+    }
+
+    /// Submit multiple values to be executed like [`execute_many`](BatchExecutor::execute_many),
+    /// but block the current thread until the results are returned. See
+    /// [`blocking_execute`](BatchExecutor::blocking_execute). Requires the
+    /// `blocking` feature.
+    #[cfg(feature = "blocking")]
+    pub fn blocking_execute_many(
+        &self,
+        values: Vec<E::Value>,
+    ) -> Result<Vec<E::Result>, ExecuteError> {
+        futures_executor::block_on(self.execute_many(values))
+    }
+
+    /// Submit each value from a stream to be executed by the [`Executor`](crate::Executor).
+    /// Returns a stream containing the result for each value, in the same
+    /// order as the input stream. Values are submitted as they are received,
+    /// so values from the stream are batched the same way as separate calls
+    /// to [`execute`](BatchExecutor::execute).
+    ///
+    /// At most `max_pending_values` values will be waiting for results at
+    /// once. This should usually be at least as large as the batch size, so
+    /// each batch can be filled.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_pending_values` is 0.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchExecutor, Executor};
+    /// # use futures_util::StreamExt;
+    /// # struct UserInserter;
+    /// # impl Executor for UserInserter {
+    /// #     type Value = u64;
+    /// #     type Result = u64;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn execute(&self, values: Vec<u64>) -> anyhow::Result<Vec<u64>> {
+    /// #         Ok(values)
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_inserter = BatchExecutor::build(UserInserter).finish();
+    ///
+    /// let user_ids = futures_util::stream::iter(0..1000);
+    /// let mut results = batch_inserter.execute_stream(user_ids, 100);
+    /// while let Some(result) = results.next().await {
+    ///     let result = result?;
+    ///     // ...
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn execute_stream<S>(
+        &self,
+        values: S,
+        max_pending_values: usize,
+    ) -> impl Stream<Item = Result<Option<E::Result>, ExecuteError>>
+    where
+        S: Stream<Item = E::Value>,
+    {
+        assert_ne!(max_pending_values, 0, "max_pending_values must be non-zero");
+
+        let batch_executor = self.clone();
+        values
+            .map(move |value| {
+                let batch_executor = batch_executor.clone();
+                async move { batch_executor.execute(value).await }
+            })
+            .buffered(max_pending_values)
+    }
+
+    /// Dispatch any values that are currently queued immediately, without
+    /// waiting for the timeout set by [`delay_duration`](BatchExecutorBuilder::delay_duration)
+    /// or for the batch to fill up. This is useful for graceful shutdown, or
+    /// at points where the caller knows no more values will be submitted
+    /// soon, such as at the end of handling a web request. Does nothing if
+    /// no values are queued.
+    ///
+    /// Note that `flush` only dispatches the batch, and does not wait for
+    /// the batch to finish executing.
+    pub async fn flush(&self) {
+        tracing::debug!(batch_executor = %self.label, "flushing pending values");
+
+        // Ignore error if the execute task has already stopped
+        let _ = self.execute_task.send(ExecuteMessage::Flush).await;
+    }
+
+    /// Shut down the `BatchExecutor`, such as when a service is stopping.
+    /// Once called, new calls to [`execute`](BatchExecutor::execute) or
+    /// [`execute_many`](BatchExecutor::execute_many) will fail with
+    /// [`ExecuteError::SendError`]. Any values that were already queued are
+    /// dispatched immediately, and `shutdown` returns once every in-flight
+    /// batch has finished and the execute task has stopped, so no queued
+    /// values are lost.
+    ///
+    /// This affects every clone of the `BatchExecutor`. If the
+    /// `BatchExecutor` was already shut down, this returns immediately.
+    pub async fn shutdown(&self) {
+        tracing::debug!(batch_executor = %self.label, "shutting down");
+
+        // Don't restart the execute task once it stops
+        self.execute_task.close();
+
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        let sent = self
+            .execute_task
+            .send(ExecuteMessage::Shutdown(shutdown_tx))
+            .await;
+        if sent.is_ok() {
+            // Ignore error if the execute task stopped some other way
+            let _ = shutdown_rx.await;
+        }
+    }
+
     async fn execute_values(&self, values: Vec<E::Value>) -> Result<Vec<E::Result>, ExecuteError> {
-        let execute_request_tx = self.execute_request_tx.clone();
-        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        // Wait for room before queueing the values, and hold onto it until
+        // the results come back
+        let _pending_values_permit = match &self.pending_values_limit {
+            Some(pending_values_limit) => Some(pending_values_limit.acquire(values.len()).await),
+            None => None,
+        };
 
-        tracing::debug!(
-            batch_executor = %self.label,
-            "sending a batch of values to execute",
-        );
-        let execute_request = ExecuteRequest { values, result_tx };
-        execute_request_tx
-            .send(execute_request)
-            .await
-            .map_err(|_| ExecuteError::SendError)?;
+        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        self.send_values(values, result_tx, None).await?;
 
         match result_rx.await {
             Ok(Ok(results)) => {
@@ -160,25 +350,107 @@ where
                 tracing::info!("error returned while executing: {execute_error}");
                 Err(ExecuteError::ExecutorError(execute_error))
             }
-            Err(recv_error) => {
-                panic!(
-                    "Batch result channel for batch executor {} hung up with error: {recv_error}",
-                    self.label,
-                );
+            Err(_) => {
+                // The execute task stopped while the batch was in flight,
+                // such as when the runtime it was spawned on shut down
+                tracing::warn!(batch_executor = %self.label, "batch stopped before it finished");
+                Err(ExecuteError::SendError)
             }
         }
     }
+
+    async fn send_values(
+        &self,
+        values: Vec<E::Value>,
+        result_tx: ResultSender<E::Result>,
+        pending_values_permit: Option<tokio::sync::OwnedSemaphorePermit>,
+    ) -> Result<(), ExecuteError> {
+        tracing::debug!(
+            batch_executor = %self.label,
+            "sending a batch of values to execute",
+        );
+        let execute_request = ExecuteRequest {
+            values,
+            result_tx,
+            pending_values_permit,
+            context: CallerContext::current(),
+        };
+        self.execute_task
+            .send(ExecuteMessage::Execute(execute_request))
+            .await
+            .map_err(|_| ExecuteError::SendError)
+    }
+}
+
+impl<E> BatchExecutor<E>
+where
+    E: TryExecutor + Send + Sync + 'static,
+    E::Value: Clone,
+{
+    /// Submit multiple values to be executed like [`execute_many`](BatchExecutor::execute_many),
+    /// but return each input value paired with its result. If the
+    /// [`Executor`](crate::Executor) did not return enough results, then the
+    /// values without a result are paired with `None`, so callers don't
+    /// need to match results to values by index.
+    #[allow(clippy::type_complexity)]
+    #[tracing::instrument(skip_all, fields(batch_executor = %self.label, num_values = values.len()))]
+    pub async fn execute_many_zipped(
+        &self,
+        values: Vec<E::Value>,
+    ) -> Result<Vec<(E::Value, Option<E::Result>)>, ExecuteError> {
+        let mut results = self.execute_values(values.clone()).await?.into_iter();
+        let zipped = values
+            .into_iter()
+            .map(|value| (value, results.next()))
+            .collect();
+        Ok(zipped)
+    }
+}
+
+impl<Fun, Fut, V, R, E> BatchExecutor<FnExecutor<Fun, V, R, E>>
+where
+    Fun: Fn(Vec<V>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Vec<R>, E>> + Send,
+    V: Send + 'static,
+    R: Send + 'static,
+    E: Display + 'static,
+{
+    /// Create a new `BatchExecutor` that calls an async closure to execute
+    /// each batch, without needing to implement [`Executor`](crate::Executor).
+    /// The closure is called with the values for each batch, and should
+    /// return a result for each value (see [`Executor::execute`](crate::Executor::execute)).
+    /// Returns a [`BatchExecutorBuilder`], the same as [`BatchExecutor::build`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::BatchExecutor;
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_inserter = BatchExecutor::from_fn(|names: Vec<String>| async move {
+    ///     // Insert the users, returning the ID of each one
+    ///     let ids: Vec<u64> = (1..=names.len() as u64).collect();
+    ///     anyhow::Ok(ids)
+    /// })
+    /// .finish();
+    ///
+    /// let id = batch_inserter.execute("Alice".to_string()).await?;
+    /// assert_eq!(id, Some(1));
+    /// # Ok(()) }
+    /// ```
+    pub fn from_fn(execute_fn: Fun) -> BatchExecutorBuilder<FnExecutor<Fun, V, R, E>> {
+        BatchExecutor::build(FnExecutor::new(execute_fn))
+    }
 }
 
 impl<E> Clone for BatchExecutor<E>
 where
-    E: Executor,
+    E: TryExecutor,
 {
     fn clone(&self) -> Self {
         BatchExecutor {
-            _execute_task: self._execute_task.clone(),
-            execute_request_tx: self.execute_request_tx.clone(),
+            execute_task: self.execute_task.clone(),
             label: self.label.clone(),
+            pending_values_limit: self.pending_values_limit.clone(),
         }
     }
 }
@@ -187,27 +459,55 @@ where
 /// returned from [`BatchExecutor::build`].
 pub struct BatchExecutorBuilder<E>
 where
-    E: Executor + Send + Sync + 'static,
+    E: TryExecutor + Send + Sync + 'static,
 {
     executor: E,
-    delay_duration: tokio::time::Duration,
+    delay_duration: Duration,
     eager_batch_size: Option<usize>,
+    scheduler: Option<Arc<dyn BatchScheduler>>,
+    metrics: Option<Arc<dyn BatchMetrics>>,
+    slow_batch_threshold: Option<Duration>,
+    prepare: Option<PrepareValues<E::Value, E::Result>>,
+    max_batch_size: Option<usize>,
+    max_concurrent_batches: usize,
+    max_concurrent_chunks: usize,
+    max_pending_values: Option<usize>,
+    max_wait_duration: Option<Duration>,
+    spawner: Option<Arc<dyn Spawner>>,
+    timer: Option<Arc<dyn Timer>>,
     label: Cow<'static, str>,
 }
 
 impl<E> BatchExecutorBuilder<E>
 where
-    E: Executor + Send + Sync + 'static,
+    E: TryExecutor + Send + Sync + 'static,
 {
     /// The maximum amount of time the [`BatchExecutor`] will wait to queue up
-    /// more keys before calling the [`Executor`].
-    pub fn delay_duration(mut self, delay: tokio::time::Duration) -> Self {
+    /// more keys before calling the [`Executor`](crate::Executor).
+    pub fn delay_duration(mut self, delay: Duration) -> Self {
         self.delay_duration = delay;
         self
     }
 
+    /// The maximum amount of time to wait for more values after the first
+    /// value of a batch is queued. Each new value restarts the wait set by
+    /// [`delay_duration`](BatchExecutorBuilder::delay_duration), so under a
+    /// steady trickle of values a batch could otherwise keep waiting long
+    /// after its first value was queued. A value of `Some(duration)`
+    /// dispatches the batch once `duration` has passed, no matter how
+    /// recently the last value came in. A value of `None` (the default)
+    /// doesn't limit the wait.
+    ///
+    /// This doesn't include time spent waiting for an in-flight batch to
+    /// finish when [`max_concurrent_batches`](BatchExecutorBuilder::max_concurrent_batches)
+    /// is reached.
+    pub fn max_wait_duration(mut self, max_wait_duration: Option<Duration>) -> Self {
+        self.max_wait_duration = max_wait_duration;
+        self
+    }
+
     /// The maximum number of keys to wait for before eagerly calling the
-    /// [`Executor`]. A value of `Some(n)` will load the batch once `n` or more
+    /// [`Executor`](crate::Executor). A value of `Some(n)` will load the batch once `n` or more
     /// keys have been queued (or once the timeout set by
     /// [`delay_duration`](BatchExecutorBuilder::delay_duration) is reached,
     /// whichever comes first). A value of `None` will never eagerly dispatch
@@ -217,12 +517,177 @@ where
     /// Note that `eager_batch_size` **does not** set an upper limit on the
     /// batch! For example, if [`BatchExecutor::execute_many`] is called with
     /// more than `eager_batch_size` items, then the batch will be sent
-    /// immediately with _all_ of the provided values.
+    /// immediately with _all_ of the provided values. Use
+    /// [`max_batch_size`](BatchExecutorBuilder::max_batch_size) to set an
+    /// upper limit.
     pub fn eager_batch_size(mut self, eager_batch_size: Option<usize>) -> Self {
         self.eager_batch_size = eager_batch_size;
         self
     }
 
+    /// The maximum number of values to pass to the [`Executor`](crate::Executor) in a single
+    /// call. A value of `Some(n)` will split a queued batch with more than
+    /// `n` values into multiple calls to [`Executor::execute`](crate::Executor::execute), each with at
+    /// most `n` values. A value of `None` (the default) will never split a
+    /// batch.
+    ///
+    /// If one of the split calls returns an error, only the callers that
+    /// submitted a value in that call will receive the error.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_batch_size` is `Some(0)`.
+    pub fn max_batch_size(mut self, max_batch_size: Option<usize>) -> Self {
+        assert_ne!(max_batch_size, Some(0), "max_batch_size must be non-zero");
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// The maximum number of batches that can be executed at the same time.
+    /// While fewer than `max_concurrent_batches` batches are being executed,
+    /// the [`BatchExecutor`] will start queueing up the next batch, so a
+    /// slow call to the [`Executor`](crate::Executor) won't hold up new
+    /// values. Defaults to 1, meaning each batch waits for the previous
+    /// batch to finish.
+    ///
+    /// Note that a batch split up by [`max_batch_size`](BatchExecutorBuilder::max_batch_size)
+    /// still counts as a single batch, and its calls to the [`Executor`](crate::Executor)
+    /// are made one after another (unless [`max_concurrent_chunks`](BatchExecutorBuilder::max_concurrent_chunks)
+    /// is set).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_concurrent_batches` is 0.
+    pub fn max_concurrent_batches(mut self, max_concurrent_batches: usize) -> Self {
+        assert_ne!(
+            max_concurrent_batches, 0,
+            "max_concurrent_batches must be non-zero"
+        );
+        self.max_concurrent_batches = max_concurrent_batches;
+        self
+    }
+
+    /// The maximum number of calls to the [`Executor`](crate::Executor)
+    /// to make at the same time for a single batch that was split up by
+    /// [`max_batch_size`](BatchExecutorBuilder::max_batch_size). This
+    /// speeds up large calls to [`execute_many`](BatchExecutor::execute_many),
+    /// such as for bulk imports. Results are still returned in the same
+    /// order as the values. Defaults to 1, meaning each part of the batch
+    /// waits for the previous part to finish.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_concurrent_chunks` is 0.
+    pub fn max_concurrent_chunks(mut self, max_concurrent_chunks: usize) -> Self {
+        assert_ne!(
+            max_concurrent_chunks, 0,
+            "max_concurrent_chunks must be non-zero"
+        );
+        self.max_concurrent_chunks = max_concurrent_chunks;
+        self
+    }
+
+    /// The maximum number of values that can be waiting for results at
+    /// once, across every clone of the [`BatchExecutor`]. A value of
+    /// `Some(n)` makes calls to [`execute`](BatchExecutor::execute) and
+    /// [`execute_many`](BatchExecutor::execute_many) wait for room once `n`
+    /// values are queued or executing, so bursts of values don't grow memory
+    /// use without limit. A value of `None` (the default) never waits.
+    ///
+    /// A single call to [`execute_many`](BatchExecutor::execute_many) with
+    /// more than `n` values waits until no other values are pending.
+    /// Values queued with [`submit`](BatchExecutor::submit) also wait for
+    /// room, and count towards the limit until their batch finishes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_pending_values` is `Some(0)`.
+    pub fn max_pending_values(mut self, max_pending_values: Option<usize>) -> Self {
+        assert_ne!(
+            max_pending_values,
+            Some(0),
+            "max_pending_values must be non-zero"
+        );
+        self.max_pending_values = max_pending_values;
+        self
+    }
+
+    /// Use a custom [`BatchScheduler`] to decide when batches should be
+    /// dispatched. This overrides the [`delay_duration`](BatchExecutorBuilder::delay_duration)
+    /// and [`eager_batch_size`](BatchExecutorBuilder::eager_batch_size) options.
+    pub fn scheduler(mut self, scheduler: impl BatchScheduler + 'static) -> Self {
+        self.scheduler = Some(Arc::new(scheduler));
+        self
+    }
+
+    /// Use a [`BatchMetrics`] implementation to observe batches as they're
+    /// dispatched and completed, such as to report metrics to an
+    /// application's telemetry.
+    pub fn metrics(mut self, metrics: impl BatchMetrics + 'static) -> Self {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Log a warning whenever the [`Executor`](crate::Executor) takes
+    /// longer than `threshold` to execute a batch, including the batch size
+    /// and the label of the `BatchExecutor`. This can help flag a degraded
+    /// datastore early. To handle slow batches some other way, use
+    /// [`BatchMetrics::on_batch_completed`] instead.
+    pub fn warn_if_slower_than(mut self, threshold: Duration) -> Self {
+        self.slow_batch_threshold = Some(threshold);
+        self
+    }
+
+    /// Wrap the [`Executor`](crate::Executor) with an [`ExecutorLayer`],
+    /// such as to add logging, retries, or validation to every batch. Layers
+    /// added later wrap the layers added before them, so the last layer
+    /// added is the first to see each batch.
+    ///
+    /// The wrapped executor must take the same values and return the same
+    /// results as the original executor.
+    pub fn layer<L>(self, layer: L) -> BatchExecutorBuilder<L::Executor>
+    where
+        L: ExecutorLayer<E>,
+        L::Executor: TryExecutor<Value = E::Value, Result = E::Result> + Send + Sync + 'static,
+    {
+        BatchExecutorBuilder {
+            executor: layer.layer(self.executor),
+            delay_duration: self.delay_duration,
+            eager_batch_size: self.eager_batch_size,
+            scheduler: self.scheduler,
+            metrics: self.metrics,
+            slow_batch_threshold: self.slow_batch_threshold,
+            prepare: self.prepare,
+            max_batch_size: self.max_batch_size,
+            max_concurrent_batches: self.max_concurrent_batches,
+            max_concurrent_chunks: self.max_concurrent_chunks,
+            max_pending_values: self.max_pending_values,
+            max_wait_duration: self.max_wait_duration,
+            spawner: self.spawner,
+            timer: self.timer,
+            label: self.label,
+        }
+    }
+
+    /// Use a custom [`Spawner`] to spawn the [`BatchExecutor`]'s background
+    /// tasks, such as to run batches under an executor other than Tokio.
+    /// Defaults to [`TokioRuntime`](crate::TokioRuntime) with the `tokio`
+    /// feature, and must be set otherwise.
+    pub fn spawner(mut self, spawner: impl Spawner + 'static) -> Self {
+        self.spawner = Some(Arc::new(spawner));
+        self
+    }
+
+    /// Use a custom [`Timer`] to wait for the delay set by
+    /// [`delay_duration`](BatchExecutorBuilder::delay_duration) (or by the
+    /// [`scheduler`](BatchExecutorBuilder::scheduler)). Defaults to
+    /// [`TokioRuntime`](crate::TokioRuntime) with the `tokio` feature, and
+    /// must be set otherwise.
+    pub fn timer(mut self, timer: impl Timer + 'static) -> Self {
+        self.timer = Some(Arc::new(timer));
+        self
+    }
+
     /// Set a label for the [`BatchExecutor`]. This is only used to improve
     /// diagnostic messages, such as log messages.
     pub fn label(mut self, label: impl Into<Cow<'static, str>>) -> Self {
@@ -230,137 +695,1018 @@ where
         self
     }
 
+    /// Create and return a [`BatchExecutor`] whose background tasks are spawned
+    /// onto the Tokio runtime for `handle`. Unlike [`finish`](BatchExecutorBuilder::finish),
+    /// this can be called before the runtime has started or from outside of
+    /// it. This is the same as setting a [`spawner`](BatchExecutorBuilder::spawner)
+    /// of [`TokioRuntime::with_handle`](crate::TokioRuntime::with_handle),
+    /// except that if the runtime for `handle` shuts down, the background
+    /// task is restarted on the default runtime. Requires the `tokio`
+    /// feature (enabled by default).
+    #[cfg(feature = "tokio")]
+    pub fn finish_on(self, handle: &tokio::runtime::Handle) -> BatchExecutor<E> {
+        let fallback_spawner = crate::runtime::try_default_spawner();
+        self.spawner(crate::TokioRuntime::with_handle(handle.clone()))
+            .finish_with_fallback(fallback_spawner)
+    }
+
+    /// Create and return a [`BatchExecutor`] that doesn't spawn any background
+    /// tasks, along with a [`BatchDriver`] future that must be polled to
+    /// queue up and dispatch batches. This is useful where spawning detached
+    /// tasks isn't possible or desirable. Any [`spawner`](BatchExecutorBuilder::spawner)
+    /// set on the builder is ignored, but the [`timer`](BatchExecutorBuilder::timer)
+    /// is still used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no [`timer`](BatchExecutorBuilder::timer) was set and the `tokio`
+    /// feature is disabled.
+    pub fn finish_with_driver(self) -> (BatchExecutor<E>, BatchDriver) {
+        let (spawner, driver) = BatchDriver::new();
+        (self.spawner(spawner).finish(), driver)
+    }
+
     /// Create and return a [`BatchExecutor`] with the given options.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no [`spawner`](BatchExecutorBuilder::spawner) or
+    /// [`timer`](BatchExecutorBuilder::timer) was set and the `tokio`
+    /// feature is disabled.
     pub fn finish(self) -> BatchExecutor<E> {
-        let (execute_request_tx, mut execute_request_rx) =
-            tokio::sync::mpsc::channel::<ExecuteRequest<E::Value, E::Result>>(1);
-        let label = self.label.clone();
-
-        let execute_task = tokio::spawn({
-            async move {
-                'task: loop {
-                    // Wait for some values to come in
-                    let mut pending_values = vec![];
-                    let mut result_txs = vec![];
-
-                    tracing::trace!(batch_executor = %self.label, "waiting for values to execute...");
-                    match execute_request_rx.recv().await {
-                        Some(execute_request) => {
-                            tracing::trace!(batch_executor = %self.label, num_execute_request_values = execute_request.values.len(), "received initial execute request");
-
-                            let result_start_index = pending_values.len();
-                            pending_values.extend(execute_request.values);
-
-                            result_txs.push((result_start_index, execute_request.result_tx));
-                        }
-                        None => {
-                            // Execute queue closed, so we're done
-                            break 'task;
-                        }
-                    };
-
-                    // Wait for more values
-                    'wait_for_more_values: loop {
-                        let should_run_batch_now = match self.eager_batch_size {
-                            Some(eager_batch_size) => pending_values.len() >= eager_batch_size,
-                            None => false,
-                        };
-                        if should_run_batch_now {
-                            // We have enough values already, so don't wait for more
-                            tracing::trace!(
-                                batch_executor = %self.label,
-                                num_pending_values = pending_values.len(),
-                                eager_batch_size = ?self.eager_batch_size,
-                                "batch filled up, ready to execute now",
-                            );
-
-                            break 'wait_for_more_values;
-                        }
+        self.finish_with_fallback(None)
+    }
+
+    /// Like [`finish`](BatchExecutorBuilder::finish), but if the background
+    /// task stops right after being restarted with the configured spawner,
+    /// it's restarted again with `fallback_spawner`. Without a fallback, the
+    /// task is only ever spawned with the configured spawner.
+    fn finish_with_fallback(self, fallback_spawner: Option<Arc<dyn Spawner>>) -> BatchExecutor<E> {
+        let scheduler = self.scheduler.unwrap_or_else(|| {
+            Arc::new(DefaultBatchScheduler::new(
+                self.delay_duration,
+                self.eager_batch_size,
+            ))
+        });
+        let execute_task = ExecuteTask {
+            label: self.label,
+            executor: Arc::new(self.executor),
+            scheduler,
+            metrics: self.metrics,
+            slow_batch_threshold: self.slow_batch_threshold,
+            prepare: self.prepare,
+            spawner: self.spawner.unwrap_or_else(default_spawner),
+            timer: self.timer.unwrap_or_else(default_timer),
+            max_batch_size: self.max_batch_size,
+            max_concurrent_batches: self.max_concurrent_batches,
+            max_concurrent_chunks: self.max_concurrent_chunks,
+            max_wait_duration: self.max_wait_duration,
+        };
+        let execute_request_tx = execute_task.spawn();
+
+        // If the execute task stops unexpectedly (such as when the runtime it
+        // was spawned on shuts down), start a new one. If the spawner can't
+        // run it anymore, the new task is only spawned with the fallback
+        // spawner, if there is one
+        let label = execute_task.label.clone();
+        let execute_task_handle = TaskHandle::restartable(
+            execute_request_tx,
+            None,
+            move |use_default_spawner| {
+                let mut execute_task = execute_task.clone();
+                if use_default_spawner {
+                    execute_task.spawner = fallback_spawner.clone()?;
+                }
+                tracing::warn!(batch_executor = %execute_task.label, "execute task stopped unexpectedly, restarting");
+                Some((execute_task.spawn(), None))
+            },
+        );
+
+        BatchExecutor {
+            label,
+            execute_task: Arc::new(execute_task_handle),
+            pending_values_limit: self
+                .max_pending_values
+                .map(|max_pending_values| Arc::new(PendingValuesLimit::new(max_pending_values))),
+        }
+    }
+}
+
+/// The background task for a [`BatchExecutor`], which queues up values and
+/// dispatches them in batches. This holds everything needed to start the
+/// task, so it can be started again if it stops.
+struct ExecuteTask<E>
+where
+    E: TryExecutor,
+{
+    label: Cow<'static, str>,
+    executor: Arc<E>,
+    scheduler: Arc<dyn BatchScheduler>,
+    metrics: Option<Arc<dyn BatchMetrics>>,
+    slow_batch_threshold: Option<Duration>,
+    prepare: Option<PrepareValues<E::Value, E::Result>>,
+    spawner: Arc<dyn Spawner>,
+    timer: Arc<dyn Timer>,
+    max_batch_size: Option<usize>,
+    max_concurrent_batches: usize,
+    max_concurrent_chunks: usize,
+    max_wait_duration: Option<Duration>,
+}
+
+impl<E> Clone for ExecuteTask<E>
+where
+    E: TryExecutor,
+{
+    fn clone(&self) -> Self {
+        ExecuteTask {
+            label: self.label.clone(),
+            executor: self.executor.clone(),
+            scheduler: self.scheduler.clone(),
+            metrics: self.metrics.clone(),
+            slow_batch_threshold: self.slow_batch_threshold,
+            prepare: self.prepare.clone(),
+            spawner: self.spawner.clone(),
+            timer: self.timer.clone(),
+            max_batch_size: self.max_batch_size,
+            max_concurrent_batches: self.max_concurrent_batches,
+            max_concurrent_chunks: self.max_concurrent_chunks,
+            max_wait_duration: self.max_wait_duration,
+        }
+    }
+}
+
+impl<E> ExecuteTask<E>
+where
+    E: TryExecutor + Send + Sync + 'static,
+{
+    /// Spawn a new run of the task, returning the sending half of its queue.
+    ///
+    /// The task isn't aborted when the last `BatchExecutor` clone is
+    /// dropped. Instead, the queue closes, and the task dispatches any values
+    /// that were already queued (such as from [`submit`](BatchExecutor::submit))
+    /// before it stops.
+    fn spawn(&self) -> ExecuteRequestSender<E::Value, E::Result> {
+        let (execute_request_tx, execute_request_rx) =
+            tokio::sync::mpsc::channel::<ExecuteMessage<E::Value, E::Result>>(1);
+
+        let task = self
+            .clone()
+            .run(execute_request_rx)
+            .instrument(tracing::info_span!("execute_task", batch_executor = %self.label));
+        let task_name = format!("ultra-batch executor {}", self.label);
+        self.spawner.spawn_named(&task_name, Box::pin(task));
+        execute_request_tx
+    }
+
+    async fn run(
+        self,
+        mut execute_request_rx: tokio::sync::mpsc::Receiver<ExecuteMessage<E::Value, E::Result>>,
+    ) {
+        let ExecuteTask {
+            label,
+            executor,
+            scheduler,
+            metrics,
+            slow_batch_threshold,
+            prepare,
+            spawner,
+            timer,
+            max_batch_size,
+            max_concurrent_batches,
+            max_concurrent_chunks,
+            max_wait_duration,
+        } = self;
+        let in_flight_batches = InFlightBatches::new(max_concurrent_batches);
+        let mut shutdown_txs = vec![];
+
+        'task: loop {
+            // Wait for the in-flight batches to make room before
+            // starting a new batch
+            let permit = in_flight_batches.acquire().await;
 
-                        let delay = tokio::time::sleep(self.delay_duration);
-                        tokio::pin!(delay);
+            // Wait for some values to come in
+            let mut pending_values = vec![];
+            let mut result_txs = vec![];
+            let mut pending_values_permits = vec![];
+            let batch_context;
 
-                        tokio::select! {
-                            execute_request = execute_request_rx.recv() => {
-                                match execute_request {
-                                    Some(execute_request) => {
-                                        tracing::trace!(batch_executor = %self.label, num_execute_request_values = execute_request.values.len(), "retrieved additional execute request");
+            tracing::trace!(batch_executor = %label, "waiting for values to execute...");
+            loop {
+                match execute_request_rx.recv().await {
+                    Some(ExecuteMessage::Execute(execute_request)) => {
+                        tracing::trace!(batch_executor = %label, num_execute_request_values = execute_request.values.len(), "received initial execute request");
 
+                        batch_context = execute_request.context;
+
+                        let result_start_index = pending_values.len();
+                        pending_values.extend(execute_request.values);
+                        record_queue_depth(&metrics, &label, pending_values.len());
+
+                        result_txs.push((result_start_index, execute_request.result_tx));
+                        pending_values_permits.extend(execute_request.pending_values_permit);
+                        break;
+                    }
+                    Some(ExecuteMessage::Flush) => {
+                        // No values queued, so there's nothing to flush
+                        tracing::trace!(batch_executor = %label, "received flush with no pending values");
+                    }
+                    Some(ExecuteMessage::Shutdown(shutdown_tx)) => {
+                        // Stop accepting new requests, but keep
+                        // executing any that were already queued
+                        tracing::debug!(batch_executor = %label, "received shutdown, closing execute channel");
+                        execute_request_rx.close();
+                        shutdown_txs.push(shutdown_tx);
+                    }
+                    None => {
+                        // Execute queue closed, so we're done
+                        break 'task;
+                    }
+                }
+            }
 
-                                        let result_start_index = pending_values.len();
-                                        pending_values.extend(execute_request.values);
+            let batch_started_at = Instant::now();
+            let mut delay = DelayTimer::new(timer.clone());
 
-                                        result_txs.push((result_start_index, execute_request.result_tx));
-                                    }
-                                    None => {
-                                        // Executor queue closed, so we're done waiting for keys
-                                        tracing::debug!(batch_executor = %self.label, num_pending_values = pending_values.len(), "execute channel closed");
-                                        break 'wait_for_more_values;
-                                    }
-                                }
+            // Wait for more values
+            'wait_for_more_values: loop {
+                let pending_batch = PendingBatch {
+                    len: pending_values.len(),
+                    num_requests: result_txs.len(),
+                    elapsed: batch_started_at.elapsed(),
+                };
 
+                let schedule = match max_wait_duration {
+                    Some(max_wait_duration) if pending_batch.elapsed >= max_wait_duration => {
+                        Schedule::DispatchNow
+                    }
+                    Some(max_wait_duration) => match scheduler.schedule(&pending_batch) {
+                        // Don't wait past the maximum wait duration
+                        Schedule::WaitFor(delay_duration) => Schedule::WaitFor(
+                            delay_duration.min(max_wait_duration - pending_batch.elapsed),
+                        ),
+                        schedule => schedule,
+                    },
+                    None => scheduler.schedule(&pending_batch),
+                };
+
+                let execute_message = match schedule {
+                    Schedule::DispatchNow => {
+                        // The batch is ready, so don't wait for more values
+                        tracing::trace!(
+                            batch_executor = %label,
+                            num_pending_values = pending_values.len(),
+                            "batch scheduled, ready to execute now",
+                        );
+                        break 'wait_for_more_values;
+                    }
+                    Schedule::WaitFor(delay_duration) => {
+                        delay
+                            .sleep_until(batch_started_at + pending_batch.elapsed + delay_duration);
+                        let execute_message = std::pin::pin!(execute_request_rx.recv());
+
+                        match futures_util::future::select(execute_message, &mut delay).await {
+                            Either::Left((execute_message, _)) => execute_message,
+                            Either::Right(((), _)) => {
+                                // Reached delay, so we're done waiting for values
+                                tracing::trace!(
+                                    batch_executor = %label,
+                                    num_pending_values = pending_values.len(),
+                                    "delay reached while waiting for more values to execute"
+                                );
+                                break 'wait_for_more_values;
                             }
-                            _ = &mut delay => {
-                                // Reached delay, so we're done waiting for keys
+                        }
+                    }
+                    Schedule::WaitForNextTick => {
+                        // Let other tasks run, then only take values
+                        // that were queued in the meantime
+                        spawner.yield_now().await;
+
+                        match execute_request_rx.try_recv() {
+                            Ok(execute_message) => Some(execute_message),
+                            Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {
                                 tracing::trace!(
-                                    batch_executor = %self.label,
+                                    batch_executor = %label,
                                     num_pending_values = pending_values.len(),
-                                    "delay reached while waiting for more values to fetch"
+                                    "no more values queued after yielding"
                                 );
                                 break 'wait_for_more_values;
                             }
-                        };
+                            Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => None,
+                        }
                     }
+                };
 
-                    tracing::trace!(batch_executor = %self.label, num_pending_values = pending_values.len(), num_pending_channels = result_txs.len(), "fetching values");
-                    let mut result = self
-                        .executor
-                        .execute(pending_values)
-                        .await
-                        .map_err(|error| error.to_string());
+                match execute_message {
+                    Some(ExecuteMessage::Execute(execute_request)) => {
+                        tracing::trace!(batch_executor = %label, num_execute_request_values = execute_request.values.len(), "retrieved additional execute request");
 
-                    for (result_range, result_tx) in result_txs.into_iter().rev() {
-                        let result = match &mut result {
-                            Ok(result) => {
-                                if result_range <= result.len() {
-                                    Ok(result.split_off(result_range))
-                                } else {
-                                    Ok(vec![])
-                                }
-                            }
-                            Err(error) => Err(error.clone()),
-                        };
+                        let result_start_index = pending_values.len();
+                        pending_values.extend(execute_request.values);
+                        record_queue_depth(&metrics, &label, pending_values.len());
 
-                        // Ignore error if receiver was already closed
-                        let _ = result_tx.send(result);
+                        result_txs.push((result_start_index, execute_request.result_tx));
+                        pending_values_permits.extend(execute_request.pending_values_permit);
+                    }
+                    Some(ExecuteMessage::Flush) => {
+                        // Caller asked to dispatch the batch now
+                        tracing::trace!(batch_executor = %label, num_pending_values = pending_values.len(), "flushing pending values");
+                        break 'wait_for_more_values;
+                    }
+                    Some(ExecuteMessage::Shutdown(shutdown_tx)) => {
+                        // Stop accepting new requests, then keep
+                        // collecting values until the queue is drained
+                        tracing::debug!(batch_executor = %label, num_pending_values = pending_values.len(), "received shutdown, closing execute channel");
+                        execute_request_rx.close();
+                        shutdown_txs.push(shutdown_tx);
+                    }
+                    None => {
+                        // Executor queue closed, so we're done waiting for values
+                        tracing::debug!(batch_executor = %label, num_pending_values = pending_values.len(), "execute channel closed");
+                        break 'wait_for_more_values;
                     }
                 }
             }
-        });
 
-        BatchExecutor {
+            tracing::trace!(batch_executor = %label, num_pending_values = pending_values.len(), num_pending_channels = result_txs.len(), "fetching values");
+            record_queue_depth(&metrics, &label, 0);
+            let batch = ExecuteBatch {
+                label: label.clone(),
+                executor: executor.clone(),
+                scheduler: scheduler.clone(),
+                metrics: metrics.clone(),
+                slow_batch_threshold,
+                prepare: prepare.clone(),
+                context: batch_context,
+                max_batch_size,
+                max_concurrent_chunks,
+                wait_duration: batch_started_at.elapsed(),
+                values: pending_values,
+                result_txs,
+            };
+            spawner.spawn(Box::pin(async move {
+                batch.run().await;
+                drop(permit);
+                drop(pending_values_permits);
+            }));
+        }
+
+        // Let any in-flight batches finish before reporting that
+        // we've shut down
+        if !shutdown_txs.is_empty() {
+            in_flight_batches.wait_for_all().await;
+            tracing::debug!(batch_executor = %label, "finished shutting down");
+            for shutdown_tx in shutdown_txs {
+                let _ = shutdown_tx.send(());
+            }
+        }
+    }
+}
+
+impl<E> BatchExecutorBuilder<E>
+where
+    E: TryExecutor + Send + Sync + 'static,
+    E::Value: Hash + Eq,
+    E::Result: Clone,
+{
+    /// Execute identical values only once per batch. When enabled, duplicate
+    /// values queued in the same batch (whether from the same call to
+    /// [`execute_many`](BatchExecutor::execute_many) or from separate
+    /// callers) are only passed to the [`Executor`](crate::Executor) once, and the result
+    /// for that value is cloned and returned to each caller that submitted
+    /// it. This mirrors how [`BatchFetcher`](crate::BatchFetcher)
+    /// deduplicates keys.
+    pub fn deduplicate_values(mut self) -> Self {
+        let prepare = self.prepare.get_or_insert_with(PrepareValues::new);
+        prepare.dedup_values = Some(dedup_values::<E::Value>);
+        self
+    }
+}
+
+impl<E> BatchExecutorBuilder<E>
+where
+    E: TryExecutor + Send + Sync + 'static,
+    E::Result: Clone,
+{
+    /// Set a hook to sort or merge the pending values in each batch just
+    /// before they're passed to the [`Executor`](crate::Executor). For
+    /// example, the hook could sort rows by primary key for better locality
+    /// in a database, or merge multiple upserts for the same row into one.
+    /// Each caller still receives the result for each of the values it
+    /// submitted, and callers whose values were merged together each receive
+    /// a clone of the merged value's result.
+    ///
+    /// The hook runs after [`deduplicate_values`](BatchExecutorBuilder::deduplicate_values)
+    /// (if enabled), and before the batch is split by [`max_batch_size`](BatchExecutorBuilder::max_batch_size).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchExecutor, Executor};
+    /// # #[derive(Clone)] struct Upsert { id: u64, name: String }
+    /// # struct UserUpserter;
+    /// # impl Executor for UserUpserter {
+    /// #     type Value = Upsert;
+    /// #     type Result = ();
+    /// #     type Error = anyhow::Error;
+    /// #     async fn execute(&self, values: Vec<Upsert>) -> anyhow::Result<Vec<()>> {
+    /// #         unimplemented!();
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_upserter = BatchExecutor::build(UserUpserter)
+    ///     .prepare_values(|values| {
+    ///         // Sort upserts by ID, then only keep the last upsert for each ID
+    ///         values.sort_by_key(|upsert| upsert.id);
+    ///         values.coalesce(|previous, next| {
+    ///             if previous.id == next.id {
+    ///                 *previous = next;
+    ///                 Ok(())
+    ///             } else {
+    ///                 Err(next)
+    ///             }
+    ///         });
+    ///     })
+    ///     .finish();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn prepare_values(
+        mut self,
+        prepare_values: impl Fn(&mut PendingValues<E::Value>) + Send + Sync + 'static,
+    ) -> Self {
+        let prepare = self.prepare.get_or_insert_with(PrepareValues::new);
+        prepare.prepare_values = Some(Arc::new(prepare_values));
+        self
+    }
+
+    /// Remember the result for each value for `ttl`, so submitting a value
+    /// again within that window returns the remembered result instead of
+    /// executing it again. This is meant for idempotent operations that are
+    /// expensive to run. `key` is called with each value to identify the
+    /// logical operation it represents, and values with equal keys share a
+    /// result.
+    ///
+    /// Only successful results are remembered. Values are still queued into
+    /// batches as usual, and only values without a remembered result are
+    /// passed to the [`Executor`](crate::Executor).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::BatchExecutor;
+    /// # #[derive(Clone)] struct Report { user_id: u64 }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_generator = BatchExecutor::from_fn(|reports: Vec<Report>| async move {
+    ///     // Expensive, but always returns the same result for the same user
+    ///     anyhow::Ok(reports.iter().map(|report| report.user_id * 2).collect::<Vec<_>>())
+    /// })
+    /// .memoize_results(|report| report.user_id, tokio::time::Duration::from_secs(60))
+    /// .finish();
+    ///
+    /// assert_eq!(batch_generator.execute(Report { user_id: 1 }).await?, Some(2));
+    ///
+    /// // Returns the remembered result without calling the executor again
+    /// assert_eq!(batch_generator.execute(Report { user_id: 1 }).await?, Some(2));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn memoize_results<K>(
+        self,
+        key: impl Fn(&E::Value) -> K + Send + Sync + 'static,
+        ttl: Duration,
+    ) -> BatchExecutorBuilder<Memoized<E, K>>
+    where
+        E::Error: Send,
+        K: Hash + Eq + Send + 'static,
+    {
+        self.layer(MemoizeLayer {
+            key: Arc::new(key),
+            ttl,
+        })
+    }
+
+    /// After each batch, insert every successful result into the cache of
+    /// `batch_fetcher`, so loading a value that was just written doesn't
+    /// need to fetch it again. `cache_entry` is called with each result to
+    /// get the key and value to cache.
+    ///
+    /// A load for the same key that was already being fetched when the
+    /// batch was executed may overwrite the cached result with the value
+    /// it fetched. See [`WriteThrough`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchExecutor, BatchFetcher};
+    /// # use std::collections::HashMap;
+    /// # #[derive(Clone)] struct User { id: u64, name: String }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let user_loader = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+    ///     // Would load users from the database
+    ///     anyhow::Ok(HashMap::<u64, User>::new())
+    /// })
+    /// .finish();
+    ///
+    /// let user_inserter = BatchExecutor::from_fn(|users: Vec<User>| async move {
+    ///     // Would insert users into the database
+    ///     anyhow::Ok(users)
+    /// })
+    /// .write_through(user_loader.clone(), |user| (user.id, user.clone()))
+    /// .finish();
+    ///
+    /// let new_user = User { id: 1, name: "Alice".to_string() };
+    /// user_inserter.execute(new_user).await?;
+    ///
+    /// // Loaded from the cache, without calling the fetcher
+    /// let user = user_loader.load(1).await?;
+    /// assert_eq!(user.name, "Alice");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn write_through<F>(
+        self,
+        batch_fetcher: BatchFetcher<F>,
+        cache_entry: impl Fn(&E::Result) -> (F::Key, F::Value) + Send + Sync + 'static,
+    ) -> BatchExecutorBuilder<WriteThrough<E, F>>
+    where
+        F: LocalFetcher + 'static,
+    {
+        self.layer(WriteThroughLayer {
+            batch_fetcher,
+            cache_entry: Arc::new(cache_entry),
+        })
+    }
+}
+
+/// The values queued in a batch, passed to the hook set with
+/// [`BatchExecutorBuilder::prepare_values`]. Values can be modified,
+/// reordered, or merged, and [`BatchExecutor`] keeps track of which
+/// value each caller's result comes from.
+#[derive(Debug)]
+pub struct PendingValues<V> {
+    values: Vec<V>,
+    value_indices: Vec<usize>,
+}
+
+impl<V> PendingValues<V> {
+    fn new(values: Vec<V>) -> Self {
+        let value_indices = (0..values.len()).collect();
+        PendingValues {
+            values,
+            value_indices,
+        }
+    }
+
+    /// Get the pending values, in the order they'll be executed.
+    pub fn values(&self) -> &[V] {
+        &self.values
+    }
+
+    /// Get mutable access to the pending values, such as to update values
+    /// in place.
+    pub fn values_mut(&mut self) -> &mut [V] {
+        &mut self.values
+    }
+
+    /// The number of pending values.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns `true` if there are no pending values.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Sort the pending values with a comparison function. The sort is
+    /// stable, so equal values keep their relative order.
+    pub fn sort_by(&mut self, mut compare: impl FnMut(&V, &V) -> std::cmp::Ordering) {
+        let mut order: Vec<usize> = (0..self.values.len()).collect();
+        order.sort_by(|&a, &b| compare(&self.values[a], &self.values[b]));
+
+        let mut values: Vec<_> = std::mem::take(&mut self.values)
+            .into_iter()
+            .map(Some)
+            .collect();
+        let mut positions = vec![0; order.len()];
+        let sorted_values = order
+            .into_iter()
+            .enumerate()
+            .map(|(position, index)| {
+                positions[index] = position;
+                values[index].take().expect("value already taken")
+            })
+            .collect();
+
+        self.remap(sorted_values, &positions);
+    }
+
+    /// Sort the pending values with a key extraction function. The sort is
+    /// stable, so values with equal keys keep their relative order.
+    pub fn sort_by_key<K>(&mut self, mut f: impl FnMut(&V) -> K)
+    where
+        K: Ord,
+    {
+        self.sort_by(|a, b| f(a).cmp(&f(b)));
+    }
+
+    /// Merge adjacent pending values. `merge` is called with each value and
+    /// the value before it: returning `Ok(())` means the value was merged
+    /// into the previous value, and returning `Err(value)` keeps the value
+    /// separate. Callers of merged values each receive a clone of the
+    /// result for the merged value.
+    pub fn coalesce(&mut self, mut merge: impl FnMut(&mut V, V) -> Result<(), V>) {
+        let mut merged_values: Vec<V> = Vec::with_capacity(self.values.len());
+        let positions: Vec<_> = std::mem::take(&mut self.values)
+            .into_iter()
+            .map(|value| {
+                let value = match merged_values.last_mut() {
+                    Some(previous) => match merge(previous, value) {
+                        Ok(()) => return merged_values.len() - 1,
+                        Err(value) => value,
+                    },
+                    None => value,
+                };
+                merged_values.push(value);
+                merged_values.len() - 1
+            })
+            .collect();
+
+        self.remap(merged_values, &positions);
+    }
+
+    /// Replace the values, where `positions` maps the index of each old value
+    /// to its index in the new values.
+    fn remap(&mut self, values: Vec<V>, positions: &[usize]) {
+        self.values = values;
+        for index in &mut self.value_indices {
+            *index = positions[*index];
+        }
+    }
+}
+
+/// Limits the number of values waiting for results from a [`BatchExecutor`],
+/// shared between each of its clones.
+struct PendingValuesLimit {
+    semaphore: Arc<tokio::sync::Semaphore>,
+    max_pending_values: u32,
+}
+
+impl PendingValuesLimit {
+    fn new(max_pending_values: usize) -> Self {
+        let max_pending_values = max_pending_values
+            .min(tokio::sync::Semaphore::MAX_PERMITS)
+            .try_into()
+            .unwrap_or(u32::MAX);
+        PendingValuesLimit {
+            semaphore: Arc::new(tokio::sync::Semaphore::new(max_pending_values as usize)),
+            max_pending_values,
+        }
+    }
+
+    /// Wait until there's room for `num_values` more values. Requests with
+    /// more values than the limit wait until no other values are pending.
+    async fn acquire(&self, num_values: usize) -> tokio::sync::OwnedSemaphorePermit {
+        let num_permits = u32::try_from(num_values)
+            .unwrap_or(u32::MAX)
+            .min(self.max_pending_values);
+        self.semaphore
+            .clone()
+            .acquire_many_owned(num_permits)
+            .await
+            .expect("pending values semaphore closed")
+    }
+}
+
+type ResultSender<R> = tokio::sync::oneshot::Sender<Result<Vec<R>, String>>;
+
+type ExecuteRequestSender<V, R> = tokio::sync::mpsc::Sender<ExecuteMessage<V, R>>;
+
+/// A batch of values that's ready to be executed, along with everything
+/// needed to execute it.
+struct ExecuteBatch<E>
+where
+    E: TryExecutor,
+{
+    label: Cow<'static, str>,
+    executor: Arc<E>,
+    scheduler: Arc<dyn BatchScheduler>,
+    metrics: Option<Arc<dyn BatchMetrics>>,
+    slow_batch_threshold: Option<Duration>,
+    prepare: Option<PrepareValues<E::Value, E::Result>>,
+    context: CallerContext,
+    max_batch_size: Option<usize>,
+    max_concurrent_chunks: usize,
+    wait_duration: Duration,
+    values: Vec<E::Value>,
+    result_txs: Vec<(usize, ResultSender<E::Result>)>,
+}
+
+impl<E> ExecuteBatch<E>
+where
+    E: TryExecutor,
+{
+    async fn run(self) {
+        let ExecuteBatch {
             label,
-            _execute_task: Arc::new(execute_task),
-            execute_request_tx,
+            executor,
+            scheduler,
+            metrics,
+            slow_batch_threshold,
+            prepare,
+            context,
+            max_batch_size,
+            max_concurrent_chunks,
+            wait_duration,
+            values,
+            result_txs,
+        } = self;
+        let num_submitted_values = values.len();
+
+        let (values, value_indices) = match &prepare {
+            Some(prepare) => {
+                let mut pending_values = PendingValues::new(values);
+                if let Some(dedup_values) = prepare.dedup_values {
+                    dedup_values(&mut pending_values);
+                }
+                if let Some(prepare_values) = &prepare.prepare_values {
+                    prepare_values(&mut pending_values);
+                }
+
+                (pending_values.values, Some(pending_values.value_indices))
+            }
+            None => (values, None),
+        };
+
+        let mut outcomes = Vec::with_capacity(values.len());
+        let mut errors = vec![];
+        let max_batch_size = max_batch_size.unwrap_or(values.len());
+        let mut values = values.into_iter().peekable();
+        let mut chunks = vec![];
+        while values.peek().is_some() {
+            let chunk_start_index = chunks.len() * max_batch_size;
+            let chunk_values: Vec<_> = values.by_ref().take(max_batch_size).collect();
+            chunks.push((chunk_start_index, chunk_values));
+        }
+
+        // Execute each chunk, then gather the results in order
+        let mut chunk_results = futures_util::stream::iter(chunks)
+            .map(|(chunk_start_index, batch_values)| {
+                let num_batch_values = batch_values.len();
+
+                if let Some(metrics) = &metrics {
+                    metrics.on_batch_dispatched(&DispatchedBatch {
+                        label: &label,
+                        size: num_batch_values,
+                        waiters: count_waiters(
+                            &result_txs,
+                            value_indices.as_deref(),
+                            num_submitted_values,
+                            chunk_start_index..chunk_start_index + num_batch_values,
+                        ),
+                    });
+                }
+
+                let execute_started_at = Instant::now();
+                let result = context.clone().scope(|| executor.try_execute(batch_values));
+                async move {
+                    // Only keep the error messages, so the results can be
+                    // held while waiting on other chunks
+                    let result = result.await;
+                    let duration = execute_started_at.elapsed();
+                    let result = result
+                        .map(|results| {
+                            results
+                                .into_iter()
+                                .map(|result| result.map_err(|error| error.to_string()))
+                                .collect::<Vec<_>>()
+                        })
+                        .map_err(|error| error.to_string());
+                    (num_batch_values, duration, result)
+                }
+            })
+            .buffered(max_concurrent_chunks);
+
+        while let Some((num_batch_values, duration, result)) = chunk_results.next().await {
+            if let Some(threshold) = slow_batch_threshold {
+                if duration > threshold {
+                    tracing::warn!(
+                        batch_executor = %label,
+                        num_values = num_batch_values,
+                        ?duration,
+                        ?threshold,
+                        "batch took longer than the slow batch threshold",
+                    );
+                }
+            }
+            scheduler.batch_completed(&CompletedBatch {
+                len: num_batch_values,
+                wait_duration,
+                duration,
+            });
+
+            if let Some(metrics) = &metrics {
+                metrics.on_batch_completed(&FinishedBatch {
+                    label: &label,
+                    size: num_batch_values,
+                    duration,
+                    result: result.as_ref().map(|_| ()).map_err(|error| error.as_str()),
+                });
+            }
+
+            match result {
+                Ok(results) => {
+                    // Pad out missing results so later values stay aligned
+                    let results = results
+                        .into_iter()
+                        .map(|result| match result {
+                            Ok(result) => Ok(Some(result)),
+                            Err(error) => {
+                                let error_index = errors.len();
+                                errors.push(error);
+                                Err(error_index)
+                            }
+                        })
+                        .chain(std::iter::repeat_with(|| Ok(None)));
+                    outcomes.extend(results.take(num_batch_values));
+                }
+                Err(error) => {
+                    let error_index = errors.len();
+                    errors.push(error);
+                    outcomes
+                        .extend(std::iter::repeat_with(|| Err(error_index)).take(num_batch_values));
+                }
+            }
+        }
+        drop(chunk_results);
+
+        let mut outcomes = match (prepare, value_indices) {
+            (Some(prepare), Some(value_indices)) => {
+                (prepare.expand_outcomes)(outcomes, &value_indices)
+            }
+            _ => outcomes,
+        };
+
+        for (result_start_index, result_tx) in result_txs.into_iter().rev() {
+            let request_outcomes = outcomes.split_off(result_start_index.min(outcomes.len()));
+            let result = request_result(request_outcomes, &errors);
+
+            // Ignore error if receiver was already closed
+            let _ = result_tx.send(result);
+        }
+    }
+}
+
+/// Count the requests that submitted at least one of the values in `range`
+/// (indices into the prepared values).
+fn count_waiters<R>(
+    result_txs: &[(usize, ResultSender<R>)],
+    value_indices: Option<&[usize]>,
+    num_submitted_values: usize,
+    range: std::ops::Range<usize>,
+) -> usize {
+    let request_ends = result_txs
+        .iter()
+        .skip(1)
+        .map(|(start_index, _)| *start_index)
+        .chain([num_submitted_values]);
+    result_txs
+        .iter()
+        .zip(request_ends)
+        .filter(|((start_index, _), end_index)| {
+            (*start_index..*end_index).any(|submitted_index| {
+                let value_index = match value_indices {
+                    Some(value_indices) => value_indices[submitted_index],
+                    None => submitted_index,
+                };
+                range.contains(&value_index)
+            })
+        })
+        .count()
+}
+
+/// Type-erased functions used to deduplicate or otherwise prepare values,
+/// so the worker doesn't need `Hash`/`Eq`/`Clone` bounds unless one of these
+/// options is enabled.
+struct PrepareValues<V, R> {
+    dedup_values: Option<fn(&mut PendingValues<V>)>,
+    prepare_values: Option<PrepareValuesFn<V>>,
+    expand_outcomes: fn(ValueOutcomes<R>, &[usize]) -> ValueOutcomes<R>,
+}
+
+type PrepareValuesFn<V> = Arc<dyn Fn(&mut PendingValues<V>) + Send + Sync>;
+
+impl<V, R> PrepareValues<V, R>
+where
+    R: Clone,
+{
+    fn new() -> Self {
+        PrepareValues {
+            dedup_values: None,
+            prepare_values: None,
+            expand_outcomes: expand_outcomes::<R>,
         }
     }
 }
 
+impl<V, R> Clone for PrepareValues<V, R> {
+    fn clone(&self) -> Self {
+        PrepareValues {
+            dedup_values: self.dedup_values,
+            prepare_values: self.prepare_values.clone(),
+            expand_outcomes: self.expand_outcomes,
+        }
+    }
+}
+
+/// Merge identical values, keeping the unique values in order of first
+/// appearance.
+fn dedup_values<V>(pending_values: &mut PendingValues<V>)
+where
+    V: Hash + Eq,
+{
+    let mut unique_indices = HashMap::new();
+    let positions: Vec<_> = std::mem::take(&mut pending_values.values)
+        .into_iter()
+        .map(|value| {
+            let next_index = unique_indices.len();
+            *unique_indices.entry(value).or_insert(next_index)
+        })
+        .collect();
+
+    let mut unique_values: Vec<_> = unique_indices.into_iter().collect();
+    unique_values.sort_unstable_by_key(|(_, index)| *index);
+    let unique_values = unique_values.into_iter().map(|(value, _)| value).collect();
+
+    pending_values.remap(unique_values, &positions);
+}
+
+/// Maps the outcomes for the prepared values back to each of the original
+/// values.
+fn expand_outcomes<R>(outcomes: ValueOutcomes<R>, value_indices: &[usize]) -> ValueOutcomes<R>
+where
+    R: Clone,
+{
+    value_indices
+        .iter()
+        .map(|&index| outcomes[index].clone())
+        .collect()
+}
+
+/// The outcome of executing each value: either the result returned by the
+/// [`Executor`](crate::Executor) (if any), or the index of the error returned for the batch
+/// the value was executed in.
+type ValueOutcomes<R> = Vec<Result<Option<R>, usize>>;
+
+/// Build the result for a single request from the outcomes of its values.
+/// The request fails if any of its values failed, and otherwise gets the
+/// results up to the first value without a result.
+fn request_result<R>(outcomes: ValueOutcomes<R>, errors: &[String]) -> Result<Vec<R>, String> {
+    if let Some(&error_index) = outcomes.iter().find_map(|outcome| outcome.as_ref().err()) {
+        return Err(errors[error_index].clone());
+    }
+
+    let results = outcomes
+        .into_iter()
+        .map_while(|outcome| outcome.ok().flatten())
+        .collect();
+    Ok(results)
+}
+
+enum ExecuteMessage<V, R> {
+    Execute(ExecuteRequest<V, R>),
+    Flush,
+    Shutdown(tokio::sync::oneshot::Sender<()>),
+}
+
 struct ExecuteRequest<V, R> {
     values: Vec<V>,
-    result_tx: tokio::sync::oneshot::Sender<Result<Vec<R>, String>>,
+    result_tx: ResultSender<R>,
+
+    /// Room reserved for the values with [`BatchExecutorBuilder::max_pending_values`],
+    /// held until the batch finishes. Only set by [`BatchExecutor::submit`],
+    /// since other callers hold onto it while waiting for their results.
+    pending_values_permit: Option<tokio::sync::OwnedSemaphorePermit>,
+
+    /// The caller's context, propagated into the executor if this is the
+    /// first request in a batch.
+    context: CallerContext,
 }
 
 /// Error indicating that execution of one or more values from a
 /// [`BatchExecutor`] failed.
 #[derive(Debug, thiserror::Error)]
 pub enum ExecuteError {
-    /// The [`Executor`] returned an error while loading the batch. The message
-    /// contains the error message specified by [`Executor::Error`].
+    /// The [`Executor`](crate::Executor) returned an error while loading the batch,
+    /// or the [`TryExecutor`] returned an error for one of the submitted
+    /// values. The message contains the error message specified by
+    /// [`Executor::Error`](crate::Executor::Error).
     #[error("error while executing batch: {}", _0)]
     ExecutorError(String),
 
-    /// The request could not be sent to the [`BatchExecutor`].
+    /// The request could not be sent to the [`BatchExecutor`], or its
+    /// background task stopped before the request finished.
     #[error("error sending execution request")]
     SendError,
 }
@@ -1,8 +1,34 @@
-use crate::cache::{CacheLookup, CacheLookupState, CacheStore};
-use crate::Fetcher;
-use std::borrow::Cow;
-use std::collections::HashSet;
-use std::sync::Arc;
+use crate::cache::{lock, CacheKind, CacheLookup, CacheLookupState, CacheState, CacheStore};
+use crate::context::CallerContext;
+use crate::metrics::record_queue_depth;
+use crate::ordered_map::OrderedMap;
+#[cfg(feature = "tokio")]
+use crate::runtime::spawn_local_named;
+use crate::runtime::{
+    default_spawner, default_timer, AbortOnDrop, BatchDriver, DelayTimer, InFlightBatches, Instant,
+    TaskHandle,
+};
+use crate::scheduler::BatchDelay;
+use crate::{
+    BatchMetrics, BatchScheduler, CacheAccess, CacheEvent, CacheSnapshot, CompletedBatch,
+    DefaultBatchScheduler, DispatchedBatch, Exclusive, ExclusiveFetcher, Fetcher, FinishedBatch,
+    FnFetcher, LocalFetcher, MapFetcher, MapFetcherAdapter, PairsFetcher, PairsFetcherAdapter,
+    PendingBatch, Schedule, SharedFetcher, SharedFetcherAdapter, Spawner, Timer,
+};
+use futures_util::future::Either;
+use futures_util::stream::{FuturesUnordered, Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use std::borrow::{Borrow, Cow};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tracing::Instrument;
 
 /// Batches and caches loads from some datastore. A `BatchFetcher` can be
 /// used with any type that implements [`Fetcher`]. `BatchFetcher`s are
@@ -17,9 +43,11 @@ use std::sync::Arc;
 /// A `BatchFetcher` is designed to be ephemeral. In the context of a web
 /// service, this means callers should most likely create a new `BatchFetcher`
 /// for each request, and **not** a `BatchFetcher` shared across multiple
-/// requests. `BatchFetcher`s have no concept of cache invalidation, so old
-/// values are stored indefinitely (which means callers may get stale data or
-/// may exhaust memory endlessly).
+/// requests. Values are stored indefinitely unless they're explicitly
+/// invalidated (with [`invalidate`](BatchFetcher::invalidate) or
+/// [`invalidate_from`](BatchFetcher::invalidate_from)), which means callers
+/// of a long-lived `BatchFetcher` may get stale data or may exhaust memory
+/// endlessly.
 ///
 /// `BatchFetcher`s introduce a small amount of latency for loads. Each time a
 /// `BatchFetcher` receives a key to fetch that hasn't been cached (or a set of
@@ -39,6 +67,11 @@ use std::sync::Arc;
 /// requests will fail. Subsequent calls to [`load`](BatchFetcher::load) or
 /// [`load_many`](BatchFetcher::load_many) with the same keys **will retry**.
 ///
+/// If every caller waiting on a key stops waiting before its batch is
+/// dispatched (for example, because the future returned by
+/// [`load`](BatchFetcher::load) was dropped), then that key will be removed
+/// from the batch and won't be fetched.
+///
 /// If the underlying [`Fetcher`] succeeds but does not return a value for a
 /// given key during a batch request, then the `BatchFetcher` will mark that key
 /// as "not found" and an error value of [`NotFound`](LoadError::NotFound) will
@@ -48,17 +81,20 @@ use std::sync::Arc;
 /// not retry**.
 pub struct BatchFetcher<F>
 where
-    F: Fetcher,
+    F: LocalFetcher,
 {
     label: Cow<'static, str>,
     cache_store: CacheStore<F::Key, F::Value>,
-    _fetch_task: Arc<tokio::task::JoinHandle<()>>,
-    fetch_request_tx: tokio::sync::mpsc::Sender<FetchRequest<F::Key>>,
+    stats: Arc<FetcherStats>,
+    queued_keys: Arc<QueuedKeys<F::Key, F::Value>>,
+    metrics: Option<Arc<dyn BatchMetrics>>,
+    validate_key: Option<ValidateKey<F::Key>>,
+    fetch_task: Arc<TaskHandle<FetchMessage<F::Key, F::Value>>>,
 }
 
 impl<F> BatchFetcher<F>
 where
-    F: Fetcher + Send + Sync + 'static,
+    F: LocalFetcher + 'static,
 {
     /// Create a new `BatchFetcher` that uses the given [`Fetcher`] to retrieve
     /// data. Returns a [`BatchFetcherBuilder`], which can be used to customize
@@ -119,8 +155,18 @@ where
     pub fn build(fetcher: F) -> BatchFetcherBuilder<F> {
         BatchFetcherBuilder {
             fetcher,
-            delay_duration: tokio::time::Duration::from_millis(10),
+            delay: BatchDelay::Duration(Duration::from_millis(10)),
             eager_batch_size: Some(100),
+            scheduler: None,
+            metrics: None,
+            slow_batch_threshold: None,
+            max_batch_size: None,
+            max_concurrent_batches: 1,
+            shard_keys: None,
+            validate_key: None,
+            cache_kind: CacheKind::Default,
+            spawner: None,
+            timer: None,
             label: "unlabeled-batch-fetcher".into(),
         }
     }
@@ -133,136 +179,1229 @@ where
     /// detailed loading semantics.
     #[tracing::instrument(skip_all, fields(batch_fetcher = %self.label))]
     pub async fn load(&self, key: F::Key) -> Result<F::Value, LoadError> {
-        let mut values = self.load_keys(&[key]).await?;
-        Ok(values.remove(0))
+        let cache_lookup = self.load_keys(CacheLookup::new([key])).await?;
+        cache_lookup.into_value()
     }
 
     /// Load all the values for the given keys, either by calling the `Fetcher`
     /// or by loading cached values. Values are returned in the same order as
     /// the input keys. Returns an error if _any_ load fails.
     ///
+    /// Keys can be given as any iterator over either owned keys or references
+    /// to keys (see [`IntoKey`]). Owned keys are used as-is, while borrowed
+    /// keys are cloned.
+    ///
+    /// See the type-level docs for [`BatchFetcher`](#load-semantics) for more
+    /// detailed loading semantics.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, Fetcher, Cache};
+    /// # struct UserFetcher;
+    /// # impl Fetcher for UserFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = u64;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, u64>) -> anyhow::Result<()> {
+    /// #         for key in keys {
+    /// #             values.insert(*key, *key);
+    /// #         }
+    /// #         Ok(())
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::build(UserFetcher).finish();
+    ///
+    /// // Load from a slice of keys
+    /// let users = batch_fetcher.load_many(&[1, 2, 3]).await?;
+    ///
+    /// // Load from an iterator of owned keys
+    /// let users = batch_fetcher.load_many((1..=3).map(|id| id * 10)).await?;
+    /// # Ok(()) }
+    /// ```
+    #[tracing::instrument(skip_all, fields(batch_fetcher = %self.label, num_keys = tracing::field::Empty))]
+    pub async fn load_many<I>(&self, keys: I) -> Result<Vec<F::Value>, LoadError>
+    where
+        I: IntoIterator,
+        I::Item: IntoKey<F::Key>,
+    {
+        let cache_lookup = CacheLookup::new(keys.into_iter().map(IntoKey::into_key));
+        tracing::Span::current().record("num_keys", cache_lookup.len());
+
+        let cache_lookup = self.load_keys(cache_lookup).await?;
+        cache_lookup.lookup_result()
+    }
+
+    /// Load the value with the associated key like [`load`](BatchFetcher::load),
+    /// but block the current thread until the value is loaded. This lets
+    /// synchronous code (such as a CLI tool) share a `BatchFetcher` with async
+    /// code. Requires the `blocking` feature.
+    ///
+    /// This must not be called from within an async context, since blocking
+    /// the thread that runs the `BatchFetcher`'s background tasks would
+    /// deadlock. Outside of a runtime, the default [`TokioRuntime`](crate::TokioRuntime)
+    /// runs the background tasks on its own thread.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::BatchFetcher;
+    /// # use std::collections::HashMap;
+    /// # fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+    ///     let names: HashMap<u64, String> =
+    ///         ids.into_iter().map(|id| (id, format!("User {id}"))).collect();
+    ///     anyhow::Ok(names)
+    /// })
+    /// .finish();
+    ///
+    /// let name = batch_fetcher.blocking_load(1)?;
+    /// assert_eq!(name, "User 1");
+    /// # Ok(()) }
+    /// ```
+    #[cfg(feature = "blocking")]
+    pub fn blocking_load(&self, key: F::Key) -> Result<F::Value, LoadError> {
+        futures_executor::block_on(self.load(key))
+    }
+
+    /// Load all the values for the given keys like [`load_many`](BatchFetcher::load_many),
+    /// but block the current thread until the values are loaded. See
+    /// [`blocking_load`](BatchFetcher::blocking_load). Requires the `blocking`
+    /// feature.
+    #[cfg(feature = "blocking")]
+    pub fn blocking_load_many<I>(&self, keys: I) -> Result<Vec<F::Value>, LoadError>
+    where
+        I: IntoIterator,
+        I::Item: IntoKey<F::Key>,
+    {
+        futures_executor::block_on(self.load_many(keys))
+    }
+
+    /// Load the value with the associated key, like [`load`](BatchFetcher::load),
+    /// but use `fallback` to compute the value if the [`Fetcher`] didn't
+    /// return a value for the key. The fallback value is cached, so later
+    /// loads for the same key will return it instead of failing with
+    /// [`LoadError::NotFound`]. Other errors are returned as-is.
+    ///
+    /// If multiple callers compute a fallback for the same key at the same
+    /// time, then each caller gets the value it computed, and the last value
+    /// computed stays in the cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, Fetcher, Cache};
+    /// # #[derive(Clone)] struct Settings { theme: &'static str }
+    /// # struct SettingsFetcher;
+    /// # impl Fetcher for SettingsFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = Settings;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, Settings>) -> anyhow::Result<()> {
+    /// #         Ok(())
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::build(SettingsFetcher).finish();
+    ///
+    /// // Use the default settings for users who haven't saved any settings
+    /// let user_id = 123;
+    /// let settings = batch_fetcher
+    ///     .load_or_else(user_id, || async { Settings { theme: "light" } })
+    ///     .await?;
+    /// # Ok(()) }
+    /// ```
+    #[tracing::instrument(skip_all, fields(batch_fetcher = %self.label))]
+    pub async fn load_or_else<Fut>(
+        &self,
+        key: F::Key,
+        fallback: impl FnOnce() -> Fut,
+    ) -> Result<F::Value, LoadError>
+    where
+        Fut: Future<Output = F::Value>,
+    {
+        let cache_lookup = self.load_keys(CacheLookup::new([key.clone()])).await?;
+        match cache_lookup.into_value() {
+            Ok(value) => Ok(value),
+            Err(LoadError::NotFound) => {
+                tracing::debug!(batch_fetcher = %self.label, "value not found, using fallback value");
+                let value = fallback().await;
+                self.cache_store.as_cache().insert(key, value.clone());
+                Ok(value)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Load the value with the associated key, using a borrowed form of the
+    /// key. This works like [`load`](BatchFetcher::load), but takes any
+    /// borrowed form of the key (like [`HashMap::get`]), so a `String`-keyed
+    /// `BatchFetcher` can be queried with a `&str`. An owned key is only
+    /// created if the value isn't already cached.
+    ///
+    /// See the type-level docs for [`BatchFetcher`](#load-semantics) for more
+    /// detailed loading semantics.
+    #[tracing::instrument(skip_all, fields(batch_fetcher = %self.label))]
+    pub async fn load_borrowed<Q>(&self, key: &Q) -> Result<F::Value, LoadError>
+    where
+        F::Key: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = F::Key> + ?Sized,
+    {
+        let mut values = self.load_borrowed_keys(vec![key]).await?;
+        Ok(values.remove(0))
+    }
+
+    /// Load all the values for the given keys, using borrowed forms of the
+    /// keys. This works like [`load_many`](BatchFetcher::load_many), but
+    /// takes any borrowed form of the key (like [`HashMap::get`]). Owned keys
+    /// are only created for values that aren't already cached.
+    ///
+    /// See the type-level docs for [`BatchFetcher`](#load-semantics) for more
+    /// detailed loading semantics.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, Fetcher, Cache};
+    /// # struct UserFetcher;
+    /// # impl Fetcher for UserFetcher {
+    /// #     type Key = String;
+    /// #     type Value = String;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[String], values: &mut Cache<'_, String, String>) -> anyhow::Result<()> {
+    /// #         for key in keys {
+    /// #             values.insert(key.clone(), key.clone());
+    /// #         }
+    /// #         Ok(())
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::build(UserFetcher).finish();
+    /// let users = batch_fetcher.load_many_borrowed(["alice", "bob"]).await?;
+    /// # Ok(()) }
+    /// ```
+    #[tracing::instrument(skip_all, fields(batch_fetcher = %self.label, num_keys = tracing::field::Empty))]
+    pub async fn load_many_borrowed<'a, Q, I>(&self, keys: I) -> Result<Vec<F::Value>, LoadError>
+    where
+        I: IntoIterator<Item = &'a Q>,
+        F::Key: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = F::Key> + ?Sized + 'a,
+    {
+        let keys: Vec<_> = keys.into_iter().collect();
+        tracing::Span::current().record("num_keys", keys.len());
+
+        self.load_borrowed_keys(keys).await
+    }
+
+    /// Load all the values for the given keys, either by calling the `Fetcher`
+    /// or by loading cached values. Returns a `HashMap` containing each
+    /// value that was found, keyed by its key. Unlike [`load_many`](BatchFetcher::load_many),
+    /// keys whose values were not found are left out of the map instead of
+    /// returning [`LoadError::NotFound`], but an error is still returned if
+    /// fetching fails.
+    ///
+    /// Keys can be given the same way as with [`load_many`](BatchFetcher::load_many).
     /// See the type-level docs for [`BatchFetcher`](#load-semantics) for more
     /// detailed loading semantics.
-    #[tracing::instrument(skip_all, fields(batch_fetcher = %self.label, num_keys = keys.len()))]
-    pub async fn load_many(&self, keys: &[F::Key]) -> Result<Vec<F::Value>, LoadError> {
-        let values = self.load_keys(keys).await?;
-        Ok(values)
+    #[tracing::instrument(skip_all, fields(batch_fetcher = %self.label, num_keys = tracing::field::Empty))]
+    pub async fn load_many_map<I>(&self, keys: I) -> Result<HashMap<F::Key, F::Value>, LoadError>
+    where
+        I: IntoIterator,
+        I::Item: IntoKey<F::Key>,
+    {
+        let cache_lookup = CacheLookup::new(keys.into_iter().map(IntoKey::into_key));
+        tracing::Span::current().record("num_keys", cache_lookup.len());
+
+        let cache_lookup = self.load_keys(cache_lookup).await?;
+        Ok(cache_lookup.found_values())
+    }
+
+    /// Load the values for the given keys, returning a stream that yields
+    /// each key along with its value as soon as the batch containing the key
+    /// finishes (or immediately if the value was already cached). Values are
+    /// yielded in the order they finish loading, not in the order of the
+    /// input keys. Unlike [`load_many`](BatchFetcher::load_many), each key
+    /// gets its own result, so a key that isn't found doesn't fail the other
+    /// keys.
+    ///
+    /// This is useful when loading many keys that are split across multiple
+    /// batches (such as with [`max_batch_size`](BatchFetcherBuilder::max_batch_size)),
+    /// since the caller can start processing values before the slowest
+    /// batch finishes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, Fetcher, Cache};
+    /// # use futures_util::StreamExt;
+    /// # struct UserFetcher;
+    /// # impl Fetcher for UserFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = u64;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, u64>) -> anyhow::Result<()> {
+    /// #         for key in keys {
+    /// #             values.insert(*key, *key);
+    /// #         }
+    /// #         Ok(())
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::build(UserFetcher)
+    ///     .max_batch_size(Some(100))
+    ///     .max_concurrent_batches(4)
+    ///     .finish();
+    ///
+    /// let mut users = batch_fetcher.load_stream(0..1000);
+    /// while let Some((user_id, user)) = users.next().await {
+    ///     let user = user?;
+    ///     // ...
+    /// }
+    /// # Ok(()) }
+    /// ```
+    pub fn load_stream<I>(
+        &self,
+        keys: I,
+    ) -> impl Stream<Item = (F::Key, Result<F::Value, LoadError>)>
+    where
+        I: IntoIterator,
+        I::Item: IntoKey<F::Key>,
+    {
+        keys.into_iter()
+            .map(|key| {
+                let key = key.into_key();
+                let batch_fetcher = self.clone();
+                async move {
+                    let result = batch_fetcher.load(key.clone()).await;
+                    (key, result)
+                }
+            })
+            .collect::<FuturesUnordered<_>>()
+    }
+
+    /// Remove a key from the cache, so the next load for the key calls the
+    /// [`Fetcher`] again. This works for keys that were loaded or marked as
+    /// "not found". Returns `true` if the key was cached.
+    ///
+    /// Note that a batch that is already fetching the key may still cache
+    /// the value it fetched after the key is invalidated.
+    pub fn invalidate(&self, key: &F::Key) -> bool {
+        let removed = self.cache_store.remove(key);
+        if removed {
+            tracing::trace!(batch_fetcher = %self.label, "invalidated cached key");
+        }
+        removed
+    }
+
+    /// Invalidate each key yielded by `keys`, such as keys for records that
+    /// were changed by another process, received through Postgres
+    /// `LISTEN`/`NOTIFY` or a pub/sub channel. Returns once the stream ends.
+    /// See [`invalidate`](BatchFetcher::invalidate) for details.
+    ///
+    /// The returned future needs to be polled for keys to be invalidated, so
+    /// it should usually be spawned as its own task.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, Fetcher, Cache};
+    /// # struct UserFetcher;
+    /// # impl Fetcher for UserFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = u64;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, u64>) -> anyhow::Result<()> {
+    /// #         for key in keys {
+    /// #             values.insert(*key, *key);
+    /// #         }
+    /// #         Ok(())
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::build(UserFetcher).finish();
+    ///
+    /// // Receives the IDs of users changed elsewhere, such as from a
+    /// // pub/sub subscription
+    /// let (changed_tx, changed_rx) = tokio::sync::mpsc::channel::<u64>(100);
+    /// let changed_user_ids = futures_util::stream::unfold(changed_rx, |mut changed_rx| async move {
+    ///     let user_id = changed_rx.recv().await?;
+    ///     Some((user_id, changed_rx))
+    /// });
+    ///
+    /// tokio::spawn({
+    ///     let batch_fetcher = batch_fetcher.clone();
+    ///     async move { batch_fetcher.invalidate_from(changed_user_ids).await }
+    /// });
+    /// # Ok(()) }
+    /// ```
+    pub async fn invalidate_from(&self, keys: impl Stream<Item = F::Key>) {
+        let mut keys = std::pin::pin!(keys);
+        while let Some(key) = keys.next().await {
+            self.invalidate(&key);
+        }
+    }
+
+    /// Dispatch any keys that are currently queued immediately, without
+    /// waiting for the timeout set by [`delay_duration`](BatchFetcherBuilder::delay_duration)
+    /// or for the batch to fill up. This is useful when the caller knows
+    /// that no more keys will be loaded soon, such as at the end of a
+    /// GraphQL resolver pass. Does nothing if no keys are queued.
+    ///
+    /// Note that `flush` only dispatches the batch, and does not wait for
+    /// the batch to finish loading.
+    pub async fn flush(&self) {
+        tracing::debug!(batch_fetcher = %self.label, "flushing pending keys");
+
+        // Ignore error if the fetch task has already stopped
+        let _ = self.fetch_task.send(FetchMessage::Flush).await;
+    }
+
+    /// Shut down the `BatchFetcher`, such as when a service is stopping.
+    /// Once called, new loads that need to call the [`Fetcher`] will fail
+    /// with [`LoadError::SendError`] (values that were already cached can
+    /// still be loaded). Any keys that were already queued are dispatched
+    /// immediately, and `shutdown` returns once every in-flight batch has
+    /// finished and the fetch task has stopped.
+    ///
+    /// This affects every clone of the `BatchFetcher`. If the `BatchFetcher`
+    /// was already shut down, this returns immediately.
+    pub async fn shutdown(&self) {
+        tracing::debug!(batch_fetcher = %self.label, "shutting down");
+
+        // Don't restart the fetch task once it stops
+        self.fetch_task.close();
+
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+        let sent = self
+            .fetch_task
+            .send(FetchMessage::Shutdown(shutdown_tx))
+            .await;
+        if sent.is_ok() {
+            // Ignore error if the fetch task stopped some other way
+            let _ = shutdown_rx.await;
+        }
+    }
+
+    /// The number of keys queued in the batch that is currently waiting to
+    /// be dispatched.
+    pub fn pending_keys_len(&self) -> usize {
+        self.stats.pending_keys.load(Ordering::Relaxed)
+    }
+
+    /// The number of keys in the cache, including keys that were marked as
+    /// "not found".
+    pub fn cached_len(&self) -> usize {
+        self.cache_store.len()
+    }
+
+    /// Insert a value into the cache, such as a value that was just written
+    /// by a [`WriteThrough`](crate::WriteThrough) executor.
+    pub(crate) fn insert_cached(&self, key: F::Key, value: F::Value) {
+        self.cache_store.as_cache().insert(key, value);
+    }
+
+    /// The number of batches currently being fetched by the [`Fetcher`].
+    pub fn in_flight_batches(&self) -> usize {
+        self.stats.in_flight_batches.load(Ordering::Relaxed)
+    }
+
+    /// Get aggregate stats for every batch dispatched so far, such as to
+    /// measure how effective batching is for this `BatchFetcher`.
+    pub fn stats(&self) -> BatchFetcherStats {
+        BatchFetcherStats {
+            num_batches: self.stats.num_batches.load(Ordering::Relaxed),
+            num_keys_requested: self.stats.num_keys_requested.load(Ordering::Relaxed),
+            num_keys_fetched: self.stats.num_keys_fetched.load(Ordering::Relaxed),
+            total_wait_duration: Duration::from_nanos(
+                self.stats.total_wait_nanos.load(Ordering::Relaxed),
+            ),
+        }
+    }
+
+    /// Subscribe to a stream of [`CacheEvent`]s for each change to the
+    /// cache from now on, such as to mirror cached values into another
+    /// system. Each subscriber gets its own copy of every event.
+    ///
+    /// Up to 1024 events are buffered for each subscriber. If a subscriber
+    /// falls further behind than that, the oldest events are skipped and a
+    /// warning is logged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, CacheEvent};
+    /// # use futures_util::StreamExt;
+    /// # use std::collections::HashMap;
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+    ///     anyhow::Ok(ids.into_iter().map(|id| (id, id * 10)).collect::<HashMap<_, _>>())
+    /// })
+    /// .finish();
+    ///
+    /// let mut cache_events = Box::pin(batch_fetcher.subscribe_cache_events());
+    ///
+    /// batch_fetcher.load(1).await?;
+    /// let event = cache_events.next().await;
+    /// assert!(matches!(event, Some(CacheEvent::Inserted { key: 1, value: 10 })));
+    /// # Ok(()) }
+    /// ```
+    pub fn subscribe_cache_events(&self) -> impl Stream<Item = CacheEvent<F::Key, F::Value>> {
+        let label = self.label.clone();
+        let receiver = self.cache_store.subscribe();
+        futures_util::stream::unfold(receiver, move |mut receiver| {
+            let label = label.clone();
+            async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) => return Some((event, receiver)),
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(num_skipped)) => {
+                            tracing::warn!(
+                                batch_fetcher = %label,
+                                num_skipped,
+                                "cache event subscriber fell behind, skipping events",
+                            );
+                        }
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            }
+        })
+    }
+
+    /// Get a copy of every key in the cache, along with its value or whether
+    /// it was marked as "not found", such as for an admin endpoint or to
+    /// check what was loaded in a test. Unlike [`inspect`](BatchFetcher::inspect),
+    /// this clones every cached value.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, CacheEntry};
+    /// # use std::collections::HashMap;
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+    ///     // Only even IDs are found
+    ///     anyhow::Ok(ids.into_iter().filter(|id| id % 2 == 0).map(|id| (id, id)).collect::<HashMap<_, _>>())
+    /// })
+    /// .finish();
+    ///
+    /// let _ = batch_fetcher.load_many(&[1, 2, 3, 4]).await;
+    ///
+    /// let snapshot = batch_fetcher.cache_snapshot();
+    /// assert_eq!(snapshot.len(), 4);
+    /// assert_eq!(snapshot.num_not_found(), 2);
+    /// assert_eq!(snapshot.get(&2), Some(&CacheEntry::Loaded(2)));
+    /// assert_eq!(snapshot.get(&3), Some(&CacheEntry::NotFound));
+    /// # Ok(()) }
+    /// ```
+    pub fn cache_snapshot(&self) -> CacheSnapshot<F::Key, F::Value> {
+        self.cache_store.snapshot()
+    }
+
+    /// Get a snapshot of the queue, in-flight batches, and cache, such as
+    /// to troubleshoot a stuck `BatchFetcher`. This doesn't wait on the
+    /// background task, so it can be called even if the task is stuck. Use
+    /// [`inspect_with_keys`](BatchFetcher::inspect_with_keys) to also
+    /// include the queued keys themselves.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::BatchFetcher;
+    /// # use std::collections::HashMap;
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+    ///     anyhow::Ok(ids.into_iter().map(|id| (id, id)).collect::<HashMap<_, _>>())
+    /// })
+    /// .finish();
+    ///
+    /// batch_fetcher.load_many(&[1, 2, 3]).await?;
+    ///
+    /// let state = batch_fetcher.inspect();
+    /// assert_eq!(state.num_queued_keys, 0);
+    /// assert!(state.in_flight_batches.is_empty());
+    /// assert_eq!(state.num_cached, 3);
+    /// # Ok(()) }
+    /// ```
+    pub fn inspect(&self) -> BatchFetcherState<F::Key> {
+        self.inspect_state(false)
+    }
+
+    /// Get a snapshot like [`inspect`](BatchFetcher::inspect), including a
+    /// copy of each queued key in
+    /// [`queued_keys`](BatchFetcherState::queued_keys) so they can be
+    /// logged.
+    pub fn inspect_with_keys(&self) -> BatchFetcherState<F::Key>
+    where
+        F::Key: Debug,
+    {
+        self.inspect_state(true)
+    }
+
+    fn inspect_state(&self, include_keys: bool) -> BatchFetcherState<F::Key> {
+        let (num_queued_keys, num_queued_waiters, queued_keys) = {
+            let queued_keys = self.queued_keys.lock();
+            let num_queued_waiters = queued_keys
+                .values()
+                .flatten()
+                .map(|key_waiter| key_waiter.waiter.as_ptr())
+                .collect::<HashSet<_>>()
+                .len();
+            let keys = include_keys.then(|| queued_keys.keys().cloned().collect());
+            (queued_keys.len(), num_queued_waiters, keys)
+        };
+
+        let mut in_flight_batches: Vec<_> = self
+            .stats
+            .lock_in_flight()
+            .iter()
+            .map(|(batch_id, batch)| {
+                (
+                    *batch_id,
+                    InFlightBatchState {
+                        num_keys: batch.num_keys,
+                        num_waiters: batch.num_waiters,
+                        elapsed: batch.started_at.elapsed(),
+                    },
+                )
+            })
+            .collect();
+        in_flight_batches.sort_by_key(|(batch_id, _)| *batch_id);
+
+        BatchFetcherState {
+            label: self.label.clone(),
+            num_queued_keys,
+            num_queued_waiters,
+            queued_keys,
+            in_flight_batches: in_flight_batches
+                .into_iter()
+                .map(|(_, batch)| batch)
+                .collect(),
+            num_cached: self.cache_store.len(),
+        }
+    }
+
+    fn record_cache_access(&self, num_hits: usize, num_negative_hits: usize, num_misses: usize) {
+        tracing::debug!(
+            batch_fetcher = %self.label,
+            num_cache_hits = num_hits,
+            num_cache_negative_hits = num_negative_hits,
+            num_cache_misses = num_misses,
+            "looked up keys in cache",
+        );
+
+        let Some(metrics) = &self.metrics else {
+            return;
+        };
+
+        // Keys cached as "not found" still count as cache hits
+        let num_hits = num_hits + num_negative_hits;
+        if num_hits > 0 {
+            metrics.on_cache_hit(&CacheAccess {
+                label: &self.label,
+                num_keys: num_hits,
+            });
+        }
+        if num_misses > 0 {
+            metrics.on_cache_miss(&CacheAccess {
+                label: &self.label,
+                num_keys: num_misses,
+            });
+        }
+    }
+
+    async fn load_borrowed_keys<Q>(&self, keys: Vec<&Q>) -> Result<Vec<F::Value>, LoadError>
+    where
+        F::Key: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = F::Key> + ?Sized,
+    {
+        let cached_values: Vec<_> = keys.iter().map(|key| self.cache_store.get(*key)).collect();
+        let uncached_keys: Vec<_> = keys
+            .iter()
+            .zip(&cached_values)
+            .filter(|(_, cached_value)| cached_value.is_none())
+            .map(|(key, _)| (*key).to_owned())
+            .collect();
+
+        // Misses are recorded when loading the uncached keys
+        self.stats
+            .num_keys_requested
+            .fetch_add((keys.len() - uncached_keys.len()) as u64, Ordering::Relaxed);
+        let num_negative_hits = cached_values
+            .iter()
+            .filter(|cached_value| matches!(cached_value, Some(Err(LoadError::NotFound))))
+            .count();
+        self.record_cache_access(
+            keys.len() - uncached_keys.len() - num_negative_hits,
+            num_negative_hits,
+            0,
+        );
+
+        let fetched_values = if uncached_keys.is_empty() {
+            tracing::debug!(batch_fetcher = %self.label, "all keys have already been looked up");
+            HashMap::new()
+        } else {
+            self.load_keys(CacheLookup::new(uncached_keys))
+                .await?
+                .found_values()
+        };
+
+        keys.into_iter()
+            .zip(cached_values)
+            .map(|(key, cached_value)| match cached_value {
+                Some(result) => result,
+                None => fetched_values.get(key).cloned().ok_or(LoadError::NotFound),
+            })
+            .collect()
     }
 
-    async fn load_keys(&self, keys: &[F::Key]) -> Result<Vec<F::Value>, LoadError> {
-        let mut cache_lookup = CacheLookup::new(keys.to_vec());
+    async fn load_keys(
+        &self,
+        mut cache_lookup: CacheLookup<F::Key, F::Value>,
+    ) -> Result<CacheLookup<F::Key, F::Value>, LoadError> {
+        self.stats
+            .num_keys_requested
+            .fetch_add(cache_lookup.len() as u64, Ordering::Relaxed);
+
+        let lookup_state = cache_lookup.lookup(&self.cache_store);
+        let num_pending = cache_lookup.num_pending();
+        let num_negative_hits = cache_lookup.num_not_found();
+        self.record_cache_access(
+            cache_lookup.num_keys() - num_pending - num_negative_hits,
+            num_negative_hits,
+            num_pending,
+        );
 
-        match cache_lookup.lookup(&self.cache_store) {
-            CacheLookupState::Done(result) => {
+        match lookup_state {
+            CacheLookupState::Done => {
                 tracing::debug!(batch_fetcher = %self.label, "all keys have already been looked up");
-                return result;
+                return Ok(cache_lookup);
             }
             CacheLookupState::Pending => {}
         }
         let pending_keys = cache_lookup.pending_keys();
 
-        let fetch_request_tx = self.fetch_request_tx.clone();
-        let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+        // Reject invalid keys before they're queued, so they never reach
+        // the fetcher or get cached
+        if let Some(validate_key) = &self.validate_key {
+            for key in &pending_keys {
+                validate_key(key).map_err(LoadError::InvalidKey)?;
+            }
+        }
 
         tracing::debug!(
             num_pending_keys = pending_keys.len(),
             batch_fetcher = %self.label,
             "sending a batch of keys to fetch",
         );
+        let waiter = Arc::new(FetchWaiter::new(
+            pending_keys.len(),
+            tracing::Span::current(),
+        ));
         let fetch_request = FetchRequest {
             keys: pending_keys,
-            result_tx,
+            waiter: WaiterRef::new(waiter.clone()),
+            context: CallerContext::current(),
         };
-        fetch_request_tx
-            .send(fetch_request)
+        let fetch_result = WaitForFetch(waiter);
+        self.fetch_task
+            .send(FetchMessage::Load(fetch_request))
             .await
             .map_err(|_| LoadError::SendError)?;
 
-        match result_rx.await {
-            Ok(Ok(())) => {
+        let resolved = match fetch_result.await {
+            Some(Ok(resolved)) => {
                 tracing::debug!(batch_fetcher = %self.label, "fetch response returned successfully");
+                resolved
             }
-            Ok(Err(fetch_error)) => {
+            Some(Err(fetch_error)) => {
                 tracing::info!("error returned while fetching keys: {fetch_error}");
                 return Err(LoadError::FetchError(fetch_error));
             }
-            Err(recv_error) => {
-                panic!(
-                    "Batch result channel for batch fetcher {} hung up with error: {recv_error}",
-                    self.label,
-                );
+            None => {
+                // The fetch task stopped while the batch was in flight, such
+                // as when the runtime it was spawned on shut down
+                tracing::warn!(batch_fetcher = %self.label, "batch stopped before it finished");
+                return Err(LoadError::SendError);
             }
-        }
+        };
 
-        match cache_lookup.lookup(&self.cache_store) {
-            CacheLookupState::Done(result) => {
+        // The batches send back the value for each key, so the cache doesn't
+        // need to be checked again
+        match cache_lookup.resolve_pending(resolved) {
+            CacheLookupState::Done => {
                 tracing::debug!("all keys have now been looked up");
-                result
+                Ok(cache_lookup)
             }
             CacheLookupState::Pending => {
-                panic!(
-                    "Batch result for batch fetcher {} is still pending after result channel was sent",
-                    self.label,
-                );
+                tracing::warn!(batch_fetcher = %self.label, "batch stopped before fetching every key");
+                Err(LoadError::SendError)
             }
         }
     }
 }
 
-impl<F> Clone for BatchFetcher<F>
+impl<Fun, Fut, K, V, E> BatchFetcher<FnFetcher<Fun, K, V, E>>
 where
-    F: Fetcher,
+    Fun: Fn(Vec<K>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<HashMap<K, V>, E>> + Send,
+    K: Clone + Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    E: Display + 'static,
 {
-    fn clone(&self) -> Self {
-        BatchFetcher {
-            cache_store: self.cache_store.clone(),
-            _fetch_task: self._fetch_task.clone(),
-            fetch_request_tx: self.fetch_request_tx.clone(),
-            label: self.label.clone(),
-        }
+    /// Create a new `BatchFetcher` that calls an async closure to fetch each
+    /// batch, without needing to implement [`Fetcher`]. The closure is called
+    /// with the keys for each batch, and should return a `HashMap` containing
+    /// the value for each key that was found. Returns a [`BatchFetcherBuilder`],
+    /// the same as [`BatchFetcher::build`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::BatchFetcher;
+    /// # use std::collections::HashMap;
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::from_fn(|user_ids: Vec<u64>| async move {
+    ///     let users: HashMap<u64, String> = user_ids
+    ///         .into_iter()
+    ///         .map(|id| (id, format!("User {id}")))
+    ///         .collect();
+    ///     anyhow::Ok(users)
+    /// })
+    /// .finish();
+    ///
+    /// let user = batch_fetcher.load(1).await?;
+    /// assert_eq!(user, "User 1");
+    /// # Ok(()) }
+    /// ```
+    pub fn from_fn(fetch_fn: Fun) -> BatchFetcherBuilder<FnFetcher<Fun, K, V, E>> {
+        BatchFetcher::build(FnFetcher::new(fetch_fn))
     }
 }
 
-/// Used to configure a new [`BatchFetcher`]. A `BatchFetcherBuilder` is
-/// returned from [`BatchFetcher::build`].
-pub struct BatchFetcherBuilder<F>
+impl<F> BatchFetcher<MapFetcherAdapter<F>>
 where
-    F: Fetcher + Send + Sync + 'static,
+    F: MapFetcher + Send + Sync + 'static,
+    F::Key: 'static,
+    F::Value: 'static,
+    F::Error: 'static,
 {
-    fetcher: F,
-    delay_duration: tokio::time::Duration,
-    eager_batch_size: Option<usize>,
-    label: Cow<'static, str>,
+    /// Create a new `BatchFetcher` that uses the given [`MapFetcher`] to
+    /// fetch values, without needing to implement [`Fetcher`]. Returns a
+    /// [`BatchFetcherBuilder`], the same as [`BatchFetcher::build`]. See
+    /// [`MapFetcher`] for an example.
+    pub fn build_map(fetcher: F) -> BatchFetcherBuilder<MapFetcherAdapter<F>> {
+        BatchFetcher::build(MapFetcherAdapter::new(fetcher))
+    }
 }
 
-impl<F> BatchFetcherBuilder<F>
+impl<F> BatchFetcher<PairsFetcherAdapter<F>>
 where
-    F: Fetcher + Send + Sync + 'static,
+    F: PairsFetcher + Send + Sync + 'static,
+    F::Key: 'static,
+    F::Value: 'static,
+    F::Error: 'static,
 {
-    /// The maximum amount of time the [`BatchFetcher`] will wait to queue up
-    /// more keys before calling the [`Fetcher`].
-    pub fn delay_duration(mut self, delay: tokio::time::Duration) -> Self {
-        self.delay_duration = delay;
-        self
+    /// Create a new `BatchFetcher` that uses the given [`PairsFetcher`] to
+    /// fetch values, without needing to implement [`Fetcher`]. Returns a
+    /// [`BatchFetcherBuilder`], the same as [`BatchFetcher::build`]. See
+    /// [`PairsFetcher`] for an example.
+    pub fn build_pairs(fetcher: F) -> BatchFetcherBuilder<PairsFetcherAdapter<F>> {
+        BatchFetcher::build(PairsFetcherAdapter::new(fetcher))
     }
+}
 
-    /// The maximum number of keys to wait for before eagerly calling the
-    /// [`Fetcher`]. A value of `Some(n)` will load the batch once `n` or more
-    /// keys have been queued (or once the timeout set by
-    /// [`delay_duration`](BatchFetcherBuilder::delay_duration) is reached,
-    /// whichever comes first). A value of `None` will never eagerly dispatch
-    /// the queue, and the [`BatchFetcher`] will always wait for the timeout set
-    /// by [`delay_duration`](BatchFetcherBuilder::delay_duration).
+impl<F> BatchFetcher<Exclusive<F>>
+where
+    F: ExclusiveFetcher + Send + 'static,
+    F::Key: 'static,
+    F::Value: 'static,
+    F::Error: 'static,
+{
+    /// Create a new `BatchFetcher` that uses the given [`ExclusiveFetcher`]
+    /// to fetch values, fetching one batch at a time. Returns a
+    /// [`BatchFetcherBuilder`], the same as [`BatchFetcher::build`]. See
+    /// [`ExclusiveFetcher`] for an example.
+    pub fn build_exclusive(fetcher: F) -> BatchFetcherBuilder<Exclusive<F>> {
+        BatchFetcher::build(Exclusive::new(fetcher))
+    }
+}
+
+impl<F> BatchFetcher<SharedFetcherAdapter<F>>
+where
+    F: SharedFetcher + Send + Sync + 'static,
+    F::Key: 'static,
+    F::Value: 'static,
+    F::Error: 'static,
+{
+    /// Create a new `BatchFetcher` that uses the given [`SharedFetcher`] to
+    /// fetch values that don't implement `Clone`. Loading a key returns an
+    /// `Arc` pointing to the cached value. Returns a [`BatchFetcherBuilder`],
+    /// the same as [`BatchFetcher::build`]. See [`SharedFetcher`] for an
+    /// example.
+    pub fn build_shared(fetcher: F) -> BatchFetcherBuilder<SharedFetcherAdapter<F>> {
+        BatchFetcher::build(SharedFetcherAdapter::new(fetcher))
+    }
+}
+
+impl<F> Clone for BatchFetcher<F>
+where
+    F: LocalFetcher,
+{
+    fn clone(&self) -> Self {
+        BatchFetcher {
+            cache_store: self.cache_store.clone(),
+            stats: self.stats.clone(),
+            queued_keys: self.queued_keys.clone(),
+            metrics: self.metrics.clone(),
+            validate_key: self.validate_key.clone(),
+            fetch_task: self.fetch_task.clone(),
+            label: self.label.clone(),
+        }
+    }
+}
+
+/// A key that can be passed to [`BatchFetcher::load_many`]. This is
+/// implemented for owned keys (which are used as-is) and for references to
+/// keys (which are cloned). For `Arc` keys such as `Arc<str>`, it's also
+/// implemented for references to the inner value (such as `&str`), which
+/// are copied into a new `Arc`.
+pub trait IntoKey<K> {
+    /// Convert into an owned key.
+    fn into_key(self) -> K;
+}
+
+impl<K> IntoKey<K> for K {
+    fn into_key(self) -> K {
+        self
+    }
+}
+
+impl<K> IntoKey<K> for &K
+where
+    K: Clone,
+{
+    fn into_key(self) -> K {
+        self.clone()
+    }
+}
+
+impl<'a, K> IntoKey<Arc<K>> for &'a K
+where
+    K: ?Sized,
+    Arc<K>: From<&'a K>,
+{
+    fn into_key(self) -> Arc<K> {
+        Arc::from(self)
+    }
+}
+
+/// Used to configure a new [`BatchFetcher`]. A `BatchFetcherBuilder` is
+/// returned from [`BatchFetcher::build`].
+pub struct BatchFetcherBuilder<F>
+where
+    F: LocalFetcher + 'static,
+{
+    fetcher: F,
+    delay: BatchDelay,
+    eager_batch_size: Option<usize>,
+    scheduler: Option<Arc<dyn BatchScheduler>>,
+    metrics: Option<Arc<dyn BatchMetrics>>,
+    slow_batch_threshold: Option<Duration>,
+    max_batch_size: Option<usize>,
+    max_concurrent_batches: usize,
+    shard_keys: Option<ShardKeys<F::Key, F::Value>>,
+    validate_key: Option<ValidateKey<F::Key>>,
+    cache_kind: CacheKind,
+    spawner: Option<Arc<dyn Spawner>>,
+    timer: Option<Arc<dyn Timer>>,
+    label: Cow<'static, str>,
+}
+
+impl<F> BatchFetcherBuilder<F>
+where
+    F: LocalFetcher + 'static,
+{
+    /// The maximum amount of time the [`BatchFetcher`] will wait to queue up
+    /// more keys before calling the [`Fetcher`].
+    pub fn delay_duration(mut self, delay: Duration) -> Self {
+        self.delay = BatchDelay::Duration(delay);
+        self
+    }
+
+    /// Dispatch each batch as soon as the tasks queueing up keys yield,
+    /// rather than waiting for a fixed delay. This works similarly to
+    /// DataLoader's default behavior: keys loaded from multiple futures
+    /// in the same "tick" (such as futures run with [`tokio::join!`] or
+    /// tasks spawned together) are grouped into one batch, without adding
+    /// any timer-based latency.
+    ///
+    /// This replaces any value set by [`delay_duration`](BatchFetcherBuilder::delay_duration)
+    /// (and calling [`delay_duration`](BatchFetcherBuilder::delay_duration)
+    /// afterwards switches back to a fixed delay).
+    pub fn dispatch_on_next_tick(mut self) -> Self {
+        self.delay = BatchDelay::NextTick;
+        self
+    }
+
+    /// The maximum number of keys to wait for before eagerly calling the
+    /// [`Fetcher`]. A value of `Some(n)` will load the batch once `n` or more
+    /// keys have been queued (or once the timeout set by
+    /// [`delay_duration`](BatchFetcherBuilder::delay_duration) is reached,
+    /// whichever comes first). A value of `None` will never eagerly dispatch
+    /// the queue, and the [`BatchFetcher`] will always wait for the timeout set
+    /// by [`delay_duration`](BatchFetcherBuilder::delay_duration).
     ///
     /// Note that `eager_batch_size` **does not** set an upper limit on the
     /// batch! For example, if [`BatchFetcher::load_many`] is called with more
     /// than `eager_batch_size` items, then the batch will be sent immediately
-    /// with _all_ of the provided keys.
+    /// with _all_ of the provided keys. Use
+    /// [`max_batch_size`](BatchFetcherBuilder::max_batch_size) to set an
+    /// upper limit.
     pub fn eager_batch_size(mut self, eager_batch_size: Option<usize>) -> Self {
         self.eager_batch_size = eager_batch_size;
         self
     }
 
+    /// Use a custom [`BatchScheduler`] to decide when batches should be
+    /// dispatched. This overrides the [`delay_duration`](BatchFetcherBuilder::delay_duration),
+    /// [`dispatch_on_next_tick`](BatchFetcherBuilder::dispatch_on_next_tick),
+    /// and [`eager_batch_size`](BatchFetcherBuilder::eager_batch_size) options.
+    pub fn scheduler(mut self, scheduler: impl BatchScheduler + 'static) -> Self {
+        self.scheduler = Some(Arc::new(scheduler));
+        self
+    }
+
+    /// Use a [`BatchMetrics`] implementation to observe batches and cache
+    /// lookups, such as to report metrics to an application's telemetry.
+    pub fn metrics(mut self, metrics: impl BatchMetrics + 'static) -> Self {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Log a warning whenever the [`Fetcher`] takes longer than `threshold`
+    /// to fetch a batch, including the batch size and the label of the
+    /// `BatchFetcher`. This can help flag a degraded datastore early. To
+    /// handle slow batches some other way, use
+    /// [`BatchMetrics::on_batch_completed`] instead.
+    pub fn warn_if_slower_than(mut self, threshold: Duration) -> Self {
+        self.slow_batch_threshold = Some(threshold);
+        self
+    }
+
+    /// The maximum number of keys to pass to the [`Fetcher`] in a single
+    /// call. A value of `Some(n)` will split a queued batch with more than
+    /// `n` keys into multiple calls to [`Fetcher::fetch`], each with at most
+    /// `n` keys. A value of `None` (the default) will never split a batch.
+    ///
+    /// If one of the split calls returns an error, only the callers waiting
+    /// on a key from that call will receive the error.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_batch_size` is `Some(0)`.
+    pub fn max_batch_size(mut self, max_batch_size: Option<usize>) -> Self {
+        assert_ne!(max_batch_size, Some(0), "max_batch_size must be non-zero");
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// The maximum number of batches that can be fetched at the same time.
+    /// While fewer than `max_concurrent_batches` batches are being fetched,
+    /// the [`BatchFetcher`] will start queueing up the next batch, so a
+    /// slow call to [`Fetcher::fetch`] won't hold up new loads. Defaults to
+    /// 1, meaning each batch waits for the previous batch to finish.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_concurrent_batches` is 0.
+    pub fn max_concurrent_batches(mut self, max_concurrent_batches: usize) -> Self {
+        assert_ne!(
+            max_concurrent_batches, 0,
+            "max_concurrent_batches must be non-zero"
+        );
+        self.max_concurrent_batches = max_concurrent_batches;
+        self
+    }
+
+    /// Partition each batch by shard, calling [`Fetcher::fetch`] separately
+    /// with the keys for each shard. `shard` is called with each key and
+    /// returns the shard it belongs to (such as a database shard or a
+    /// tenant ID), so a single call to the `Fetcher` never mixes keys from
+    /// different shards.
+    ///
+    /// Each shard's keys are fetched as a separate batch, and are split
+    /// further if there are more than
+    /// [`max_batch_size`](BatchFetcherBuilder::max_batch_size) keys. Use
+    /// [`max_concurrent_batches`](BatchFetcherBuilder::max_concurrent_batches)
+    /// to fetch the shards in parallel.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::BatchFetcher;
+    /// # use std::collections::HashMap;
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// // Keys are `(tenant_id, user_id)` pairs
+    /// let batch_fetcher = BatchFetcher::from_fn(|keys: Vec<(u64, u64)>| async move {
+    ///     // Every key in a batch has the same tenant ID
+    ///     let tenant_id = keys[0].0;
+    ///     assert!(keys.iter().all(|(key_tenant_id, _)| *key_tenant_id == tenant_id));
+    ///     anyhow::Ok(keys.into_iter().map(|key| (key, key.1)).collect::<HashMap<_, _>>())
+    /// })
+    /// .shard_by(|(tenant_id, _)| *tenant_id)
+    /// .max_concurrent_batches(4)
+    /// .finish();
+    ///
+    /// let users = batch_fetcher.load_many(&[(1, 10), (2, 20), (1, 11)]).await?;
+    /// assert_eq!(users, vec![10, 20, 11]);
+    /// # Ok(()) }
+    /// ```
+    pub fn shard_by<S>(mut self, shard: impl Fn(&F::Key) -> S + Send + Sync + 'static) -> Self
+    where
+        S: Hash + Eq + 'static,
+    {
+        self.shard_keys = Some(Arc::new(move |pending_keys| {
+            // Keep the shards in the order they were first seen
+            let mut shard_indices = HashMap::<S, usize>::new();
+            let mut shards = Vec::<PendingKeys<_, _>>::new();
+            for (key, waiters) in pending_keys {
+                let shard_index = *shard_indices.entry(shard(&key)).or_insert_with(|| {
+                    shards.push(vec![]);
+                    shards.len() - 1
+                });
+                shards[shard_index].push((key, waiters));
+            }
+            shards
+        }));
+        self
+    }
+
+    /// Check each key before it's queued, rejecting keys that are obviously
+    /// invalid (such as empty strings or nil UUIDs). If `validate` returns an
+    /// error for any key, the load fails immediately with
+    /// [`LoadError::InvalidKey`], and none of its keys are fetched. Invalid
+    /// keys are never passed to the [`Fetcher`], so they can't fail the rest
+    /// of a batch or be cached as "not found".
+    ///
+    /// Keys that are already cached aren't validated again.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, LoadError};
+    /// # use std::collections::HashMap;
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::from_fn(|names: Vec<String>| async move {
+    ///     anyhow::Ok(names.into_iter().map(|name| (name.clone(), name.len())).collect::<HashMap<_, _>>())
+    /// })
+    /// .validate_keys(|name: &String| {
+    ///     if name.is_empty() {
+    ///         Err("name must not be empty")
+    ///     } else {
+    ///         Ok(())
+    ///     }
+    /// })
+    /// .finish();
+    ///
+    /// let result = batch_fetcher.load(String::new()).await;
+    /// assert!(matches!(result, Err(LoadError::InvalidKey(_))));
+    /// # Ok(()) }
+    /// ```
+    pub fn validate_keys<E>(
+        mut self,
+        validate: impl Fn(&F::Key) -> Result<(), E> + Send + Sync + 'static,
+    ) -> Self
+    where
+        E: Display,
+    {
+        self.validate_key = Some(Arc::new(move |key| {
+            validate(key).map_err(|error| Arc::from(error.to_string()))
+        }));
+        self
+    }
+
+    /// Store cached values in a map optimized for reads, for workloads
+    /// where nearly every load is a cache hit. Cached values are split
+    /// into shards that each have their own read-write lock, so loads that
+    /// hit the cache don't block each other. Inserting values after a
+    /// batch is fetched is slightly slower than with the default cache.
+    ///
+    /// This replaces any hasher set by [`cache_hasher`](BatchFetcherBuilder::cache_hasher)
+    /// (and calling [`cache_hasher`](BatchFetcherBuilder::cache_hasher)
+    /// afterwards replaces this option).
+    pub fn read_optimized_cache(mut self) -> Self {
+        self.cache_kind = CacheKind::ReadOptimized;
+        self
+    }
+
+    /// Hash cached keys with a custom hasher instead of the standard
+    /// library's default hasher, such as a faster non-cryptographic hasher
+    /// for integer or UUID keys. Cached values are stored in shards like
+    /// with [`read_optimized_cache`](BatchFetcherBuilder::read_optimized_cache).
+    ///
+    /// Only use a hasher without protection against HashDoS attacks if
+    /// keys can't be chosen by untrusted callers.
+    ///
+    /// The hasher is stored as a trait object, so each hash goes through
+    /// dynamic dispatch. This keeps the hasher out of the `BatchFetcher`
+    /// type, and the cost is small next to the locking around each lookup,
+    /// but it does mean the hasher can't be inlined.
+    ///
+    /// This replaces [`read_optimized_cache`](BatchFetcherBuilder::read_optimized_cache)
+    /// if it was set (and calling [`read_optimized_cache`](BatchFetcherBuilder::read_optimized_cache)
+    /// afterwards goes back to the standard library's hasher).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::BatchFetcher;
+    /// # use std::collections::HashMap;
+    /// # use std::hash::BuildHasherDefault;
+    /// # #[derive(Default)] struct FxHasher(u64);
+    /// # impl std::hash::Hasher for FxHasher {
+    /// #     fn finish(&self) -> u64 { self.0 }
+    /// #     fn write(&mut self, bytes: &[u8]) {
+    /// #         for byte in bytes {
+    /// #             self.0 = (self.0.rotate_left(5) ^ u64::from(*byte)).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    /// #         }
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+    ///     anyhow::Ok(ids.into_iter().map(|id| (id, id)).collect::<HashMap<_, _>>())
+    /// })
+    /// .cache_hasher(BuildHasherDefault::<FxHasher>::default())
+    /// .finish();
+    ///
+    /// assert_eq!(batch_fetcher.load(1).await?, 1);
+    /// # Ok(()) }
+    /// ```
+    pub fn cache_hasher(mut self, hasher: impl BuildHasher + Send + Sync + 'static) -> Self {
+        self.cache_kind = CacheKind::CustomHasher(Arc::new(hasher));
+        self
+    }
+
+    /// Use a custom [`Spawner`] to spawn the [`BatchFetcher`]'s background
+    /// tasks, such as to run batches under an executor other than Tokio.
+    /// Defaults to [`TokioRuntime`](crate::TokioRuntime) with the `tokio`
+    /// feature, and must be set otherwise.
+    pub fn spawner(mut self, spawner: impl Spawner + 'static) -> Self {
+        self.spawner = Some(Arc::new(spawner));
+        self
+    }
+
+    /// Use a custom [`Timer`] to wait for the delay set by
+    /// [`delay_duration`](BatchFetcherBuilder::delay_duration) (or by the
+    /// [`scheduler`](BatchFetcherBuilder::scheduler)). Defaults to
+    /// [`TokioRuntime`](crate::TokioRuntime) with the `tokio` feature, and
+    /// must be set otherwise.
+    pub fn timer(mut self, timer: impl Timer + 'static) -> Self {
+        self.timer = Some(Arc::new(timer));
+        self
+    }
+
     /// Set a label for the [`BatchFetcher`]. This is only used to improve
     /// diagnostic messages, such as log messages.
     pub fn label(mut self, label: impl Into<Cow<'static, str>>) -> Self {
@@ -270,128 +1409,1184 @@ where
         self
     }
 
+    /// Create and return a [`BatchFetcher`] that runs its background tasks
+    /// on the current Tokio [`LocalSet`](tokio::task::LocalSet) (using
+    /// [`spawn_local`](tokio::task::spawn_local)), so the fetcher doesn't
+    /// need to be `Send` or `Sync`. See [`LocalFetcher`]. Any
+    /// [`spawner`](BatchFetcherBuilder::spawner) set on the builder is
+    /// ignored, but the [`timer`](BatchFetcherBuilder::timer) is still used.
+    /// Requires the `tokio` feature (enabled by default).
+    ///
+    /// Unlike [`finish`](BatchFetcherBuilder::finish), the background task
+    /// isn't restarted if it stops unexpectedly, since it can only be spawned
+    /// from within the [`LocalSet`](tokio::task::LocalSet).
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a [`LocalSet`](tokio::task::LocalSet).
+    #[cfg(feature = "tokio")]
+    pub fn finish_local(self) -> BatchFetcher<F> {
+        let fetch_task = self.into_fetch_task(LocalSpawner);
+        let (fetch_request_tx, fetch_task_guard, task) = fetch_task.start();
+        spawn_local_named(&fetch_task.task_name(), task);
+        fetch_task.batch_fetcher(TaskHandle::new(fetch_request_tx, Some(fetch_task_guard)))
+    }
+
+    /// Create the fetch task for the [`BatchFetcher`], which uses `spawner`
+    /// to dispatch each batch.
+    fn into_fetch_task<S>(self, spawner: S) -> FetchTask<F, S> {
+        let scheduler = self.scheduler.unwrap_or_else(|| {
+            Arc::new(DefaultBatchScheduler::from_options(
+                self.delay,
+                self.eager_batch_size,
+            ))
+        });
+        let cache_store = CacheStore::with_kind(self.cache_kind);
+        FetchTask {
+            label: self.label,
+            fetcher: Arc::new(self.fetcher),
+            cache_store,
+            stats: Arc::new(FetcherStats::default()),
+            queued_keys: Arc::new(QueuedKeys::default()),
+            in_flight_keys: Arc::new(InFlightKeys::default()),
+            scheduler,
+            metrics: self.metrics,
+            slow_batch_threshold: self.slow_batch_threshold,
+            timer: self.timer.unwrap_or_else(default_timer),
+            spawner,
+            max_batch_size: self.max_batch_size,
+            max_concurrent_batches: self.max_concurrent_batches,
+            shard_keys: self.shard_keys,
+            validate_key: self.validate_key,
+        }
+    }
+}
+
+impl<F> BatchFetcherBuilder<F>
+where
+    F: Fetcher + Send + Sync + 'static,
+{
+    /// Create and return a [`BatchFetcher`] whose background tasks are spawned
+    /// onto the Tokio runtime for `handle`. Unlike [`finish`](BatchFetcherBuilder::finish),
+    /// this can be called before the runtime has started or from outside of
+    /// it. This is the same as setting a [`spawner`](BatchFetcherBuilder::spawner)
+    /// of [`TokioRuntime::with_handle`](crate::TokioRuntime::with_handle),
+    /// except that if the runtime for `handle` shuts down, the background
+    /// task is restarted on the default runtime. Requires the `tokio`
+    /// feature (enabled by default).
+    #[cfg(feature = "tokio")]
+    pub fn finish_on(self, handle: &tokio::runtime::Handle) -> BatchFetcher<F> {
+        let fallback_spawner = crate::runtime::try_default_spawner();
+        self.spawner(crate::TokioRuntime::with_handle(handle.clone()))
+            .finish_with_fallback(fallback_spawner)
+    }
+
+    /// Create and return a [`BatchFetcher`] that doesn't spawn any background
+    /// tasks, along with a [`BatchDriver`] future that must be polled to
+    /// queue up and dispatch batches. This is useful where spawning detached
+    /// tasks isn't possible or desirable. Any [`spawner`](BatchFetcherBuilder::spawner)
+    /// set on the builder is ignored, but the [`timer`](BatchFetcherBuilder::timer)
+    /// is still used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no [`timer`](BatchFetcherBuilder::timer) was set and the `tokio`
+    /// feature is disabled.
+    pub fn finish_with_driver(self) -> (BatchFetcher<F>, BatchDriver) {
+        let (spawner, driver) = BatchDriver::new();
+        (self.spawner(spawner).finish(), driver)
+    }
+
     /// Create and return a [`BatchFetcher`] with the given options.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no [`spawner`](BatchFetcherBuilder::spawner) or
+    /// [`timer`](BatchFetcherBuilder::timer) was set and the `tokio`
+    /// feature is disabled.
     pub fn finish(self) -> BatchFetcher<F> {
-        let cache_store = CacheStore::new();
+        self.finish_with_fallback(None)
+    }
 
-        let (fetch_request_tx, mut fetch_request_rx) =
-            tokio::sync::mpsc::channel::<FetchRequest<F::Key>>(1);
-        let label = self.label.clone();
+    /// Like [`finish`](BatchFetcherBuilder::finish), but if the background
+    /// task stops right after being restarted with the configured spawner,
+    /// it's restarted again with `fallback_spawner`. Without a fallback, the
+    /// task is only ever spawned with the configured spawner.
+    fn finish_with_fallback(self, fallback_spawner: Option<Arc<dyn Spawner>>) -> BatchFetcher<F> {
+        let spawner = self.spawner.clone().unwrap_or_else(default_spawner);
+        let fetch_task = self.into_fetch_task(spawner);
+        let (fetch_request_tx, fetch_task_guard, task) = fetch_task.start();
+        fetch_task
+            .spawner
+            .spawn_named(&fetch_task.task_name(), Box::pin(task));
 
-        let fetch_task = tokio::spawn({
-            let cache_store = cache_store.clone();
-            async move {
-                'task: loop {
-                    // Wait for some keys to come in
-                    let mut pending_keys = HashSet::new();
-                    let mut result_txs = vec![];
-
-                    tracing::trace!(batch_fetcher = %self.label, "waiting for keys to fetch...");
-                    match fetch_request_rx.recv().await {
-                        Some(fetch_request) => {
-                            tracing::trace!(batch_fetcher = %self.label, num_fetch_request_keys = fetch_request.keys.len(), "received initial fetch request");
-
-                            for key in fetch_request.keys {
-                                pending_keys.insert(key);
-                            }
-                            result_txs.push(fetch_request.result_tx);
-                        }
-                        None => {
-                            // Fetch queue closed, so we're done
-                            break 'task;
-                        }
-                    };
+        // If the fetch task stops unexpectedly (such as when the runtime it
+        // was spawned on shuts down), start a new one with the same cache. If
+        // the spawner can't run it anymore, the new task is only spawned with
+        // the fallback spawner, if there is one
+        let fetch_task_handle = TaskHandle::restartable(
+            fetch_request_tx,
+            Some(fetch_task_guard),
+            {
+                let fetch_task = fetch_task.clone();
+                move |use_default_spawner| {
+                    let mut fetch_task = fetch_task.clone();
+                    if use_default_spawner {
+                        fetch_task.spawner = fallback_spawner.clone()?;
+                    }
+                    tracing::warn!(batch_fetcher = %fetch_task.label, "fetch task stopped unexpectedly, restarting");
+                    let (fetch_request_tx, fetch_task_guard, task) = fetch_task.start();
+                    fetch_task
+                        .spawner
+                        .spawn_named(&fetch_task.task_name(), Box::pin(task));
+                    Some((fetch_request_tx, Some(fetch_task_guard)))
+                }
+            },
+        );
+        fetch_task.batch_fetcher(fetch_task_handle)
+    }
+}
 
-                    // Wait for more keys
-                    'wait_for_more_keys: loop {
-                        let should_run_batch_now = match self.eager_batch_size {
-                            Some(eager_batch_size) => pending_keys.len() >= eager_batch_size,
-                            None => false,
-                        };
-                        if should_run_batch_now {
-                            // We have enough keys already, so don't wait for more
-                            tracing::trace!(
-                                batch_fetcher = %self.label,
-                                num_pending_keys = pending_keys.len(),
-                                eager_batch_size = ?self.eager_batch_size,
-                                "batch filled up, ready to fetch keys now",
-                            );
+/// The background task for a [`BatchFetcher`], which queues up keys and
+/// dispatches them in batches. This holds everything needed to start the
+/// task, so it can be started again with the same cache if it stops.
+struct FetchTask<F, S>
+where
+    F: LocalFetcher,
+{
+    label: Cow<'static, str>,
+    fetcher: Arc<F>,
+    cache_store: CacheStore<F::Key, F::Value>,
+    stats: Arc<FetcherStats>,
+    queued_keys: Arc<QueuedKeys<F::Key, F::Value>>,
+    in_flight_keys: Arc<InFlightKeys<F::Key, F::Value>>,
+    scheduler: Arc<dyn BatchScheduler>,
+    metrics: Option<Arc<dyn BatchMetrics>>,
+    slow_batch_threshold: Option<Duration>,
+    timer: Arc<dyn Timer>,
+    spawner: S,
+    max_batch_size: Option<usize>,
+    max_concurrent_batches: usize,
+    shard_keys: Option<ShardKeys<F::Key, F::Value>>,
+    validate_key: Option<ValidateKey<F::Key>>,
+}
 
-                            break 'wait_for_more_keys;
-                        }
+impl<F, S> Clone for FetchTask<F, S>
+where
+    F: LocalFetcher,
+    S: Clone,
+{
+    fn clone(&self) -> Self {
+        FetchTask {
+            label: self.label.clone(),
+            fetcher: self.fetcher.clone(),
+            cache_store: self.cache_store.clone(),
+            stats: self.stats.clone(),
+            queued_keys: self.queued_keys.clone(),
+            in_flight_keys: self.in_flight_keys.clone(),
+            scheduler: self.scheduler.clone(),
+            metrics: self.metrics.clone(),
+            slow_batch_threshold: self.slow_batch_threshold,
+            timer: self.timer.clone(),
+            spawner: self.spawner.clone(),
+            max_batch_size: self.max_batch_size,
+            max_concurrent_batches: self.max_concurrent_batches,
+            shard_keys: self.shard_keys.clone(),
+            validate_key: self.validate_key.clone(),
+        }
+    }
+}
 
-                        let delay = tokio::time::sleep(self.delay_duration);
-                        tokio::pin!(delay);
-
-                        tokio::select! {
-                            fetch_request = fetch_request_rx.recv() => {
-                                match fetch_request {
-                                    Some(fetch_request) => {
-                                        tracing::trace!(batch_fetcher = %self.label, num_fetch_request_keys = fetch_request.keys.len(), "retrieved additional fetch request");
-
-                                        for key in fetch_request.keys {
-                                            pending_keys.insert(key);
-                                        }
-                                        result_txs.push(fetch_request.result_tx);
-                                    }
-                                    None => {
-                                        // Fetch queue closed, so we're done waiting for keys
-                                        tracing::debug!(batch_fetcher = %self.label, num_pending_keys = pending_keys.len(), "fetch channel closed");
-                                        break 'wait_for_more_keys;
-                                    }
-                                }
+impl<F, S> FetchTask<F, S>
+where
+    F: LocalFetcher + 'static,
+    S: SpawnFetchBatch<F> + Clone,
+{
+    /// Create a [`BatchFetcher`] that sends requests to this task.
+    fn batch_fetcher(
+        &self,
+        fetch_task: TaskHandle<FetchMessage<F::Key, F::Value>>,
+    ) -> BatchFetcher<F> {
+        BatchFetcher {
+            label: self.label.clone(),
+            cache_store: self.cache_store.clone(),
+            stats: self.stats.clone(),
+            queued_keys: self.queued_keys.clone(),
+            metrics: self.metrics.clone(),
+            validate_key: self.validate_key.clone(),
+            fetch_task: Arc::new(fetch_task),
+        }
+    }
 
-                            }
-                            _ = &mut delay => {
+    /// Create the future for a new run of the task, along with the sending
+    /// half of its queue and a guard that aborts it.
+    fn start(
+        &self,
+    ) -> (
+        FetchMessageSender<F::Key, F::Value>,
+        AbortOnDrop,
+        impl Future<Output = ()>,
+    ) {
+        let (fetch_request_tx, fetch_request_rx) =
+            tokio::sync::mpsc::channel::<FetchMessage<F::Key, F::Value>>(1);
+
+        // Stop the fetch task once every clone of the `BatchFetcher` is gone,
+        // even if it's waiting on an in-flight batch
+        let task = self
+            .clone()
+            .run(fetch_request_rx)
+            .instrument(tracing::info_span!("fetch_task", batch_fetcher = %self.label));
+        let (task, fetch_task_guard) = AbortOnDrop::new(task);
+        (fetch_request_tx, fetch_task_guard, task)
+    }
+
+    /// The name of the spawned task, to identify it in tools like
+    /// tokio-console.
+    fn task_name(&self) -> String {
+        format!("ultra-batch fetcher {}", self.label)
+    }
+
+    async fn run(
+        self,
+        mut fetch_request_rx: tokio::sync::mpsc::Receiver<FetchMessage<F::Key, F::Value>>,
+    ) {
+        let FetchTask {
+            label,
+            fetcher,
+            cache_store,
+            stats,
+            queued_keys,
+            in_flight_keys,
+            scheduler,
+            metrics,
+            slow_batch_threshold,
+            timer,
+            spawner,
+            max_batch_size,
+            max_concurrent_batches,
+            shard_keys,
+            // Keys are validated by callers before they're queued
+            validate_key: _,
+        } = self;
+        let in_flight_batches = InFlightBatches::new(max_concurrent_batches);
+        let mut shutdown_txs = vec![];
+
+        // Drop the queued waiters if the task stops (e.g. if it's aborted),
+        // so their callers get `LoadError::SendError` instead of waiting
+        // forever
+        let _clear_queued_keys = ClearOnDrop(&queued_keys);
+
+        'task: loop {
+            // Wait for the in-flight batches to make room before
+            // starting a new batch
+            let mut batch_permit = Some(in_flight_batches.acquire().await);
+
+            // Wait for some keys to come in
+            let mut num_pending_keys;
+            let mut num_waiters = 0;
+            let batch_context;
+
+            tracing::trace!(batch_fetcher = %label, "waiting for keys to fetch...");
+            loop {
+                match fetch_request_rx.recv().await {
+                    Some(FetchMessage::Load(fetch_request)) => {
+                        tracing::trace!(batch_fetcher = %label, num_fetch_request_keys = fetch_request.keys.len(), "received initial fetch request");
+
+                        batch_context = fetch_request.context.clone();
+                        num_pending_keys = queued_keys.add(fetch_request, &in_flight_keys);
+                        num_waiters += 1;
+                        stats
+                            .pending_keys
+                            .store(num_pending_keys, Ordering::Relaxed);
+                        record_queue_depth(&metrics, &label, num_pending_keys);
+                        break;
+                    }
+                    Some(FetchMessage::Flush) => {
+                        // No keys queued, so there's nothing to flush
+                        tracing::trace!(batch_fetcher = %label, "received flush with no pending keys");
+                    }
+                    Some(FetchMessage::Shutdown(shutdown_tx)) => {
+                        // Stop accepting new requests, but keep
+                        // fetching any that were already queued
+                        tracing::debug!(batch_fetcher = %label, "received shutdown, closing fetch channel");
+                        fetch_request_rx.close();
+                        shutdown_txs.push(shutdown_tx);
+                    }
+                    None => {
+                        // Fetch queue closed, so we're done
+                        break 'task;
+                    }
+                }
+            }
+
+            let batch_started_at = Instant::now();
+            let mut delay = DelayTimer::new(timer.clone());
+            fetcher.on_batch_queued(&PendingBatch {
+                len: num_pending_keys,
+                num_requests: num_waiters,
+                elapsed: Duration::ZERO,
+            });
+
+            // Wait for more keys
+            'wait_for_more_keys: loop {
+                let pending_batch = PendingBatch {
+                    len: num_pending_keys,
+                    num_requests: num_waiters,
+                    elapsed: batch_started_at.elapsed(),
+                };
+                let schedule = scheduler.schedule(&pending_batch);
+
+                let fetch_message = match schedule {
+                    Schedule::DispatchNow => {
+                        // The batch is ready, so don't wait for more keys
+                        tracing::trace!(
+                            batch_fetcher = %label,
+                            num_pending_keys,
+                            "batch scheduled, ready to fetch keys now",
+                        );
+                        break 'wait_for_more_keys;
+                    }
+                    Schedule::WaitFor(delay_duration) => {
+                        delay
+                            .sleep_until(batch_started_at + pending_batch.elapsed + delay_duration);
+                        let fetch_message = std::pin::pin!(fetch_request_rx.recv());
+
+                        match futures_util::future::select(fetch_message, &mut delay).await {
+                            Either::Left((fetch_message, _)) => fetch_message,
+                            Either::Right(((), _)) => {
                                 // Reached delay, so we're done waiting for keys
                                 tracing::trace!(
-                                    batch_fetcher = %self.label,
-                                    num_pending_keys = pending_keys.len(),
+                                    batch_fetcher = %label,
+                                    num_pending_keys,
                                     "delay reached while waiting for more keys to fetch"
                                 );
                                 break 'wait_for_more_keys;
                             }
-                        };
+                        }
                     }
+                    Schedule::WaitForNextTick => {
+                        // Let other tasks run, then only take keys
+                        // that were queued in the meantime
+                        spawner.yield_now().await;
 
-                    let result = {
-                        let mut cache = cache_store.as_cache();
+                        match fetch_request_rx.try_recv() {
+                            Ok(fetch_message) => Some(fetch_message),
+                            Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {
+                                tracing::trace!(
+                                    batch_fetcher = %label,
+                                    num_pending_keys,
+                                    "no more keys queued after yielding"
+                                );
+                                break 'wait_for_more_keys;
+                            }
+                            Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => None,
+                        }
+                    }
+                };
 
-                        tracing::trace!(batch_fetcher = %self.label, num_pending_keys = pending_keys.len(), num_pending_channels = result_txs.len(), "fetching keys");
-                        let pending_keys: Vec<_> = pending_keys.into_iter().collect();
-                        let result = self
-                            .fetcher
-                            .fetch(&pending_keys, &mut cache)
-                            .await
-                            .map_err(|error| error.to_string());
+                match fetch_message {
+                    Some(FetchMessage::Load(fetch_request)) => {
+                        tracing::trace!(batch_fetcher = %label, num_fetch_request_keys = fetch_request.keys.len(), "retrieved additional fetch request");
 
-                        if result.is_ok() {
-                            cache.mark_keys_not_found(pending_keys);
-                        }
+                        num_pending_keys = queued_keys.add(fetch_request, &in_flight_keys);
+                        num_waiters += 1;
+                        stats
+                            .pending_keys
+                            .store(num_pending_keys, Ordering::Relaxed);
+                        record_queue_depth(&metrics, &label, num_pending_keys);
+                    }
+                    Some(FetchMessage::Flush) => {
+                        // Caller asked to dispatch the batch now
+                        tracing::trace!(batch_fetcher = %label, num_pending_keys, "flushing pending keys");
+                        break 'wait_for_more_keys;
+                    }
+                    Some(FetchMessage::Shutdown(shutdown_tx)) => {
+                        // Stop accepting new requests, then keep
+                        // collecting keys until the queue is drained
+                        tracing::debug!(batch_fetcher = %label, num_pending_keys, "received shutdown, closing fetch channel");
+                        fetch_request_rx.close();
+                        shutdown_txs.push(shutdown_tx);
+                    }
+                    None => {
+                        // Fetch queue closed, so we're done waiting for keys
+                        tracing::debug!(batch_fetcher = %label, num_pending_keys, "fetch channel closed");
+                        break 'wait_for_more_keys;
+                    }
+                }
+            }
+
+            // Don't bother fetching keys that no caller is waiting
+            // on anymore (e.g. if the load future was dropped)
+            let mut pending_keys = queued_keys.take();
+            pending_keys.retain(|_, waiters| {
+                waiters
+                    .iter()
+                    .any(|KeyWaiter { waiter, .. }| !waiter.is_cancelled())
+            });
+            stats.pending_keys.store(0, Ordering::Relaxed);
+            record_queue_depth(&metrics, &label, 0);
+            if pending_keys.is_empty() {
+                tracing::debug!(batch_fetcher = %label, "all callers waiting on batch were cancelled");
+                continue 'task;
+            }
+
+            tracing::trace!(batch_fetcher = %label, num_pending_keys = pending_keys.len(), num_pending_channels = num_waiters, "fetching keys");
 
-                        result
+            // Split the keys by shard, then split each shard into batches
+            let pending_keys: Vec<_> = pending_keys.into_iter().collect();
+            let shards = match &shard_keys {
+                Some(shard_keys) => shard_keys(pending_keys),
+                None => vec![pending_keys],
+            };
+            for mut pending_keys in shards {
+                let max_batch_size = max_batch_size.unwrap_or(pending_keys.len());
+                while !pending_keys.is_empty() {
+                    let rest = pending_keys.split_off(max_batch_size.min(pending_keys.len()));
+                    let (batch_keys, batch_waiters): (Vec<_>, Vec<_>) =
+                        std::mem::replace(&mut pending_keys, rest)
+                            .into_iter()
+                            .unzip();
+
+                    // Wait for an in-flight batch to finish if we're
+                    // already at the concurrency limit
+                    let permit = match batch_permit.take() {
+                        Some(permit) => permit,
+                        None => in_flight_batches.acquire().await,
                     };
 
-                    for result_tx in result_txs {
-                        // Ignore error if receiver was already closed
-                        let _ = result_tx.send(result.clone());
+                    tracing::trace!(batch_fetcher = %label, num_batch_keys = batch_keys.len(), num_in_flight_batches = stats.in_flight_batches.load(Ordering::Relaxed), "dispatching batch of keys");
+                    let wait_duration = batch_started_at.elapsed();
+                    let num_batch_waiters = count_waiters(&batch_waiters);
+                    let batch_id =
+                        stats.start_batch(batch_keys.len(), num_batch_waiters, wait_duration);
+                    if let Some(metrics) = &metrics {
+                        metrics.on_batch_dispatched(&DispatchedBatch {
+                            label: &label,
+                            size: batch_keys.len(),
+                            waiters: num_batch_waiters,
+                        });
                     }
+                    spawner.spawn_fetch_batch(FetchBatch {
+                        label: label.clone(),
+                        fetcher: fetcher.clone(),
+                        cache_store: cache_store.clone(),
+                        stats: stats.clone(),
+                        scheduler: scheduler.clone(),
+                        metrics: metrics.clone(),
+                        slow_batch_threshold,
+                        context: batch_context.clone(),
+                        batch_info: BatchInfo {
+                            batch_id,
+                            label: label.clone(),
+                            num_waiters: num_batch_waiters,
+                        },
+                        wait_duration,
+                        keys: in_flight_keys.start(batch_keys, batch_waiters),
+                        permit,
+                    });
                 }
             }
+        }
+
+        // Let any in-flight batches finish before reporting that
+        // we've shut down
+        if !shutdown_txs.is_empty() {
+            in_flight_batches.wait_for_all().await;
+            tracing::debug!(batch_fetcher = %label, "finished shutting down");
+            for shutdown_tx in shutdown_txs {
+                let _ = shutdown_tx.send(());
+            }
+        }
+    }
+}
+
+/// A batch of keys that's ready to be fetched, along with everything
+/// needed to fetch it.
+struct FetchBatch<F>
+where
+    F: LocalFetcher,
+{
+    label: Cow<'static, str>,
+    fetcher: Arc<F>,
+    cache_store: CacheStore<F::Key, F::Value>,
+    stats: Arc<FetcherStats>,
+    scheduler: Arc<dyn BatchScheduler>,
+    metrics: Option<Arc<dyn BatchMetrics>>,
+    slow_batch_threshold: Option<Duration>,
+    context: CallerContext,
+    batch_info: BatchInfo,
+    wait_duration: Duration,
+    keys: BatchKeys<F::Key, F::Value>,
+    permit: tokio::sync::OwnedSemaphorePermit,
+}
+
+impl<F> FetchBatch<F>
+where
+    F: LocalFetcher,
+{
+    async fn run(self) {
+        // The batch runs in its own task, so link it back to the span of
+        // each caller waiting on it
+        let span = tracing::debug_span!(
+            "fetch_batch",
+            batch_fetcher = %self.label,
+            num_keys = self.keys.len(),
+        );
+        self.keys.link_waiters(&span);
+
+        self.fetch().instrument(span).await;
+    }
+
+    async fn fetch(self) {
+        self.fetcher.on_batch_dispatching(&self.batch_info);
+
+        let mut cache = self.cache_store.as_batch_cache(&self.batch_info);
+        let fetch_started_at = Instant::now();
+        let result = self
+            .context
+            .clone()
+            .scope(|| self.fetcher.fetch(&self.keys.keys, &mut cache))
+            .await;
+        let duration = fetch_started_at.elapsed();
+        if let Some(threshold) = self.slow_batch_threshold {
+            if duration > threshold {
+                tracing::warn!(
+                    batch_fetcher = %self.label,
+                    num_keys = self.keys.len(),
+                    ?duration,
+                    ?threshold,
+                    "batch took longer than the slow batch threshold",
+                );
+            }
+        }
+        self.scheduler.batch_completed(&CompletedBatch {
+            len: self.keys.len(),
+            wait_duration: self.wait_duration,
+            duration,
         });
 
-        BatchFetcher {
-            label,
-            cache_store,
-            _fetch_task: Arc::new(fetch_task),
-            fetch_request_tx,
+        // The error is shared by every waiter in the batch
+        let result = result.map_err(|error| Arc::<str>::from(error.to_string()));
+        let finished_batch = FinishedBatch {
+            label: &self.label,
+            size: self.keys.len(),
+            duration,
+            result: result.as_ref().map(|_| ()).map_err(|error| &**error),
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.on_batch_completed(&finished_batch);
+        }
+        self.fetcher.on_batch_complete(&finished_batch);
+
+        // Stop tracking the batch before waking any callers, so they never
+        // see their own batch as still in flight
+        self.stats.finish_batch(self.batch_info.batch_id);
+
+        let mut keys = self.keys;
+        match result {
+            Ok(()) => {
+                // Update the cache before taking the waiters, so any load
+                // that misses the batch finds its keys in the cache instead
+                let resolved = cache.resolve_keys(&keys.keys);
+                let waiters = keys.finish();
+                for (load_state, waiters) in resolved.into_iter().zip(&waiters) {
+                    for KeyWaiter { waiter, key_index } in waiters {
+                        waiter.resolve(*key_index, load_state.clone());
+                    }
+                }
+            }
+            Err(error) => {
+                for KeyWaiter { waiter, .. } in keys.finish().iter().flatten() {
+                    waiter.fail(&error);
+                }
+            }
+        }
+
+        // Each caller is woken once the last batch containing one of its
+        // keys is dropped
+        drop(keys);
+        drop(self.permit);
+    }
+}
+
+/// Used by a fetch task to dispatch each batch in its own task.
+trait SpawnFetchBatch<F>
+where
+    F: LocalFetcher,
+{
+    fn spawn_fetch_batch(&self, batch: FetchBatch<F>);
+
+    fn yield_now(&self) -> impl Future<Output = ()>;
+}
+
+impl<F> SpawnFetchBatch<F> for Arc<dyn Spawner>
+where
+    F: Fetcher + Send + Sync + 'static,
+{
+    fn spawn_fetch_batch(&self, batch: FetchBatch<F>) {
+        self.spawn(Box::pin(batch.run()));
+    }
+
+    fn yield_now(&self) -> impl Future<Output = ()> {
+        Spawner::yield_now(self)
+    }
+}
+
+/// Spawns each batch onto the current Tokio `LocalSet`.
+#[cfg(feature = "tokio")]
+#[derive(Clone)]
+struct LocalSpawner;
+
+#[cfg(feature = "tokio")]
+impl<F> SpawnFetchBatch<F> for LocalSpawner
+where
+    F: LocalFetcher + 'static,
+{
+    fn spawn_fetch_batch(&self, batch: FetchBatch<F>) {
+        tokio::task::spawn_local(batch.run());
+    }
+
+    fn yield_now(&self) -> impl Future<Output = ()> {
+        tokio::task::yield_now()
+    }
+}
+
+/// Counters shared between a [`BatchFetcher`] and its fetch task, used for
+/// introspection.
+#[derive(Default)]
+struct FetcherStats {
+    pending_keys: AtomicUsize,
+    in_flight_batches: AtomicUsize,
+    num_batches: AtomicU64,
+    num_keys_requested: AtomicU64,
+    num_keys_fetched: AtomicU64,
+    total_wait_nanos: AtomicU64,
+    in_flight: Mutex<HashMap<u64, InFlightBatch>>,
+}
+
+impl FetcherStats {
+    /// Record that a batch was dispatched, returning an ID to pass to
+    /// [`finish_batch`](FetcherStats::finish_batch) once it's done.
+    fn start_batch(&self, num_keys: usize, num_waiters: usize, wait_duration: Duration) -> u64 {
+        let wait_nanos = u64::try_from(wait_duration.as_nanos()).unwrap_or(u64::MAX);
+        let batch_id = self.num_batches.fetch_add(1, Ordering::Relaxed);
+        self.num_keys_fetched
+            .fetch_add(num_keys as u64, Ordering::Relaxed);
+        self.total_wait_nanos
+            .fetch_add(wait_nanos, Ordering::Relaxed);
+
+        self.in_flight_batches.fetch_add(1, Ordering::Relaxed);
+        self.lock_in_flight().insert(
+            batch_id,
+            InFlightBatch {
+                num_keys,
+                num_waiters,
+                started_at: Instant::now(),
+            },
+        );
+
+        batch_id
+    }
+
+    fn finish_batch(&self, batch_id: u64) {
+        self.in_flight_batches.fetch_sub(1, Ordering::Relaxed);
+        self.lock_in_flight().remove(&batch_id);
+    }
+
+    fn lock_in_flight(&self) -> std::sync::MutexGuard<'_, HashMap<u64, InFlightBatch>> {
+        lock(&self.in_flight)
+    }
+}
+
+struct InFlightBatch {
+    num_keys: usize,
+    num_waiters: usize,
+    started_at: Instant,
+}
+
+/// The keys queued for the next batch, along with the waiters for each
+/// key. This is shared between a [`BatchFetcher`] and its fetch task so the
+/// queue can be inspected. Keys are kept in the order they were first
+/// queued, so the [`Fetcher`] sees them in a stable order.
+struct QueuedKeys<K, V>(Mutex<OrderedMap<K, Vec<KeyWaiter<V>>>>);
+
+impl<K, V> Default for QueuedKeys<K, V> {
+    fn default() -> Self {
+        QueuedKeys(Mutex::new(OrderedMap::default()))
+    }
+}
+
+impl<K, V> QueuedKeys<K, V>
+where
+    K: Clone + Hash + Eq,
+{
+    /// Queue the keys from a request, returning the new number of
+    /// queued keys. Keys that are already being fetched by an in-flight
+    /// batch wait on that batch instead of being queued again.
+    fn add(&self, fetch_request: FetchRequest<K, V>, in_flight_keys: &InFlightKeys<K, V>) -> usize {
+        let mut queued_keys = self.lock();
+        fetch_request.add_to_batch(&mut queued_keys, &mut in_flight_keys.lock());
+        queued_keys.len()
+    }
+
+    /// Take all the queued keys, leaving the queue empty.
+    fn take(&self) -> OrderedMap<K, Vec<KeyWaiter<V>>> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, OrderedMap<K, Vec<KeyWaiter<V>>>> {
+        lock(&self.0)
+    }
+}
+
+/// Clears the queued keys when dropped.
+struct ClearOnDrop<'a, K, V>(&'a QueuedKeys<K, V>)
+where
+    K: Clone + Hash + Eq;
+
+impl<K, V> Drop for ClearOnDrop<'_, K, V>
+where
+    K: Clone + Hash + Eq,
+{
+    fn drop(&mut self) {
+        drop(self.0.take());
+    }
+}
+
+/// The keys being fetched by in-flight batches, along with the waiters for
+/// each key. Loads for a key that's already being fetched are added as
+/// waiters here instead of being fetched again in the next batch.
+struct InFlightKeys<K, V>(Mutex<HashMap<K, Vec<KeyWaiter<V>>>>);
+
+impl<K, V> Default for InFlightKeys<K, V> {
+    fn default() -> Self {
+        InFlightKeys(Mutex::new(HashMap::new()))
+    }
+}
+
+impl<K, V> InFlightKeys<K, V>
+where
+    K: Clone + Hash + Eq,
+{
+    /// Mark the keys for a batch as in flight, returning a guard that
+    /// removes them once the batch finishes.
+    fn start(self: &Arc<Self>, keys: Vec<K>, waiters: Vec<Vec<KeyWaiter<V>>>) -> BatchKeys<K, V> {
+        let mut in_flight_keys = self.lock();
+        for (key, waiters) in keys.iter().zip(waiters) {
+            in_flight_keys.insert(key.clone(), waiters);
+        }
+
+        BatchKeys {
+            keys,
+            in_flight_keys: self.clone(),
+            finished: false,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<K, Vec<KeyWaiter<V>>>> {
+        lock(&self.0)
+    }
+}
+
+/// The keys fetched by a single batch. The keys stay in flight until
+/// [`finish`](BatchKeys::finish) is called, or until the batch is dropped
+/// (e.g. if the fetcher panics).
+struct BatchKeys<K, V>
+where
+    K: Clone + Hash + Eq,
+{
+    keys: Vec<K>,
+    in_flight_keys: Arc<InFlightKeys<K, V>>,
+    finished: bool,
+}
+
+impl<K, V> BatchKeys<K, V>
+where
+    K: Clone + Hash + Eq,
+{
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Link `span` to the span of each caller currently waiting on the
+    /// batch.
+    fn link_waiters(&self, span: &tracing::Span) {
+        let in_flight_keys = self.in_flight_keys.lock();
+        let mut linked_waiters = HashSet::new();
+        let waiters = self
+            .keys
+            .iter()
+            .filter_map(|key| in_flight_keys.get(key))
+            .flatten();
+        for KeyWaiter { waiter, .. } in waiters {
+            if linked_waiters.insert(waiter.as_ptr()) {
+                span.follows_from(&waiter.span);
+            }
+        }
+    }
+
+    /// Stop tracking the keys as in flight, returning the waiters for each
+    /// key in the same order as the keys.
+    fn finish(&mut self) -> Vec<Vec<KeyWaiter<V>>> {
+        self.finished = true;
+        let mut in_flight_keys = self.in_flight_keys.lock();
+        self.keys
+            .iter()
+            .map(|key| in_flight_keys.remove(key).unwrap_or_default())
+            .collect()
+    }
+}
+
+impl<K, V> Drop for BatchKeys<K, V>
+where
+    K: Clone + Hash + Eq,
+{
+    fn drop(&mut self) {
+        if !self.finished {
+            // Drop the waiters outside of the lock
+            let waiters = self.finish();
+            drop(waiters);
         }
     }
 }
 
-struct FetchRequest<K> {
+/// Count the distinct waiters for a batch, since a waiter is listed once
+/// for each of its keys.
+fn count_waiters<V>(waiters: &[Vec<KeyWaiter<V>>]) -> usize {
+    let waiters: HashSet<_> = waiters
+        .iter()
+        .flatten()
+        .map(|key_waiter| key_waiter.waiter.as_ptr())
+        .collect();
+    waiters.len()
+}
+
+/// Aggregate stats about the batches dispatched by a [`BatchFetcher`],
+/// returned by [`BatchFetcher::stats`].
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::BatchFetcher;
+/// # use std::collections::HashMap;
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+///     anyhow::Ok(ids.into_iter().map(|id| (id, id)).collect::<HashMap<_, _>>())
+/// })
+/// .finish();
+///
+/// let (first, second) = tokio::join!(
+///     batch_fetcher.load_many(&[1, 2]),
+///     batch_fetcher.load_many(&[2, 3]),
+/// );
+/// first?;
+/// second?;
+///
+/// let stats = batch_fetcher.stats();
+/// assert_eq!(stats.num_batches, 1);
+/// assert_eq!(stats.num_keys_requested, 4);
+/// assert_eq!(stats.num_keys_fetched, 3);
+/// assert_eq!(stats.average_batch_size(), 3.0);
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BatchFetcherStats {
+    /// The number of batches passed to the [`Fetcher`].
+    pub num_batches: u64,
+
+    /// The total number of keys passed to `load`, `load_many`, etc.,
+    /// including keys that were already cached or requested more than once.
+    pub num_keys_requested: u64,
+
+    /// The total number of keys passed to the [`Fetcher`]. The difference
+    /// from [`num_keys_requested`](BatchFetcherStats::num_keys_requested) is
+    /// the number of fetches saved by caching and deduplicating keys.
+    pub num_keys_fetched: u64,
+
+    /// The total time batches spent waiting for more keys before being
+    /// dispatched.
+    pub total_wait_duration: Duration,
+}
+
+/// A snapshot of the state of a [`BatchFetcher`], returned by
+/// [`BatchFetcher::inspect`] and [`BatchFetcher::inspect_with_keys`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct BatchFetcherState<K> {
+    /// The label of the [`BatchFetcher`].
+    pub label: Cow<'static, str>,
+
+    /// The number of distinct keys queued for the next batch.
+    pub num_queued_keys: usize,
+
+    /// The number of separate requests (calls to `load`, `load_many`, etc.)
+    /// waiting on the queued keys.
+    pub num_queued_waiters: usize,
+
+    /// The keys queued for the next batch, in the order they were first
+    /// queued. This is only set by [`BatchFetcher::inspect_with_keys`].
+    pub queued_keys: Option<Vec<K>>,
+
+    /// The batches currently being fetched, from oldest to newest.
+    pub in_flight_batches: Vec<InFlightBatchState>,
+
+    /// The number of keys in the cache, including keys that were marked as
+    /// "not found".
+    pub num_cached: usize,
+}
+
+/// Details about a batch being fetched, which a [`Fetcher`] can get from
+/// [`Cache::batch_info`](crate::Cache::batch_info), such as to log a
+/// correlation ID for the batch.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct BatchInfo {
+    /// An ID for the batch, which is unique within the batch fetcher. IDs
+    /// start at 0 and count up with each batch.
+    pub batch_id: u64,
+
+    /// The label of the batch fetcher, set with
+    /// [`BatchFetcherBuilder::label`] or [`SyncBatchFetcherBuilder::label`](crate::SyncBatchFetcherBuilder::label).
+    pub label: Cow<'static, str>,
+
+    /// The number of separate requests waiting on the batch.
+    pub num_waiters: usize,
+}
+
+/// A batch that is currently being fetched, as part of a
+/// [`BatchFetcherState`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct InFlightBatchState {
+    /// The number of keys passed to the [`Fetcher`].
+    pub num_keys: usize,
+
+    /// The number of separate requests waiting on the batch.
+    pub num_waiters: usize,
+
+    /// How long it's been since the batch was dispatched.
+    pub elapsed: Duration,
+}
+
+impl BatchFetcherStats {
+    /// The average number of keys passed to the [`Fetcher`] in each batch,
+    /// or 0 if no batches have been dispatched.
+    pub fn average_batch_size(&self) -> f64 {
+        if self.num_batches == 0 {
+            return 0.0;
+        }
+
+        self.num_keys_fetched as f64 / self.num_batches as f64
+    }
+
+    /// The average time each batch spent waiting for more keys before being
+    /// dispatched, or zero if no batches have been dispatched.
+    pub fn average_wait_duration(&self) -> Duration {
+        match u32::try_from(self.num_batches) {
+            Ok(0) => Duration::ZERO,
+            Ok(num_batches) => self.total_wait_duration / num_batches,
+            Err(_) => self.total_wait_duration.div_f64(self.num_batches as f64),
+        }
+    }
+}
+
+/// The keys pending for a batch, along with the waiters for each key.
+type PendingKeys<K, V> = Vec<(K, Vec<KeyWaiter<V>>)>;
+
+/// Splits the pending keys for a batch into groups by shard, set with
+/// [`BatchFetcherBuilder::shard_by`].
+type ShardKeys<K, V> = Arc<dyn Fn(PendingKeys<K, V>) -> Vec<PendingKeys<K, V>> + Send + Sync>;
+
+/// Checks each key before it's queued, set with
+/// [`BatchFetcherBuilder::validate_keys`].
+type ValidateKey<K> = Arc<dyn Fn(&K) -> Result<(), Arc<str>> + Send + Sync>;
+
+type FetchMessageSender<K, V> = tokio::sync::mpsc::Sender<FetchMessage<K, V>>;
+
+enum FetchMessage<K, V> {
+    Load(FetchRequest<K, V>),
+    Flush,
+    Shutdown(tokio::sync::oneshot::Sender<()>),
+}
+
+struct FetchRequest<K, V> {
     keys: Vec<K>,
-    result_tx: tokio::sync::oneshot::Sender<Result<(), String>>,
+    waiter: WaiterRef<V>,
+
+    /// The caller's context, propagated into the fetcher if this is the
+    /// first request in a batch.
+    context: CallerContext,
+}
+
+impl<K, V> FetchRequest<K, V>
+where
+    K: Clone + std::hash::Hash + Eq,
+{
+    fn add_to_batch(
+        self,
+        pending_keys: &mut OrderedMap<K, Vec<KeyWaiter<V>>>,
+        in_flight_keys: &mut HashMap<K, Vec<KeyWaiter<V>>>,
+    ) {
+        for (key_index, key) in self.keys.into_iter().enumerate() {
+            let key_waiter = KeyWaiter {
+                waiter: self.waiter.clone(),
+                key_index,
+            };
+            match in_flight_keys.get_mut(&key) {
+                Some(waiters) => waiters.push(key_waiter),
+                None => pending_keys.get_or_default(key).push(key_waiter),
+            }
+        }
+    }
+}
+
+/// A waiter for one of the keys in a batch, along with the position of the
+/// key in the waiter's [`FetchRequest`].
+struct KeyWaiter<V> {
+    waiter: WaiterRef<V>,
+    key_index: usize,
+}
+
+const WAITER_PENDING: u8 = 0;
+const WAITER_DONE: u8 = 1;
+const WAITER_HUNG_UP: u8 = 2;
+
+/// Tracks the result of a single [`FetchRequest`] whose keys may be split
+/// across multiple batches. The caller and every batch containing one of
+/// its keys share the same `FetchWaiter`, so queueing a request only
+/// allocates once. Each batch stores the value it fetched for each of the
+/// request's keys, and the caller is woken once every batch it belongs to
+/// has finished, i.e. when the last [`WaiterRef`] is dropped.
+struct FetchWaiter<V> {
+    /// The number of [`WaiterRef`]s held by the fetch task and its batches.
+    refs: AtomicUsize,
+    state: AtomicU8,
+    result: Mutex<WaiterResult<V>>,
+    cancelled: AtomicBool,
+    waker: AtomicWaker,
+
+    /// The caller's span, linked to the span of each batch that fetches
+    /// one of its keys.
+    span: tracing::Span,
+}
+
+struct WaiterResult<V> {
+    /// The resolved state of each key in the request, by index.
+    resolved: Vec<Option<CacheState<V>>>,
+    error: Option<Arc<str>>,
+}
+
+impl<V> FetchWaiter<V> {
+    fn new(num_keys: usize, span: tracing::Span) -> Self {
+        FetchWaiter {
+            refs: AtomicUsize::new(0),
+            state: AtomicU8::new(WAITER_PENDING),
+            result: Mutex::new(WaiterResult {
+                resolved: std::iter::repeat_with(|| None).take(num_keys).collect(),
+                error: None,
+            }),
+            cancelled: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+            span,
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn lock_result(&self) -> std::sync::MutexGuard<'_, WaiterResult<V>> {
+        lock(&self.result)
+    }
+
+    fn resolve(&self, key_index: usize, load_state: CacheState<V>) {
+        self.lock_result().resolved[key_index] = Some(load_state);
+    }
+
+    fn fail(&self, error: &Arc<str>) {
+        let mut result = self.lock_result();
+        if result.error.is_none() {
+            result.error = Some(error.clone());
+        }
+    }
+}
+
+/// A reference to a [`FetchWaiter`] held by the fetch task or one of its
+/// batches. The caller is woken when the last one is dropped.
+struct WaiterRef<V>(Arc<FetchWaiter<V>>);
+
+impl<V> WaiterRef<V> {
+    fn new(waiter: Arc<FetchWaiter<V>>) -> Self {
+        waiter.refs.fetch_add(1, Ordering::Relaxed);
+        WaiterRef(waiter)
+    }
+
+    fn as_ptr(&self) -> *const FetchWaiter<V> {
+        Arc::as_ptr(&self.0)
+    }
+}
+
+impl<V> std::ops::Deref for WaiterRef<V> {
+    type Target = FetchWaiter<V>;
+
+    fn deref(&self) -> &FetchWaiter<V> {
+        &self.0
+    }
+}
+
+impl<V> Clone for WaiterRef<V> {
+    fn clone(&self) -> Self {
+        WaiterRef::new(self.0.clone())
+    }
+}
+
+impl<V> Drop for WaiterRef<V> {
+    fn drop(&mut self) {
+        if self.0.refs.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return;
+        }
+
+        // Hang up instead of reporting a (possibly incomplete) result
+        let state = if std::thread::panicking() {
+            WAITER_HUNG_UP
+        } else {
+            WAITER_DONE
+        };
+        self.0.state.store(state, Ordering::Release);
+        self.0.waker.wake();
+    }
+}
+
+/// Waits for the result of a [`FetchRequest`], which is the resolved state
+/// of each of its keys. Resolves to `None` if a batch panicked before
+/// finishing. Dropping it marks the request as cancelled.
+struct WaitForFetch<V>(Arc<FetchWaiter<V>>);
+
+impl<V> Future for WaitForFetch<V> {
+    type Output = Option<Result<Vec<Option<CacheState<V>>>, Arc<str>>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let waiter = &self.0;
+        waiter.waker.register(cx.waker());
+        match waiter.state.load(Ordering::Acquire) {
+            WAITER_PENDING => Poll::Pending,
+            WAITER_DONE => {
+                let mut result = waiter.lock_result();
+                Poll::Ready(Some(match result.error.take() {
+                    Some(error) => Err(error),
+                    None => Ok(std::mem::take(&mut result.resolved)),
+                }))
+            }
+            _ => Poll::Ready(None),
+        }
+    }
+}
+
+impl<V> Drop for WaitForFetch<V> {
+    fn drop(&mut self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+    }
 }
 
 /// Error indicating that loading one or more values from a [`BatchFetcher`]
@@ -399,15 +2594,23 @@ struct FetchRequest<K> {
 #[derive(Debug, thiserror::Error)]
 pub enum LoadError {
     /// The [`Fetcher`] returned an error while loading the batch. The message
-    /// contains the error message specified by [`Fetcher::Error`].
+    /// contains the error message specified by [`Fetcher::Error`]. The
+    /// message is shared by every load waiting on the failed batch.
     #[error("error while fetching from batch: {}", _0)]
-    FetchError(String),
+    FetchError(Arc<str>),
 
-    /// The request could not be sent to the [`BatchFetcher`].
+    /// The request could not be sent to the [`BatchFetcher`], or its
+    /// background task stopped before the request finished.
     #[error("error sending fetch request")]
     SendError,
 
     /// The [`Fetcher`] did not return a value for one or more keys in the batch.
     #[error("value not found")]
     NotFound,
+
+    /// One or more keys were rejected by the validation function set with
+    /// [`BatchFetcherBuilder::validate_keys`], so they were never fetched.
+    /// The message contains the error returned by the validation function.
+    #[error("invalid key: {}", _0)]
+    InvalidKey(Arc<str>),
 }
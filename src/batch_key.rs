@@ -0,0 +1,79 @@
+/// Define a struct for a composite key made up of multiple fields, such as a
+/// tenant ID plus a record ID, for use as a [`Fetcher::Key`](crate::Fetcher::Key)
+/// or [`KeyedExecutor::Key`](crate::KeyedExecutor::Key).
+///
+/// The struct derives `Debug`, `Clone`, `PartialEq`, `Eq`, `Hash`,
+/// `PartialOrd`, and `Ord`. The ordering compares each field in the order
+/// they're declared, which gives a canonical order for sorting keys (e.g.
+/// to group keys by tenant before fetching). Conversions to and from a
+/// tuple of the fields are also implemented, so keys can be built from
+/// tuples with `.into()`. Each field type must implement all of the derived
+/// traits.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{batch_key, Cache, Fetcher};
+/// # #[derive(Clone)] struct User { tenant_id: u64, id: u64 }
+/// batch_key! {
+///     /// Identifies a user within a tenant
+///     pub struct TenantUserId {
+///         pub tenant_id: u64,
+///         pub user_id: u64,
+///     }
+/// }
+///
+/// struct UserFetcher;
+///
+/// impl Fetcher for UserFetcher {
+///     type Key = TenantUserId;
+///     type Value = User;
+///     type Error = anyhow::Error;
+///
+///     async fn fetch(&self, keys: &[TenantUserId], values: &mut Cache<'_, TenantUserId, User>) -> anyhow::Result<()> {
+///         let mut keys = keys.to_vec();
+///         keys.sort();
+///         for key in keys {
+///             let user = User { tenant_id: key.tenant_id, id: key.user_id };
+///             values.insert(key, user);
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// let key: TenantUserId = (1, 2).into();
+/// assert_eq!(key, TenantUserId { tenant_id: 1, user_id: 2 });
+/// ```
+#[macro_export]
+macro_rules! batch_key {
+    ($(
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $field:ident : $field_ty:ty
+            ),+ $(,)?
+        }
+    )*) => {$(
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        $vis struct $name {
+            $(
+                $(#[$field_meta])*
+                $field_vis $field: $field_ty,
+            )+
+        }
+
+        impl ::core::convert::From<($($field_ty,)+)> for $name {
+            fn from(($($field,)+): ($($field_ty,)+)) -> Self {
+                $name { $($field),+ }
+            }
+        }
+
+        impl ::core::convert::From<$name> for ($($field_ty,)+) {
+            fn from(key: $name) -> Self {
+                ($(key.$field,)+)
+            }
+        }
+    )*};
+}
@@ -0,0 +1,241 @@
+use crate::{
+    BatchExecutor, BatchExecutorBuilder, BatchFetcher, BatchFetcherBuilder, Cache, Executor,
+    Fetcher,
+};
+use futures_util::future::BoxFuture;
+use std::fmt::Display;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+
+/// An object-safe variant of [`Fetcher`] that returns a boxed future, so it
+/// can be used as a trait object such as `Box<dyn BoxedFetcher<...>>`. Use
+/// [`BatchFetcher::build_boxed`] to create a [`BatchFetcher`] from a
+/// `BoxedFetcher`. Requires the `boxed` feature.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, BoxedFetcher, Cache};
+/// # use futures_util::future::BoxFuture;
+/// struct UserNameFetcher;
+///
+/// impl BoxedFetcher for UserNameFetcher {
+///     type Key = u64;
+///     type Value = String;
+///     type Error = anyhow::Error;
+///
+///     fn fetch<'a>(
+///         &'a self,
+///         keys: &'a [u64],
+///         values: &'a mut Cache<'_, u64, String>,
+///     ) -> BoxFuture<'a, anyhow::Result<()>> {
+///         Box::pin(async move {
+///             for &key in keys {
+///                 values.insert(key, format!("User {key}"));
+///             }
+///             Ok(())
+///         })
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let fetcher: Box<dyn BoxedFetcher<Key = u64, Value = String, Error = anyhow::Error>> =
+///     Box::new(UserNameFetcher);
+/// let batch_fetcher = BatchFetcher::build_boxed(fetcher).finish();
+///
+/// let name = batch_fetcher.load(1).await?;
+/// assert_eq!(name, "User 1");
+/// # Ok(()) }
+/// ```
+pub trait BoxedFetcher: Send + Sync {
+    /// The type used to look up a single value in a batch. See
+    /// [`Fetcher::Key`].
+    type Key: Clone + Hash + Eq + Send + Sync;
+
+    /// The type returned in a batch. See [`Fetcher::Value`].
+    type Value: Clone + Send + Sync;
+
+    /// The error indicating that fetching a batch failed.
+    type Error: Display;
+
+    /// Retrieve the values associated with the given keys, and insert them
+    /// into `values` if found. See [`Fetcher::fetch`].
+    fn fetch<'a>(
+        &'a self,
+        keys: &'a [Self::Key],
+        values: &'a mut Cache<'_, Self::Key, Self::Value>,
+    ) -> BoxFuture<'a, Result<(), Self::Error>>;
+}
+
+impl<F> BoxedFetcher for Box<F>
+where
+    F: BoxedFetcher + ?Sized,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    fn fetch<'a>(
+        &'a self,
+        keys: &'a [Self::Key],
+        values: &'a mut Cache<'_, Self::Key, Self::Value>,
+    ) -> BoxFuture<'a, Result<(), Self::Error>> {
+        (**self).fetch(keys, values)
+    }
+}
+
+impl<F> BoxedFetcher for Arc<F>
+where
+    F: BoxedFetcher + ?Sized,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    fn fetch<'a>(
+        &'a self,
+        keys: &'a [Self::Key],
+        values: &'a mut Cache<'_, Self::Key, Self::Value>,
+    ) -> BoxFuture<'a, Result<(), Self::Error>> {
+        (**self).fetch(keys, values)
+    }
+}
+
+/// An object-safe variant of [`Executor`] that returns a boxed future, so it
+/// can be used as a trait object such as `Box<dyn BoxedExecutor<...>>`. Use
+/// [`BatchExecutor::build_boxed`] to create a [`BatchExecutor`] from a
+/// `BoxedExecutor`. Requires the `boxed` feature.
+pub trait BoxedExecutor: Send + Sync {
+    /// The input value provided by the caller to do something.
+    type Value: Send;
+
+    /// The output value returned by the executor back to the caller for each
+    /// input value.
+    type Result: Send;
+
+    /// The error indicating that executing a batch failed.
+    type Error: Display;
+
+    /// Execute the operation for each value in the batch, returning a result
+    /// for each value. See [`Executor::execute`].
+    fn execute(
+        &self,
+        values: Vec<Self::Value>,
+    ) -> BoxFuture<'_, Result<Vec<Self::Result>, Self::Error>>;
+}
+
+impl<E> BoxedExecutor for Box<E>
+where
+    E: BoxedExecutor + ?Sized,
+{
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+
+    fn execute(
+        &self,
+        values: Vec<Self::Value>,
+    ) -> BoxFuture<'_, Result<Vec<Self::Result>, Self::Error>> {
+        (**self).execute(values)
+    }
+}
+
+impl<E> BoxedExecutor for Arc<E>
+where
+    E: BoxedExecutor + ?Sized,
+{
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+
+    fn execute(
+        &self,
+        values: Vec<Self::Value>,
+    ) -> BoxFuture<'_, Result<Vec<Self::Result>, Self::Error>> {
+        (**self).execute(values)
+    }
+}
+
+/// Adapts a [`BoxedFetcher`] into a [`Fetcher`] or a [`BoxedExecutor`] into
+/// an [`Executor`]. Created with [`BatchFetcher::build_boxed`],
+/// [`BatchExecutor::build_boxed`], or [`Boxed::new`].
+#[derive(Debug, Clone)]
+pub struct Boxed<T> {
+    inner: T,
+}
+
+impl<T> Boxed<T> {
+    /// Wrap a [`BoxedFetcher`] or [`BoxedExecutor`] so it can be used with
+    /// a [`BatchFetcher`] or [`BatchExecutor`].
+    pub fn new(inner: T) -> Self {
+        Boxed { inner }
+    }
+
+    /// Get a reference to the wrapped [`BoxedFetcher`] or [`BoxedExecutor`].
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<F> Fetcher for Boxed<F>
+where
+    F: BoxedFetcher,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        self.inner.fetch(keys, values).await
+    }
+}
+
+impl<E> Executor for Boxed<E>
+where
+    E: BoxedExecutor,
+{
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+
+    fn execute(
+        &self,
+        values: Vec<Self::Value>,
+    ) -> impl Future<Output = Result<Vec<Self::Result>, Self::Error>> + Send {
+        self.inner.execute(values)
+    }
+}
+
+impl<F> BatchFetcher<Boxed<F>>
+where
+    F: BoxedFetcher + 'static,
+    F::Key: 'static,
+    F::Value: 'static,
+    F::Error: 'static,
+{
+    /// Create a new `BatchFetcher` that uses the given [`BoxedFetcher`] to
+    /// fetch values, such as a `Box<dyn BoxedFetcher<...>>` chosen at
+    /// runtime. Returns a [`BatchFetcherBuilder`], the same as
+    /// [`BatchFetcher::build`]. Requires the `boxed` feature.
+    pub fn build_boxed(fetcher: F) -> BatchFetcherBuilder<Boxed<F>> {
+        BatchFetcher::build(Boxed::new(fetcher))
+    }
+}
+
+impl<E> BatchExecutor<Boxed<E>>
+where
+    E: BoxedExecutor + 'static,
+{
+    /// Create a new `BatchExecutor` that uses the given [`BoxedExecutor`]
+    /// to execute values, such as a `Box<dyn BoxedExecutor<...>>` chosen at
+    /// runtime. Returns a [`BatchExecutorBuilder`], which can be used to
+    /// customize the `BatchExecutor`. Requires the `boxed` feature.
+    pub fn build_boxed(executor: E) -> BatchExecutorBuilder<Boxed<E>> {
+        BatchExecutor::build(Boxed::new(executor))
+    }
+}
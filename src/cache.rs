@@ -1,14 +1,20 @@
-use crate::LoadError;
+use crate::{BatchInfo, LoadError};
 use chashmap::CHashMap;
+use smallvec::SmallVec;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::hash::Hash;
-use std::sync::Arc;
+use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::broadcast;
 
 /// Holds the results of loading a batch of data from a [`Fetcher`](crate::Fetcher).
 /// Implementors of [`Fetcher`](crate::Fetcher) should call [`insert`](Cache::insert)
 /// for each value that was loaded in a batch request.
 pub struct Cache<'a, K, V> {
-    map_ref: &'a CHashMap<K, CacheState<V>>,
+    map_ref: &'a CacheMap<K, V>,
+    batch_info: Option<&'a BatchInfo>,
+    events: Option<&'a CacheEventSender<K, V>>,
 }
 
 impl<'a, K, V> Cache<'a, K, V>
@@ -18,46 +24,745 @@ where
 {
     /// Insert a value into the cache for the given key.
     pub fn insert(&mut self, key: K, value: V) {
+        send_event(self.events, || CacheEvent::Inserted {
+            key: key.clone(),
+            value: value.clone(),
+        });
         self.map_ref.insert(key, CacheState::Loaded(value));
     }
 
+    /// Get details about the batch being fetched, such as to log a
+    /// correlation ID or record metrics for the batch. Returns `None` if
+    /// the cache isn't being filled for a batch, such as when a [`Fetcher`](crate::Fetcher)
+    /// is called directly.
+    pub fn batch_info(&self) -> Option<&'a BatchInfo> {
+        self.batch_info
+    }
+
     pub(crate) fn mark_keys_not_found(&mut self, keys: Vec<K>) {
         for key in keys {
-            self.map_ref
-                .alter(key, |value| Some(value.unwrap_or(CacheState::NotFound)));
+            self.resolve_key(&key);
+        }
+    }
+
+    /// Like [`mark_keys_not_found`](Cache::mark_keys_not_found), but also
+    /// returns the cached state of each key, in the same order as `keys`.
+    pub(crate) fn resolve_keys(&mut self, keys: &[K]) -> Vec<CacheState<V>> {
+        keys.iter().map(|key| self.resolve_key(key)).collect()
+    }
+
+    fn resolve_key(&self, key: &K) -> CacheState<V> {
+        match self.events {
+            Some(events) if events.receiver_count() > 0 => {
+                let was_cached = self.map_ref.get(key).is_some();
+                let load_state = self.map_ref.resolve(key);
+                if !was_cached && matches!(load_state, CacheState::NotFound) {
+                    let _ = events.send(CacheEvent::NotFound { key: key.clone() });
+                }
+                load_state
+            }
+            _ => self.map_ref.resolve(key),
         }
     }
 }
 
+impl<'a, K, V> Cache<'a, K, Arc<V>>
+where
+    K: Clone + Hash + Eq,
+{
+    /// Insert a value that doesn't need to implement `Clone` into a cache
+    /// of `Arc` values, wrapping it in an `Arc`. Every caller that loads
+    /// the key gets a pointer to the same value. See [`SharedFetcher`](crate::SharedFetcher)
+    /// for an example.
+    pub fn insert_shared(&mut self, key: K, value: V) {
+        self.insert(key, Arc::new(value));
+    }
+}
+
+/// The number of events that can be buffered for each subscriber of a
+/// [`CacheStore`] before the oldest events are dropped.
+const CACHE_EVENTS_CAPACITY: usize = 1024;
+
+type CacheEventSender<K, V> = broadcast::Sender<CacheEvent<K, V>>;
+
 #[derive(Clone)]
 pub(crate) struct CacheStore<K, V> {
-    map: Arc<CHashMap<K, CacheState<V>>>,
+    map: Arc<CacheMap<K, V>>,
+    // Only created once there's a subscriber, so stores that are never
+    // subscribed to don't allocate a buffer for events
+    events: Arc<OnceLock<CacheEventSender<K, V>>>,
 }
 
 impl<K, V> CacheStore<K, V> {
+    fn from_map(map: CacheMap<K, V>) -> Self {
+        CacheStore {
+            map: Arc::new(map),
+            events: Arc::new(OnceLock::new()),
+        }
+    }
+
     pub(crate) fn new() -> Self {
-        let map = Arc::new(CHashMap::new());
-        CacheStore { map }
+        CacheStore::from_map(CacheMap::Concurrent(CHashMap::new()))
+    }
+
+    /// Create a store that favors reads over writes. Each key is stored in
+    /// one of several shards, and lookups only take a shared lock on the
+    /// key's shard.
+    pub(crate) fn read_optimized() -> Self {
+        CacheStore::from_map(CacheMap::ReadOptimized(ShardedMap::new()))
+    }
+
+    /// Create a store that hashes keys with a custom hasher. Keys are
+    /// stored in shards like [`read_optimized`](CacheStore::read_optimized).
+    pub(crate) fn with_hasher(hasher: Arc<dyn KeyHasher>) -> Self {
+        CacheStore::from_map(CacheMap::CustomHasher(CustomHashedMap::new(hasher)))
+    }
+
+    pub(crate) fn with_kind(kind: CacheKind) -> Self {
+        match kind {
+            CacheKind::Default => CacheStore::new(),
+            CacheKind::ReadOptimized => CacheStore::read_optimized(),
+            CacheKind::CustomHasher(hasher) => CacheStore::with_hasher(hasher),
+        }
     }
 
     pub(crate) fn as_cache(&'_ self) -> Cache<'_, K, V> {
         let map_ref = &*self.map;
-        Cache { map_ref }
+        Cache {
+            map_ref,
+            batch_info: None,
+            events: self.events.get(),
+        }
+    }
+
+    /// Like [`as_cache`](CacheStore::as_cache), but for filling in the
+    /// values for a batch described by `batch_info`.
+    pub(crate) fn as_batch_cache<'a>(&'a self, batch_info: &'a BatchInfo) -> Cache<'a, K, V> {
+        let map_ref = &*self.map;
+        Cache {
+            map_ref,
+            batch_info: Some(batch_info),
+            events: self.events.get(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Look up a key that has already been loaded, without needing an owned
+    /// key. Returns `None` if the key hasn't been loaded yet.
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<Result<V, LoadError>>
+    where
+        K: Hash + Eq + Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        match self.map.get(key)? {
+            CacheState::Loaded(value) => Some(Ok(value)),
+            CacheState::NotFound => Some(Err(LoadError::NotFound)),
+        }
+    }
+
+    /// Remove a key from the store, whether it was loaded or marked as not
+    /// found. Returns `true` if the key was cached.
+    pub(crate) fn remove(&self, key: &K) -> bool
+    where
+        K: Clone + Hash + Eq,
+    {
+        let removed = self.map.remove(key);
+        if removed {
+            send_event(self.events.get(), || CacheEvent::Invalidated {
+                key: key.clone(),
+            });
+        }
+        removed
+    }
+
+    /// Subscribe to events for every change to the store.
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<CacheEvent<K, V>>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.events
+            .get_or_init(|| broadcast::channel(CACHE_EVENTS_CAPACITY).0)
+            .subscribe()
+    }
+
+    pub(crate) fn snapshot(&self) -> CacheSnapshot<K, V>
+    where
+        K: Clone + Hash + Eq,
+        V: Clone,
+    {
+        let entries = self
+            .map
+            .snapshot()
+            .into_iter()
+            .map(|(key, load_state)| {
+                let entry = match load_state {
+                    CacheState::Loaded(value) => CacheEntry::Loaded(value),
+                    CacheState::NotFound => CacheEntry::NotFound,
+                };
+                (key, entry)
+            })
+            .collect();
+        CacheSnapshot { entries }
+    }
+
+    /// Remove every loaded value from the store, skipping any keys that were
+    /// marked as not found.
+    pub(crate) fn take_loaded(&self) -> HashMap<K, V>
+    where
+        K: Hash + Eq,
+    {
+        self.map
+            .clear()
+            .into_iter()
+            .filter_map(|(key, load_state)| match load_state {
+                CacheState::Loaded(value) => Some((key, value)),
+                CacheState::NotFound => None,
+            })
+            .collect()
     }
 }
 
-#[derive(Clone)]
-enum CacheState<V> {
-    Loaded(V),
-    NotFound,
+/// The map backing a [`CacheStore`].
+enum CacheMap<K, V> {
+    Concurrent(CHashMap<K, CacheState<V>>),
+    ReadOptimized(ShardedMap<K, CacheState<V>>),
+    CustomHasher(CustomHashedMap<K, CacheState<V>>),
+}
+
+impl<K, V> CacheMap<K, V> {
+    fn len(&self) -> usize {
+        match self {
+            CacheMap::Concurrent(map) => map.len(),
+            CacheMap::ReadOptimized(map) => map.len(),
+            CacheMap::CustomHasher(map) => map.len(),
+        }
+    }
+
+    fn get<Q>(&self, key: &Q) -> Option<CacheState<V>>
+    where
+        K: Hash + Eq + Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        match self {
+            CacheMap::Concurrent(map) => map.get(key).as_deref().cloned(),
+            CacheMap::ReadOptimized(map) => map.get(key),
+            CacheMap::CustomHasher(map) => map.get(key),
+        }
+    }
+
+    fn insert(&self, key: K, load_state: CacheState<V>)
+    where
+        K: Hash + Eq,
+    {
+        match self {
+            CacheMap::Concurrent(map) => {
+                map.insert(key, load_state);
+            }
+            CacheMap::ReadOptimized(map) => map.insert(key, load_state),
+            CacheMap::CustomHasher(map) => map.insert(key, load_state),
+        }
+    }
+
+    /// Get the state of a key that was just fetched, marking it as "not
+    /// found" if the fetcher didn't insert a value for it.
+    fn resolve(&self, key: &K) -> CacheState<V>
+    where
+        K: Clone + Hash + Eq,
+        V: Clone,
+    {
+        if let Some(load_state) = self.get(key) {
+            return load_state;
+        }
+
+        match self {
+            CacheMap::Concurrent(map) => {
+                let mut resolved = CacheState::NotFound;
+                map.alter(key.clone(), |value| {
+                    let value = value.unwrap_or(CacheState::NotFound);
+                    resolved = value.clone();
+                    Some(value)
+                });
+                resolved
+            }
+            CacheMap::ReadOptimized(map) => map.get_or_insert(key, || CacheState::NotFound),
+            CacheMap::CustomHasher(map) => map.get_or_insert(key, || CacheState::NotFound),
+        }
+    }
+
+    fn remove<Q>(&self, key: &Q) -> bool
+    where
+        K: Hash + Eq + Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self {
+            CacheMap::Concurrent(map) => map.remove(key).is_some(),
+            CacheMap::ReadOptimized(map) => map.remove(key),
+            CacheMap::CustomHasher(map) => map.remove(key),
+        }
+    }
+
+    fn snapshot(&self) -> HashMap<K, CacheState<V>>
+    where
+        K: Clone + Hash + Eq,
+        V: Clone,
+    {
+        match self {
+            CacheMap::Concurrent(map) => map.clone().into_iter().collect(),
+            CacheMap::ReadOptimized(map) => map.snapshot(),
+            CacheMap::CustomHasher(map) => map.snapshot(),
+        }
+    }
+
+    fn clear(&self) -> Vec<(K, CacheState<V>)> {
+        match self {
+            CacheMap::Concurrent(map) => map.clear().into_iter().collect(),
+            CacheMap::ReadOptimized(map) => map.clear(),
+            CacheMap::CustomHasher(map) => map.clear(),
+        }
+    }
+}
+
+/// The number of shards in a [`ShardedMap`].
+const NUM_SHARDS: usize = 32;
+
+/// A map split into shards that are each behind their own `RwLock`, so
+/// concurrent reads never wait on each other, and writes only block reads
+/// of keys in the same shard.
+struct ShardedMap<K, V> {
+    hasher: RandomState,
+    shards: Box<[RwLock<HashMap<K, V>>]>,
+}
+
+impl<K, V> ShardedMap<K, V> {
+    fn new() -> Self {
+        ShardedMap {
+            hasher: RandomState::new(),
+            shards: (0..NUM_SHARDS)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    fn shard<Q>(&self, key: &Q) -> &RwLock<HashMap<K, V>>
+    where
+        Q: Hash + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        &self.shards[hash as usize % self.shards.len()]
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| read(shard).len()).sum()
+    }
+
+    fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Hash + Eq + Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        read(self.shard(key)).get(key).cloned()
+    }
+
+    fn insert(&self, key: K, value: V)
+    where
+        K: Hash + Eq,
+    {
+        write(self.shard(&key)).insert(key, value);
+    }
+
+    fn get_or_insert(&self, key: &K, value: impl FnOnce() -> V) -> V
+    where
+        K: Clone + Hash + Eq,
+        V: Clone,
+    {
+        write(self.shard(key))
+            .entry(key.clone())
+            .or_insert_with(value)
+            .clone()
+    }
+
+    fn remove<Q>(&self, key: &Q) -> bool
+    where
+        K: Hash + Eq + Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        write(self.shard(key)).remove(key).is_some()
+    }
+
+    /// Copy every entry, holding a read lock on every shard at once so the
+    /// copy reflects a single point in time.
+    fn snapshot(&self) -> HashMap<K, V>
+    where
+        K: Clone + Hash + Eq,
+        V: Clone,
+    {
+        let shards: Vec<_> = self.shards.iter().map(|shard| read(shard)).collect();
+        shards
+            .iter()
+            .flat_map(|shard| shard.iter())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn clear(&self) -> Vec<(K, V)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| std::mem::take(&mut *write(shard)))
+            .collect()
+    }
+}
+
+/// Which map a [`CacheStore`] uses to store cached values.
+pub(crate) enum CacheKind {
+    Default,
+    ReadOptimized,
+    CustomHasher(Arc<dyn KeyHasher>),
 }
 
-pub(crate) struct CacheLookup<K, V>
+/// Hashes keys for a [`CustomHashedMap`] with a custom [`BuildHasher`],
+/// without needing the map to be generic over the hasher's type.
+pub(crate) trait KeyHasher: Send + Sync {
+    /// Build a new hasher, call `hash` with it, and return the finished
+    /// hash.
+    fn hash_with(&self, hash: &mut dyn FnMut(&mut dyn Hasher)) -> u64;
+}
+
+impl<S> KeyHasher for S
+where
+    S: BuildHasher + Send + Sync,
+{
+    fn hash_with(&self, hash: &mut dyn FnMut(&mut dyn Hasher)) -> u64 {
+        let mut hasher = self.build_hasher();
+        hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// A hasher that uses a precomputed `u64` hash as-is.
+#[derive(Default)]
+struct IdentityHasher(u64);
+
+impl Hasher for IdentityHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ u64::from(*byte);
+        }
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+}
+
+/// The entries in one shard of a [`CustomHashedMap`], grouped by the hash
+/// of each key.
+type HashBuckets<K, V> = HashMap<u64, SmallVec<[(K, V); 1]>, BuildHasherDefault<IdentityHasher>>;
+
+/// A map split into shards like a [`ShardedMap`], but which hashes keys with
+/// a custom [`KeyHasher`]. Each key is only hashed once per operation, then
+/// the entries with the same hash are compared directly.
+struct CustomHashedMap<K, V> {
+    hasher: Arc<dyn KeyHasher>,
+    shards: Box<[RwLock<HashBuckets<K, V>>]>,
+}
+
+impl<K, V> CustomHashedMap<K, V> {
+    fn new(hasher: Arc<dyn KeyHasher>) -> Self {
+        CustomHashedMap {
+            hasher,
+            shards: (0..NUM_SHARDS)
+                .map(|_| RwLock::new(HashMap::default()))
+                .collect(),
+        }
+    }
+
+    fn hash<Q>(&self, key: &Q) -> u64
+    where
+        Q: Hash + ?Sized,
+    {
+        self.hasher
+            .hash_with(&mut |mut hasher| key.hash(&mut hasher))
+    }
+
+    fn shard(&self, hash: u64) -> &RwLock<HashBuckets<K, V>> {
+        // Use different bits than the buckets within the shard, so keys in
+        // the same shard are still spread out across its buckets
+        &self.shards[(hash >> 32) as usize % self.shards.len()]
+    }
+
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                read(shard)
+                    .values()
+                    .map(|bucket| bucket.len())
+                    .sum::<usize>()
+            })
+            .sum()
+    }
+
+    fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let hash = self.hash(key);
+        let shard = read(self.shard(hash));
+        let bucket = shard.get(&hash)?;
+        bucket
+            .iter()
+            .find(|(entry_key, _)| entry_key.borrow() == key)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn insert(&self, key: K, value: V)
+    where
+        K: Hash + Eq,
+    {
+        let hash = self.hash(&key);
+        let mut shard = write(self.shard(hash));
+        let bucket = shard.entry(hash).or_default();
+        match bucket.iter_mut().find(|(entry_key, _)| *entry_key == key) {
+            Some((_, entry_value)) => *entry_value = value,
+            None => bucket.push((key, value)),
+        }
+    }
+
+    fn get_or_insert(&self, key: &K, value: impl FnOnce() -> V) -> V
+    where
+        K: Clone + Hash + Eq,
+        V: Clone,
+    {
+        let hash = self.hash(key);
+        let mut shard = write(self.shard(hash));
+        let bucket = shard.entry(hash).or_default();
+        if let Some((_, entry_value)) = bucket.iter().find(|(entry_key, _)| entry_key == key) {
+            return entry_value.clone();
+        }
+
+        let value = value();
+        bucket.push((key.clone(), value.clone()));
+        value
+    }
+
+    fn remove<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hash(key);
+        let mut shard = write(self.shard(hash));
+        let Some(bucket) = shard.get_mut(&hash) else {
+            return false;
+        };
+        let Some(index) = bucket
+            .iter()
+            .position(|(entry_key, _)| entry_key.borrow() == key)
+        else {
+            return false;
+        };
+
+        bucket.swap_remove(index);
+        if bucket.is_empty() {
+            shard.remove(&hash);
+        }
+        true
+    }
+
+    /// Copy every entry, holding a read lock on every shard at once so the
+    /// copy reflects a single point in time.
+    fn snapshot(&self) -> HashMap<K, V>
+    where
+        K: Clone + Hash + Eq,
+        V: Clone,
+    {
+        let shards: Vec<_> = self.shards.iter().map(|shard| read(shard)).collect();
+        shards
+            .iter()
+            .flat_map(|shard| shard.values().flatten())
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn clear(&self) -> Vec<(K, V)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| std::mem::take(&mut *write(shard)))
+            .flat_map(|(_, bucket)| bucket)
+            .collect()
+    }
+}
+
+/// Lock a mutex, ignoring poisoning. Every lock in the crate guards state
+/// that's updated in a single step, so a panic while the lock is held
+/// can't leave it inconsistent.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|error| error.into_inner())
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|error| error.into_inner())
+}
+
+/// A change to the cache of a [`BatchFetcher`](crate::BatchFetcher),
+/// returned by [`BatchFetcher::subscribe_cache_events`](crate::BatchFetcher::subscribe_cache_events).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum CacheEvent<K, V> {
+    /// A value was inserted into the cache, such as after it was fetched.
+    Inserted {
+        /// The key that was inserted.
+        key: K,
+
+        /// The value that was inserted.
+        value: V,
+    },
+
+    /// A key was marked as "not found", because the [`Fetcher`](crate::Fetcher)
+    /// didn't return a value for it.
+    NotFound {
+        /// The key that wasn't found.
+        key: K,
+    },
+
+    /// A key was removed from the cache with [`BatchFetcher::invalidate`](crate::BatchFetcher::invalidate).
+    Invalidated {
+        /// The key that was removed.
+        key: K,
+    },
+}
+
+/// Send an event to the subscribers of a [`CacheStore`], only creating the
+/// event if there are any subscribers.
+fn send_event<K, V>(
+    events: Option<&CacheEventSender<K, V>>,
+    event: impl FnOnce() -> CacheEvent<K, V>,
+) {
+    if let Some(events) = events {
+        if events.receiver_count() > 0 {
+            // Ignore error if every subscriber was just dropped
+            let _ = events.send(event());
+        }
+    }
+}
+
+/// A copy of the cache of a [`BatchFetcher`](crate::BatchFetcher), returned
+/// by [`BatchFetcher::cache_snapshot`](crate::BatchFetcher::cache_snapshot).
+/// Changes made to the cache after the snapshot was taken aren't reflected
+/// in the snapshot.
+#[derive(Debug, Clone)]
+pub struct CacheSnapshot<K, V> {
+    entries: HashMap<K, CacheEntry<V>>,
+}
+
+impl<K, V> CacheSnapshot<K, V>
 where
     K: Hash + Eq,
 {
-    keys: Vec<K>,
-    entries: HashMap<K, Option<CacheState<V>>>,
+    /// Get the entry for a key, or `None` if the key wasn't cached.
+    pub fn get<Q>(&self, key: &Q) -> Option<&CacheEntry<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries.get(key)
+    }
+
+    /// Iterate over every cached key and its entry, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &CacheEntry<V>)> {
+        self.entries.iter()
+    }
+
+    /// The number of cached keys, including keys that were marked as "not
+    /// found".
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if no keys were cached.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of cached keys that have a value.
+    pub fn num_loaded(&self) -> usize {
+        self.len() - self.num_not_found()
+    }
+
+    /// The number of cached keys that were marked as "not found".
+    pub fn num_not_found(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| matches!(entry, CacheEntry::NotFound))
+            .count()
+    }
+}
+
+/// The state of a key in a [`CacheSnapshot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheEntry<V> {
+    /// The key was loaded with a value.
+    Loaded(V),
+
+    /// The [`Fetcher`](crate::Fetcher) didn't return a value for the key.
+    NotFound,
+}
+
+#[derive(Clone)]
+pub(crate) enum CacheState<V> {
+    Loaded(V),
+    NotFound,
+}
+
+/// Keys looked up at or below this count are deduplicated by comparing them
+/// directly, rather than by building a `HashMap`.
+const LINEAR_DEDUP_LIMIT: usize = 16;
+
+/// The state of a single load (or `load_many`) call while it looks up its
+/// keys. The keys are stored once, in the order they were requested, and
+/// repeated keys refer back to the first entry with an equal key by index.
+/// Loading a single key that's already cached doesn't allocate.
+pub(crate) struct CacheLookup<K, V> {
+    entries: SmallVec<[LookupEntry<K, V>; 1]>,
+}
+
+struct LookupEntry<K, V> {
+    key: K,
+    /// The index of the first entry with an equal key. Only that entry is
+    /// looked up in the cache, and it holds the state for every repeat.
+    first_index: usize,
+    /// For the first entry with a key, the number of entries that refer to
+    /// it (including itself) that haven't taken their value yet.
+    uses: usize,
+    load_state: Option<CacheState<V>>,
+}
+
+impl<K, V> LookupEntry<K, V> {
+    fn new(key: K) -> Self {
+        LookupEntry {
+            key,
+            first_index: 0,
+            uses: 0,
+            load_state: None,
+        }
+    }
 }
 
 impl<K, V> CacheLookup<K, V>
@@ -65,64 +770,172 @@ where
     K: Clone + Hash + Eq,
     V: Clone,
 {
-    pub(crate) fn new(keys: Vec<K>) -> Self {
-        let entries = keys.iter().map(|key| (key.clone(), None)).collect();
-        CacheLookup { keys, entries }
-    }
+    pub(crate) fn new(keys: impl IntoIterator<Item = K>) -> Self {
+        let mut entries: SmallVec<[LookupEntry<K, V>; 1]> =
+            keys.into_iter().map(LookupEntry::new).collect();
 
-    pub(crate) fn reload_keys_from_cache_store(&mut self, cache_store: &CacheStore<K, V>) {
-        let keys: Vec<K> = self.entries.keys().into_iter().cloned().collect();
-        for key in keys {
-            self.entries
-                .entry(key.clone())
-                .and_modify(|mut load_state| match load_state {
-                    Some(_) => {}
-                    ref mut load_state @ None => {
-                        **load_state = cache_store.map.get(&key).as_deref().cloned();
-                    }
-                });
+        let first_indices: SmallVec<[usize; LINEAR_DEDUP_LIMIT]> =
+            if entries.len() <= LINEAR_DEDUP_LIMIT {
+                (0..entries.len())
+                    .map(|index| {
+                        let key = &entries[index].key;
+                        entries[..index]
+                            .iter()
+                            .position(|entry| entry.key == *key)
+                            .unwrap_or(index)
+                    })
+                    .collect()
+            } else {
+                let mut seen = HashMap::with_capacity(entries.len());
+                entries
+                    .iter()
+                    .enumerate()
+                    .map(|(index, entry)| *seen.entry(&entry.key).or_insert(index))
+                    .collect()
+            };
+
+        for (index, first_index) in first_indices.into_iter().enumerate() {
+            entries[index].first_index = first_index;
+            entries[first_index].uses += 1;
         }
+
+        CacheLookup { entries }
     }
 
-    pub(crate) fn pending_keys(&self) -> Vec<K> {
+    fn unique_entries(&self) -> impl Iterator<Item = &LookupEntry<K, V>> {
         self.entries
             .iter()
-            .filter_map(|(key, value)| match value {
-                None => Some(key.clone()),
-                Some(_) => None,
-            })
+            .enumerate()
+            .filter(|(index, entry)| entry.first_index == *index)
+            .map(|(_, entry)| entry)
+    }
+
+    /// The total number of keys being looked up, including repeated keys.
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The number of distinct keys being looked up.
+    pub(crate) fn num_keys(&self) -> usize {
+        self.unique_entries().count()
+    }
+
+    /// The number of distinct keys that haven't been loaded yet.
+    pub(crate) fn num_pending(&self) -> usize {
+        self.unique_entries()
+            .filter(|entry| entry.load_state.is_none())
+            .count()
+    }
+
+    /// The number of keys that were cached as "not found".
+    pub(crate) fn num_not_found(&self) -> usize {
+        self.unique_entries()
+            .filter(|entry| matches!(entry.load_state, Some(CacheState::NotFound)))
+            .count()
+    }
+
+    pub(crate) fn pending_keys(&self) -> Vec<K> {
+        self.unique_entries()
+            .filter(|entry| entry.load_state.is_none())
+            .map(|entry| entry.key.clone())
             .collect()
     }
 
-    pub(crate) fn lookup_result(&self) -> Result<Vec<V>, LoadError> {
-        self.keys
-            .iter()
-            .map(|key| {
-                let load_state = self
-                    .entries
-                    .get(key)
-                    .expect("Cache lookup is missing an expected key");
-                match load_state {
-                    Some(CacheState::Loaded(value)) => Ok(value.clone()),
-                    Some(CacheState::NotFound) | None => Err(LoadError::NotFound),
-                }
+    /// Take the value for each key, in the order the keys were requested.
+    /// Returns an error if any key was not found.
+    pub(crate) fn lookup_result(mut self) -> Result<Vec<V>, LoadError> {
+        (0..self.entries.len())
+            .map(|index| self.take_value(index))
+            .collect()
+    }
+
+    /// Take the value for a lookup of a single key.
+    pub(crate) fn into_value(mut self) -> Result<V, LoadError> {
+        debug_assert_eq!(self.entries.len(), 1);
+        self.take_value(0)
+    }
+
+    /// Take the value for the entry at `index`, only cloning it if a later
+    /// entry with the same key still needs it.
+    fn take_value(&mut self, index: usize) -> Result<V, LoadError> {
+        let first_index = self.entries[index].first_index;
+        let first = &mut self.entries[first_index];
+        first.uses -= 1;
+        let load_state = if first.uses == 0 {
+            first.load_state.take()
+        } else {
+            first.load_state.clone()
+        };
+        match load_state {
+            Some(CacheState::Loaded(value)) => Ok(value),
+            Some(CacheState::NotFound) | None => Err(LoadError::NotFound),
+        }
+    }
+
+    /// Take the values that were found, skipping any keys that were not
+    /// found.
+    pub(crate) fn found_values(self) -> HashMap<K, V> {
+        self.entries
+            .into_iter()
+            .enumerate()
+            .filter(|(index, entry)| entry.first_index == *index)
+            .filter_map(|(_, entry)| match entry.load_state {
+                Some(CacheState::Loaded(value)) => Some((entry.key, value)),
+                Some(CacheState::NotFound) | None => None,
             })
             .collect()
     }
 
-    pub(crate) fn lookup(&mut self, cache_store: &CacheStore<K, V>) -> CacheLookupState<V> {
-        self.reload_keys_from_cache_store(cache_store);
-        let pending_keys = self.pending_keys();
+    /// Fill in the keys that are still pending with their resolved states,
+    /// which must be in the same order as [`pending_keys`](CacheLookup::pending_keys).
+    /// Any keys without a resolved state are left pending.
+    pub(crate) fn resolve_pending(
+        &mut self,
+        resolved: impl IntoIterator<Item = Option<CacheState<V>>>,
+    ) -> CacheLookupState {
+        let mut resolved = resolved.into_iter();
+        let mut num_pending = 0;
+        for (index, entry) in self.entries.iter_mut().enumerate() {
+            if entry.first_index != index || entry.load_state.is_some() {
+                continue;
+            }
+
+            entry.load_state = resolved.next().flatten();
+            if entry.load_state.is_none() {
+                num_pending += 1;
+            }
+        }
+
+        if num_pending == 0 {
+            CacheLookupState::Done
+        } else {
+            CacheLookupState::Pending
+        }
+    }
+
+    /// Load any keys that are still pending from `cache_store`.
+    pub(crate) fn lookup(&mut self, cache_store: &CacheStore<K, V>) -> CacheLookupState {
+        let mut num_pending = 0;
+        for (index, entry) in self.entries.iter_mut().enumerate() {
+            if entry.first_index != index || entry.load_state.is_some() {
+                continue;
+            }
+
+            entry.load_state = cache_store.map.get(&entry.key);
+            if entry.load_state.is_none() {
+                num_pending += 1;
+            }
+        }
 
-        if pending_keys.is_empty() {
-            CacheLookupState::Done(self.lookup_result())
+        if num_pending == 0 {
+            CacheLookupState::Done
         } else {
             CacheLookupState::Pending
         }
     }
 }
 
-pub(crate) enum CacheLookupState<V> {
-    Done(Result<Vec<V>, LoadError>),
+pub(crate) enum CacheLookupState {
+    Done,
     Pending,
 }
@@ -0,0 +1,266 @@
+use crate::cache::CacheStore;
+use crate::{BatchInfo, Cache, Fetcher};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+/// Call a [`Fetcher`] with a temporary cache, returning the values that were
+/// found. The fetcher sees the same batch info as the outer batch, if any.
+pub(crate) async fn fetch_values<F>(
+    fetcher: &F,
+    keys: &[F::Key],
+    batch_info: Option<&BatchInfo>,
+) -> Result<HashMap<F::Key, F::Value>, F::Error>
+where
+    F: Fetcher,
+{
+    let cache_store = CacheStore::new();
+    let mut cache = match batch_info {
+        Some(batch_info) => cache_store.as_batch_cache(batch_info),
+        None => cache_store.as_cache(),
+    };
+    fetcher.fetch(keys, &mut cache).await?;
+    Ok(cache_store.take_loaded())
+}
+
+/// A [`Fetcher`] that loads a value from one fetcher, then uses it to load a
+/// dependent value from another fetcher. Created with [`Fetcher::then_load_with`].
+#[derive(Debug, Clone)]
+pub struct ThenLoadWith<A, B, Fun> {
+    first: A,
+    then: B,
+    key_fn: Fun,
+}
+
+impl<A, B, Fun> ThenLoadWith<A, B, Fun> {
+    pub(crate) fn new(first: A, then: B, key_fn: Fun) -> Self {
+        ThenLoadWith {
+            first,
+            then,
+            key_fn,
+        }
+    }
+}
+
+impl<A, B, Fun> Fetcher for ThenLoadWith<A, B, Fun>
+where
+    A: Fetcher + Sync,
+    B: Fetcher + Sync,
+    Fun: Fn(&A::Value) -> B::Key + Sync,
+{
+    type Key = A::Key;
+    type Value = B::Value;
+    type Error = ThenLoadError<A::Error, B::Error>;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let first_values = fetch_values(&self.first, keys, values.batch_info())
+            .await
+            .map_err(ThenLoadError::First)?;
+
+        let first_keys: HashMap<A::Key, B::Key> = first_values
+            .into_iter()
+            .map(|(key, value)| (key, (self.key_fn)(&value)))
+            .collect();
+        let then_keys: HashSet<B::Key> = first_keys.values().cloned().collect();
+        let then_keys: Vec<B::Key> = then_keys.into_iter().collect();
+
+        let then_values = fetch_values(&self.then, &then_keys, values.batch_info())
+            .await
+            .map_err(ThenLoadError::Then)?;
+
+        for (key, then_key) in first_keys {
+            if let Some(value) = then_values.get(&then_key) {
+                values.insert(key, value.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A [`Fetcher`] that transforms each value from another fetcher before
+/// it's cached. Created with [`Fetcher::map_value`].
+#[derive(Debug, Clone)]
+pub struct MapValue<F, Fun> {
+    fetcher: F,
+    map_fn: Fun,
+}
+
+impl<F, Fun> MapValue<F, Fun> {
+    pub(crate) fn new(fetcher: F, map_fn: Fun) -> Self {
+        MapValue { fetcher, map_fn }
+    }
+}
+
+impl<F, Fun, V> Fetcher for MapValue<F, Fun>
+where
+    F: Fetcher + Sync,
+    Fun: Fn(F::Value) -> V + Sync,
+    V: Clone + Send + Sync,
+{
+    type Key = F::Key;
+    type Value = V;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let fetched_values = fetch_values(&self.fetcher, keys, values.batch_info()).await?;
+        for (key, value) in fetched_values {
+            values.insert(key, (self.map_fn)(value));
+        }
+
+        Ok(())
+    }
+}
+
+/// A [`Fetcher`] that accepts a different key type than another fetcher,
+/// converting each key before fetching. Created with [`Fetcher::contramap_key`].
+pub struct ContramapKey<F, Fun, K> {
+    fetcher: F,
+    key_fn: Fun,
+    _marker: PhantomData<fn(K)>,
+}
+
+impl<F, Fun, K> ContramapKey<F, Fun, K> {
+    pub(crate) fn new(fetcher: F, key_fn: Fun) -> Self {
+        ContramapKey {
+            fetcher,
+            key_fn,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<F, Fun, K> Clone for ContramapKey<F, Fun, K>
+where
+    F: Clone,
+    Fun: Clone,
+{
+    fn clone(&self) -> Self {
+        ContramapKey::new(self.fetcher.clone(), self.key_fn.clone())
+    }
+}
+
+impl<F, Fun, K> Fetcher for ContramapKey<F, Fun, K>
+where
+    F: Fetcher + Sync,
+    Fun: Fn(&K) -> F::Key + Sync,
+    K: Clone + Hash + Eq + Send + Sync,
+{
+    type Key = K;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let inner_keys: Vec<F::Key> = keys.iter().map(&self.key_fn).collect();
+        let unique_inner_keys: HashSet<F::Key> = inner_keys.iter().cloned().collect();
+        let unique_inner_keys: Vec<F::Key> = unique_inner_keys.into_iter().collect();
+
+        let fetched_values =
+            fetch_values(&self.fetcher, &unique_inner_keys, values.batch_info()).await?;
+        for (key, inner_key) in keys.iter().zip(inner_keys) {
+            if let Some(value) = fetched_values.get(&inner_key) {
+                values.insert(key.clone(), value.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A [`Fetcher`] that loads values from a primary fetcher, then loads any
+/// keys that weren't found from a fallback fetcher. Created with
+/// [`Fetcher::with_fallback`].
+#[derive(Debug, Clone)]
+pub struct WithFallback<A, B> {
+    primary: A,
+    fallback: B,
+}
+
+impl<A, B> WithFallback<A, B> {
+    pub(crate) fn new(primary: A, fallback: B) -> Self {
+        WithFallback { primary, fallback }
+    }
+}
+
+impl<A, B> Fetcher for WithFallback<A, B>
+where
+    A: Fetcher + Sync,
+    B: Fetcher<Key = A::Key, Value = A::Value> + Sync,
+{
+    type Key = A::Key;
+    type Value = A::Value;
+    type Error = FallbackError<A::Error, B::Error>;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let primary_values = fetch_values(&self.primary, keys, values.batch_info())
+            .await
+            .map_err(FallbackError::Primary)?;
+
+        let missing_keys: Vec<A::Key> = keys
+            .iter()
+            .filter(|key| !primary_values.contains_key(key))
+            .cloned()
+            .collect();
+        for (key, value) in primary_values {
+            values.insert(key, value);
+        }
+
+        if !missing_keys.is_empty() {
+            self.fallback
+                .fetch(&missing_keys, values)
+                .await
+                .map_err(FallbackError::Fallback)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The error returned by a [`WithFallback`] fetcher.
+#[derive(Debug, thiserror::Error)]
+pub enum FallbackError<A, B>
+where
+    A: Display,
+    B: Display,
+{
+    /// The primary fetcher returned an error.
+    #[error("{0}")]
+    Primary(A),
+
+    /// The fallback fetcher returned an error.
+    #[error("{0}")]
+    Fallback(B),
+}
+
+/// The error returned by a [`ThenLoadWith`] fetcher.
+#[derive(Debug, thiserror::Error)]
+pub enum ThenLoadError<A, B>
+where
+    A: Display,
+    B: Display,
+{
+    /// The first fetcher returned an error.
+    #[error("{0}")]
+    First(A),
+
+    /// The dependent fetcher returned an error.
+    #[error("{0}")]
+    Then(B),
+}
@@ -0,0 +1,44 @@
+use std::future::Future;
+
+/// Context captured from a caller when it queues a request, so it can be
+/// propagated into the batch that handles the request. This is empty unless
+/// the `opentelemetry` feature is enabled, in which case it holds the
+/// caller's OpenTelemetry context (including its trace and baggage).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CallerContext {
+    #[cfg(feature = "opentelemetry")]
+    otel_context: opentelemetry::Context,
+}
+
+impl CallerContext {
+    /// Capture the context of the current caller.
+    pub(crate) fn current() -> Self {
+        CallerContext {
+            #[cfg(feature = "opentelemetry")]
+            otel_context: opentelemetry::Context::current(),
+        }
+    }
+
+    /// Create a future with `f`, with this context set as the current
+    /// context both while creating it and while polling it.
+    pub(crate) fn scope<Fut>(self, f: impl FnOnce() -> Fut) -> impl Future<Output = Fut::Output>
+    where
+        Fut: Future,
+    {
+        #[cfg(feature = "opentelemetry")]
+        {
+            use opentelemetry::context::FutureExt as _;
+
+            let future = {
+                let _guard = self.otel_context.clone().attach();
+                f()
+            };
+            future.with_context(self.otel_context)
+        }
+
+        #[cfg(not(feature = "opentelemetry"))]
+        {
+            f()
+        }
+    }
+}
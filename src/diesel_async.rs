@@ -0,0 +1,126 @@
+//! A [`Fetcher`] for loading values with [`diesel-async`](::diesel_async)
+//! using a pool of Postgres connections. Requires the `diesel-async` feature.
+//!
+//! For the blocking version of Diesel, use a [`BlockingFetcher`](crate::BlockingFetcher)
+//! to run queries on Tokio's pool of blocking threads instead.
+
+use crate::{Cache, Fetcher};
+use ::diesel::result::Error as DieselError;
+use ::diesel_async::pooled_connection::deadpool::{Pool, PoolError};
+use ::diesel_async::AsyncPgConnection;
+use futures_util::future::BoxFuture;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+/// A [`Fetcher`] that gets a connection from a `diesel-async` [`Pool`] for
+/// each batch, then calls `fetch_fn` with the connection and the keys for the
+/// batch. `fetch_fn` should return a key and value for each row that was
+/// found. Any keys without a matching row will be marked as "not found".
+///
+/// # Examples
+///
+/// ```no_run
+/// # use ultra_batch::BatchFetcher;
+/// # use ultra_batch::diesel_async::DieselFetcher;
+/// # use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+/// # use diesel_async::pooled_connection::deadpool::Pool;
+/// # diesel::table! { users (id) { id -> BigInt, name -> Text, } }
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let manager = AsyncDieselConnectionManager::new("postgres://localhost/app");
+/// let pool = Pool::builder(manager).build()?;
+///
+/// let user_names = {
+///     use diesel::prelude::*;
+///     use diesel_async::RunQueryDsl;
+///     use futures_util::FutureExt;
+///
+///     DieselFetcher::new(pool, |conn, user_ids: Vec<i64>| {
+///         users::table
+///             .filter(users::id.eq_any(user_ids))
+///             .select((users::id, users::name))
+///             .load::<(i64, String)>(conn)
+///             .boxed()
+///     })
+/// };
+///
+/// let batch_fetcher = BatchFetcher::build(user_names).finish();
+/// let user_name = batch_fetcher.load(1).await?;
+/// # Ok(()) }
+/// ```
+pub struct DieselFetcher<K, V, Fun> {
+    pool: Pool<AsyncPgConnection>,
+    fetch_fn: Fun,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, Fun> DieselFetcher<K, V, Fun>
+where
+    Fun: for<'c> Fn(
+        &'c mut AsyncPgConnection,
+        Vec<K>,
+    ) -> BoxFuture<'c, Result<Vec<(K, V)>, DieselError>>,
+{
+    /// Create a new `DieselFetcher` that calls `fetch_fn` with a connection
+    /// from `pool` for each batch.
+    pub fn new(pool: Pool<AsyncPgConnection>, fetch_fn: Fun) -> Self {
+        DieselFetcher {
+            pool,
+            fetch_fn,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, Fun> Clone for DieselFetcher<K, V, Fun>
+where
+    Fun: Clone,
+{
+    fn clone(&self) -> Self {
+        DieselFetcher {
+            pool: self.pool.clone(),
+            fetch_fn: self.fetch_fn.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, Fun> Fetcher for DieselFetcher<K, V, Fun>
+where
+    K: Clone + Hash + Eq + Send + Sync,
+    V: Clone + Send + Sync,
+    Fun: for<'c> Fn(
+            &'c mut AsyncPgConnection,
+            Vec<K>,
+        ) -> BoxFuture<'c, Result<Vec<(K, V)>, DieselError>>
+        + Sync,
+{
+    type Key = K;
+    type Value = V;
+    type Error = DieselFetchError;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let rows = (self.fetch_fn)(&mut conn, keys.to_vec()).await?;
+        for (key, value) in rows {
+            values.insert(key, value);
+        }
+
+        Ok(())
+    }
+}
+
+/// The error returned by a [`DieselFetcher`].
+#[derive(Debug, thiserror::Error)]
+pub enum DieselFetchError {
+    /// Getting a connection from the pool failed.
+    #[error("error getting connection from pool: {0}")]
+    Pool(#[from] PoolError),
+
+    /// The query returned an error.
+    #[error("{0}")]
+    Query(#[from] DieselError),
+}
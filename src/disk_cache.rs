@@ -0,0 +1,241 @@
+//! A [`RemoteCache`] that persists encoded values to files on disk, so a
+//! CLI tool or worker can start with a warm cache the next time it runs.
+//! Requires the `disk-cache` feature.
+//!
+//! Values are stored one file per key, rather than in an embedded database
+//! such as sled or redb, so no extra dependencies are needed. This works
+//! well for caches with up to a few thousand entries; for much larger
+//! caches, implement [`RemoteCache`] on top of an embedded database instead.
+
+use crate::RemoteCache;
+use futures_util::future::join_all;
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The number of bytes at the start of each file used to store when the
+/// entry expires.
+const EXPIRES_AT_LEN: usize = 8;
+
+/// The number of bytes after the expiration time used to store the length
+/// of the key.
+const KEY_LEN_LEN: usize = 4;
+
+/// The longest file name used for a hex-encoded key. Longer keys are named
+/// after a hash of the key instead, so names stay well under the file
+/// name limit of common filesystems (usually 255 bytes), even with the
+/// suffix added for temporary files.
+const MAX_HEX_NAME_LEN: usize = 128;
+
+/// A [`RemoteCache`] that stores each value in its own file in a directory,
+/// along with when the value expires. Use with
+/// [`Fetcher::with_remote_cache`](crate::Fetcher::with_remote_cache) and a
+/// [`CacheCodec`](crate::CacheCodec).
+///
+/// Files are named after the [`Display`] form of each key, so each key
+/// needs to be displayed differently. Long keys are named after a hash of
+/// the key instead. Each file also stores the key itself, so if two keys
+/// ever end up with the same file, reading one won't return the other's
+/// value. Expired files are ignored, and are removed the next time their
+/// key is read.
+///
+/// Errors reading or writing a single file are logged, and only affect
+/// that key: a key that can't be read is treated as a cache miss, and the
+/// other keys in the batch are still read and written.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, CacheCodec, Fetcher, Cache};
+/// # use ultra_batch::disk_cache::DiskCache;
+/// # struct UserNameFetcher;
+/// # impl Fetcher for UserNameFetcher {
+/// #     type Key = u64;
+/// #     type Value = String;
+/// #     type Error = anyhow::Error;
+/// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, String>) -> anyhow::Result<()> {
+/// #         for key in keys {
+/// #             values.insert(*key, format!("User {key}"));
+/// #         }
+/// #         Ok(())
+/// #     }
+/// # }
+/// # struct Utf8Codec;
+/// # impl CacheCodec<String> for Utf8Codec {
+/// #     type Error = std::string::FromUtf8Error;
+/// #     fn encode(&self, value: &String) -> Result<Vec<u8>, Self::Error> { Ok(value.as_bytes().to_vec()) }
+/// #     fn decode(&self, bytes: &[u8]) -> Result<String, Self::Error> { String::from_utf8(bytes.to_vec()) }
+/// # }
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// # let cache_dir = std::env::temp_dir().join(format!("ultra-batch-doctest-{}", std::process::id()));
+/// let disk_cache = DiskCache::new(&cache_dir, tokio::time::Duration::from_secs(60 * 60));
+/// let user_name_fetcher = UserNameFetcher.with_remote_cache(disk_cache, Utf8Codec);
+/// let batch_fetcher = BatchFetcher::build(user_name_fetcher).finish();
+///
+/// let name = batch_fetcher.load(1).await?;
+/// assert_eq!(name, "User 1");
+/// # std::fs::remove_dir_all(&cache_dir)?;
+/// # Ok(()) }
+/// ```
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    ttl: Duration,
+    next_temp_id: AtomicU64,
+}
+
+impl DiskCache {
+    /// Create a new `DiskCache` that stores values in `dir`, keeping each
+    /// value for `ttl` after it was written. The directory is created when
+    /// the first value is written, if it doesn't already exist.
+    pub fn new(dir: impl Into<PathBuf>, ttl: Duration) -> Self {
+        DiskCache {
+            dir: dir.into(),
+            ttl,
+            next_temp_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the directory where values are stored.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Get the path of the file used to store the value for `key`.
+    pub fn entry_path(&self, key: &impl Display) -> PathBuf {
+        let key = key.to_string();
+
+        // Hex-encode the key so any key can be used as a file name. The
+        // hashed names start with a character that isn't a hex digit, so
+        // they never match the name of a hex-encoded key
+        let file_name = if key.len() * 2 <= MAX_HEX_NAME_LEN {
+            key.bytes().map(|byte| format!("{byte:02x}")).collect()
+        } else {
+            format!("h{:016x}", fnv1a(key.as_bytes()))
+        };
+        self.dir.join(file_name)
+    }
+
+    async fn read_entry(&self, key: &str) -> std::io::Result<Option<Vec<u8>>> {
+        let path = self.entry_path(&key);
+        let contents = match tokio::fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+
+        let Some((expires_at, entry_key, value)) = parse_entry(&contents) else {
+            tracing::warn!(path = %path.display(), "ignoring truncated disk cache entry");
+            return Ok(None);
+        };
+        if entry_key != key.as_bytes() {
+            // Another key with the same hash was stored in this file
+            return Ok(None);
+        }
+        if expires_at <= unix_millis(SystemTime::now()) {
+            // Ignore error if another task already removed the file
+            let _ = tokio::fs::remove_file(&path).await;
+            return Ok(None);
+        }
+
+        Ok(Some(value.to_vec()))
+    }
+
+    async fn write_entry(&self, key: &str, value: Vec<u8>) -> std::io::Result<()> {
+        let path = self.entry_path(&key);
+        let expires_at = unix_millis(SystemTime::now() + self.ttl);
+        let key_len = u32::try_from(key.len()).map_err(|_| {
+            std::io::Error::new(ErrorKind::InvalidInput, "disk cache key is too long")
+        })?;
+        let mut contents =
+            Vec::with_capacity(EXPIRES_AT_LEN + KEY_LEN_LEN + key.len() + value.len());
+        contents.extend_from_slice(&expires_at.to_be_bytes());
+        contents.extend_from_slice(&key_len.to_be_bytes());
+        contents.extend_from_slice(key.as_bytes());
+        contents.extend_from_slice(&value);
+
+        // Write to a temporary file first, so readers never see a partially
+        // written entry
+        let temp_id = self.next_temp_id.fetch_add(1, Ordering::Relaxed);
+        let temp_path = path.with_extension(format!("tmp-{}-{temp_id}", std::process::id()));
+        let result = match tokio::fs::write(&temp_path, contents).await {
+            Ok(()) => tokio::fs::rename(&temp_path, &path).await,
+            Err(error) => Err(error),
+        };
+        if result.is_err() {
+            // Don't leave the temporary file behind. Ignore error if it was
+            // never created
+            let _ = tokio::fs::remove_file(&temp_path).await;
+        }
+        result
+    }
+}
+
+impl<K> RemoteCache<K> for DiskCache
+where
+    K: Display + Send + Sync,
+{
+    type Error = std::io::Error;
+
+    async fn get_many(&self, keys: &[K]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let values = join_all(keys.iter().map(|key| async move {
+            let key = key.to_string();
+            self.read_entry(&key).await.unwrap_or_else(|error| {
+                tracing::warn!(key, "error while reading disk cache entry: {error}");
+                None
+            })
+        }))
+        .await;
+        Ok(values)
+    }
+
+    async fn set_many(&self, entries: Vec<(K, Vec<u8>)>) -> Result<(), Self::Error> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        join_all(entries.into_iter().map(|(key, value)| async move {
+            let key = key.to_string();
+            if let Err(error) = self.write_entry(&key, value).await {
+                tracing::warn!(key, "error while writing disk cache entry: {error}");
+            }
+        }))
+        .await;
+        Ok(())
+    }
+}
+
+/// Split the contents of a file into when the entry expires, the key, and
+/// the value. Returns `None` if the file is too short.
+fn parse_entry(contents: &[u8]) -> Option<(u64, &[u8], &[u8])> {
+    let (expires_at, rest) = split_at_checked(contents, EXPIRES_AT_LEN)?;
+    let (key_len, rest) = split_at_checked(rest, KEY_LEN_LEN)?;
+    let expires_at = u64::from_be_bytes(expires_at.try_into().ok()?);
+    let key_len = u32::from_be_bytes(key_len.try_into().ok()?);
+    let (key, value) = split_at_checked(rest, key_len.try_into().ok()?)?;
+    Some((expires_at, key, value))
+}
+
+fn split_at_checked(bytes: &[u8], mid: usize) -> Option<(&[u8], &[u8])> {
+    if mid <= bytes.len() {
+        Some(bytes.split_at(mid))
+    } else {
+        None
+    }
+}
+
+/// Hash bytes with 64-bit FNV-1a. Unlike the hashers in the standard
+/// library, the hash is stable across runs and Rust versions, so the same
+/// key is always stored in the same file.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
@@ -1,5 +1,6 @@
 use std::fmt::Display;
 use std::future::Future;
+use std::marker::PhantomData;
 
 /// A trait for using a batch of values to execute some operation, such
 /// as a bulk insertion in a datastore. An `Executor` will be given an
@@ -29,3 +30,202 @@ pub trait Executor {
         values: Vec<Self::Value>,
     ) -> impl Future<Output = Result<Vec<Self::Result>, Self::Error>> + Send;
 }
+
+/// A variant of [`Executor`] that can return a separate result or error for
+/// each value in the batch. When used with a [`BatchExecutor`](crate::BatchExecutor),
+/// an error for a single value only fails the caller that submitted that
+/// value, rather than every caller waiting on the batch.
+///
+/// Every [`Executor`] also implements `TryExecutor` (where each value
+/// succeeds if the batch succeeds), so a [`BatchExecutor`](crate::BatchExecutor)
+/// can be used with either trait.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::TryExecutor;
+/// # struct User { name: String }
+/// struct UserInserter;
+///
+/// impl TryExecutor for UserInserter {
+///     type Value = User;
+///     type Result = u64;
+///     type Error = anyhow::Error;
+///
+///     async fn try_execute(
+///         &self,
+///         values: Vec<User>,
+///     ) -> anyhow::Result<Vec<anyhow::Result<u64>>> {
+///         let results = values
+///             .iter()
+///             .enumerate()
+///             .map(|(index, user)| {
+///                 anyhow::ensure!(!user.name.is_empty(), "user name is empty");
+///                 Ok(index as u64)
+///             })
+///             .collect();
+///         Ok(results)
+///     }
+/// }
+/// ```
+pub trait TryExecutor {
+    /// The input value provided by the caller to do something.
+    type Value: Send;
+
+    /// The output value returned by the executor back to the caller for each
+    /// input value.
+    type Result: Send;
+
+    /// The error indicating that executing a batch or a single value failed.
+    type Error: Display;
+
+    /// Execute the operation for each value in the batch, returning a result
+    /// for each value. If `Ok(_)` is returned, a `Vec` should be returned,
+    /// where each element corresponds to the result of the input value at
+    /// the same index. If an element is `Err(_)`, then the caller that
+    /// submitted that value will receive an [`ExecuteError::ExecutorError`](crate::ExecuteError::ExecutorError).
+    /// If no element is present for a given input value, then the caller
+    /// will not receive a value. If `Err(_)` is returned, then every caller
+    /// waiting on the batch will receive an [`ExecuteError::ExecutorError`](crate::ExecuteError::ExecutorError).
+    #[allow(clippy::type_complexity)]
+    fn try_execute(
+        &self,
+        values: Vec<Self::Value>,
+    ) -> impl Future<Output = Result<Vec<Result<Self::Result, Self::Error>>, Self::Error>> + Send;
+}
+
+impl<E> TryExecutor for E
+where
+    E: Executor,
+{
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+
+    #[allow(clippy::type_complexity)]
+    fn try_execute(
+        &self,
+        values: Vec<Self::Value>,
+    ) -> impl Future<Output = Result<Vec<Result<Self::Result, Self::Error>>, Self::Error>> + Send
+    {
+        let results = self.execute(values);
+        async move {
+            let results = results.await?;
+            Ok(results.into_iter().map(Ok).collect())
+        }
+    }
+}
+
+/// An [`Executor`] that calls an async closure to execute each batch.
+/// Created with [`BatchExecutor::from_fn`](crate::BatchExecutor::from_fn) or
+/// [`FnExecutor::new`].
+///
+/// The closure is called with the values for each batch, and should return
+/// a `Vec` containing the result for each value, following the same rules
+/// as [`Executor::execute`].
+pub struct FnExecutor<Fun, V, R, E> {
+    execute_fn: Fun,
+    _marker: FnExecutorMarker<V, R, E>,
+}
+
+/// Marks the value, result, and error types of an [`FnExecutor`] without
+/// affecting its `Send`/`Sync` auto traits.
+type FnExecutorMarker<V, R, E> = PhantomData<fn() -> (V, R, E)>;
+
+impl<Fun, Fut, V, R, E> FnExecutor<Fun, V, R, E>
+where
+    Fun: Fn(Vec<V>) -> Fut,
+    Fut: Future<Output = Result<Vec<R>, E>>,
+{
+    /// Create a new `FnExecutor` that calls `execute_fn` for each batch.
+    pub fn new(execute_fn: Fun) -> Self {
+        FnExecutor {
+            execute_fn,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Fun, Fut, V, R, E> Executor for FnExecutor<Fun, V, R, E>
+where
+    Fun: Fn(Vec<V>) -> Fut,
+    Fut: Future<Output = Result<Vec<R>, E>> + Send,
+    V: Send,
+    R: Send,
+    E: Display,
+{
+    type Value = V;
+    type Result = R;
+    type Error = E;
+
+    fn execute(
+        &self,
+        values: Vec<Self::Value>,
+    ) -> impl Future<Output = Result<Vec<Self::Result>, Self::Error>> + Send {
+        (self.execute_fn)(values)
+    }
+}
+
+/// Wraps a [`TryExecutor`] with another [`TryExecutor`] that adds some
+/// behavior, similar to tower's `Layer`. Layers are added with
+/// [`BatchExecutorBuilder::layer`](crate::BatchExecutorBuilder::layer), so
+/// cross-cutting concerns like logging, retries, or validating values can
+/// be written once and combined with any executor.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchExecutor, ExecutorLayer, TryExecutor};
+/// # struct UserInserter;
+/// # impl ultra_batch::Executor for UserInserter {
+/// #     type Value = u64;
+/// #     type Result = u64;
+/// #     type Error = anyhow::Error;
+/// #     async fn execute(&self, values: Vec<u64>) -> anyhow::Result<Vec<u64>> {
+/// #         Ok(values)
+/// #     }
+/// # }
+/// /// Logs the size of each batch
+/// struct LogBatches<E>(E);
+///
+/// impl<E> TryExecutor for LogBatches<E>
+/// where
+///     E: TryExecutor + Sync,
+/// {
+///     type Value = E::Value;
+///     type Result = E::Result;
+///     type Error = E::Error;
+///
+///     async fn try_execute(
+///         &self,
+///         values: Vec<E::Value>,
+///     ) -> Result<Vec<Result<E::Result, E::Error>>, E::Error> {
+///         println!("executing {} values", values.len());
+///         self.0.try_execute(values).await
+///     }
+/// }
+///
+/// struct LogBatchesLayer;
+///
+/// impl<E> ExecutorLayer<E> for LogBatchesLayer {
+///     type Executor = LogBatches<E>;
+///
+///     fn layer(&self, executor: E) -> LogBatches<E> {
+///         LogBatches(executor)
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let batch_inserter = BatchExecutor::build(UserInserter)
+///     .layer(LogBatchesLayer)
+///     .finish();
+/// # Ok(())
+/// # }
+/// ```
+pub trait ExecutorLayer<E> {
+    /// The executor returned after wrapping an executor with this layer.
+    type Executor;
+
+    /// Wrap `executor` with this layer.
+    fn layer(&self, executor: E) -> Self::Executor;
+}
@@ -1,7 +1,14 @@
-use crate::Cache;
+use crate::combinators::{ContramapKey, MapValue, ThenLoadWith, WithFallback};
+use crate::{
+    BatchInfo, Cache, CacheCodec, FinishedBatch, PendingBatch, RemoteCache, SharedCache,
+    WithRemoteCache, WithSharedCache,
+};
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::future::Future;
 use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::Arc;
 
 /// A trait for fetching values from some datastore in bulk. A `Fetcher`
 /// will be given an array of keys and should insert fetched values into
@@ -42,12 +49,22 @@ use std::hash::Hash;
 /// ```
 pub trait Fetcher {
     /// The type used to look up a single value in a batch.
+    ///
+    /// Keys are cloned as they're queued, fetched, and cached. For keys
+    /// that are expensive to clone (such as long strings), use an `Arc`
+    /// (such as `Arc<str>`) so each clone only copies a pointer.
     type Key: Clone + Hash + Eq + Send + Sync;
 
     /// The type returned in a batch. `Value` is usually a single database
     /// record, but could also be a more sophisticated type, such as a
     /// `Vec` of values for a `Fetcher` that deals with one-to-many
     /// relationships.
+    ///
+    /// Values are cloned for each caller that loads them. For values that
+    /// can't be cloned or are expensive to clone (such as open handles or
+    /// large immutable buffers), implement [`SharedFetcher`] instead, which
+    /// stores each value in an `Arc` so every caller gets a pointer to the
+    /// same value.
     type Value: Clone + Send + Sync;
 
     /// The error indicating that fetching a batch failed.
@@ -61,9 +78,1089 @@ pub trait Fetcher {
     /// with the message from the returned error (note that any values inserted
     /// into `values` before the `Err(_)` is returned will still be cached).
     /// See the [`BatchFetcher`](crate::BatchFetcher) docs for more details.
+    ///
+    /// Details about the batch, such as its ID and the number of callers
+    /// waiting on it, are available from [`Cache::batch_info`].
     fn fetch(
         &self,
         keys: &[Self::Key],
         values: &mut Cache<'_, Self::Key, Self::Value>,
     ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Called when the first key for a new batch is queued, before waiting
+    /// for more keys. Does nothing by default. This can be used to prepare
+    /// for the upcoming batch, such as by warming up a connection.
+    fn on_batch_queued(&self, batch: &PendingBatch) {
+        let _ = batch;
+    }
+
+    /// Called just before [`fetch`](Fetcher::fetch) is called for a batch.
+    /// Does nothing by default.
+    fn on_batch_dispatching(&self, batch: &BatchInfo) {
+        let _ = batch;
+    }
+
+    /// Called after [`fetch`](Fetcher::fetch) returns for a batch, whether
+    /// or not it succeeded. Does nothing by default. This can be used to
+    /// record timings or to release resources held for the batch.
+    fn on_batch_complete(&self, batch: &FinishedBatch) {
+        let _ = batch;
+    }
+
+    /// Create a new `Fetcher` that loads a value from this fetcher, then uses
+    /// `key_fn` to get a key from the value, then loads the value for that key
+    /// from `then` (such as loading a post, then loading the post's author).
+    /// Both fetchers are called once for each batch, so both hops are batched.
+    ///
+    /// Keys are marked as "not found" if either fetcher doesn't return a
+    /// value. If either fetcher fails, the whole batch fails with a
+    /// [`ThenLoadError`](crate::ThenLoadError).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, Fetcher, Cache};
+    /// # #[derive(Clone)] struct Post { author_id: u64 }
+    /// # #[derive(Clone)] struct User;
+    /// # struct PostFetcher;
+    /// # impl Fetcher for PostFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = Post;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, Post>) -> anyhow::Result<()> {
+    /// #         unimplemented!();
+    /// #     }
+    /// # }
+    /// # struct UserFetcher;
+    /// # impl Fetcher for UserFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = User;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, User>) -> anyhow::Result<()> {
+    /// #         unimplemented!();
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let post_author_fetcher = PostFetcher.then_load_with(UserFetcher, |post| post.author_id);
+    /// let batch_fetcher = BatchFetcher::build(post_author_fetcher).finish();
+    /// # Ok(()) }
+    /// ```
+    fn then_load_with<B, Fun>(self, then: B, key_fn: Fun) -> ThenLoadWith<Self, B, Fun>
+    where
+        Self: Sized,
+        B: Fetcher,
+        Fun: Fn(&Self::Value) -> B::Key,
+    {
+        ThenLoadWith::new(self, then, key_fn)
+    }
+
+    /// Create a new `Fetcher` that transforms each value with `map_fn` before
+    /// it's cached, such as to convert database rows into domain types.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, Fetcher, Cache};
+    /// # #[derive(Clone)] struct UserRow { id: u64, name: String }
+    /// # struct UserRowFetcher;
+    /// # impl Fetcher for UserRowFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = UserRow;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, UserRow>) -> anyhow::Result<()> {
+    /// #         unimplemented!();
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let user_name_fetcher = UserRowFetcher.map_value(|user: UserRow| user.name);
+    /// let batch_fetcher = BatchFetcher::build(user_name_fetcher).finish();
+    /// # Ok(()) }
+    /// ```
+    fn map_value<Fun, V>(self, map_fn: Fun) -> MapValue<Self, Fun>
+    where
+        Self: Sized,
+        Fun: Fn(Self::Value) -> V,
+    {
+        MapValue::new(self, map_fn)
+    }
+
+    /// Create a new `Fetcher` that accepts keys of a different type,
+    /// converting each key with `key_fn` before calling this fetcher. This
+    /// can be used to expose a fetcher with a different key representation
+    /// (such as a newtype) without duplicating the fetching logic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, Fetcher, Cache};
+    /// # #[derive(Clone)] struct User;
+    /// # struct UserFetcher;
+    /// # impl Fetcher for UserFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = User;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, User>) -> anyhow::Result<()> {
+    /// #         unimplemented!();
+    /// #     }
+    /// # }
+    /// #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    /// struct UserId(u64);
+    ///
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let user_fetcher = UserFetcher.contramap_key(|user_id: &UserId| user_id.0);
+    /// let batch_fetcher = BatchFetcher::build(user_fetcher).finish();
+    /// # Ok(()) }
+    /// ```
+    fn contramap_key<Fun, K>(self, key_fn: Fun) -> ContramapKey<Self, Fun, K>
+    where
+        Self: Sized,
+        Fun: Fn(&K) -> Self::Key,
+    {
+        ContramapKey::new(self, key_fn)
+    }
+
+    /// Create a new `Fetcher` that first calls this fetcher, then calls
+    /// `fallback` with any keys that weren't found (such as checking a cache
+    /// service before falling back to a database). The values from both
+    /// fetchers are returned as one batch, and `fallback` is only called if
+    /// some keys weren't found.
+    ///
+    /// If this fetcher fails, the fallback isn't called, and the batch fails
+    /// with [`FallbackError::Primary`](crate::FallbackError::Primary).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, Fetcher, Cache};
+    /// # #[derive(Clone)] struct User;
+    /// # struct CachedUserFetcher;
+    /// # impl Fetcher for CachedUserFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = User;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, User>) -> anyhow::Result<()> {
+    /// #         unimplemented!();
+    /// #     }
+    /// # }
+    /// # struct DbUserFetcher;
+    /// # impl Fetcher for DbUserFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = User;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, User>) -> anyhow::Result<()> {
+    /// #         unimplemented!();
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let user_fetcher = CachedUserFetcher.with_fallback(DbUserFetcher);
+    /// let batch_fetcher = BatchFetcher::build(user_fetcher).finish();
+    /// # Ok(()) }
+    /// ```
+    fn with_fallback<B>(self, fallback: B) -> WithFallback<Self, B>
+    where
+        Self: Sized,
+        B: Fetcher<Key = Self::Key, Value = Self::Value>,
+    {
+        WithFallback::new(self, fallback)
+    }
+
+    /// Create a new `Fetcher` that first checks `shared_cache` for each key,
+    /// and only calls this fetcher for keys that aren't cached (or whose TTL
+    /// has expired). Fetched values are written back to `shared_cache`.
+    ///
+    /// This allows a short-lived [`BatchFetcher`](crate::BatchFetcher), such
+    /// as one created for each request, to reuse values fetched by earlier
+    /// requests. Each `BatchFetcher` still caches values itself, so a value
+    /// doesn't change during a request even if the shared cache is updated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, Fetcher, Cache, SharedCache};
+    /// # use std::sync::Arc;
+    /// # #[derive(Clone)] struct User;
+    /// # struct UserFetcher;
+    /// # impl Fetcher for UserFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = User;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, User>) -> anyhow::Result<()> {
+    /// #         unimplemented!();
+    /// #     }
+    /// # }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// // Created once and shared by every request
+    /// let user_fetcher = Arc::new(UserFetcher);
+    /// let shared_users = SharedCache::new(tokio::time::Duration::from_secs(30));
+    ///
+    /// // Created for each request
+    /// let batch_fetcher =
+    ///     BatchFetcher::build(user_fetcher.clone().with_shared_cache(shared_users.clone())).finish();
+    /// # Ok(()) }
+    /// ```
+    fn with_shared_cache(
+        self,
+        shared_cache: SharedCache<Self::Key, Self::Value>,
+    ) -> WithSharedCache<Self>
+    where
+        Self: Sized,
+    {
+        WithSharedCache::new(self, shared_cache)
+    }
+
+    /// Create a new `Fetcher` that first checks `remote_cache` for each key,
+    /// and only calls this fetcher for keys that aren't cached. Values are
+    /// converted to and from bytes with `codec`, and fetched values are
+    /// written back to `remote_cache`.
+    ///
+    /// Errors from the remote cache or the codec are logged, and the keys
+    /// are fetched as if they weren't cached.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::{BatchFetcher, CacheCodec, Fetcher, Cache, RemoteCache};
+    /// # struct UserNameFetcher;
+    /// # impl Fetcher for UserNameFetcher {
+    /// #     type Key = u64;
+    /// #     type Value = String;
+    /// #     type Error = anyhow::Error;
+    /// #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, String>) -> anyhow::Result<()> {
+    /// #         unimplemented!();
+    /// #     }
+    /// # }
+    /// # struct RedisCache;
+    /// # impl RemoteCache<u64> for RedisCache {
+    /// #     type Error = anyhow::Error;
+    /// #     async fn get_many(&self, keys: &[u64]) -> anyhow::Result<Vec<Option<Vec<u8>>>> {
+    /// #         unimplemented!();
+    /// #     }
+    /// #     async fn set_many(&self, entries: Vec<(u64, Vec<u8>)>) -> anyhow::Result<()> {
+    /// #         unimplemented!();
+    /// #     }
+    /// # }
+    /// struct Utf8Codec;
+    ///
+    /// impl CacheCodec<String> for Utf8Codec {
+    ///     type Error = std::string::FromUtf8Error;
+    ///
+    ///     fn encode(&self, value: &String) -> Result<Vec<u8>, Self::Error> {
+    ///         Ok(value.as_bytes().to_vec())
+    ///     }
+    ///
+    ///     fn decode(&self, bytes: &[u8]) -> Result<String, Self::Error> {
+    ///         String::from_utf8(bytes.to_vec())
+    ///     }
+    /// }
+    ///
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let user_name_fetcher = UserNameFetcher.with_remote_cache(RedisCache, Utf8Codec);
+    /// let batch_fetcher = BatchFetcher::build(user_name_fetcher).finish();
+    /// # Ok(()) }
+    /// ```
+    fn with_remote_cache<R, C>(self, remote_cache: R, codec: C) -> WithRemoteCache<Self, R, C>
+    where
+        Self: Sized,
+        R: RemoteCache<Self::Key>,
+        C: CacheCodec<Self::Value>,
+    {
+        WithRemoteCache::new(self, remote_cache, codec)
+    }
+}
+
+/// Allows a shared `Fetcher` to be used with a [`BatchFetcher`](crate::BatchFetcher),
+/// such as one that holds a connection pool used elsewhere in an application.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, Fetcher, Cache};
+/// # use std::sync::Arc;
+/// # struct UserFetcher;
+/// # impl Fetcher for UserFetcher {
+/// #     type Key = ();
+/// #     type Value = ();
+/// #     type Error = anyhow::Error;
+/// #     async fn fetch(&self, keys: &[()], values: &mut Cache<'_, (), ()>) -> anyhow::Result<()> {
+/// #         unimplemented!();
+/// #     }
+/// # }
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let user_fetcher = Arc::new(UserFetcher);
+/// let batch_fetcher = BatchFetcher::build(user_fetcher.clone()).finish();
+/// # Ok(()) }
+/// ```
+impl<F> Fetcher for Arc<F>
+where
+    F: Fetcher,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        (**self).fetch(keys, values)
+    }
+
+    fn on_batch_queued(&self, batch: &PendingBatch) {
+        (**self).on_batch_queued(batch)
+    }
+
+    fn on_batch_dispatching(&self, batch: &BatchInfo) {
+        (**self).on_batch_dispatching(batch)
+    }
+
+    fn on_batch_complete(&self, batch: &FinishedBatch) {
+        (**self).on_batch_complete(batch)
+    }
+}
+
+/// A version of [`Fetcher`] for fetchers that aren't `Send` or `Sync`, or
+/// whose `fetch` futures aren't `Send` (such as fetchers that wrap a
+/// single-threaded database handle). A [`BatchFetcher`](crate::BatchFetcher)
+/// for a `LocalFetcher` is created with [`BatchFetcherBuilder::finish_local`](crate::BatchFetcherBuilder::finish_local),
+/// which runs the fetcher on a Tokio [`LocalSet`](tokio::task::LocalSet).
+/// The keys and values must still be `Send` and `Sync`, so the resulting
+/// `BatchFetcher` can be shared between threads like any other.
+///
+/// Every [`Fetcher`] also implements `LocalFetcher`, so only implement
+/// this trait directly for fetchers that can't implement [`Fetcher`].
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, Cache, LocalFetcher};
+/// # use std::rc::Rc;
+/// struct LocalUserFetcher {
+///     // A handle that can't be sent between threads
+///     names: Rc<Vec<String>>,
+/// }
+///
+/// impl LocalFetcher for LocalUserFetcher {
+///     type Key = usize;
+///     type Value = String;
+///     type Error = anyhow::Error;
+///
+///     async fn fetch(&self, keys: &[usize], values: &mut Cache<'_, usize, String>) -> anyhow::Result<()> {
+///         for &key in keys {
+///             if let Some(name) = self.names.get(key) {
+///                 values.insert(key, name.clone());
+///             }
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// # #[tokio::main(flavor = "current_thread")] async fn main() -> anyhow::Result<()> {
+/// let local_set = tokio::task::LocalSet::new();
+/// local_set
+///     .run_until(async {
+///         let names = Rc::new(vec!["Alice".to_string(), "Bob".to_string()]);
+///         let batch_fetcher = BatchFetcher::build(LocalUserFetcher { names }).finish_local();
+///
+///         let name = batch_fetcher.load(1).await?;
+///         assert_eq!(name, "Bob");
+///         anyhow::Ok(())
+///     })
+///     .await?;
+/// # Ok(()) }
+/// ```
+pub trait LocalFetcher {
+    /// The type used to look up a single value in a batch. See
+    /// [`Fetcher::Key`].
+    type Key: Clone + Hash + Eq + Send + Sync;
+
+    /// The type returned in a batch. See [`Fetcher::Value`].
+    type Value: Clone + Send + Sync;
+
+    /// The error indicating that fetching a batch failed.
+    type Error: Display;
+
+    /// Retrieve the values associated with the given keys, and insert them
+    /// into `values` if found. See [`Fetcher::fetch`].
+    fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> impl Future<Output = Result<(), Self::Error>>;
+
+    /// Called when the first key for a new batch is queued. See
+    /// [`Fetcher::on_batch_queued`].
+    fn on_batch_queued(&self, batch: &PendingBatch) {
+        let _ = batch;
+    }
+
+    /// Called just before `fetch` is called for a batch. See
+    /// [`Fetcher::on_batch_dispatching`].
+    fn on_batch_dispatching(&self, batch: &BatchInfo) {
+        let _ = batch;
+    }
+
+    /// Called after `fetch` returns for a batch. See
+    /// [`Fetcher::on_batch_complete`].
+    fn on_batch_complete(&self, batch: &FinishedBatch) {
+        let _ = batch;
+    }
+}
+
+impl<F> LocalFetcher for F
+where
+    F: Fetcher,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> impl Future<Output = Result<(), Self::Error>> {
+        Fetcher::fetch(self, keys, values)
+    }
+
+    fn on_batch_queued(&self, batch: &PendingBatch) {
+        Fetcher::on_batch_queued(self, batch)
+    }
+
+    fn on_batch_dispatching(&self, batch: &BatchInfo) {
+        Fetcher::on_batch_dispatching(self, batch)
+    }
+
+    fn on_batch_complete(&self, batch: &FinishedBatch) {
+        Fetcher::on_batch_complete(self, batch)
+    }
+}
+
+/// A blocking version of [`Fetcher`], used by [`SyncBatchFetcher`](crate::SyncBatchFetcher)
+/// for applications that don't use async at all (such as ETL scripts).
+/// Each batch is fetched on the `SyncBatchFetcher`'s own thread, so `fetch`
+/// can block (e.g. by running a query with a synchronous database client).
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{Cache, SyncBatchFetcher, SyncFetcher};
+/// struct UserFetcher {
+///     names: Vec<String>,
+/// }
+///
+/// impl SyncFetcher for UserFetcher {
+///     type Key = usize;
+///     type Value = String;
+///     type Error = anyhow::Error;
+///
+///     fn fetch(&self, keys: &[usize], values: &mut Cache<'_, usize, String>) -> anyhow::Result<()> {
+///         for &key in keys {
+///             if let Some(name) = self.names.get(key) {
+///                 values.insert(key, name.clone());
+///             }
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let names = vec!["Alice".to_string(), "Bob".to_string()];
+/// let batch_fetcher = SyncBatchFetcher::build(UserFetcher { names }).finish();
+///
+/// let name = batch_fetcher.load(1)?;
+/// assert_eq!(name, "Bob");
+/// # Ok(()) }
+/// ```
+pub trait SyncFetcher {
+    /// The type used to look up a single value in a batch. See
+    /// [`Fetcher::Key`].
+    type Key: Clone + Hash + Eq + Send + Sync;
+
+    /// The type returned in a batch. See [`Fetcher::Value`].
+    type Value: Clone + Send + Sync;
+
+    /// The error indicating that fetching a batch failed.
+    type Error: Display;
+
+    /// Retrieve the values associated with the given keys, and insert them
+    /// into `values` if found. See [`Fetcher::fetch`].
+    fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error>;
+}
+
+/// A simpler version of [`Fetcher`] that returns the fetched values as a
+/// `HashMap`, instead of inserting them into a [`Cache`]. Any keys missing
+/// from the returned map will be marked as "not found". Use
+/// [`BatchFetcher::build_map`](crate::BatchFetcher::build_map) to create a
+/// [`BatchFetcher`](crate::BatchFetcher) from a `MapFetcher`.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, MapFetcher};
+/// # use std::collections::HashMap;
+/// struct UserFetcher {
+///     names: Vec<String>,
+/// }
+///
+/// impl MapFetcher for UserFetcher {
+///     type Key = usize;
+///     type Value = String;
+///     type Error = anyhow::Error;
+///
+///     async fn fetch_map(&self, keys: &[usize]) -> anyhow::Result<HashMap<usize, String>> {
+///         let names = keys
+///             .iter()
+///             .filter_map(|&key| Some((key, self.names.get(key)?.clone())))
+///             .collect();
+///         Ok(names)
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let names = vec!["Alice".to_string(), "Bob".to_string()];
+/// let batch_fetcher = BatchFetcher::build_map(UserFetcher { names }).finish();
+///
+/// let name = batch_fetcher.load(1).await?;
+/// assert_eq!(name, "Bob");
+/// # Ok(()) }
+/// ```
+pub trait MapFetcher {
+    /// The type used to look up a single value in a batch. See
+    /// [`Fetcher::Key`].
+    type Key: Clone + Hash + Eq + Send + Sync;
+
+    /// The type returned in a batch. See [`Fetcher::Value`].
+    type Value: Clone + Send + Sync;
+
+    /// The error indicating that fetching a batch failed.
+    type Error: Display;
+
+    /// Retrieve the values associated with the given keys, returning a map
+    /// containing the value for each key that was found. If `Err(_)` is
+    /// returned, then the caller(s) waiting on the batch will receive a
+    /// [`LoadError::FetchError`](crate::LoadError::FetchError).
+    fn fetch_map(
+        &self,
+        keys: &[Self::Key],
+    ) -> impl Future<Output = Result<HashMap<Self::Key, Self::Value>, Self::Error>> + Send;
+}
+
+/// Adapts a [`MapFetcher`] into a [`Fetcher`]. Created with
+/// [`BatchFetcher::build_map`](crate::BatchFetcher::build_map) or
+/// [`MapFetcherAdapter::new`].
+#[derive(Debug, Clone)]
+pub struct MapFetcherAdapter<F> {
+    fetcher: F,
+}
+
+impl<F> MapFetcherAdapter<F>
+where
+    F: MapFetcher,
+{
+    /// Wrap a [`MapFetcher`] so it can be used with a
+    /// [`BatchFetcher`](crate::BatchFetcher).
+    pub fn new(fetcher: F) -> Self {
+        MapFetcherAdapter { fetcher }
+    }
+
+    /// Get a reference to the wrapped [`MapFetcher`].
+    pub fn get_ref(&self) -> &F {
+        &self.fetcher
+    }
+}
+
+impl<F> Fetcher for MapFetcherAdapter<F>
+where
+    F: MapFetcher + Sync,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        for (key, value) in self.fetcher.fetch_map(keys).await? {
+            values.insert(key, value);
+        }
+
+        Ok(())
+    }
+}
+
+/// A version of [`Fetcher`] that returns the fetched values as a `Vec` of
+/// key-value pairs, for datastores that naturally return rows along with
+/// their keys. The pairs are inserted into the cache for you, and any keys
+/// missing from the returned pairs will be marked as "not found". If a key
+/// appears more than once, the last value is used. Use
+/// [`BatchFetcher::build_pairs`](crate::BatchFetcher::build_pairs) to create
+/// a [`BatchFetcher`](crate::BatchFetcher) from a `PairsFetcher`.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, PairsFetcher};
+/// # struct DbConnection;
+/// # impl DbConnection {
+/// #     async fn get_user_names(&self, ids: &[u64]) -> anyhow::Result<Vec<(u64, String)>> {
+/// #         Ok(ids.iter().map(|&id| (id, format!("User {id}"))).collect())
+/// #     }
+/// # }
+/// struct UserNameFetcher {
+///     db_conn: DbConnection,
+/// }
+///
+/// impl PairsFetcher for UserNameFetcher {
+///     type Key = u64;
+///     type Value = String;
+///     type Error = anyhow::Error;
+///
+///     async fn fetch_pairs(&self, keys: &[u64]) -> anyhow::Result<Vec<(u64, String)>> {
+///         self.db_conn.get_user_names(keys).await
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// # let db_conn = DbConnection;
+/// let batch_fetcher = BatchFetcher::build_pairs(UserNameFetcher { db_conn }).finish();
+///
+/// let name = batch_fetcher.load(1).await?;
+/// assert_eq!(name, "User 1");
+/// # Ok(()) }
+/// ```
+pub trait PairsFetcher {
+    /// The type used to look up a single value in a batch. See
+    /// [`Fetcher::Key`].
+    type Key: Clone + Hash + Eq + Send + Sync;
+
+    /// The type returned in a batch. See [`Fetcher::Value`].
+    type Value: Clone + Send + Sync;
+
+    /// The error indicating that fetching a batch failed.
+    type Error: Display;
+
+    /// Retrieve the values associated with the given keys, returning a pair
+    /// with the key and value for each key that was found. If `Err(_)` is
+    /// returned, then the caller(s) waiting on the batch will receive a
+    /// [`LoadError::FetchError`](crate::LoadError::FetchError).
+    #[allow(clippy::type_complexity)]
+    fn fetch_pairs(
+        &self,
+        keys: &[Self::Key],
+    ) -> impl Future<Output = Result<Vec<(Self::Key, Self::Value)>, Self::Error>> + Send;
+}
+
+/// Adapts a [`PairsFetcher`] into a [`Fetcher`]. Created with
+/// [`BatchFetcher::build_pairs`](crate::BatchFetcher::build_pairs) or
+/// [`PairsFetcherAdapter::new`].
+#[derive(Debug, Clone)]
+pub struct PairsFetcherAdapter<F> {
+    fetcher: F,
+}
+
+impl<F> PairsFetcherAdapter<F>
+where
+    F: PairsFetcher,
+{
+    /// Wrap a [`PairsFetcher`] so it can be used with a
+    /// [`BatchFetcher`](crate::BatchFetcher).
+    pub fn new(fetcher: F) -> Self {
+        PairsFetcherAdapter { fetcher }
+    }
+
+    /// Get a reference to the wrapped [`PairsFetcher`].
+    pub fn get_ref(&self) -> &F {
+        &self.fetcher
+    }
+}
+
+impl<F> Fetcher for PairsFetcherAdapter<F>
+where
+    F: PairsFetcher + Sync,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        for (key, value) in self.fetcher.fetch_pairs(keys).await? {
+            values.insert(key, value);
+        }
+
+        Ok(())
+    }
+}
+
+/// A version of [`Fetcher`] that takes `&mut self`, so the fetcher can hold
+/// state that isn't `Sync` (such as a single database connection or a
+/// prepared statement cache) without wrapping it in a `Mutex`. Use
+/// [`BatchFetcher::build_exclusive`](crate::BatchFetcher::build_exclusive)
+/// to create a [`BatchFetcher`](crate::BatchFetcher) from an
+/// `ExclusiveFetcher`.
+///
+/// Since `fetch` needs exclusive access, only one batch is fetched at a
+/// time, even if [`max_concurrent_batches`](crate::BatchFetcherBuilder::max_concurrent_batches)
+/// is set.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, Cache, ExclusiveFetcher};
+/// # use std::cell::Cell;
+/// struct UserNameFetcher {
+///     // Not `Sync`, but only used by one batch at a time
+///     num_queries: Cell<u64>,
+/// }
+///
+/// impl ExclusiveFetcher for UserNameFetcher {
+///     type Key = u64;
+///     type Value = String;
+///     type Error = anyhow::Error;
+///
+///     async fn fetch(&mut self, keys: &[u64], values: &mut Cache<'_, u64, String>) -> anyhow::Result<()> {
+///         self.num_queries.set(self.num_queries.get() + 1);
+///         for &key in keys {
+///             values.insert(key, format!("User {key}"));
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let fetcher = UserNameFetcher { num_queries: Cell::new(0) };
+/// let batch_fetcher = BatchFetcher::build_exclusive(fetcher).finish();
+///
+/// let name = batch_fetcher.load(1).await?;
+/// assert_eq!(name, "User 1");
+/// # Ok(()) }
+/// ```
+pub trait ExclusiveFetcher {
+    /// The type used to look up a single value in a batch. See
+    /// [`Fetcher::Key`].
+    type Key: Clone + Hash + Eq + Send + Sync;
+
+    /// The type returned in a batch. See [`Fetcher::Value`].
+    type Value: Clone + Send + Sync;
+
+    /// The error indicating that fetching a batch failed.
+    type Error: Display;
+
+    /// Retrieve the values associated with the given keys, and insert them
+    /// into `values` if found. See [`Fetcher::fetch`].
+    fn fetch(
+        &mut self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// Adapts an [`ExclusiveFetcher`] into a [`Fetcher`], giving each batch
+/// exclusive access to the fetcher in turn. Created with
+/// [`BatchFetcher::build_exclusive`](crate::BatchFetcher::build_exclusive)
+/// or [`Exclusive::new`].
+#[derive(Debug)]
+pub struct Exclusive<F> {
+    fetcher: tokio::sync::Mutex<F>,
+}
+
+impl<F> Exclusive<F>
+where
+    F: ExclusiveFetcher,
+{
+    /// Wrap an [`ExclusiveFetcher`] so it can be used with a
+    /// [`BatchFetcher`](crate::BatchFetcher).
+    pub fn new(fetcher: F) -> Self {
+        Exclusive {
+            fetcher: tokio::sync::Mutex::new(fetcher),
+        }
+    }
+
+    /// Unwrap the [`ExclusiveFetcher`].
+    pub fn into_inner(self) -> F {
+        self.fetcher.into_inner()
+    }
+}
+
+impl<F> Fetcher for Exclusive<F>
+where
+    F: ExclusiveFetcher + Send,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let mut fetcher = self.fetcher.lock().await;
+        fetcher.fetch(keys, values).await
+    }
+}
+
+/// A version of [`Fetcher`] for values that don't implement `Clone`, such
+/// as open handles or large immutable buffers. Each value is stored in an
+/// `Arc`, and loading a key returns an `Arc` pointing to the cached value,
+/// so the value is never copied. Use
+/// [`BatchFetcher::build_shared`](crate::BatchFetcher::build_shared) to
+/// create a [`BatchFetcher`](crate::BatchFetcher) from a `SharedFetcher`.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, Cache, SharedFetcher};
+/// # use std::sync::Arc;
+/// // An open file handle, which can't be cloned
+/// struct Handle {
+///     path: String,
+/// }
+///
+/// struct OpenHandles;
+///
+/// impl SharedFetcher for OpenHandles {
+///     type Key = String;
+///     type Value = Handle;
+///     type Error = anyhow::Error;
+///
+///     async fn fetch(&self, keys: &[String], values: &mut Cache<'_, String, Arc<Handle>>) -> anyhow::Result<()> {
+///         for key in keys {
+///             values.insert_shared(key.clone(), Handle { path: key.clone() });
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let batch_fetcher = BatchFetcher::build_shared(OpenHandles).finish();
+///
+/// let handle: Arc<Handle> = batch_fetcher.load("a.txt".to_string()).await?;
+/// assert_eq!(handle.path, "a.txt");
+/// # Ok(()) }
+/// ```
+pub trait SharedFetcher {
+    /// The type used to look up a single value in a batch. See
+    /// [`Fetcher::Key`].
+    type Key: Clone + Hash + Eq + Send + Sync;
+
+    /// The type returned in a batch. Unlike [`Fetcher::Value`], this
+    /// doesn't need to implement `Clone`.
+    type Value: Send + Sync;
+
+    /// The error indicating that fetching a batch failed.
+    type Error: Display;
+
+    /// Retrieve the values associated with the given keys, and insert them
+    /// into `values` with [`Cache::insert_shared`] if found. See
+    /// [`Fetcher::fetch`].
+    fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Arc<Self::Value>>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// Adapts a [`SharedFetcher`] into a [`Fetcher`] that returns each value in
+/// an `Arc`. Created with
+/// [`BatchFetcher::build_shared`](crate::BatchFetcher::build_shared) or
+/// [`SharedFetcherAdapter::new`].
+#[derive(Debug, Clone)]
+pub struct SharedFetcherAdapter<F> {
+    fetcher: F,
+}
+
+impl<F> SharedFetcherAdapter<F>
+where
+    F: SharedFetcher,
+{
+    /// Wrap a [`SharedFetcher`] so it can be used with a
+    /// [`BatchFetcher`](crate::BatchFetcher).
+    pub fn new(fetcher: F) -> Self {
+        SharedFetcherAdapter { fetcher }
+    }
+
+    /// Get a reference to the wrapped [`SharedFetcher`].
+    pub fn get_ref(&self) -> &F {
+        &self.fetcher
+    }
+}
+
+impl<F> Fetcher for SharedFetcherAdapter<F>
+where
+    F: SharedFetcher + Sync,
+{
+    type Key = F::Key;
+    type Value = Arc<F::Value>;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        self.fetcher.fetch(keys, values).await
+    }
+}
+
+/// A [`Fetcher`] that calls an async closure to fetch each batch. Created
+/// with [`BatchFetcher::from_fn`](crate::BatchFetcher::from_fn) or
+/// [`FnFetcher::new`].
+///
+/// The closure is called with the keys for each batch, and should return a
+/// `HashMap` containing the value for each key that was found. Any keys
+/// missing from the map will be marked as "not found".
+pub struct FnFetcher<Fun, K, V, E> {
+    fetch_fn: Fun,
+    _marker: FnFetcherMarker<K, V, E>,
+}
+
+/// Marks the key, value, and error types of an [`FnFetcher`] without
+/// affecting its `Send`/`Sync` auto traits.
+type FnFetcherMarker<K, V, E> = PhantomData<fn() -> (K, V, E)>;
+
+impl<Fun, Fut, K, V, E> FnFetcher<Fun, K, V, E>
+where
+    Fun: Fn(Vec<K>) -> Fut,
+    Fut: Future<Output = Result<HashMap<K, V>, E>>,
+{
+    /// Create a new `FnFetcher` that calls `fetch_fn` for each batch.
+    pub fn new(fetch_fn: Fun) -> Self {
+        FnFetcher {
+            fetch_fn,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Fun, Fut, K, V, E> Fetcher for FnFetcher<Fun, K, V, E>
+where
+    Fun: Fn(Vec<K>) -> Fut,
+    Fut: Future<Output = Result<HashMap<K, V>, E>> + Send,
+    K: Clone + Hash + Eq + Send + Sync,
+    V: Clone + Send + Sync,
+    E: Display,
+{
+    type Key = K;
+    type Value = V;
+    type Error = E;
+
+    fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let fetched_values = (self.fetch_fn)(keys.to_vec());
+        async move {
+            for (key, value) in fetched_values.await? {
+                values.insert(key, value);
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// A [`Fetcher`] that calls a blocking function to fetch each batch, such as
+/// a query using the blocking version of Diesel. Each batch runs on Tokio's
+/// pool of blocking threads (see [`tokio::task::spawn_blocking`]), so slow
+/// queries don't block other tasks.
+///
+/// Like [`FnFetcher`], the function is called with the keys for each batch,
+/// and should return a `HashMap` containing the value for each key that was
+/// found. Requires the `tokio` feature (enabled by default).
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, BlockingFetcher};
+/// # use std::collections::HashMap;
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let user_names = BlockingFetcher::new(|user_ids: Vec<u64>| {
+///     // Run a blocking query, such as with a connection from an r2d2 pool
+///     let user_names: HashMap<u64, String> = user_ids
+///         .into_iter()
+///         .map(|id| (id, format!("User {id}")))
+///         .collect();
+///     anyhow::Ok(user_names)
+/// });
+/// let batch_fetcher = BatchFetcher::build(user_names).finish();
+///
+/// let user_name = batch_fetcher.load(1).await?;
+/// assert_eq!(user_name, "User 1");
+/// # Ok(()) }
+/// ```
+#[cfg(feature = "tokio")]
+pub struct BlockingFetcher<Fun, K, V, E> {
+    fetch_fn: Arc<Fun>,
+    _marker: FnFetcherMarker<K, V, E>,
+}
+
+#[cfg(feature = "tokio")]
+impl<Fun, K, V, E> BlockingFetcher<Fun, K, V, E>
+where
+    Fun: Fn(Vec<K>) -> Result<HashMap<K, V>, E>,
+{
+    /// Create a new `BlockingFetcher` that calls `fetch_fn` for each batch.
+    pub fn new(fetch_fn: Fun) -> Self {
+        BlockingFetcher {
+            fetch_fn: Arc::new(fetch_fn),
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<Fun, K, V, E> Clone for BlockingFetcher<Fun, K, V, E> {
+    fn clone(&self) -> Self {
+        BlockingFetcher {
+            fetch_fn: self.fetch_fn.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<Fun, K, V, E> Fetcher for BlockingFetcher<Fun, K, V, E>
+where
+    Fun: Fn(Vec<K>) -> Result<HashMap<K, V>, E> + Send + Sync + 'static,
+    K: Clone + Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+    E: Display + Send + 'static,
+{
+    type Key = K;
+    type Value = V;
+    type Error = E;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let fetch_fn = self.fetch_fn.clone();
+        let keys = keys.to_vec();
+        let fetched_values = match tokio::task::spawn_blocking(move || fetch_fn(keys)).await {
+            Ok(fetched_values) => fetched_values?,
+            Err(join_error) => match join_error.try_into_panic() {
+                Ok(panic) => std::panic::resume_unwind(panic),
+                Err(join_error) => panic!("blocking fetch was cancelled: {join_error}"),
+            },
+        };
+
+        for (key, value) in fetched_values {
+            values.insert(key, value);
+        }
+
+        Ok(())
+    }
 }
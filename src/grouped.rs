@@ -0,0 +1,130 @@
+use crate::{BatchExecutor, BatchExecutorBuilder, ExecuteError, Executor};
+use std::fmt::Display;
+use std::future::Future;
+
+/// A variant of [`Executor`] that receives the values from each caller as a
+/// separate group, instead of one flattened list. Use this when the
+/// boundaries between requests matter, such as running each caller's values
+/// in its own transaction while still batching the calls together. Use
+/// [`BatchExecutor::build_grouped`] to create a [`BatchExecutor`] from a
+/// `GroupedExecutor`.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchExecutor, GroupedExecutor};
+/// # struct User;
+/// # struct DbConnection;
+/// # impl DbConnection {
+/// #     async fn insert_users_in_transaction(&self, users: &[User]) -> anyhow::Result<Vec<u64>> { Ok(vec![]) }
+/// # }
+/// struct UserInserter {
+///     db_conn: DbConnection,
+/// }
+///
+/// impl GroupedExecutor for UserInserter {
+///     type Value = User;
+///     type Result = u64;
+///     type Error = anyhow::Error;
+///
+///     async fn execute(&self, groups: Vec<Vec<User>>) -> anyhow::Result<Vec<Vec<u64>>> {
+///         let mut results = vec![];
+///         for users in groups {
+///             results.push(self.db_conn.insert_users_in_transaction(&users).await?);
+///         }
+///         Ok(results)
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// # let db_conn = DbConnection;
+/// let batch_inserter = BatchExecutor::build_grouped(UserInserter { db_conn }).finish();
+/// let user_ids = batch_inserter.execute_group(vec![User, User]).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait GroupedExecutor {
+    /// The input value provided by the caller to do something.
+    type Value: Send;
+
+    /// The output value returned by the executor back to the caller for each
+    /// input value.
+    type Result: Send;
+
+    /// The error indicating that executing a batch failed.
+    type Error: Display + Send;
+
+    /// Execute the operation for each group of values in the batch, where
+    /// each group holds the values submitted by one caller. The returned
+    /// `Vec` should contain the results for each group in the same order as
+    /// `groups`, and the results within each group follow the same rules as
+    /// [`Executor::execute`].
+    fn execute(
+        &self,
+        groups: Vec<Vec<Self::Value>>,
+    ) -> impl Future<Output = Result<Vec<Vec<Self::Result>>, Self::Error>> + Send;
+}
+
+/// Adapts a [`GroupedExecutor`] into an [`Executor`] where each value is a
+/// whole group. Created with [`BatchExecutor::build_grouped`] or
+/// [`Grouped::new`].
+///
+/// Note that batch sizes count groups rather than the values inside them, so
+/// [`max_batch_size`](crate::BatchExecutorBuilder::max_batch_size) limits the
+/// number of callers in a batch.
+#[derive(Debug, Clone)]
+pub struct Grouped<E> {
+    executor: E,
+}
+
+impl<E> Grouped<E>
+where
+    E: GroupedExecutor,
+{
+    /// Wrap a [`GroupedExecutor`] so it can be used with a [`BatchExecutor`].
+    pub fn new(executor: E) -> Self {
+        Grouped { executor }
+    }
+
+    /// Get a reference to the wrapped [`GroupedExecutor`].
+    pub fn get_ref(&self) -> &E {
+        &self.executor
+    }
+}
+
+impl<E> Executor for Grouped<E>
+where
+    E: GroupedExecutor + Sync,
+{
+    type Value = Vec<E::Value>;
+    type Result = Vec<E::Result>;
+    type Error = E::Error;
+
+    async fn execute(&self, groups: Vec<Self::Value>) -> Result<Vec<Self::Result>, Self::Error> {
+        self.executor.execute(groups).await
+    }
+}
+
+impl<E> BatchExecutor<Grouped<E>>
+where
+    E: GroupedExecutor + Send + Sync + 'static,
+{
+    /// Create a new `BatchExecutor` that uses the given [`GroupedExecutor`]
+    /// to execute values, keeping the values from each caller in their own
+    /// group. Returns a [`BatchExecutorBuilder`], which can be used to
+    /// customize the `BatchExecutor`. See [`GroupedExecutor`] for an example.
+    pub fn build_grouped(executor: E) -> BatchExecutorBuilder<Grouped<E>> {
+        BatchExecutor::build(Grouped::new(executor))
+    }
+
+    /// Execute a group of values, which will be passed to the
+    /// [`GroupedExecutor`] together as one group. Returns the results for
+    /// the group, which follow the same rules as [`BatchExecutor::execute_many`].
+    pub async fn execute_group(
+        &self,
+        values: Vec<E::Value>,
+    ) -> Result<Vec<E::Result>, ExecuteError> {
+        let results = self.execute(values).await?;
+        Ok(results.unwrap_or_default())
+    }
+}
@@ -0,0 +1,126 @@
+//! Helpers for using [`BatchFetcher`]s with [`juniper`](::juniper). Requires
+//! the `juniper` feature.
+//!
+//! A [`LoaderRegistry`] can be used as a Juniper context directly, or a
+//! custom context type can implement [`LoaderContext`] to get
+//! [`BatchFetcher`]s from a [`LoaderRegistry`] it contains. [`LoadError`] and
+//! [`ExecuteError`] implement [`IntoFieldError`], so they can be returned
+//! from resolvers, and include a `code` extension describing the error.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ultra_batch::{BatchFetcher, Fetcher, Cache, LoaderRegistry};
+//! # use ultra_batch::juniper::LoaderContext;
+//! # use juniper::{FieldResult, IntoFieldError};
+//! # struct FetchUserNames;
+//! # impl Fetcher for FetchUserNames {
+//! #     type Key = u64;
+//! #     type Value = String;
+//! #     type Error = anyhow::Error;
+//! #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, String>) -> anyhow::Result<()> {
+//! #         for key in keys {
+//! #             values.insert(*key, format!("User {key}"));
+//! #         }
+//! #         Ok(())
+//! #     }
+//! # }
+//! struct RequestContext {
+//!     loaders: LoaderRegistry,
+//! }
+//!
+//! impl juniper::Context for RequestContext {}
+//!
+//! impl LoaderContext for RequestContext {
+//!     fn loaders(&self) -> &LoaderRegistry {
+//!         &self.loaders
+//!     }
+//! }
+//!
+//! async fn user_name(context: &RequestContext, id: u64) -> FieldResult<String> {
+//!     let user_names = context.batch_fetcher::<FetchUserNames>()?;
+//!     let user_name = user_names.load(id).await.map_err(IntoFieldError::into_field_error)?;
+//!     Ok(user_name)
+//! }
+//!
+//! # #[tokio::main] async fn main() -> anyhow::Result<()> {
+//! let mut loaders = LoaderRegistry::new();
+//! loaders.insert(BatchFetcher::build(FetchUserNames).finish());
+//! let context = RequestContext { loaders };
+//!
+//! assert_eq!(user_name(&context, 1).await.unwrap(), "User 1");
+//! # Ok(()) }
+//! ```
+
+use crate::{BatchFetcher, ExecuteError, Fetcher, LoadError, LoaderRegistry};
+use ::juniper::{
+    graphql_value, DefaultScalarValue, FieldError, FieldResult, IntoFieldError, ScalarValue, Value,
+};
+
+/// A Juniper context that contains a [`LoaderRegistry`]. `S` is the
+/// [`ScalarValue`] type used by the schema, which only needs to be set for
+/// schemas that use a custom scalar type.
+pub trait LoaderContext<S = DefaultScalarValue>: ::juniper::Context
+where
+    S: ScalarValue,
+{
+    /// Get the [`LoaderRegistry`] for the current request.
+    fn loaders(&self) -> &LoaderRegistry;
+
+    /// Get the [`BatchFetcher`] for the [`Fetcher`] type `F` from the
+    /// context's [`LoaderRegistry`]. Returns an error if the registry doesn't
+    /// contain a `BatchFetcher` for `F`.
+    fn batch_fetcher<F>(&self) -> FieldResult<&BatchFetcher<F>, S>
+    where
+        F: Fetcher + Send + Sync + 'static,
+    {
+        self.loaders().get::<F>().ok_or_else(|| {
+            FieldError::new(
+                format!(
+                    "BatchFetcher for `{}` does not exist",
+                    std::any::type_name::<F>()
+                ),
+                Value::null(),
+            )
+        })
+    }
+}
+
+impl ::juniper::Context for LoaderRegistry {}
+
+impl<S> LoaderContext<S> for LoaderRegistry
+where
+    S: ScalarValue,
+{
+    fn loaders(&self) -> &LoaderRegistry {
+        self
+    }
+}
+
+impl<S> IntoFieldError<S> for LoadError
+where
+    S: ScalarValue,
+{
+    fn into_field_error(self) -> FieldError<S> {
+        let extensions: Value<S> = match self {
+            LoadError::FetchError(_) => graphql_value!({ "code": "FETCH_ERROR" }),
+            LoadError::SendError => graphql_value!({ "code": "SEND_ERROR" }),
+            LoadError::NotFound => graphql_value!({ "code": "NOT_FOUND" }),
+            LoadError::InvalidKey(_) => graphql_value!({ "code": "INVALID_KEY" }),
+        };
+        FieldError::new(self, extensions)
+    }
+}
+
+impl<S> IntoFieldError<S> for ExecuteError
+where
+    S: ScalarValue,
+{
+    fn into_field_error(self) -> FieldError<S> {
+        let extensions: Value<S> = match self {
+            ExecuteError::ExecutorError(_) => graphql_value!({ "code": "EXECUTOR_ERROR" }),
+            ExecuteError::SendError => graphql_value!({ "code": "SEND_ERROR" }),
+        };
+        FieldError::new(self, extensions)
+    }
+}
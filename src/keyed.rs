@@ -0,0 +1,125 @@
+use crate::Executor;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::hash::Hash;
+
+/// A trait for executing a batch of values, returning results keyed by an
+/// ID for each value instead of by position. Wrap a `KeyedExecutor` with
+/// [`Keyed`] to use it with a [`BatchExecutor`](crate::BatchExecutor).
+///
+/// With a plain [`Executor`], each result is matched to a value by its
+/// index, so a missing result shifts every later result onto the wrong
+/// value (which is why a [`BatchExecutor`](crate::BatchExecutor) stops
+/// returning results after the first missing one). A `KeyedExecutor`
+/// instead returns a map from each value's key to its result, and each
+/// caller receives `Some(result)` if a result was returned for its
+/// value's key, or `None` otherwise.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchExecutor, Keyed, KeyedExecutor};
+/// # use std::collections::HashMap;
+/// # struct User { id: u64 }
+/// # struct DbConnection;
+/// # impl DbConnection {
+/// #     async fn insert_users(&self, users: &[User]) -> anyhow::Result<Vec<u64>> { Ok(vec![]) }
+/// # }
+/// struct UserInserter {
+///     db_conn: DbConnection,
+/// }
+///
+/// impl KeyedExecutor for UserInserter {
+///     type Key = u64;
+///     type Value = User;
+///     type Result = bool;
+///     type Error = anyhow::Error;
+///
+///     fn key(user: &User) -> u64 {
+///         user.id
+///     }
+///
+///     async fn execute(&self, users: Vec<User>) -> anyhow::Result<HashMap<u64, bool>> {
+///         // Only returns the IDs of users that were inserted
+///         let inserted_ids = self.db_conn.insert_users(&users).await?;
+///         Ok(inserted_ids.into_iter().map(|id| (id, true)).collect())
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// # let db_conn = DbConnection;
+/// let batch_inserter = BatchExecutor::build(Keyed::new(UserInserter { db_conn })).finish();
+/// let inserted = batch_inserter.execute(User { id: 1 }).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait KeyedExecutor {
+    /// The type used to match each result to its input value.
+    type Key: Hash + Eq + Send;
+
+    /// The input value provided by the caller to do something.
+    type Value: Send;
+
+    /// The output value returned by the executor back to the caller for each
+    /// input value. If the same key appears multiple times in a batch, each
+    /// caller receives a clone of the result.
+    type Result: Clone + Send;
+
+    /// The error indicating that executing a batch failed.
+    type Error: Display;
+
+    /// Get the key used to match a value to its result.
+    fn key(value: &Self::Value) -> Self::Key;
+
+    /// Execute the operation for each value in the batch, returning a map
+    /// containing the result for each value's key. Any values whose keys are
+    /// missing from the map will not receive a result. If `Err(_)` is
+    /// returned, then the caller waiting on the batch will receive an
+    /// [`ExecuteError::ExecutorError`](crate::ExecuteError::ExecutorError).
+    fn execute(
+        &self,
+        values: Vec<Self::Value>,
+    ) -> impl Future<Output = Result<HashMap<Self::Key, Self::Result>, Self::Error>> + Send;
+}
+
+/// Adapts a [`KeyedExecutor`] into an [`Executor`]. The adapted executor
+/// returns a result for every value: `Some(result)` if the [`KeyedExecutor`]
+/// returned a result for the value's key, or `None` otherwise.
+#[derive(Debug, Clone)]
+pub struct Keyed<E> {
+    executor: E,
+}
+
+impl<E> Keyed<E>
+where
+    E: KeyedExecutor,
+{
+    /// Wrap a [`KeyedExecutor`] so it can be used with a
+    /// [`BatchExecutor`](crate::BatchExecutor).
+    pub fn new(executor: E) -> Self {
+        Keyed { executor }
+    }
+
+    /// Get a reference to the wrapped [`KeyedExecutor`].
+    pub fn get_ref(&self) -> &E {
+        &self.executor
+    }
+}
+
+impl<E> Executor for Keyed<E>
+where
+    E: KeyedExecutor + Sync,
+{
+    type Value = E::Value;
+    type Result = Option<E::Result>;
+    type Error = E::Error;
+
+    async fn execute(&self, values: Vec<Self::Value>) -> Result<Vec<Self::Result>, Self::Error> {
+        let keys: Vec<_> = values.iter().map(E::key).collect();
+        let results = self.executor.execute(values).await?;
+
+        let results = keys.iter().map(|key| results.get(key).cloned()).collect();
+        Ok(results)
+    }
+}
@@ -9,14 +9,98 @@
 //! or more advanced query operations, see the [`BatchExecutor`] type and
 //! the [`Executor`] trait.
 
+#[cfg(feature = "async-graphql")]
+pub mod async_graphql;
+#[cfg(feature = "axum")]
+pub mod axum;
+pub(crate) mod batch_context;
 pub(crate) mod batch_executor;
 pub(crate) mod batch_fetcher;
+pub(crate) mod batch_key;
+#[cfg(feature = "boxed")]
+pub(crate) mod boxed;
 pub(crate) mod cache;
+pub(crate) mod combinators;
+pub(crate) mod context;
+#[cfg(feature = "diesel-async")]
+pub mod diesel_async;
+#[cfg(feature = "disk-cache")]
+pub mod disk_cache;
 pub(crate) mod executor;
 pub(crate) mod fetcher;
+pub(crate) mod grouped;
+#[cfg(feature = "juniper")]
+pub mod juniper;
+pub(crate) mod keyed;
+pub(crate) mod many_to_many;
+pub(crate) mod memoized;
+pub(crate) mod metrics;
+#[cfg(feature = "nats")]
+pub mod nats;
+pub(crate) mod ordered_map;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub(crate) mod registry;
+pub(crate) mod remote_cache;
+pub(crate) mod request_context;
+#[cfg(feature = "reqwest")]
+pub mod reqwest;
+pub(crate) mod runtime;
+pub(crate) mod scheduler;
+#[cfg(feature = "sea-orm")]
+pub mod sea_orm;
+pub(crate) mod shared_cache;
+#[cfg(feature = "sqlx-postgres")]
+pub mod sqlx;
+pub(crate) mod sync_batch_fetcher;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tonic")]
+pub mod tonic;
+#[cfg(feature = "tower")]
+pub mod tower;
+pub(crate) mod transactional;
+pub(crate) mod write_through;
 
-pub use batch_executor::{BatchExecutor, BatchExecutorBuilder, ExecuteError};
-pub use batch_fetcher::{BatchFetcher, BatchFetcherBuilder, LoadError};
-pub use cache::Cache;
-pub use executor::Executor;
-pub use fetcher::Fetcher;
+pub use batch_context::{BatchContext, ContextExecutor, WithContext};
+pub use batch_executor::{BatchExecutor, BatchExecutorBuilder, ExecuteError, PendingValues};
+pub use batch_fetcher::{
+    BatchFetcher, BatchFetcherBuilder, BatchFetcherState, BatchFetcherStats, BatchInfo,
+    InFlightBatchState, IntoKey, LoadError,
+};
+#[cfg(feature = "boxed")]
+pub use boxed::{Boxed, BoxedExecutor, BoxedFetcher};
+pub use cache::{Cache, CacheEntry, CacheEvent, CacheSnapshot};
+pub use combinators::{
+    ContramapKey, FallbackError, MapValue, ThenLoadError, ThenLoadWith, WithFallback,
+};
+pub use executor::{Executor, ExecutorLayer, FnExecutor, TryExecutor};
+#[cfg(feature = "tokio")]
+pub use fetcher::BlockingFetcher;
+pub use fetcher::{
+    Exclusive, ExclusiveFetcher, Fetcher, FnFetcher, LocalFetcher, MapFetcher, MapFetcherAdapter,
+    PairsFetcher, PairsFetcherAdapter, SharedFetcher, SharedFetcherAdapter, SyncFetcher,
+};
+pub use grouped::{Grouped, GroupedExecutor};
+pub use keyed::{Keyed, KeyedExecutor};
+pub use many_to_many::ManyToManyFetcher;
+pub use memoized::Memoized;
+pub use metrics::{BatchMetrics, CacheAccess, DispatchedBatch, FinishedBatch, QueueDepth};
+pub use registry::{LoaderFactory, LoaderRegistry};
+#[cfg(feature = "serde-json")]
+pub use remote_cache::JsonCodec;
+pub use remote_cache::{CacheCodec, RemoteCache, WithRemoteCache};
+pub use request_context::{RequestContextFetcher, WithRequestContext};
+#[cfg(feature = "tokio")]
+pub use runtime::TokioRuntime;
+#[cfg(feature = "wasm")]
+pub use runtime::WasmRuntime;
+pub use runtime::{BatchDriver, Spawner, Timer};
+pub use scheduler::{
+    AdaptiveBatchScheduler, BatchScheduler, CompletedBatch, DefaultBatchScheduler, PendingBatch,
+    Schedule,
+};
+pub use shared_cache::{SharedCache, WithSharedCache};
+pub use sync_batch_fetcher::{SyncBatchFetcher, SyncBatchFetcherBuilder};
+pub use transactional::{Transactional, TransactionalExecutor};
+pub use write_through::WriteThrough;
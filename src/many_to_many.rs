@@ -0,0 +1,150 @@
+use crate::{BatchFetcher, Cache, Fetcher, LoadError};
+
+/// A [`Fetcher`] for many-to-many associations, which loads a `Vec` of
+/// related values for each key by composing two [`BatchFetcher`]s: one that
+/// loads the IDs associated with each key (such as from a join table), and
+/// one that loads the value for each of those IDs.
+///
+/// Both hops are batched: each batch of keys loads all of its associations
+/// with a single call to [`load_many_map`](BatchFetcher::load_many_map), then
+/// loads every associated value with another. Since each hop goes through its
+/// own `BatchFetcher`, they can also share batches and caches with other parts
+/// of an application.
+///
+/// Keys with no associations (i.e. keys not found by the associations
+/// fetcher) are marked as "not found". Associated IDs whose values aren't
+/// found are left out of the `Vec` for each key. If either hop fails to
+/// fetch, then callers waiting on the batch will receive a
+/// [`LoadError::FetchError`].
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, Cache, Fetcher, ManyToManyFetcher};
+/// # #[derive(Clone)] struct Group { id: u64 }
+/// # struct DbConnection;
+/// # impl DbConnection {
+/// #     async fn get_group_ids_for_users(&self, user_ids: &[u64]) -> anyhow::Result<Vec<(u64, Vec<u64>)>> { Ok(vec![]) }
+/// #     async fn get_groups_by_ids(&self, group_ids: &[u64]) -> anyhow::Result<Vec<Group>> { Ok(vec![]) }
+/// # }
+/// struct UserGroupIdsFetcher {
+///     db_conn: DbConnection,
+/// }
+///
+/// impl Fetcher for UserGroupIdsFetcher {
+///     type Key = u64;
+///     type Value = Vec<u64>;
+///     type Error = anyhow::Error;
+///
+///     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, Vec<u64>>) -> anyhow::Result<()> {
+///         let group_ids = self.db_conn.get_group_ids_for_users(keys).await?;
+///         for (user_id, group_ids) in group_ids {
+///             values.insert(user_id, group_ids);
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// struct GroupFetcher {
+///     db_conn: DbConnection,
+/// }
+///
+/// impl Fetcher for GroupFetcher {
+///     type Key = u64;
+///     type Value = Group;
+///     type Error = anyhow::Error;
+///
+///     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, Group>) -> anyhow::Result<()> {
+///         let groups = self.db_conn.get_groups_by_ids(keys).await?;
+///         for group in groups {
+///             values.insert(group.id, group);
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let user_group_ids = BatchFetcher::build(UserGroupIdsFetcher { db_conn: DbConnection }).finish();
+/// let groups = BatchFetcher::build(GroupFetcher { db_conn: DbConnection }).finish();
+///
+/// let user_groups = BatchFetcher::build(ManyToManyFetcher::new(user_group_ids, groups)).finish();
+/// # Ok(()) }
+/// ```
+pub struct ManyToManyFetcher<A, B>
+where
+    A: Fetcher<Value = Vec<B::Key>> + Send + Sync + 'static,
+    B: Fetcher + Send + Sync + 'static,
+{
+    associations: BatchFetcher<A>,
+    values: BatchFetcher<B>,
+}
+
+impl<A, B> ManyToManyFetcher<A, B>
+where
+    A: Fetcher<Value = Vec<B::Key>> + Send + Sync + 'static,
+    B: Fetcher + Send + Sync + 'static,
+{
+    /// Create a new `ManyToManyFetcher`, which uses `associations` to load
+    /// the IDs associated with each key, then uses `values` to load the value
+    /// for each ID.
+    pub fn new(associations: BatchFetcher<A>, values: BatchFetcher<B>) -> Self {
+        ManyToManyFetcher {
+            associations,
+            values,
+        }
+    }
+
+    /// Get a reference to the [`BatchFetcher`] used to load associations.
+    pub fn associations(&self) -> &BatchFetcher<A> {
+        &self.associations
+    }
+
+    /// Get a reference to the [`BatchFetcher`] used to load associated values.
+    pub fn values(&self) -> &BatchFetcher<B> {
+        &self.values
+    }
+}
+
+impl<A, B> Clone for ManyToManyFetcher<A, B>
+where
+    A: Fetcher<Value = Vec<B::Key>> + Send + Sync + 'static,
+    B: Fetcher + Send + Sync + 'static,
+{
+    fn clone(&self) -> Self {
+        ManyToManyFetcher {
+            associations: self.associations.clone(),
+            values: self.values.clone(),
+        }
+    }
+}
+
+impl<A, B> Fetcher for ManyToManyFetcher<A, B>
+where
+    A: Fetcher<Value = Vec<B::Key>> + Send + Sync + 'static,
+    B: Fetcher + Send + Sync + 'static,
+{
+    type Key = A::Key;
+    type Value = Vec<B::Value>;
+    type Error = LoadError;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let associations = self.associations.load_many_map(keys).await?;
+
+        let related_ids = associations.values().flatten();
+        let related_values = self.values.load_many_map(related_ids).await?;
+
+        for (key, related_ids) in associations {
+            let key_values = related_ids
+                .iter()
+                .filter_map(|id| related_values.get(id).cloned())
+                .collect();
+            values.insert(key, key_values);
+        }
+
+        Ok(())
+    }
+}
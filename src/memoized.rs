@@ -0,0 +1,195 @@
+use crate::cache::lock;
+use crate::runtime::Instant;
+use crate::{ExecutorLayer, TryExecutor};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A [`TryExecutor`] that remembers the result for each value, so values
+/// submitted again within a window return the remembered result instead of
+/// being executed again. Created with [`BatchExecutorBuilder::memoize_results`](crate::BatchExecutorBuilder::memoize_results).
+///
+/// Values are matched by the key returned by the key function, so
+/// different values can share a result if they represent the same logical
+/// operation. Values that share a key within one batch are only executed
+/// once. Only successful results are remembered.
+pub struct Memoized<E, K>
+where
+    E: TryExecutor,
+{
+    executor: E,
+    key: MemoKeyFn<E::Value, K>,
+    ttl: Duration,
+    results: Mutex<HashMap<K, (Instant, E::Result)>>,
+}
+
+type MemoKeyFn<V, K> = Arc<dyn Fn(&V) -> K + Send + Sync>;
+
+impl<E, K> Memoized<E, K>
+where
+    E: TryExecutor,
+    K: Hash + Eq,
+{
+    /// Get a reference to the wrapped executor.
+    pub fn get_ref(&self) -> &E {
+        &self.executor
+    }
+
+    fn lock_results(&self) -> std::sync::MutexGuard<'_, HashMap<K, (Instant, E::Result)>> {
+        lock(&self.results)
+    }
+}
+
+impl<E, K> TryExecutor for Memoized<E, K>
+where
+    E: TryExecutor + Sync,
+    E::Result: Clone,
+    E::Error: Send,
+    K: Hash + Eq + Send,
+{
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+
+    async fn try_execute(
+        &self,
+        values: Vec<Self::Value>,
+    ) -> Result<Vec<Result<Self::Result, Self::Error>>, Self::Error> {
+        let keys: Vec<K> = values.iter().map(|value| (self.key)(value)).collect();
+
+        // Values with the same key in one batch are only executed once
+        let mut missed_values = vec![];
+        let mut duplicate_values = vec![];
+        let mut sources: Vec<ResultSource<E::Result>> = {
+            let memoized_results = self.lock_results();
+            let mut missed_indices = HashMap::new();
+            values
+                .into_iter()
+                .zip(&keys)
+                .map(|(value, key)| {
+                    if let Some((memoized_at, result)) = memoized_results.get(key) {
+                        if memoized_at.elapsed() < self.ttl {
+                            return ResultSource::Memoized(result.clone());
+                        }
+                    }
+
+                    match missed_indices.entry(key) {
+                        Entry::Occupied(entry) => {
+                            duplicate_values.push(Some(value));
+                            ResultSource::Duplicate {
+                                missed: *entry.get(),
+                                duplicate: duplicate_values.len() - 1,
+                            }
+                        }
+                        Entry::Vacant(entry) => {
+                            entry.insert(missed_values.len());
+                            missed_values.push(value);
+                            ResultSource::Missed(missed_values.len() - 1)
+                        }
+                    }
+                })
+                .collect()
+        };
+
+        let mut missed_results: Vec<_> = if missed_values.is_empty() {
+            vec![]
+        } else {
+            tracing::trace!(
+                num_memoized = sources.len() - missed_values.len() - duplicate_values.len(),
+                num_missed = missed_values.len(),
+                num_duplicates = duplicate_values.len(),
+                "executing values without a memoized result",
+            );
+            self.executor.try_execute(missed_values).await?
+        }
+        .into_iter()
+        .map(Some)
+        .collect();
+
+        // Errors can't be cloned, so duplicates of a value that failed are
+        // executed again to get their own result
+        let mut retried_values = vec![];
+        for source in &mut sources {
+            if let ResultSource::Duplicate { missed, duplicate } = *source {
+                if let Some(Some(Err(_))) = missed_results.get(missed) {
+                    retried_values.extend(duplicate_values[duplicate].take());
+                    *source = ResultSource::Retried(retried_values.len() - 1);
+                }
+            }
+        }
+        let mut retried_results: Vec<_> = if retried_values.is_empty() {
+            vec![]
+        } else {
+            self.executor.try_execute(retried_values).await?
+        }
+        .into_iter()
+        .map(Some)
+        .collect();
+
+        let now = Instant::now();
+        let mut memoized_results = self.lock_results();
+        memoized_results.retain(|_, (memoized_at, _)| memoized_at.elapsed() < self.ttl);
+
+        // Results are matched to values by position, so stop at the first
+        // value that the executor didn't return a result for
+        let results = keys
+            .into_iter()
+            .zip(sources)
+            .map_while(|(key, source)| {
+                let result = match source {
+                    ResultSource::Memoized(result) => return Some(Ok(result)),
+                    ResultSource::Missed(missed) | ResultSource::Duplicate { missed, .. } => {
+                        match missed_results.get_mut(missed)? {
+                            Some(Ok(result)) => Ok(result.clone()),
+                            result => result.take()?,
+                        }
+                    }
+                    ResultSource::Retried(retried) => retried_results.get_mut(retried)?.take()?,
+                };
+                if let Ok(result) = &result {
+                    memoized_results.insert(key, (now, result.clone()));
+                }
+                Some(result)
+            })
+            .collect();
+        Ok(results)
+    }
+}
+
+/// Where [`Memoized`] gets the result for each value in a batch.
+enum ResultSource<R> {
+    /// A remembered result that hasn't expired.
+    Memoized(R),
+    /// The result at this index from executing the missed values.
+    Missed(usize),
+    /// A value with the same key as the missed value at index `missed`,
+    /// which shares its result.
+    Duplicate { missed: usize, duplicate: usize },
+    /// A duplicate of a value that failed, which was executed again to get
+    /// its own result.
+    Retried(usize),
+}
+
+/// The [`ExecutorLayer`] used by [`BatchExecutorBuilder::memoize_results`](crate::BatchExecutorBuilder::memoize_results).
+pub(crate) struct MemoizeLayer<V, K> {
+    pub(crate) key: MemoKeyFn<V, K>,
+    pub(crate) ttl: Duration,
+}
+
+impl<E, K> ExecutorLayer<E> for MemoizeLayer<E::Value, K>
+where
+    E: TryExecutor,
+{
+    type Executor = Memoized<E, K>;
+
+    fn layer(&self, executor: E) -> Memoized<E, K> {
+        Memoized {
+            executor,
+            key: self.key.clone(),
+            ttl: self.ttl,
+            results: Mutex::new(HashMap::new()),
+        }
+    }
+}
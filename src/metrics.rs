@@ -0,0 +1,175 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Hooks for collecting metrics from a [`BatchFetcher`](crate::BatchFetcher)
+/// or [`BatchExecutor`](crate::BatchExecutor), such as to report batch sizes
+/// and latencies to an application's own telemetry. Set with
+/// [`BatchFetcherBuilder::metrics`](crate::BatchFetcherBuilder::metrics) or
+/// [`BatchExecutorBuilder::metrics`](crate::BatchExecutorBuilder::metrics).
+///
+/// Every method does nothing by default, so implementors only need to
+/// override the hooks they're interested in. Hooks are called from the
+/// background tasks and from callers loading values, so they should return
+/// quickly.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, BatchMetrics, DispatchedBatch};
+/// # use std::collections::HashMap;
+/// # use std::sync::atomic::{AtomicUsize, Ordering};
+/// # use std::sync::Arc;
+/// #[derive(Default)]
+/// struct CountBatches {
+///     num_batches: AtomicUsize,
+/// }
+///
+/// impl BatchMetrics for CountBatches {
+///     fn on_batch_dispatched(&self, _batch: &DispatchedBatch) {
+///         self.num_batches.fetch_add(1, Ordering::Relaxed);
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let metrics = Arc::new(CountBatches::default());
+/// let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+///     anyhow::Ok(ids.into_iter().map(|id| (id, id)).collect::<HashMap<_, _>>())
+/// })
+/// .metrics(metrics.clone())
+/// .finish();
+///
+/// batch_fetcher.load_many(&[1, 2, 3]).await?;
+/// assert_eq!(metrics.num_batches.load(Ordering::Relaxed), 1);
+/// # Ok(()) }
+/// ```
+pub trait BatchMetrics: Send + Sync {
+    /// Called when a batch is dispatched to the [`Fetcher`](crate::Fetcher)
+    /// or [`Executor`](crate::Executor).
+    fn on_batch_dispatched(&self, batch: &DispatchedBatch) {
+        let _ = batch;
+    }
+
+    /// Called after a dispatched batch has finished, whether or not it
+    /// succeeded.
+    fn on_batch_completed(&self, batch: &FinishedBatch) {
+        let _ = batch;
+    }
+
+    /// Called when keys passed to a [`BatchFetcher`](crate::BatchFetcher)
+    /// were already in its cache (including keys cached as "not found").
+    fn on_cache_hit(&self, access: &CacheAccess) {
+        let _ = access;
+    }
+
+    /// Called when keys passed to a [`BatchFetcher`](crate::BatchFetcher)
+    /// weren't in its cache, and need to be fetched.
+    fn on_cache_miss(&self, access: &CacheAccess) {
+        let _ = access;
+    }
+
+    /// Called when the number of items queued for the next batch changes,
+    /// including when the queue is emptied because a batch was dispatched.
+    fn on_queue_depth(&self, queue: &QueueDepth) {
+        let _ = queue;
+    }
+}
+
+impl<M> BatchMetrics for Arc<M>
+where
+    M: BatchMetrics + ?Sized,
+{
+    fn on_batch_dispatched(&self, batch: &DispatchedBatch) {
+        (**self).on_batch_dispatched(batch)
+    }
+
+    fn on_batch_completed(&self, batch: &FinishedBatch) {
+        (**self).on_batch_completed(batch)
+    }
+
+    fn on_cache_hit(&self, access: &CacheAccess) {
+        (**self).on_cache_hit(access)
+    }
+
+    fn on_cache_miss(&self, access: &CacheAccess) {
+        (**self).on_cache_miss(access)
+    }
+
+    fn on_queue_depth(&self, queue: &QueueDepth) {
+        (**self).on_queue_depth(queue)
+    }
+}
+
+/// Details about a batch that was just dispatched, passed to
+/// [`BatchMetrics::on_batch_dispatched`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DispatchedBatch<'a> {
+    /// The label of the [`BatchFetcher`](crate::BatchFetcher) or
+    /// [`BatchExecutor`](crate::BatchExecutor).
+    pub label: &'a str,
+
+    /// The number of items (keys for a [`BatchFetcher`](crate::BatchFetcher),
+    /// or values for a [`BatchExecutor`](crate::BatchExecutor)) in the batch.
+    pub size: usize,
+
+    /// The number of separate requests (calls to `load`, `execute`, etc.)
+    /// waiting on the batch.
+    pub waiters: usize,
+}
+
+/// Details about a batch that has finished, passed to
+/// [`BatchMetrics::on_batch_completed`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FinishedBatch<'a> {
+    /// The label of the [`BatchFetcher`](crate::BatchFetcher) or
+    /// [`BatchExecutor`](crate::BatchExecutor).
+    pub label: &'a str,
+
+    /// The number of items in the batch.
+    pub size: usize,
+
+    /// How long the [`Fetcher`](crate::Fetcher) or
+    /// [`Executor`](crate::Executor) took to process the batch.
+    pub duration: Duration,
+
+    /// Whether the batch succeeded, or the error message if it failed.
+    pub result: Result<(), &'a str>,
+}
+
+/// Details about a cache lookup, passed to [`BatchMetrics::on_cache_hit`]
+/// and [`BatchMetrics::on_cache_miss`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CacheAccess<'a> {
+    /// The label of the [`BatchFetcher`](crate::BatchFetcher).
+    pub label: &'a str,
+
+    /// The number of keys that were looked up.
+    pub num_keys: usize,
+}
+
+/// The number of items waiting for the next batch, passed to
+/// [`BatchMetrics::on_queue_depth`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct QueueDepth<'a> {
+    /// The label of the [`BatchFetcher`](crate::BatchFetcher) or
+    /// [`BatchExecutor`](crate::BatchExecutor).
+    pub label: &'a str,
+
+    /// The number of items (unique keys for a
+    /// [`BatchFetcher`](crate::BatchFetcher), or values for a
+    /// [`BatchExecutor`](crate::BatchExecutor)) queued for the next batch.
+    pub depth: usize,
+}
+
+pub(crate) fn record_queue_depth(
+    metrics: &Option<Arc<dyn BatchMetrics>>,
+    label: &str,
+    depth: usize,
+) {
+    if let Some(metrics) = metrics {
+        metrics.on_queue_depth(&QueueDepth { label, depth });
+    }
+}
@@ -0,0 +1,93 @@
+//! An [`Executor`](crate::Executor) for publishing batches of messages to
+//! [NATS](::async_nats). Requires the `nats` feature.
+
+use crate::TryExecutor;
+use ::async_nats::client::FlushError;
+use ::async_nats::{Client, PublishError};
+
+/// A message to publish with a [`NatsPublisher`].
+#[derive(Debug, Clone)]
+pub struct NatsMessage {
+    /// The subject to publish the message to.
+    pub subject: String,
+
+    /// The message payload.
+    pub payload: Vec<u8>,
+}
+
+/// A [`TryExecutor`] that publishes each batch of messages to NATS, then
+/// flushes the client once for the whole batch. Each caller receives `()`
+/// once its message has been published and flushed, or an
+/// [`ExecuteError::ExecutorError`](crate::ExecuteError::ExecutorError) if
+/// publishing its message failed. If flushing fails, every caller waiting
+/// on the batch receives an error.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use ultra_batch::BatchExecutor;
+/// # use ultra_batch::nats::{NatsMessage, NatsPublisher};
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let client = async_nats::connect("localhost:4222").await?;
+/// let batch_publisher = BatchExecutor::build(NatsPublisher::new(client)).finish();
+///
+/// batch_publisher
+///     .execute(NatsMessage {
+///         subject: "events.user_created".to_string(),
+///         payload: b"{\"id\":1}".to_vec(),
+///     })
+///     .await?;
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct NatsPublisher {
+    client: Client,
+}
+
+impl NatsPublisher {
+    /// Create a new `NatsPublisher` that publishes messages using `client`.
+    pub fn new(client: Client) -> Self {
+        NatsPublisher { client }
+    }
+
+    /// Get a reference to the NATS client.
+    pub fn get_ref(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl TryExecutor for NatsPublisher {
+    type Value = NatsMessage;
+    type Result = ();
+    type Error = NatsPublishError;
+
+    async fn try_execute(
+        &self,
+        values: Vec<NatsMessage>,
+    ) -> Result<Vec<Result<(), NatsPublishError>>, NatsPublishError> {
+        let mut results = Vec::with_capacity(values.len());
+        for message in values {
+            let result = self
+                .client
+                .publish(message.subject, message.payload.into())
+                .await
+                .map_err(NatsPublishError::Publish);
+            results.push(result);
+        }
+
+        self.client.flush().await?;
+        Ok(results)
+    }
+}
+
+/// The error returned by a [`NatsPublisher`].
+#[derive(Debug, thiserror::Error)]
+pub enum NatsPublishError {
+    /// Publishing a message failed.
+    #[error("error publishing message: {0}")]
+    Publish(PublishError),
+
+    /// Flushing the batch of messages failed.
+    #[error("error flushing messages: {0}")]
+    Flush(#[from] FlushError),
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// A map that keeps its entries in the order their keys were first
+/// inserted. Used for queueing keys, so a [`Fetcher`](crate::Fetcher) sees
+/// keys in a stable order.
+pub(crate) struct OrderedMap<K, V> {
+    indices: HashMap<K, usize>,
+    entries: Vec<(K, V)>,
+}
+
+impl<K, V> OrderedMap<K, V>
+where
+    K: Clone + Hash + Eq,
+{
+    /// Get the value for `key`, inserting the default value at the end if
+    /// the key isn't in the map yet.
+    pub(crate) fn get_or_default(&mut self, key: K) -> &mut V
+    where
+        V: Default,
+    {
+        let index = match self.indices.get(&key) {
+            Some(&index) => index,
+            None => {
+                let index = self.entries.len();
+                self.indices.insert(key.clone(), index);
+                self.entries.push((key, V::default()));
+                index
+            }
+        };
+        &mut self.entries[index].1
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.iter().map(|(key, _)| key)
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.iter().map(|(_, value)| value)
+    }
+
+    /// Keep only the entries where `keep` returns `true`, preserving their
+    /// order.
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&K, &mut V) -> bool) {
+        let len = self.entries.len();
+        self.entries.retain_mut(|(key, value)| keep(key, value));
+        if self.entries.len() != len {
+            self.indices.clear();
+            for (index, (key, _)) in self.entries.iter().enumerate() {
+                self.indices.insert(key.clone(), index);
+            }
+        }
+    }
+}
+
+impl<K, V> Default for OrderedMap<K, V> {
+    fn default() -> Self {
+        OrderedMap {
+            indices: HashMap::new(),
+            entries: vec![],
+        }
+    }
+}
+
+impl<K, V> IntoIterator for OrderedMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = std::vec::IntoIter<(K, V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
@@ -0,0 +1,156 @@
+//! A [`BatchMetrics`](crate::BatchMetrics) implementation that exports
+//! metrics to [Prometheus](::prometheus). Requires the `prometheus` feature.
+
+use crate::{BatchMetrics, CacheAccess, DispatchedBatch, FinishedBatch, QueueDepth};
+use ::prometheus::{
+    exponential_buckets, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+};
+
+/// [`BatchMetrics`] that record Prometheus counters, gauges, and histograms
+/// for each [`BatchFetcher`](crate::BatchFetcher) or
+/// [`BatchExecutor`](crate::BatchExecutor), using its label as the `label`
+/// metric label. Create one `PrometheusMetrics` per registry, then pass a
+/// clone of it to each builder.
+///
+/// The following metrics are registered:
+///
+/// - `ultra_batch_batches_total`: The number of batches dispatched.
+/// - `ultra_batch_batch_size`: A histogram of the number of items in each
+///   batch.
+/// - `ultra_batch_batch_duration_seconds`: A histogram of how long each
+///   batch took, with a `result` label of either `ok` or `error`.
+/// - `ultra_batch_cache_hits_total` and `ultra_batch_cache_misses_total`:
+///   The number of keys found or not found in a
+///   [`BatchFetcher`](crate::BatchFetcher)'s cache. The cache hit ratio can
+///   be computed from these in a query.
+/// - `ultra_batch_queue_depth`: The number of items currently queued for
+///   the next batch.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::BatchFetcher;
+/// # use ultra_batch::prometheus::PrometheusMetrics;
+/// # use std::collections::HashMap;
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let registry = prometheus::Registry::new();
+/// let metrics = PrometheusMetrics::new(&registry)?;
+///
+/// let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+///     anyhow::Ok(ids.into_iter().map(|id| (id, id)).collect::<HashMap<_, _>>())
+/// })
+/// .label("users")
+/// .metrics(metrics.clone())
+/// .finish();
+///
+/// batch_fetcher.load_many(&[1, 2, 3]).await?;
+/// assert!(!registry.gather().is_empty());
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PrometheusMetrics {
+    batches: IntCounterVec,
+    batch_size: HistogramVec,
+    batch_duration: HistogramVec,
+    cache_hits: IntCounterVec,
+    cache_misses: IntCounterVec,
+    queue_depth: IntGaugeVec,
+}
+
+impl PrometheusMetrics {
+    /// Create the metrics and register them with `registry`. Returns an
+    /// error if the metrics were already registered, such as by another
+    /// `PrometheusMetrics` using the same registry.
+    pub fn new(registry: &Registry) -> ::prometheus::Result<Self> {
+        let batches = IntCounterVec::new(
+            Opts::new("ultra_batch_batches_total", "Number of batches dispatched"),
+            &["label"],
+        )?;
+        let batch_size = HistogramVec::new(
+            HistogramOpts::new("ultra_batch_batch_size", "Number of items in each batch")
+                .buckets(exponential_buckets(1.0, 2.0, 12)?),
+            &["label"],
+        )?;
+        let batch_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "ultra_batch_batch_duration_seconds",
+                "Time taken to process each batch",
+            ),
+            &["label", "result"],
+        )?;
+        let cache_hits = IntCounterVec::new(
+            Opts::new(
+                "ultra_batch_cache_hits_total",
+                "Number of keys found in the cache",
+            ),
+            &["label"],
+        )?;
+        let cache_misses = IntCounterVec::new(
+            Opts::new(
+                "ultra_batch_cache_misses_total",
+                "Number of keys not found in the cache",
+            ),
+            &["label"],
+        )?;
+        let queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "ultra_batch_queue_depth",
+                "Number of items queued for the next batch",
+            ),
+            &["label"],
+        )?;
+
+        registry.register(Box::new(batches.clone()))?;
+        registry.register(Box::new(batch_size.clone()))?;
+        registry.register(Box::new(batch_duration.clone()))?;
+        registry.register(Box::new(cache_hits.clone()))?;
+        registry.register(Box::new(cache_misses.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+
+        Ok(PrometheusMetrics {
+            batches,
+            batch_size,
+            batch_duration,
+            cache_hits,
+            cache_misses,
+            queue_depth,
+        })
+    }
+}
+
+impl BatchMetrics for PrometheusMetrics {
+    fn on_batch_dispatched(&self, batch: &DispatchedBatch) {
+        self.batches.with_label_values(&[batch.label]).inc();
+        self.batch_size
+            .with_label_values(&[batch.label])
+            .observe(batch.size as f64);
+    }
+
+    fn on_batch_completed(&self, batch: &FinishedBatch) {
+        let result = match batch.result {
+            Ok(()) => "ok",
+            Err(_) => "error",
+        };
+        self.batch_duration
+            .with_label_values(&[batch.label, result])
+            .observe(batch.duration.as_secs_f64());
+    }
+
+    fn on_cache_hit(&self, access: &CacheAccess) {
+        self.cache_hits
+            .with_label_values(&[access.label])
+            .inc_by(access.num_keys as u64);
+    }
+
+    fn on_cache_miss(&self, access: &CacheAccess) {
+        self.cache_misses
+            .with_label_values(&[access.label])
+            .inc_by(access.num_keys as u64);
+    }
+
+    fn on_queue_depth(&self, queue: &QueueDepth) {
+        self.queue_depth
+            .with_label_values(&[queue.label])
+            .set(queue.depth as i64);
+    }
+}
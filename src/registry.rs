@@ -0,0 +1,309 @@
+use crate::scheduler::BatchDelay;
+use crate::{BatchFetcher, BatchFetcherBuilder, BatchMetrics, Fetcher, Spawner, Timer};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Stores [`BatchFetcher`]s keyed by the type of their [`Fetcher`], so a
+/// context object (such as a GraphQL context) can hold any number of
+/// loaders without a separate field for each one.
+///
+/// A registry holds at most one `BatchFetcher` for each `Fetcher` type.
+/// Like the `BatchFetcher`s it holds, a `LoaderRegistry` should usually be
+/// created for each request.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, Fetcher, Cache, LoaderRegistry};
+/// # struct FetchUsers;
+/// # impl Fetcher for FetchUsers {
+/// #     type Key = ();
+/// #     type Value = ();
+/// #     type Error = anyhow::Error;
+/// #     async fn fetch(&self, keys: &[()], values: &mut Cache<'_, (), ()>) -> anyhow::Result<()> {
+/// #         unimplemented!();
+/// #     }
+/// # }
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let mut registry = LoaderRegistry::new();
+/// registry.insert(BatchFetcher::build(FetchUsers).finish());
+///
+/// let user_loader = registry.get::<FetchUsers>().expect("user loader not registered");
+/// # Ok(()) }
+/// ```
+#[derive(Default)]
+pub struct LoaderRegistry {
+    loaders: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl LoaderRegistry {
+    /// Create a new, empty `LoaderRegistry`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a [`BatchFetcher`] to the registry. If the registry already held
+    /// a `BatchFetcher` for the same [`Fetcher`] type, it is replaced, and
+    /// the old one is returned.
+    pub fn insert<F>(&mut self, batch_fetcher: BatchFetcher<F>) -> Option<BatchFetcher<F>>
+    where
+        F: Fetcher + Send + Sync + 'static,
+    {
+        let previous = self
+            .loaders
+            .insert(TypeId::of::<F>(), Box::new(batch_fetcher))?;
+        previous.downcast().ok().map(|previous| *previous)
+    }
+
+    /// Get the [`BatchFetcher`] for the [`Fetcher`] type `F`, or `None` if
+    /// one hasn't been added to the registry.
+    pub fn get<F>(&self) -> Option<&BatchFetcher<F>>
+    where
+        F: Fetcher + Send + Sync + 'static,
+    {
+        let loader = self.loaders.get(&TypeId::of::<F>())?;
+        loader.downcast_ref()
+    }
+
+    /// Remove and return the [`BatchFetcher`] for the [`Fetcher`] type `F`,
+    /// if one was added to the registry.
+    pub fn remove<F>(&mut self) -> Option<BatchFetcher<F>>
+    where
+        F: Fetcher + Send + Sync + 'static,
+    {
+        let loader = self.loaders.remove(&TypeId::of::<F>())?;
+        loader.downcast().ok().map(|loader| *loader)
+    }
+
+    /// Returns the number of [`BatchFetcher`]s in the registry.
+    pub fn len(&self) -> usize {
+        self.loaders.len()
+    }
+
+    /// Returns `true` if the registry doesn't contain any [`BatchFetcher`]s.
+    pub fn is_empty(&self) -> bool {
+        self.loaders.is_empty()
+    }
+}
+
+impl std::fmt::Debug for LoaderRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoaderRegistry")
+            .field("len", &self.loaders.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Holds shared options and [`Fetcher`] constructors, and creates a fresh
+/// set of [`BatchFetcher`]s for each request. Since each `BatchFetcher`
+/// keeps its own cache, it's recommended to create new `BatchFetcher`s for
+/// each request (see the [`BatchFetcher`] docs). A `LoaderFactory` lets the
+/// options be set up once, such as when an application starts.
+///
+/// Each option is only applied if it was set on the factory, so any
+/// options that weren't set use the same defaults as [`BatchFetcher::build`].
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, Fetcher, Cache, LoaderFactory};
+/// # use std::sync::Arc;
+/// # struct DbPool;
+/// # struct FetchUsers { db_pool: Arc<DbPool> }
+/// # impl Fetcher for FetchUsers {
+/// #     type Key = ();
+/// #     type Value = ();
+/// #     type Error = anyhow::Error;
+/// #     async fn fetch(&self, keys: &[()], values: &mut Cache<'_, (), ()>) -> anyhow::Result<()> {
+/// #         unimplemented!();
+/// #     }
+/// # }
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let db_pool = Arc::new(DbPool);
+/// let factory = LoaderFactory::new()
+///     .eager_batch_size(Some(50))
+///     .delay_duration(tokio::time::Duration::from_millis(5))
+///     .fetcher(move || FetchUsers { db_pool: db_pool.clone() });
+///
+/// // For each request:
+/// let loaders = factory.create();
+/// let user_loader = loaders.get::<FetchUsers>().unwrap();
+/// # Ok(()) }
+/// ```
+#[derive(Clone, Default)]
+pub struct LoaderFactory {
+    delay: Option<BatchDelay>,
+    eager_batch_size: Option<Option<usize>>,
+    max_batch_size: Option<Option<usize>>,
+    max_concurrent_batches: Option<usize>,
+    spawner: Option<Arc<dyn Spawner>>,
+    timer: Option<Arc<dyn Timer>>,
+    metrics: Option<Arc<dyn BatchMetrics>>,
+    slow_batch_threshold: Option<Duration>,
+    constructors: Vec<LoaderConstructor>,
+}
+
+type LoaderConstructor = Arc<dyn Fn(&LoaderFactory, &mut LoaderRegistry) + Send + Sync>;
+
+impl LoaderFactory {
+    /// Create a new `LoaderFactory` with no [`Fetcher`] constructors.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a constructor for a [`Fetcher`]. Each call to
+    /// [`create`](LoaderFactory::create) calls `make_fetcher` and adds a
+    /// new [`BatchFetcher`] for it to the returned registry.
+    pub fn fetcher<F>(mut self, make_fetcher: impl Fn() -> F + Send + Sync + 'static) -> Self
+    where
+        F: Fetcher + Send + Sync + 'static,
+    {
+        self.constructors.push(Arc::new(move |factory, registry| {
+            registry.insert(factory.build(make_fetcher()).finish());
+        }));
+        self
+    }
+
+    /// Set the [`delay_duration`](BatchFetcherBuilder::delay_duration) for
+    /// each [`BatchFetcher`].
+    pub fn delay_duration(mut self, delay: Duration) -> Self {
+        self.delay = Some(BatchDelay::Duration(delay));
+        self
+    }
+
+    /// Use [`dispatch_on_next_tick`](BatchFetcherBuilder::dispatch_on_next_tick)
+    /// for each [`BatchFetcher`].
+    pub fn dispatch_on_next_tick(mut self) -> Self {
+        self.delay = Some(BatchDelay::NextTick);
+        self
+    }
+
+    /// Set the [`eager_batch_size`](BatchFetcherBuilder::eager_batch_size)
+    /// for each [`BatchFetcher`].
+    pub fn eager_batch_size(mut self, eager_batch_size: Option<usize>) -> Self {
+        self.eager_batch_size = Some(eager_batch_size);
+        self
+    }
+
+    /// Set the [`max_batch_size`](BatchFetcherBuilder::max_batch_size) for
+    /// each [`BatchFetcher`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_batch_size` is `Some(0)`.
+    pub fn max_batch_size(mut self, max_batch_size: Option<usize>) -> Self {
+        assert_ne!(max_batch_size, Some(0), "max_batch_size must be non-zero");
+        self.max_batch_size = Some(max_batch_size);
+        self
+    }
+
+    /// Set the [`max_concurrent_batches`](BatchFetcherBuilder::max_concurrent_batches)
+    /// for each [`BatchFetcher`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_concurrent_batches` is 0.
+    pub fn max_concurrent_batches(mut self, max_concurrent_batches: usize) -> Self {
+        assert_ne!(
+            max_concurrent_batches, 0,
+            "max_concurrent_batches must be non-zero"
+        );
+        self.max_concurrent_batches = Some(max_concurrent_batches);
+        self
+    }
+
+    /// Set the [`spawner`](BatchFetcherBuilder::spawner) for each
+    /// [`BatchFetcher`].
+    pub fn spawner(mut self, spawner: impl Spawner + 'static) -> Self {
+        self.spawner = Some(Arc::new(spawner));
+        self
+    }
+
+    /// Set the [`timer`](BatchFetcherBuilder::timer) for each
+    /// [`BatchFetcher`].
+    pub fn timer(mut self, timer: impl Timer + 'static) -> Self {
+        self.timer = Some(Arc::new(timer));
+        self
+    }
+
+    /// Set the [`metrics`](BatchFetcherBuilder::metrics) for each
+    /// [`BatchFetcher`].
+    pub fn metrics(mut self, metrics: impl BatchMetrics + 'static) -> Self {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Set the [`warn_if_slower_than`](BatchFetcherBuilder::warn_if_slower_than)
+    /// threshold for each [`BatchFetcher`].
+    pub fn warn_if_slower_than(mut self, threshold: Duration) -> Self {
+        self.slow_batch_threshold = Some(threshold);
+        self
+    }
+
+    /// Create a [`BatchFetcherBuilder`] for `fetcher` using the factory's
+    /// options. This can be used to create a [`BatchFetcher`] that wasn't
+    /// added with [`fetcher`](LoaderFactory::fetcher), or to set additional
+    /// options (such as a [`label`](BatchFetcherBuilder::label)).
+    pub fn build<F>(&self, fetcher: F) -> BatchFetcherBuilder<F>
+    where
+        F: Fetcher + Send + Sync + 'static,
+    {
+        let mut builder = BatchFetcher::build(fetcher);
+        match self.delay {
+            Some(BatchDelay::Duration(delay)) => builder = builder.delay_duration(delay),
+            Some(BatchDelay::NextTick) => builder = builder.dispatch_on_next_tick(),
+            None => {}
+        }
+        if let Some(eager_batch_size) = self.eager_batch_size {
+            builder = builder.eager_batch_size(eager_batch_size);
+        }
+        if let Some(max_batch_size) = self.max_batch_size {
+            builder = builder.max_batch_size(max_batch_size);
+        }
+        if let Some(max_concurrent_batches) = self.max_concurrent_batches {
+            builder = builder.max_concurrent_batches(max_concurrent_batches);
+        }
+        if let Some(spawner) = &self.spawner {
+            builder = builder.spawner(spawner.clone());
+        }
+        if let Some(timer) = &self.timer {
+            builder = builder.timer(timer.clone());
+        }
+        if let Some(metrics) = &self.metrics {
+            builder = builder.metrics(metrics.clone());
+        }
+        if let Some(threshold) = self.slow_batch_threshold {
+            builder = builder.warn_if_slower_than(threshold);
+        }
+        builder
+    }
+
+    /// Create a new [`LoaderRegistry`] containing a fresh [`BatchFetcher`]
+    /// from each constructor added with [`fetcher`](LoaderFactory::fetcher).
+    /// This should usually be called once per request.
+    pub fn create(&self) -> LoaderRegistry {
+        let mut registry = LoaderRegistry::new();
+        for constructor in &self.constructors {
+            constructor(self, &mut registry);
+        }
+        registry
+    }
+}
+
+impl std::fmt::Debug for LoaderFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoaderFactory")
+            .field("delay", &self.delay)
+            .field("eager_batch_size", &self.eager_batch_size)
+            .field("max_batch_size", &self.max_batch_size)
+            .field("max_concurrent_batches", &self.max_concurrent_batches)
+            .field("has_spawner", &self.spawner.is_some())
+            .field("has_timer", &self.timer.is_some())
+            .field("has_metrics", &self.metrics.is_some())
+            .field("slow_batch_threshold", &self.slow_batch_threshold)
+            .field("num_fetchers", &self.constructors.len())
+            .finish()
+    }
+}
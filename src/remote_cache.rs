@@ -0,0 +1,189 @@
+use crate::combinators::fetch_values;
+use crate::{Cache, Fetcher};
+use std::fmt::Display;
+use std::future::Future;
+
+/// Converts cached values to and from bytes, so they can be stored in a
+/// [`RemoteCache`]. Used with [`Fetcher::with_remote_cache`].
+///
+/// With the `serde-json` feature, `JsonCodec` encodes any value that
+/// implements `serde::Serialize` and `serde::Deserialize` as JSON.
+pub trait CacheCodec<V> {
+    /// The error indicating that encoding or decoding a value failed.
+    type Error: Display;
+
+    /// Convert a value into bytes.
+    fn encode(&self, value: &V) -> Result<Vec<u8>, Self::Error>;
+
+    /// Convert bytes returned by [`encode`](CacheCodec::encode) back into a
+    /// value.
+    fn decode(&self, bytes: &[u8]) -> Result<V, Self::Error>;
+}
+
+/// A cache outside of the process that stores encoded values, such as
+/// Redis or Memcached. Used with [`Fetcher::with_remote_cache`], which
+/// encodes and decodes values with a [`CacheCodec`].
+pub trait RemoteCache<K> {
+    /// The error indicating that reading or writing the cache failed.
+    type Error: Display;
+
+    /// Get the encoded value for each key, returning `None` for keys that
+    /// aren't cached. The returned `Vec` should have one element for each
+    /// key, in the same order as `keys`.
+    fn get_many(
+        &self,
+        keys: &[K],
+    ) -> impl Future<Output = Result<Vec<Option<Vec<u8>>>, Self::Error>> + Send;
+
+    /// Store the encoded value for each key.
+    fn set_many(
+        &self,
+        entries: Vec<(K, Vec<u8>)>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// A [`Fetcher`] that checks a [`RemoteCache`] before calling the wrapped
+/// fetcher, and writes fetched values back to the remote cache. Created
+/// with [`Fetcher::with_remote_cache`].
+#[derive(Debug, Clone)]
+pub struct WithRemoteCache<F, R, C> {
+    fetcher: F,
+    remote_cache: R,
+    codec: C,
+}
+
+impl<F, R, C> WithRemoteCache<F, R, C> {
+    pub(crate) fn new(fetcher: F, remote_cache: R, codec: C) -> Self {
+        WithRemoteCache {
+            fetcher,
+            remote_cache,
+            codec,
+        }
+    }
+
+    /// Get a reference to the wrapped [`Fetcher`].
+    pub fn get_ref(&self) -> &F {
+        &self.fetcher
+    }
+
+    /// Get a reference to the [`RemoteCache`].
+    pub fn remote_cache(&self) -> &R {
+        &self.remote_cache
+    }
+}
+
+impl<F, R, C> Fetcher for WithRemoteCache<F, R, C>
+where
+    F: Fetcher + Sync,
+    R: RemoteCache<F::Key> + Sync,
+    C: CacheCodec<F::Value> + Sync,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        // Errors from the remote cache are logged, and every key is treated
+        // as a cache miss
+        let cached_values = match self.remote_cache.get_many(keys).await {
+            Ok(cached_values) => cached_values,
+            Err(error) => {
+                tracing::warn!("error while reading from remote cache: {error}");
+                vec![]
+            }
+        };
+
+        let mut missing_keys = vec![];
+        let mut cached_values = cached_values.into_iter();
+        for key in keys {
+            let cached_value = cached_values.next().flatten().and_then(|bytes| {
+                self.codec
+                    .decode(&bytes)
+                    .map_err(|error| {
+                        tracing::warn!("error while decoding value from remote cache: {error}");
+                    })
+                    .ok()
+            });
+            match cached_value {
+                Some(value) => values.insert(key.clone(), value),
+                None => missing_keys.push(key.clone()),
+            }
+        }
+        if missing_keys.is_empty() {
+            return Ok(());
+        }
+
+        tracing::trace!(
+            num_cached = keys.len() - missing_keys.len(),
+            num_missing = missing_keys.len(),
+            "fetching keys missing from remote cache",
+        );
+        let fetched_values =
+            fetch_values(&self.fetcher, &missing_keys, values.batch_info()).await?;
+
+        let mut encoded_values = Vec::with_capacity(fetched_values.len());
+        for (key, value) in fetched_values {
+            match self.codec.encode(&value) {
+                Ok(bytes) => encoded_values.push((key.clone(), bytes)),
+                Err(error) => {
+                    tracing::warn!("error while encoding value for remote cache: {error}");
+                }
+            }
+            values.insert(key, value);
+        }
+
+        if !encoded_values.is_empty() {
+            if let Err(error) = self.remote_cache.set_many(encoded_values).await {
+                tracing::warn!("error while writing to remote cache: {error}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A [`CacheCodec`] that encodes values as JSON using [`serde_json`].
+/// Requires the `serde-json` feature.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{CacheCodec, JsonCodec};
+/// #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+/// struct User {
+///     id: u64,
+///     name: String,
+/// }
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let user = User { id: 1, name: "Alice".to_string() };
+/// let bytes = JsonCodec.encode(&user)?;
+/// assert_eq!(bytes, br#"{"id":1,"name":"Alice"}"#);
+///
+/// let decoded: User = JsonCodec.decode(&bytes)?;
+/// assert_eq!(decoded, user);
+/// # Ok(()) }
+/// ```
+#[cfg(feature = "serde-json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "serde-json")]
+impl<V> CacheCodec<V> for JsonCodec
+where
+    V: serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Error = serde_json::Error;
+
+    fn encode(&self, value: &V) -> Result<Vec<u8>, Self::Error> {
+        serde_json::to_vec(value)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<V, Self::Error> {
+        serde_json::from_slice(bytes)
+    }
+}
@@ -0,0 +1,200 @@
+use crate::cache::CacheStore;
+use crate::{BatchFetcher, BatchFetcherBuilder, Cache, Fetcher, IntoKey, LoadError};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::hash::Hash;
+
+/// A variant of [`Fetcher`] that receives a context supplied by each caller,
+/// such as a tenant, an auth principal, or a locale. Use
+/// [`BatchFetcher::build_with_request_context`] to create a [`BatchFetcher`]
+/// from a `RequestContextFetcher`, then load values with
+/// [`load_with_context`](BatchFetcher::load_with_context) or
+/// [`load_many_with_context`](BatchFetcher::load_many_with_context).
+///
+/// Batches are split by context, so keys loaded with different contexts
+/// never share a batch. Values are also cached separately for each context.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, Cache, RequestContextFetcher};
+/// # struct DbConnection;
+/// # impl DbConnection {
+/// #     async fn get_user_names(&self, tenant_id: u64, ids: &[u64]) -> anyhow::Result<Vec<(u64, String)>> {
+/// #         Ok(ids.iter().map(|&id| (id, format!("User {tenant_id}/{id}"))).collect())
+/// #     }
+/// # }
+/// struct UserNameFetcher {
+///     db_conn: DbConnection,
+/// }
+///
+/// impl RequestContextFetcher for UserNameFetcher {
+///     type Context = u64;
+///     type Key = u64;
+///     type Value = String;
+///     type Error = anyhow::Error;
+///
+///     async fn fetch(
+///         &self,
+///         tenant_id: &u64,
+///         keys: &[u64],
+///         values: &mut Cache<'_, u64, String>,
+///     ) -> anyhow::Result<()> {
+///         for (id, name) in self.db_conn.get_user_names(*tenant_id, keys).await? {
+///             values.insert(id, name);
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// # let db_conn = DbConnection;
+/// let batch_fetcher =
+///     BatchFetcher::build_with_request_context(UserNameFetcher { db_conn }).finish();
+///
+/// let name = batch_fetcher.load_with_context(7, 1).await?;
+/// assert_eq!(name, "User 7/1");
+/// # Ok(()) }
+/// ```
+pub trait RequestContextFetcher {
+    /// The context supplied by each caller. Keys with the same context are
+    /// batched together.
+    type Context: Clone + Hash + Eq + Send + Sync;
+
+    /// The type used to look up a single value in a batch. See
+    /// [`Fetcher::Key`].
+    type Key: Clone + Hash + Eq + Send + Sync;
+
+    /// The type returned in a batch. See [`Fetcher::Value`].
+    type Value: Clone + Send + Sync;
+
+    /// The error indicating that fetching a batch failed.
+    type Error: Display;
+
+    /// Retrieve the values associated with the given keys for `context`,
+    /// and insert them into `values` if found. Every key in a batch was
+    /// loaded with the same context. See [`Fetcher::fetch`].
+    fn fetch(
+        &self,
+        context: &Self::Context,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// Adapts a [`RequestContextFetcher`] into a [`Fetcher`] whose keys are
+/// `(context, key)` pairs. Created with
+/// [`BatchFetcher::build_with_request_context`] or [`WithRequestContext::new`].
+///
+/// When used directly with [`BatchFetcher::build`], a batch can contain keys
+/// with different contexts, and the wrapped fetcher is called once for each
+/// context. Use [`BatchFetcher::build_with_request_context`] to give each
+/// context its own batches instead.
+#[derive(Debug, Clone)]
+pub struct WithRequestContext<F> {
+    fetcher: F,
+}
+
+impl<F> WithRequestContext<F>
+where
+    F: RequestContextFetcher,
+{
+    /// Wrap a [`RequestContextFetcher`] so it can be used with a
+    /// [`BatchFetcher`].
+    pub fn new(fetcher: F) -> Self {
+        WithRequestContext { fetcher }
+    }
+
+    /// Get a reference to the wrapped [`RequestContextFetcher`].
+    pub fn get_ref(&self) -> &F {
+        &self.fetcher
+    }
+}
+
+impl<F> Fetcher for WithRequestContext<F>
+where
+    F: RequestContextFetcher + Sync,
+{
+    type Key = (F::Context, F::Key);
+    type Value = F::Value;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let mut keys_by_context = HashMap::<&F::Context, Vec<F::Key>>::new();
+        for (context, key) in keys {
+            keys_by_context
+                .entry(context)
+                .or_default()
+                .push(key.clone());
+        }
+
+        for (context, keys) in keys_by_context {
+            let cache_store = CacheStore::new();
+            let mut cache = match values.batch_info() {
+                Some(batch_info) => cache_store.as_batch_cache(batch_info),
+                None => cache_store.as_cache(),
+            };
+            self.fetcher.fetch(context, &keys, &mut cache).await?;
+
+            for (key, value) in cache_store.take_loaded() {
+                values.insert((context.clone(), key), value);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<F> BatchFetcher<WithRequestContext<F>>
+where
+    F: RequestContextFetcher + Send + Sync + 'static,
+    F::Context: 'static,
+    F::Key: 'static,
+    F::Value: 'static,
+    F::Error: 'static,
+{
+    /// Create a new `BatchFetcher` that uses the given
+    /// [`RequestContextFetcher`] to fetch values, giving each context its
+    /// own batches (see [`BatchFetcherBuilder::shard_by`]). Returns a
+    /// [`BatchFetcherBuilder`], the same as [`BatchFetcher::build`]. See
+    /// [`RequestContextFetcher`] for an example.
+    pub fn build_with_request_context(fetcher: F) -> BatchFetcherBuilder<WithRequestContext<F>> {
+        BatchFetcher::build(WithRequestContext::new(fetcher))
+            .shard_by(|(context, _): &(F::Context, F::Key)| context.clone())
+    }
+
+    /// Load the value for the given key with a context, which is passed to
+    /// the [`RequestContextFetcher`] along with the batch. See
+    /// [`BatchFetcher::load`].
+    pub async fn load_with_context(
+        &self,
+        context: F::Context,
+        key: F::Key,
+    ) -> Result<F::Value, LoadError> {
+        self.load((context, key)).await
+    }
+
+    /// Load the values for the given keys with a context, which is passed
+    /// to the [`RequestContextFetcher`] along with the batch. See
+    /// [`BatchFetcher::load_many`].
+    pub async fn load_many_with_context<I>(
+        &self,
+        context: F::Context,
+        keys: I,
+    ) -> Result<Vec<F::Value>, LoadError>
+    where
+        I: IntoIterator,
+        I::Item: IntoKey<F::Key>,
+    {
+        let keys: Vec<_> = keys
+            .into_iter()
+            .map(|key| (context.clone(), key.into_key()))
+            .collect();
+        self.load_many(keys).await
+    }
+}
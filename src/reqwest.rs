@@ -0,0 +1,151 @@
+//! A [`Fetcher`] for batching lookups from REST endpoints using
+//! [`reqwest`](::reqwest). Requires the `reqwest` feature.
+
+use crate::{Cache, Fetcher};
+use ::reqwest::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+/// A [`Fetcher`] that looks up each batch of keys with a single HTTP request,
+/// for endpoints that support batch lookups (such as `GET /users?ids=1,2,3`).
+///
+/// For each batch, `build_request` is called with the client and the keys
+/// for the batch, and should return the request to send. The response body
+/// is decoded as a JSON array of values, and `key_fn` is called to get the
+/// key for each value. Any keys without a returned value will be marked as
+/// "not found". For the common case of a comma-separated query parameter,
+/// use [`HttpFetcher::ids_query`].
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::BatchFetcher;
+/// # use ultra_batch::reqwest::HttpFetcher;
+/// # #[derive(Clone, serde::Deserialize)] struct User { id: u64, name: String }
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let client = reqwest::Client::new();
+/// let user_fetcher = HttpFetcher::new(
+///     client,
+///     |client, ids: &[u64]| client.post("https://example.com/users/batch").json(ids),
+///     |user: &User| user.id,
+/// );
+/// let batch_fetcher = BatchFetcher::build(user_fetcher).finish();
+/// # Ok(()) }
+/// ```
+pub struct HttpFetcher<K, V, BuildFn, KeyFn> {
+    client: Client,
+    build_request: BuildFn,
+    key_fn: KeyFn,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, BuildFn, KeyFn> HttpFetcher<K, V, BuildFn, KeyFn>
+where
+    BuildFn: Fn(&Client, &[K]) -> RequestBuilder,
+    KeyFn: Fn(&V) -> K,
+{
+    /// Create a new `HttpFetcher` that uses `build_request` to build the
+    /// request for each batch, and uses `key_fn` to get the key for each
+    /// returned value.
+    pub fn new(client: Client, build_request: BuildFn, key_fn: KeyFn) -> Self {
+        HttpFetcher {
+            client,
+            build_request,
+            key_fn,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, KeyFn> HttpFetcher<K, V, (), KeyFn>
+where
+    K: Display,
+    KeyFn: Fn(&V) -> K,
+{
+    /// Create a new `HttpFetcher` that sends a `GET` request to `url` for
+    /// each batch, with the keys joined by commas in the query parameter
+    /// `param` (e.g. `https://example.com/users?ids=1,2,3`).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use ultra_batch::BatchFetcher;
+    /// # use ultra_batch::reqwest::HttpFetcher;
+    /// # #[derive(Clone, serde::Deserialize)] struct User { id: u64, name: String }
+    /// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+    /// let client = reqwest::Client::new();
+    /// let user_fetcher = HttpFetcher::ids_query(
+    ///     client,
+    ///     "https://example.com/users",
+    ///     "ids",
+    ///     |user: &User| user.id,
+    /// );
+    /// let batch_fetcher = BatchFetcher::build(user_fetcher).finish();
+    /// # Ok(()) }
+    /// ```
+    pub fn ids_query(
+        client: Client,
+        url: impl Into<String>,
+        param: impl Into<String>,
+        key_fn: KeyFn,
+    ) -> HttpFetcher<K, V, impl Fn(&Client, &[K]) -> RequestBuilder, KeyFn> {
+        let url = url.into();
+        let param = param.into();
+        HttpFetcher::new(
+            client,
+            move |client: &Client, keys: &[K]| {
+                let ids = keys
+                    .iter()
+                    .map(|key| key.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                client.get(&url).query(&[(&param, ids)])
+            },
+            key_fn,
+        )
+    }
+}
+
+impl<K, V, BuildFn, KeyFn> Clone for HttpFetcher<K, V, BuildFn, KeyFn>
+where
+    BuildFn: Clone,
+    KeyFn: Clone,
+{
+    fn clone(&self) -> Self {
+        HttpFetcher {
+            client: self.client.clone(),
+            build_request: self.build_request.clone(),
+            key_fn: self.key_fn.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, BuildFn, KeyFn> Fetcher for HttpFetcher<K, V, BuildFn, KeyFn>
+where
+    K: Clone + Hash + Eq + Send + Sync,
+    V: Clone + Send + Sync + DeserializeOwned,
+    BuildFn: Fn(&Client, &[K]) -> RequestBuilder + Sync,
+    KeyFn: Fn(&V) -> K + Sync,
+{
+    type Key = K;
+    type Value = V;
+    type Error = ::reqwest::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let request = (self.build_request)(&self.client, keys);
+        let response = request.send().await?.error_for_status()?;
+        let fetched_values: Vec<V> = response.json().await?;
+        for value in fetched_values {
+            values.insert((self.key_fn)(&value), value);
+        }
+
+        Ok(())
+    }
+}
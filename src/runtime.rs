@@ -0,0 +1,653 @@
+use crate::cache::lock;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+pub(crate) use std::time::Instant;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub(crate) use wasm_timer::Instant;
+
+/// A trait for spawning the background tasks used by a
+/// [`BatchFetcher`](crate::BatchFetcher) or [`BatchExecutor`](crate::BatchExecutor).
+/// Each `BatchFetcher` or `BatchExecutor` spawns one long-running task to
+/// queue up batches, plus one task for each batch that gets dispatched.
+///
+/// With the `tokio` feature (enabled by default), tasks are spawned with
+/// [`TokioRuntime`] (or [`WasmRuntime`] when targeting `wasm32` with the
+/// `wasm` feature) unless a different spawner is set with
+/// [`BatchFetcherBuilder::spawner`](crate::BatchFetcherBuilder::spawner) or
+/// [`BatchExecutorBuilder::spawner`](crate::BatchExecutorBuilder::spawner).
+/// Implement this trait to run batches on another executor, such as
+/// async-std, smol, or an embedded executor.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchFetcher, Spawner};
+/// # use futures_util::future::BoxFuture;
+/// # use std::collections::HashMap;
+/// struct MySpawner;
+///
+/// impl Spawner for MySpawner {
+///     fn spawn(&self, task: BoxFuture<'static, ()>) {
+///         // Or `async_std::task::spawn(task);`, `smol::spawn(task).detach();`, etc.
+///         tokio::spawn(task);
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+///     anyhow::Ok(ids.into_iter().map(|id| (id, id * 2)).collect::<HashMap<_, _>>())
+/// })
+/// .spawner(MySpawner)
+/// .finish();
+/// # Ok(()) }
+/// ```
+pub trait Spawner: Send + Sync {
+    /// Spawn a task to run in the background. The task should be polled to
+    /// completion even if nothing waits on it.
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+
+    /// Spawn a task like [`spawn`](Spawner::spawn), with a name identifying
+    /// the [`BatchFetcher`](crate::BatchFetcher) or
+    /// [`BatchExecutor`](crate::BatchExecutor) it belongs to. This is used
+    /// for the long-running task of each `BatchFetcher` or `BatchExecutor`,
+    /// so it can be identified in tools like tokio-console. By default, the
+    /// name is ignored.
+    fn spawn_named(&self, name: &str, task: BoxFuture<'static, ()>) {
+        let _ = name;
+        self.spawn(task)
+    }
+
+    /// Return a future that yields to the executor once, letting other
+    /// tasks run before it completes. This is used by
+    /// [`dispatch_on_next_tick`](crate::BatchFetcherBuilder::dispatch_on_next_tick).
+    /// By default, the future wakes itself once and returns `Pending`.
+    fn yield_now(&self) -> BoxFuture<'static, ()> {
+        Box::pin(YieldNow { yielded: false })
+    }
+}
+
+impl<S> Spawner for Arc<S>
+where
+    S: Spawner + ?Sized,
+{
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        (**self).spawn(task)
+    }
+
+    fn spawn_named(&self, name: &str, task: BoxFuture<'static, ()>) {
+        (**self).spawn_named(name, task)
+    }
+
+    fn yield_now(&self) -> BoxFuture<'static, ()> {
+        (**self).yield_now()
+    }
+}
+
+/// A trait for waiting on a delay, used by a [`BatchFetcher`](crate::BatchFetcher)
+/// or [`BatchExecutor`](crate::BatchExecutor) to wait for more keys or values
+/// before dispatching a batch.
+///
+/// With the `tokio` feature (enabled by default), [`TokioRuntime`] is used
+/// (or [`WasmRuntime`] when targeting `wasm32` with the `wasm` feature)
+/// unless a different timer is set with [`BatchFetcherBuilder::timer`](crate::BatchFetcherBuilder::timer)
+/// or [`BatchExecutorBuilder::timer`](crate::BatchExecutorBuilder::timer).
+pub trait Timer: Send + Sync {
+    /// Return a future that completes once `duration` has passed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+impl<T> Timer for Arc<T>
+where
+    T: Timer + ?Sized,
+{
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        (**self).sleep(duration)
+    }
+}
+
+/// Waits until a single deadline while a batch is being queued up. The
+/// current sleep is kept when the deadline moves later, and only replaced
+/// once it fires before the latest deadline (or when the deadline moves
+/// earlier), so each request that arrives doesn't need a new sleep.
+pub(crate) struct DelayTimer {
+    timer: Arc<dyn Timer>,
+    deadline: Instant,
+    sleep: Option<(Instant, BoxFuture<'static, ()>)>,
+}
+
+impl DelayTimer {
+    pub(crate) fn new(timer: Arc<dyn Timer>) -> Self {
+        DelayTimer {
+            timer,
+            deadline: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    /// Wait until `deadline` instead of the previous deadline.
+    pub(crate) fn sleep_until(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+        if matches!(self.sleep, Some((sleep_deadline, _)) if sleep_deadline > deadline) {
+            self.sleep = None;
+        }
+    }
+}
+
+impl Future for DelayTimer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        loop {
+            let (sleep_deadline, sleep) = match &mut this.sleep {
+                Some(sleep) => sleep,
+                None => {
+                    let now = Instant::now();
+                    if this.deadline <= now {
+                        return Poll::Ready(());
+                    }
+
+                    let sleep = this.timer.sleep(this.deadline - now);
+                    this.sleep.insert((this.deadline, sleep))
+                }
+            };
+
+            futures_util::ready!(sleep.as_mut().poll(cx));
+
+            // If the deadline moved since this sleep started, keep waiting
+            let reached_deadline = *sleep_deadline >= this.deadline;
+            this.sleep = None;
+            if reached_deadline {
+                return Poll::Ready(());
+            }
+        }
+    }
+}
+
+/// A [`Spawner`] and [`Timer`] that uses Tokio. This is the default for
+/// both. Requires the `tokio` feature (enabled by default).
+///
+/// Tasks are spawned onto the runtime set with [`TokioRuntime::with_handle`],
+/// or else onto the current Tokio runtime. If there is no current runtime
+/// (e.g. when a [`BatchFetcher`](crate::BatchFetcher) is created before
+/// the application's runtime starts), tasks are spawned onto a shared
+/// single-threaded runtime that runs on a background thread.
+///
+/// With the `tokio-console` feature, and when building with
+/// `RUSTFLAGS="--cfg tokio_unstable"`, the long-running task of each
+/// [`BatchFetcher`](crate::BatchFetcher) or [`BatchExecutor`](crate::BatchExecutor)
+/// is named after its label, so it can be identified in tokio-console.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Default)]
+pub struct TokioRuntime {
+    handle: Option<tokio::runtime::Handle>,
+}
+
+#[cfg(feature = "tokio")]
+impl TokioRuntime {
+    /// Create a new `TokioRuntime` that spawns tasks onto the current Tokio
+    /// runtime.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new `TokioRuntime` that always spawns tasks onto the
+    /// runtime for `handle`.
+    pub fn with_handle(handle: tokio::runtime::Handle) -> Self {
+        TokioRuntime {
+            handle: Some(handle),
+        }
+    }
+
+    fn handle(&self) -> tokio::runtime::Handle {
+        match &self.handle {
+            Some(handle) => handle.clone(),
+            None => tokio::runtime::Handle::try_current().unwrap_or_else(|_| {
+                tracing::debug!("no current Tokio runtime, spawning onto fallback runtime");
+                fallback_tokio_handle().clone()
+            }),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Spawner for TokioRuntime {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        self.handle().spawn(task);
+    }
+
+    #[cfg(all(tokio_unstable, feature = "tokio-console"))]
+    fn spawn_named(&self, name: &str, task: BoxFuture<'static, ()>) {
+        let handle = self.handle();
+        if let Err(error) = tokio::task::Builder::new()
+            .name(name)
+            .spawn_on(task, &handle)
+        {
+            tracing::error!(task = name, %error, "failed to spawn task");
+        }
+    }
+
+    fn yield_now(&self) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::task::yield_now())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioRuntime {
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Spawn a task onto the current Tokio [`LocalSet`](tokio::task::LocalSet),
+/// named like [`Spawner::spawn_named`].
+#[cfg(feature = "tokio")]
+pub(crate) fn spawn_local_named(name: &str, task: impl Future<Output = ()> + 'static) {
+    #[cfg(all(tokio_unstable, feature = "tokio-console"))]
+    if let Err(error) = tokio::task::Builder::new().name(name).spawn_local(task) {
+        tracing::error!(task = name, %error, "failed to spawn task");
+    }
+
+    #[cfg(not(all(tokio_unstable, feature = "tokio-console")))]
+    {
+        let _ = name;
+        tokio::task::spawn_local(task);
+    }
+}
+
+/// Returns the handle for a runtime used to spawn tasks outside of any
+/// Tokio runtime. The runtime is started on a background thread the first
+/// time it's needed, and runs for the rest of the process.
+#[cfg(feature = "tokio")]
+fn fallback_tokio_handle() -> &'static tokio::runtime::Handle {
+    static FALLBACK_HANDLE: std::sync::OnceLock<tokio::runtime::Handle> =
+        std::sync::OnceLock::new();
+
+    FALLBACK_HANDLE.get_or_init(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .expect("failed to build fallback Tokio runtime");
+        let handle = runtime.handle().clone();
+        std::thread::Builder::new()
+            .name("ultra-batch-runtime".into())
+            .spawn(move || runtime.block_on(std::future::pending::<()>()))
+            .expect("failed to spawn fallback Tokio runtime thread");
+        handle
+    })
+}
+
+/// A [`Spawner`] and [`Timer`] for `wasm32-unknown-unknown`, such as in a
+/// browser or an edge runtime. Tasks are spawned onto the JavaScript event
+/// loop with [`wasm_bindgen_futures::spawn_local`], and delays use
+/// [`wasm_timer::Delay`]. This is the default for both when targeting
+/// `wasm32` with the `wasm` feature. Requires the `wasm` feature.
+///
+/// When building for `wasm32`, the `tokio` feature should usually be
+/// disabled (with `default-features = false`).
+#[cfg(feature = "wasm")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WasmRuntime;
+
+#[cfg(feature = "wasm")]
+impl Spawner for WasmRuntime {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        wasm_bindgen_futures::spawn_local(task);
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Timer for WasmRuntime {
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let delay = wasm_timer::Delay::new(duration);
+        Box::pin(async move {
+            // The delay only fails if the timer was dropped, in which case
+            // there's nothing left to wait for
+            let _ = delay.await;
+        })
+    }
+}
+
+/// A future that runs the background work of a [`BatchFetcher`](crate::BatchFetcher)
+/// or [`BatchExecutor`](crate::BatchExecutor) without spawning any tasks.
+/// Returned by [`BatchFetcherBuilder::finish_with_driver`](crate::BatchFetcherBuilder::finish_with_driver)
+/// and [`BatchExecutorBuilder::finish_with_driver`](crate::BatchExecutorBuilder::finish_with_driver).
+///
+/// Batches are only queued and dispatched while the `BatchDriver` is being
+/// polled, so it should be polled alongside any code that loads or executes
+/// values (for example, with [`tokio::join!`] or [`futures_util::future::join`]).
+/// The future completes once every clone of the `BatchFetcher` or
+/// `BatchExecutor` has been dropped and any in-flight batches have
+/// finished.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::BatchFetcher;
+/// # use std::collections::HashMap;
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let (batch_fetcher, driver) = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+///     anyhow::Ok(ids.into_iter().map(|id| (id, id * 2)).collect::<HashMap<_, _>>())
+/// })
+/// .finish_with_driver();
+///
+/// let (value, ()) = tokio::join!(
+///     async move { batch_fetcher.load(1).await },
+///     driver,
+/// );
+/// assert_eq!(value?, 2);
+/// # Ok(()) }
+/// ```
+#[must_use = "batches are only dispatched while the driver is polled"]
+pub struct BatchDriver {
+    tasks: FuturesUnordered<BoxFuture<'static, ()>>,
+    task_rx: tokio::sync::mpsc::UnboundedReceiver<BoxFuture<'static, ()>>,
+    task_rx_closed: bool,
+}
+
+impl BatchDriver {
+    pub(crate) fn new() -> (DriverSpawner, Self) {
+        let (task_tx, task_rx) = tokio::sync::mpsc::unbounded_channel();
+        let driver = BatchDriver {
+            tasks: FuturesUnordered::new(),
+            task_rx,
+            task_rx_closed: false,
+        };
+        (DriverSpawner { task_tx }, driver)
+    }
+}
+
+impl Future for BatchDriver {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            // Pick up any tasks spawned since the last poll
+            while !self.task_rx_closed {
+                match self.task_rx.poll_recv(cx) {
+                    Poll::Ready(Some(task)) => self.tasks.push(task),
+                    Poll::Ready(None) => self.task_rx_closed = true,
+                    Poll::Pending => break,
+                }
+            }
+
+            match self.tasks.poll_next_unpin(cx) {
+                Poll::Ready(Some(())) => {
+                    // A task finished, so check for new tasks and keep going
+                }
+                Poll::Ready(None) if self.task_rx_closed => return Poll::Ready(()),
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for BatchDriver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BatchDriver")
+            .field("num_tasks", &self.tasks.len())
+            .finish()
+    }
+}
+
+/// A [`Spawner`] that hands tasks to a [`BatchDriver`] instead of spawning
+/// them.
+pub(crate) struct DriverSpawner {
+    task_tx: tokio::sync::mpsc::UnboundedSender<BoxFuture<'static, ()>>,
+}
+
+impl Spawner for DriverSpawner {
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        // If the driver was dropped, then the task would never run anyway
+        let _ = self.task_tx.send(task);
+    }
+}
+
+pub(crate) fn default_spawner() -> Arc<dyn Spawner> {
+    try_default_spawner().unwrap_or_else(|| {
+        panic!("no spawner set (enable the `tokio` feature or set a `Spawner` on the builder)")
+    })
+}
+
+/// Returns the default [`Spawner`], or `None` if no runtime feature is
+/// enabled.
+pub(crate) fn try_default_spawner() -> Option<Arc<dyn Spawner>> {
+    #[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+    {
+        Some(Arc::new(WasmRuntime))
+    }
+
+    #[cfg(all(feature = "tokio", not(all(feature = "wasm", target_arch = "wasm32"))))]
+    {
+        Some(Arc::new(TokioRuntime::new()))
+    }
+
+    #[cfg(not(any(feature = "tokio", all(feature = "wasm", target_arch = "wasm32"))))]
+    {
+        None
+    }
+}
+
+pub(crate) fn default_timer() -> Arc<dyn Timer> {
+    #[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+    {
+        Arc::new(WasmRuntime)
+    }
+
+    #[cfg(all(feature = "tokio", not(all(feature = "wasm", target_arch = "wasm32"))))]
+    {
+        Arc::new(TokioRuntime::new())
+    }
+
+    #[cfg(not(any(feature = "tokio", all(feature = "wasm", target_arch = "wasm32"))))]
+    {
+        panic!("no timer set (enable the `tokio` feature or set a `Timer` on the builder)")
+    }
+}
+
+/// Aborts a background task once the last handle that owns this guard is
+/// dropped.
+pub(crate) struct AbortOnDrop(futures_util::future::AbortHandle);
+
+impl AbortOnDrop {
+    /// Wrap `task` so it can be aborted, returning the wrapped task and a
+    /// guard that aborts it when dropped.
+    pub(crate) fn new<Fut>(task: Fut) -> (impl Future<Output = ()>, Self)
+    where
+        Fut: Future<Output = ()>,
+    {
+        let (task, abort_handle) = futures_util::future::abortable(task);
+        let task = async move {
+            // Ignore error if the task was aborted
+            let _ = task.await;
+        };
+        (task, AbortOnDrop(abort_handle))
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// The sending half of a restarted task's queue, and (optionally) a guard
+/// that aborts the task.
+type RestartedTask<M> = (tokio::sync::mpsc::Sender<M>, Option<AbortOnDrop>);
+
+/// Restarts a background task. If the argument is `true`, the task is
+/// spawned with the default [`Spawner`] instead of the configured one, or
+/// isn't restarted at all (returning `None`) if it can't fall back to the
+/// default spawner.
+type RestartTask<M> = Box<dyn Fn(bool) -> Option<RestartedTask<M>> + Send + Sync>;
+
+/// A handle to a background task that receives messages from a queue. Once
+/// the handle is dropped, the queue is closed and the task is aborted if it
+/// has an abort guard. Tasks without a guard can finish draining their
+/// queue. If the task is restartable, it's restarted if it stops before
+/// [`close`](TaskHandle::close) is called.
+pub(crate) struct TaskHandle<M> {
+    state: Mutex<TaskState<M>>,
+    restart: Option<RestartTask<M>>,
+}
+
+struct TaskState<M> {
+    tx: tokio::sync::mpsc::Sender<M>,
+    closed: bool,
+    use_default_spawner: bool,
+    _guard: Option<AbortOnDrop>,
+}
+
+impl<M> TaskHandle<M> {
+    /// Create a handle for a task that won't be restarted.
+    pub(crate) fn new(tx: tokio::sync::mpsc::Sender<M>, guard: Option<AbortOnDrop>) -> Self {
+        TaskHandle {
+            state: Mutex::new(TaskState {
+                tx,
+                closed: false,
+                use_default_spawner: false,
+                _guard: guard,
+            }),
+            restart: None,
+        }
+    }
+
+    /// Create a handle for a task that gets restarted with `restart` if it
+    /// stops unexpectedly. `restart` is called with `true` if the task
+    /// should be spawned with the default [`Spawner`], and returns `None`
+    /// if it can't be.
+    pub(crate) fn restartable(
+        tx: tokio::sync::mpsc::Sender<M>,
+        guard: Option<AbortOnDrop>,
+        restart: impl Fn(bool) -> Option<RestartedTask<M>> + Send + Sync + 'static,
+    ) -> Self {
+        TaskHandle {
+            restart: Some(Box::new(restart)),
+            ..TaskHandle::new(tx, guard)
+        }
+    }
+
+    /// Send a message to the task. If the task has stopped without being
+    /// closed, it's restarted and the message is sent to the new task.
+    pub(crate) async fn send(
+        &self,
+        message: M,
+    ) -> Result<(), tokio::sync::mpsc::error::SendError<M>> {
+        let tx = self.lock().tx.clone();
+        let Err(tokio::sync::mpsc::error::SendError(message)) = tx.send(message).await else {
+            return Ok(());
+        };
+
+        let Some(tx) = self.restart(&tx, false) else {
+            return Err(tokio::sync::mpsc::error::SendError(message));
+        };
+        let Err(tokio::sync::mpsc::error::SendError(message)) = tx.send(message).await else {
+            return Ok(());
+        };
+
+        // The restarted task stopped right away too, which happens when the
+        // spawner's runtime has shut down (or a `BatchDriver` was dropped),
+        // so use the default spawner from now on if that's allowed
+        match self.restart(&tx, true) {
+            Some(tx) => tx.send(message).await,
+            None => Err(tokio::sync::mpsc::error::SendError(message)),
+        }
+    }
+
+    /// Mark the task as closed, so it won't be restarted once it stops.
+    pub(crate) fn close(&self) {
+        self.lock().closed = true;
+    }
+
+    /// Restart the task if it's restartable and hasn't been closed, unless
+    /// it was already restarted since `failed_tx` was taken. If
+    /// `use_default_spawner` is set, this and every later restart uses the
+    /// default [`Spawner`], or fails if the task can't fall back to it.
+    fn restart(
+        &self,
+        failed_tx: &tokio::sync::mpsc::Sender<M>,
+        use_default_spawner: bool,
+    ) -> Option<tokio::sync::mpsc::Sender<M>> {
+        let restart = self.restart.as_ref()?;
+        let mut state = self.lock();
+        if state.closed {
+            return None;
+        }
+
+        if state.tx.same_channel(failed_tx) {
+            state.use_default_spawner |= use_default_spawner;
+            let (tx, guard) = restart(state.use_default_spawner)?;
+            state.tx = tx;
+            state._guard = guard;
+        }
+        Some(state.tx.clone())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, TaskState<M>> {
+        lock(&self.state)
+    }
+}
+
+/// Limits the number of batches that can be in flight at the same time.
+pub(crate) struct InFlightBatches {
+    semaphore: Arc<tokio::sync::Semaphore>,
+    max_concurrent_batches: u32,
+}
+
+impl InFlightBatches {
+    pub(crate) fn new(max_concurrent_batches: usize) -> Self {
+        let max_concurrent_batches = max_concurrent_batches
+            .min(tokio::sync::Semaphore::MAX_PERMITS)
+            .try_into()
+            .unwrap_or(u32::MAX);
+        InFlightBatches {
+            semaphore: Arc::new(tokio::sync::Semaphore::new(max_concurrent_batches as usize)),
+            max_concurrent_batches,
+        }
+    }
+
+    /// Wait until fewer than the maximum number of batches are in flight.
+    /// The returned permit should be held until the dispatched batch
+    /// finishes.
+    pub(crate) async fn acquire(&self) -> tokio::sync::OwnedSemaphorePermit {
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("in-flight batch semaphore closed")
+    }
+
+    /// Wait until every in-flight batch has finished.
+    pub(crate) async fn wait_for_all(&self) {
+        let _ = self
+            .semaphore
+            .acquire_many(self.max_concurrent_batches)
+            .await
+            .expect("in-flight batch semaphore closed");
+    }
+}
+
+struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
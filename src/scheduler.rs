@@ -0,0 +1,329 @@
+use crate::cache::lock;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A trait for deciding when a queued batch should be dispatched. A
+/// [`BatchFetcher`](crate::BatchFetcher) or [`BatchExecutor`](crate::BatchExecutor)
+/// asks its scheduler what to do each time the pending batch changes, and
+/// again whenever the wait returned by the scheduler runs out.
+///
+/// By default, batches are scheduled using a [`DefaultBatchScheduler`], which
+/// is configured through the builder options like
+/// [`delay_duration`](crate::BatchFetcherBuilder::delay_duration) and
+/// [`eager_batch_size`](crate::BatchFetcherBuilder::eager_batch_size). A
+/// custom scheduler can be set with [`BatchFetcherBuilder::scheduler`](crate::BatchFetcherBuilder::scheduler)
+/// or [`BatchExecutorBuilder::scheduler`](crate::BatchExecutorBuilder::scheduler).
+///
+/// # Examples
+///
+/// A scheduler that waits for at most 20ms after the first key was queued,
+/// no matter how many more keys come in:
+///
+/// ```
+/// # use ultra_batch::{BatchScheduler, PendingBatch, Schedule};
+/// # use std::time::Duration;
+/// struct MaxAgeScheduler;
+///
+/// impl BatchScheduler for MaxAgeScheduler {
+///     fn schedule(&self, batch: &PendingBatch) -> Schedule {
+///         let max_age = Duration::from_millis(20);
+///         match max_age.checked_sub(batch.elapsed) {
+///             Some(remaining) if !remaining.is_zero() => Schedule::WaitFor(remaining),
+///             _ => Schedule::DispatchNow,
+///         }
+///     }
+/// }
+/// ```
+pub trait BatchScheduler: Send + Sync {
+    /// Decide what to do with the current pending batch. This is only called
+    /// while the batch contains at least one request.
+    fn schedule(&self, batch: &PendingBatch) -> Schedule;
+
+    /// Called after each dispatched batch has finished (whether or not it
+    /// succeeded). Schedulers can use this to adjust future decisions based
+    /// on observed batch sizes and latencies. Does nothing by default.
+    fn batch_completed(&self, batch: &CompletedBatch) {
+        let _ = batch;
+    }
+}
+
+impl<S> BatchScheduler for Arc<S>
+where
+    S: BatchScheduler + ?Sized,
+{
+    fn schedule(&self, batch: &PendingBatch) -> Schedule {
+        (**self).schedule(batch)
+    }
+
+    fn batch_completed(&self, batch: &CompletedBatch) {
+        (**self).batch_completed(batch)
+    }
+}
+
+/// A snapshot of a batch that is waiting to be dispatched, passed to
+/// [`BatchScheduler::schedule`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PendingBatch {
+    /// The number of items (keys for a [`BatchFetcher`](crate::BatchFetcher),
+    /// or values for a [`BatchExecutor`](crate::BatchExecutor)) queued in
+    /// the batch.
+    pub len: usize,
+
+    /// The number of separate requests (calls to `load`, `execute`, etc.)
+    /// waiting on the batch.
+    pub num_requests: usize,
+
+    /// How long it's been since the first request was added to the batch.
+    pub elapsed: Duration,
+}
+
+/// Details about a batch that has finished, passed to
+/// [`BatchScheduler::batch_completed`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CompletedBatch {
+    /// The number of items passed to the [`Fetcher`](crate::Fetcher) or
+    /// [`Executor`](crate::Executor).
+    pub len: usize,
+
+    /// How long the batch waited between its first request being queued
+    /// and being dispatched.
+    pub wait_duration: Duration,
+
+    /// How long the [`Fetcher`](crate::Fetcher) or [`Executor`](crate::Executor)
+    /// took to run the batch.
+    pub duration: Duration,
+}
+
+/// The decision returned by a [`BatchScheduler`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Dispatch the pending batch immediately.
+    DispatchNow,
+
+    /// Wait for another request for up to the given duration. If a request
+    /// is queued before the duration runs out, the scheduler will be asked
+    /// again. Otherwise, the batch is dispatched.
+    WaitFor(Duration),
+
+    /// Let other tasks run, then dispatch the batch unless a new request
+    /// was queued in the meantime (in which case the scheduler will be
+    /// asked again).
+    WaitForNextTick,
+}
+
+/// The [`BatchScheduler`] used unless a custom scheduler is set. Each batch
+/// waits for more requests for a fixed delay (or until the next tick), and
+/// is dispatched early once it reaches an "eager" batch size.
+#[derive(Debug, Clone)]
+pub struct DefaultBatchScheduler {
+    delay: BatchDelay,
+    eager_batch_size: Option<usize>,
+}
+
+impl DefaultBatchScheduler {
+    /// Create a scheduler that waits up to `delay_duration` for each new
+    /// request, and dispatches as soon as `eager_batch_size` items are
+    /// queued (if set). This matches the behavior of the
+    /// [`delay_duration`](crate::BatchFetcherBuilder::delay_duration) and
+    /// [`eager_batch_size`](crate::BatchFetcherBuilder::eager_batch_size)
+    /// builder options.
+    pub fn new(delay_duration: Duration, eager_batch_size: Option<usize>) -> Self {
+        DefaultBatchScheduler {
+            delay: BatchDelay::Duration(delay_duration),
+            eager_batch_size,
+        }
+    }
+
+    /// Create a scheduler that dispatches on the next tick, and dispatches
+    /// as soon as `eager_batch_size` items are queued (if set). This matches
+    /// the behavior of the [`dispatch_on_next_tick`](crate::BatchFetcherBuilder::dispatch_on_next_tick)
+    /// builder option.
+    pub fn next_tick(eager_batch_size: Option<usize>) -> Self {
+        DefaultBatchScheduler {
+            delay: BatchDelay::NextTick,
+            eager_batch_size,
+        }
+    }
+
+    pub(crate) fn from_options(delay: BatchDelay, eager_batch_size: Option<usize>) -> Self {
+        DefaultBatchScheduler {
+            delay,
+            eager_batch_size,
+        }
+    }
+}
+
+impl BatchScheduler for DefaultBatchScheduler {
+    fn schedule(&self, batch: &PendingBatch) -> Schedule {
+        let should_run_batch_now = match self.eager_batch_size {
+            Some(eager_batch_size) => batch.len >= eager_batch_size,
+            None => false,
+        };
+        if should_run_batch_now {
+            return Schedule::DispatchNow;
+        }
+
+        match self.delay {
+            BatchDelay::Duration(delay_duration) => Schedule::WaitFor(delay_duration),
+            BatchDelay::NextTick => Schedule::WaitForNextTick,
+        }
+    }
+}
+
+/// A [`BatchScheduler`] that tunes its delay and eager batch size based on
+/// recently observed batches, so they don't need to be hand-tuned for each
+/// deployment.
+///
+/// The delay tracks a fraction of the recent average batch duration (within
+/// the range set by [`new`](AdaptiveBatchScheduler::new)): when each batch
+/// is slow, waiting a little longer to build a bigger batch is relatively
+/// cheap, and when each batch is fast, any delay is mostly added latency.
+/// The eager batch size tracks the number of items that are expected to
+/// arrive within that delay, based on the recent arrival rate (but never
+/// drops below 2, so sparse traffic still gets batched). Unlike the
+/// [`DefaultBatchScheduler`], the delay is measured from the first request
+/// in a batch, so a steady stream of requests can't hold up a batch longer
+/// than the current delay.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{AdaptiveBatchScheduler, BatchFetcher, Fetcher, Cache};
+/// # struct UserFetcher;
+/// # impl Fetcher for UserFetcher {
+/// #     type Key = ();
+/// #     type Value = ();
+/// #     type Error = anyhow::Error;
+/// #     async fn fetch(&self, keys: &[()], values: &mut Cache<'_, (), ()>) -> anyhow::Result<()> {
+/// #         unimplemented!();
+/// #     }
+/// # }
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let scheduler = AdaptiveBatchScheduler::new(
+///     tokio::time::Duration::from_millis(1),
+///     tokio::time::Duration::from_millis(20),
+/// );
+/// let batch_fetcher = BatchFetcher::build(UserFetcher)
+///     .scheduler(scheduler)
+///     .finish();
+/// # Ok(()) }
+/// ```
+#[derive(Debug)]
+pub struct AdaptiveBatchScheduler {
+    min_delay: Duration,
+    max_delay: Duration,
+    state: Mutex<AdaptiveState>,
+}
+
+#[derive(Debug)]
+struct AdaptiveState {
+    delay: Duration,
+    eager_batch_size: Option<usize>,
+    avg_duration_secs: Option<f64>,
+    avg_items_per_sec: Option<f64>,
+}
+
+/// How much weight the latest batch has when updating running averages.
+const ADAPTIVE_SMOOTHING: f64 = 0.2;
+
+/// The fraction of the average batch duration to use as the delay.
+const ADAPTIVE_DELAY_RATIO: f64 = 0.5;
+
+impl AdaptiveBatchScheduler {
+    /// Create a scheduler whose delay stays between `min_delay` and
+    /// `max_delay`. The delay starts at `max_delay` until batches have
+    /// been observed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min_delay` is greater than `max_delay`.
+    pub fn new(min_delay: Duration, max_delay: Duration) -> Self {
+        assert!(
+            min_delay <= max_delay,
+            "min_delay must not be greater than max_delay"
+        );
+
+        AdaptiveBatchScheduler {
+            min_delay,
+            max_delay,
+            state: Mutex::new(AdaptiveState {
+                delay: max_delay,
+                eager_batch_size: None,
+                avg_duration_secs: None,
+                avg_items_per_sec: None,
+            }),
+        }
+    }
+
+    /// The delay currently used for new batches.
+    pub fn current_delay(&self) -> Duration {
+        self.lock_state().delay
+    }
+
+    /// The eager batch size currently used for new batches, if enough
+    /// batches have been observed to estimate one.
+    pub fn current_eager_batch_size(&self) -> Option<usize> {
+        self.lock_state().eager_batch_size
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, AdaptiveState> {
+        lock(&self.state)
+    }
+}
+
+impl BatchScheduler for AdaptiveBatchScheduler {
+    fn schedule(&self, batch: &PendingBatch) -> Schedule {
+        let state = self.lock_state();
+
+        let should_run_batch_now = match state.eager_batch_size {
+            Some(eager_batch_size) => batch.len >= eager_batch_size,
+            None => false,
+        };
+        if should_run_batch_now {
+            return Schedule::DispatchNow;
+        }
+
+        match state.delay.checked_sub(batch.elapsed) {
+            Some(remaining) if !remaining.is_zero() => Schedule::WaitFor(remaining),
+            _ => Schedule::DispatchNow,
+        }
+    }
+
+    fn batch_completed(&self, batch: &CompletedBatch) {
+        let mut state = self.lock_state();
+
+        let duration_secs = batch.duration.as_secs_f64();
+        let avg_duration_secs = running_average(state.avg_duration_secs, duration_secs);
+        state.avg_duration_secs = Some(avg_duration_secs);
+
+        // Treat very short waits as 1ms to avoid wild arrival rate estimates
+        let wait_secs = batch.wait_duration.as_secs_f64().max(0.001);
+        let items_per_sec = batch.len as f64 / wait_secs;
+        let avg_items_per_sec = running_average(state.avg_items_per_sec, items_per_sec);
+        state.avg_items_per_sec = Some(avg_items_per_sec);
+
+        let delay = Duration::from_secs_f64(avg_duration_secs * ADAPTIVE_DELAY_RATIO);
+        state.delay = delay.clamp(self.min_delay, self.max_delay);
+
+        // An eager batch size of 1 would dispatch every key on its own, so
+        // always wait for at least a second key before dispatching early
+        let expected_items = avg_items_per_sec * state.delay.as_secs_f64();
+        state.eager_batch_size = Some((expected_items.ceil() as usize).max(2));
+    }
+}
+
+fn running_average(average: Option<f64>, value: f64) -> f64 {
+    match average {
+        Some(average) => average + ADAPTIVE_SMOOTHING * (value - average),
+        None => value,
+    }
+}
+
+/// How long a batch waits for more requests before being dispatched.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum BatchDelay {
+    Duration(Duration),
+    NextTick,
+}
@@ -0,0 +1,111 @@
+//! A [`Fetcher`] for loading [SeaORM](::sea_orm) entities by a key column.
+//! Requires the `sea-orm` feature.
+
+use crate::{Cache, Fetcher};
+use ::sea_orm::sea_query::ValueType;
+use ::sea_orm::{
+    ColumnTrait, DatabaseConnection, DbErr, EntityTrait, ModelTrait, QueryFilter, Value,
+};
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+/// A [`Fetcher`] that loads the models for a SeaORM entity, looking up each
+/// batch of keys in a single query using a key column (such as the primary
+/// key, or a unique column). Any keys without a matching model will be
+/// marked as "not found".
+///
+/// The key type `K` should match the type of the key column. If the key
+/// column isn't unique, then each key will load one of its matching models.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use ultra_batch::BatchFetcher;
+/// # use ultra_batch::sea_orm::EntityFetcher;
+/// # mod user {
+/// #     use sea_orm::entity::prelude::*;
+/// #     #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+/// #     #[sea_orm(table_name = "users")]
+/// #     pub struct Model {
+/// #         #[sea_orm(primary_key)]
+/// #         pub id: i64,
+/// #         pub name: String,
+/// #     }
+/// #     #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+/// #     pub enum Relation {}
+/// #     impl ActiveModelBehavior for ActiveModel {}
+/// # }
+/// # async fn connect() -> sea_orm::DatabaseConnection { unimplemented!() }
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let db = connect().await;
+/// let users = EntityFetcher::<user::Entity, i64>::new(db, user::Column::Id);
+/// let batch_fetcher = BatchFetcher::build(users).finish();
+///
+/// let user = batch_fetcher.load(1).await?;
+/// # Ok(()) }
+/// ```
+pub struct EntityFetcher<E, K>
+where
+    E: EntityTrait,
+{
+    db: DatabaseConnection,
+    column: E::Column,
+    _marker: PhantomData<fn() -> K>,
+}
+
+impl<E, K> EntityFetcher<E, K>
+where
+    E: EntityTrait,
+{
+    /// Create a new `EntityFetcher` that loads models from `db`, looking up
+    /// keys using `column`.
+    pub fn new(db: DatabaseConnection, column: E::Column) -> Self {
+        EntityFetcher {
+            db,
+            column,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<E, K> Clone for EntityFetcher<E, K>
+where
+    E: EntityTrait,
+{
+    fn clone(&self) -> Self {
+        EntityFetcher {
+            db: self.db.clone(),
+            column: self.column,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<E, K> Fetcher for EntityFetcher<E, K>
+where
+    E: EntityTrait,
+    E::Model: Clone + Sync,
+    K: Clone + Hash + Eq + Send + Sync + Into<Value> + ValueType,
+{
+    type Key = K;
+    type Value = E::Model;
+    type Error = DbErr;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let models = E::find()
+            .filter(self.column.is_in(keys.iter().cloned()))
+            .all(&self.db)
+            .await?;
+        for model in models {
+            let key = K::try_from(model.get(self.column))
+                .map_err(|error| DbErr::Type(error.to_string()))?;
+            values.insert(key, model);
+        }
+
+        Ok(())
+    }
+}
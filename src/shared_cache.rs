@@ -0,0 +1,167 @@
+use crate::cache::lock;
+use crate::combinators::fetch_values;
+use crate::runtime::Instant;
+use crate::{Cache, Fetcher};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// A cache of fetched values that can be shared by many [`BatchFetcher`](crate::BatchFetcher)s,
+/// such as a cache shared across requests in a web service, where each
+/// request creates its own `BatchFetcher`. Values are kept for a fixed TTL.
+/// Use with [`Fetcher::with_shared_cache`].
+///
+/// Cloning a `SharedCache` returns a handle to the same cache.
+pub struct SharedCache<K, V> {
+    inner: Arc<SharedCacheInner<K, V>>,
+}
+
+struct SharedCacheInner<K, V> {
+    ttl: Duration,
+    entries: Mutex<HashMap<K, (Instant, V)>>,
+}
+
+impl<K, V> SharedCache<K, V>
+where
+    K: Hash + Eq,
+{
+    /// Create a new, empty `SharedCache` that keeps each value for `ttl`
+    /// after it was fetched.
+    pub fn new(ttl: Duration) -> Self {
+        SharedCache {
+            inner: Arc::new(SharedCacheInner {
+                ttl,
+                entries: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Remove a key from the cache, so the next batch that needs it calls
+    /// the [`Fetcher`] again. Returns `true` if the key was cached. Note
+    /// that `BatchFetcher`s that already loaded the key will keep their own
+    /// copy of the value.
+    pub fn invalidate(&self, key: &K) -> bool {
+        self.lock_entries().remove(key).is_some()
+    }
+
+    /// The number of values in the cache, including values whose TTL has
+    /// expired but haven't been removed yet.
+    pub fn len(&self) -> usize {
+        self.lock_entries().len()
+    }
+
+    /// Returns `true` if the cache holds no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock_entries(&self) -> MutexGuard<'_, HashMap<K, (Instant, V)>> {
+        lock(&self.inner.entries)
+    }
+}
+
+impl<K, V> Clone for SharedCache<K, V> {
+    fn clone(&self) -> Self {
+        SharedCache {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// A [`Fetcher`] that checks a [`SharedCache`] before calling the wrapped
+/// fetcher, and writes fetched values back to the shared cache. Created
+/// with [`Fetcher::with_shared_cache`].
+pub struct WithSharedCache<F>
+where
+    F: Fetcher,
+{
+    fetcher: F,
+    shared_cache: SharedCache<F::Key, F::Value>,
+}
+
+impl<F> WithSharedCache<F>
+where
+    F: Fetcher,
+{
+    pub(crate) fn new(fetcher: F, shared_cache: SharedCache<F::Key, F::Value>) -> Self {
+        WithSharedCache {
+            fetcher,
+            shared_cache,
+        }
+    }
+
+    /// Get a reference to the wrapped [`Fetcher`].
+    pub fn get_ref(&self) -> &F {
+        &self.fetcher
+    }
+
+    /// Get a reference to the [`SharedCache`].
+    pub fn shared_cache(&self) -> &SharedCache<F::Key, F::Value> {
+        &self.shared_cache
+    }
+}
+
+impl<F> Clone for WithSharedCache<F>
+where
+    F: Fetcher + Clone,
+{
+    fn clone(&self) -> Self {
+        WithSharedCache {
+            fetcher: self.fetcher.clone(),
+            shared_cache: self.shared_cache.clone(),
+        }
+    }
+}
+
+impl<F> Fetcher for WithSharedCache<F>
+where
+    F: Fetcher + Sync,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let ttl = self.shared_cache.inner.ttl;
+
+        let missing_keys: Vec<F::Key> = {
+            let entries = self.shared_cache.lock_entries();
+            keys.iter()
+                .filter(|key| match entries.get(key) {
+                    Some((cached_at, value)) if cached_at.elapsed() < ttl => {
+                        values.insert((*key).clone(), value.clone());
+                        false
+                    }
+                    _ => true,
+                })
+                .cloned()
+                .collect()
+        };
+        if missing_keys.is_empty() {
+            return Ok(());
+        }
+
+        tracing::trace!(
+            num_cached = keys.len() - missing_keys.len(),
+            num_missing = missing_keys.len(),
+            "fetching keys missing from shared cache",
+        );
+        let fetched_values =
+            fetch_values(&self.fetcher, &missing_keys, values.batch_info()).await?;
+
+        let now = Instant::now();
+        let mut entries = self.shared_cache.lock_entries();
+        entries.retain(|_, (cached_at, _)| cached_at.elapsed() < ttl);
+        for (key, value) in fetched_values {
+            entries.insert(key.clone(), (now, value.clone()));
+            values.insert(key, value);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+//! A [`Fetcher`] for loading rows from Postgres using [`sqlx`](::sqlx).
+//! Requires the `sqlx-postgres` feature.
+
+use crate::{Cache, Fetcher};
+use ::sqlx::postgres::{PgPool, PgRow, Postgres};
+use ::sqlx::{Encode, Type};
+use std::borrow::Cow;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+/// A [`Fetcher`] that loads a batch of keys from Postgres with a single
+/// query, for the common `SELECT ... WHERE id = ANY($1)` loader.
+///
+/// The query is called with the keys for each batch bound as an array to
+/// `$1`, and each returned row is converted into a key and value with
+/// `map_row`. Any keys without a matching row will be marked as "not found".
+///
+/// # Examples
+///
+/// ```no_run
+/// # use ultra_batch::BatchFetcher;
+/// # use ultra_batch::sqlx::SqlxFetcher;
+/// # use sqlx::Row;
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// let pool = sqlx::PgPool::connect("postgres://localhost/app").await?;
+/// let user_names = SqlxFetcher::new(
+///     pool,
+///     "SELECT id, name FROM users WHERE id = ANY($1)",
+///     |row| Ok((row.try_get::<i64, _>("id")?, row.try_get::<String, _>("name")?)),
+/// );
+/// let batch_fetcher = BatchFetcher::build(user_names).finish();
+///
+/// let user_name = batch_fetcher.load(1).await?;
+/// # Ok(()) }
+/// ```
+pub struct SqlxFetcher<K, V, Fun> {
+    pool: PgPool,
+    query: Cow<'static, str>,
+    map_row: Fun,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, Fun> SqlxFetcher<K, V, Fun>
+where
+    Fun: Fn(&PgRow) -> Result<(K, V), ::sqlx::Error>,
+{
+    /// Create a new `SqlxFetcher` that runs `query` using `pool`, and
+    /// converts each row with `map_row`.
+    pub fn new(pool: PgPool, query: impl Into<Cow<'static, str>>, map_row: Fun) -> Self {
+        SqlxFetcher {
+            pool,
+            query: query.into(),
+            map_row,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, Fun> Clone for SqlxFetcher<K, V, Fun>
+where
+    Fun: Clone,
+{
+    fn clone(&self) -> Self {
+        SqlxFetcher {
+            pool: self.pool.clone(),
+            query: self.query.clone(),
+            map_row: self.map_row.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, Fun> Fetcher for SqlxFetcher<K, V, Fun>
+where
+    K: Clone + Hash + Eq + Send + Sync + 'static,
+    Vec<K>: for<'q> Encode<'q, Postgres> + Type<Postgres>,
+    V: Clone + Send + Sync,
+    Fun: Fn(&PgRow) -> Result<(K, V), ::sqlx::Error> + Sync,
+{
+    type Key = K;
+    type Value = V;
+    type Error = ::sqlx::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        let rows = ::sqlx::query(&self.query)
+            .bind(keys.to_vec())
+            .fetch_all(&self.pool)
+            .await?;
+        for row in &rows {
+            let (key, value) = (self.map_row)(row)?;
+            values.insert(key, value);
+        }
+
+        Ok(())
+    }
+}
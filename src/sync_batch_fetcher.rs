@@ -0,0 +1,384 @@
+use crate::cache::{lock, CacheLookup, CacheLookupState, CacheStore};
+use crate::ordered_map::OrderedMap;
+use crate::{BatchInfo, IntoKey, LoadError, SyncFetcher};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Batches and caches loads from a [`SyncFetcher`] without using async,
+/// for synchronous applications (such as ETL scripts). Each
+/// `SyncBatchFetcher` runs its own worker thread, which queues up keys from
+/// every thread calling [`load`](SyncBatchFetcher::load) and then calls the
+/// [`SyncFetcher`] with each batch. Cloning a `SyncBatchFetcher` is shallow
+/// and will use the same worker thread and cache.
+///
+/// Loading works the same way as with [`BatchFetcher`](crate::BatchFetcher),
+/// except that [`load`](SyncBatchFetcher::load) blocks the current thread
+/// until the value is loaded. Keys that aren't returned by the
+/// [`SyncFetcher`] are marked as "not found", and will not be retried. The
+/// worker thread stops once every clone of the `SyncBatchFetcher` has been
+/// dropped.
+pub struct SyncBatchFetcher<F>
+where
+    F: SyncFetcher,
+{
+    label: Cow<'static, str>,
+    cache_store: CacheStore<F::Key, F::Value>,
+    fetch_request_tx: Sender<SyncFetchRequest<F::Key>>,
+}
+
+impl<F> SyncBatchFetcher<F>
+where
+    F: SyncFetcher + Send + 'static,
+{
+    /// Create a new `SyncBatchFetcher` that uses the given [`SyncFetcher`]
+    /// to retrieve data. Returns a [`SyncBatchFetcherBuilder`], which can be
+    /// used to customize the `SyncBatchFetcher`. Call
+    /// [`.finish()`](SyncBatchFetcherBuilder::finish) to create the
+    /// `SyncBatchFetcher`.
+    pub fn build(fetcher: F) -> SyncBatchFetcherBuilder<F> {
+        SyncBatchFetcherBuilder {
+            fetcher,
+            delay_duration: Duration::from_millis(10),
+            eager_batch_size: Some(100),
+            max_batch_size: None,
+            label: "unlabeled-sync-batch-fetcher".into(),
+        }
+    }
+
+    /// Load the value with the associated key, either by calling the
+    /// [`SyncFetcher`] or by loading the cached value. Blocks the current
+    /// thread until the value is loaded. Returns an error if the value could
+    /// not be loaded or if a value for the given key was not found.
+    #[tracing::instrument(skip_all, fields(sync_batch_fetcher = %self.label))]
+    pub fn load(&self, key: F::Key) -> Result<F::Value, LoadError> {
+        let cache_lookup = self.load_keys(CacheLookup::new([key]))?;
+        cache_lookup.into_value()
+    }
+
+    /// Load all the values for the given keys, either by calling the
+    /// [`SyncFetcher`] or by loading cached values. Blocks the current thread
+    /// until every value is loaded. Values are returned in the same order as
+    /// the input keys. Returns an error if _any_ load fails.
+    #[tracing::instrument(skip_all, fields(sync_batch_fetcher = %self.label, num_keys = tracing::field::Empty))]
+    pub fn load_many<I>(&self, keys: I) -> Result<Vec<F::Value>, LoadError>
+    where
+        I: IntoIterator,
+        I::Item: IntoKey<F::Key>,
+    {
+        let cache_lookup = CacheLookup::new(keys.into_iter().map(IntoKey::into_key));
+        tracing::Span::current().record("num_keys", cache_lookup.len());
+
+        let cache_lookup = self.load_keys(cache_lookup)?;
+        cache_lookup.lookup_result()
+    }
+
+    /// The number of keys in the cache, including keys that were marked as
+    /// "not found".
+    pub fn cached_len(&self) -> usize {
+        self.cache_store.len()
+    }
+
+    fn load_keys(
+        &self,
+        mut cache_lookup: CacheLookup<F::Key, F::Value>,
+    ) -> Result<CacheLookup<F::Key, F::Value>, LoadError> {
+        match cache_lookup.lookup(&self.cache_store) {
+            CacheLookupState::Done => {
+                tracing::debug!(sync_batch_fetcher = %self.label, "all keys have already been looked up");
+                return Ok(cache_lookup);
+            }
+            CacheLookupState::Pending => {}
+        }
+        let pending_keys = cache_lookup.pending_keys();
+
+        tracing::debug!(
+            num_pending_keys = pending_keys.len(),
+            sync_batch_fetcher = %self.label,
+            "sending a batch of keys to fetch",
+        );
+        let fetch_result = Arc::new(FetchResult::default());
+        let fetch_request = SyncFetchRequest {
+            keys: pending_keys,
+            result_tx: FetchResultSender(fetch_result.clone()),
+        };
+        self.fetch_request_tx
+            .send(fetch_request)
+            .map_err(|_| LoadError::SendError)?;
+
+        fetch_result.wait()?;
+        tracing::debug!(sync_batch_fetcher = %self.label, "fetch response returned successfully");
+
+        match cache_lookup.lookup(&self.cache_store) {
+            CacheLookupState::Done => Ok(cache_lookup),
+            CacheLookupState::Pending => {
+                panic!(
+                    "Batch result for sync batch fetcher {} is still pending after result was sent",
+                    self.label,
+                );
+            }
+        }
+    }
+}
+
+impl<F> Clone for SyncBatchFetcher<F>
+where
+    F: SyncFetcher,
+{
+    fn clone(&self) -> Self {
+        SyncBatchFetcher {
+            label: self.label.clone(),
+            cache_store: self.cache_store.clone(),
+            fetch_request_tx: self.fetch_request_tx.clone(),
+        }
+    }
+}
+
+/// Used to configure a new [`SyncBatchFetcher`]. A `SyncBatchFetcherBuilder`
+/// is returned from [`SyncBatchFetcher::build`].
+pub struct SyncBatchFetcherBuilder<F>
+where
+    F: SyncFetcher + Send + 'static,
+{
+    fetcher: F,
+    delay_duration: Duration,
+    eager_batch_size: Option<usize>,
+    max_batch_size: Option<usize>,
+    label: Cow<'static, str>,
+}
+
+impl<F> SyncBatchFetcherBuilder<F>
+where
+    F: SyncFetcher + Send + 'static,
+{
+    /// The maximum amount of time the [`SyncBatchFetcher`] will wait to queue
+    /// up more keys before calling the [`SyncFetcher`].
+    pub fn delay_duration(mut self, delay: Duration) -> Self {
+        self.delay_duration = delay;
+        self
+    }
+
+    /// The maximum number of keys to wait for before eagerly calling the
+    /// [`SyncFetcher`]. See [`BatchFetcherBuilder::eager_batch_size`](crate::BatchFetcherBuilder::eager_batch_size).
+    pub fn eager_batch_size(mut self, eager_batch_size: Option<usize>) -> Self {
+        self.eager_batch_size = eager_batch_size;
+        self
+    }
+
+    /// The maximum number of keys to pass to the [`SyncFetcher`] in a single
+    /// call. See [`BatchFetcherBuilder::max_batch_size`](crate::BatchFetcherBuilder::max_batch_size).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_batch_size` is `Some(0)`.
+    pub fn max_batch_size(mut self, max_batch_size: Option<usize>) -> Self {
+        assert_ne!(max_batch_size, Some(0), "max_batch_size must be non-zero");
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Set a label for the [`SyncBatchFetcher`]. This is only used to improve
+    /// diagnostic messages, such as log messages.
+    pub fn label(mut self, label: impl Into<Cow<'static, str>>) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    /// Create and return a [`SyncBatchFetcher`] with the given options,
+    /// starting its worker thread.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the worker thread could not be spawned.
+    pub fn finish(self) -> SyncBatchFetcher<F> {
+        let cache_store = CacheStore::new();
+        let (fetch_request_tx, fetch_request_rx) = std::sync::mpsc::channel();
+        let label = self.label.clone();
+
+        std::thread::Builder::new()
+            .name("ultra-batch-sync-fetcher".into())
+            .spawn({
+                let cache_store = cache_store.clone();
+                move || self.run(cache_store, fetch_request_rx)
+            })
+            .expect("failed to spawn sync batch fetcher thread");
+
+        SyncBatchFetcher {
+            label,
+            cache_store,
+            fetch_request_tx,
+        }
+    }
+
+    /// Queue up keys and fetch them in batches until every
+    /// [`SyncBatchFetcher`] has been dropped.
+    fn run(
+        self,
+        cache_store: CacheStore<F::Key, F::Value>,
+        fetch_request_rx: Receiver<SyncFetchRequest<F::Key>>,
+    ) {
+        let mut next_batch_id = 0;
+
+        'task: loop {
+            // Wait for some keys to come in
+            tracing::trace!(sync_batch_fetcher = %self.label, "waiting for keys to fetch...");
+            let fetch_request = match fetch_request_rx.recv() {
+                Ok(fetch_request) => fetch_request,
+                Err(_) => {
+                    // Fetch queue closed, so we're done
+                    break 'task;
+                }
+            };
+
+            let batch_deadline = Instant::now() + self.delay_duration;
+            let mut pending_keys: OrderedMap<F::Key, Vec<usize>> = OrderedMap::default();
+            let mut result_txs = vec![];
+            fetch_request.add_to_batch(&mut pending_keys, &mut result_txs);
+
+            // Wait for more keys
+            loop {
+                let eager_batch_size_reached = self
+                    .eager_batch_size
+                    .is_some_and(|eager_batch_size| pending_keys.len() >= eager_batch_size);
+                if eager_batch_size_reached {
+                    tracing::trace!(sync_batch_fetcher = %self.label, num_pending_keys = pending_keys.len(), "batch filled up, ready to fetch keys now");
+                    break;
+                }
+
+                let timeout = batch_deadline.saturating_duration_since(Instant::now());
+                match fetch_request_rx.recv_timeout(timeout) {
+                    Ok(fetch_request) => {
+                        fetch_request.add_to_batch(&mut pending_keys, &mut result_txs);
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        tracing::trace!(sync_batch_fetcher = %self.label, num_pending_keys = pending_keys.len(), "delay reached while waiting for more keys to fetch");
+                        break;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {
+                        tracing::debug!(sync_batch_fetcher = %self.label, num_pending_keys = pending_keys.len(), "fetch channel closed");
+                        break;
+                    }
+                }
+            }
+
+            tracing::trace!(sync_batch_fetcher = %self.label, num_pending_keys = pending_keys.len(), num_pending_requests = result_txs.len(), "fetching keys");
+
+            // Fetch the keys, keeping track of which requests saw an error
+            let mut errors: Vec<Option<Arc<str>>> = vec![None; result_txs.len()];
+            let mut pending_keys: Vec<_> = pending_keys.into_iter().collect();
+            let max_batch_size = self.max_batch_size.unwrap_or(pending_keys.len());
+            while !pending_keys.is_empty() {
+                let rest = pending_keys.split_off(max_batch_size.min(pending_keys.len()));
+                let (batch_keys, batch_requests): (Vec<_>, Vec<_>) =
+                    std::mem::replace(&mut pending_keys, rest)
+                        .into_iter()
+                        .unzip();
+
+                let num_waiters = batch_requests
+                    .iter()
+                    .flatten()
+                    .collect::<HashSet<_>>()
+                    .len();
+                let batch_info = BatchInfo {
+                    batch_id: next_batch_id,
+                    label: self.label.clone(),
+                    num_waiters,
+                };
+                next_batch_id += 1;
+
+                let mut cache = cache_store.as_batch_cache(&batch_info);
+                match self.fetcher.fetch(&batch_keys, &mut cache) {
+                    Ok(()) => {
+                        cache.mark_keys_not_found(batch_keys);
+                    }
+                    Err(error) => {
+                        let error = Arc::<str>::from(error.to_string());
+                        tracing::info!(sync_batch_fetcher = %self.label, "error returned while fetching keys: {error}");
+                        for request_index in batch_requests.into_iter().flatten() {
+                            errors[request_index] = Some(error.clone());
+                        }
+                    }
+                }
+            }
+
+            for (result_tx, error) in result_txs.into_iter().zip(errors) {
+                match error {
+                    Some(error) => result_tx.send(Err(LoadError::FetchError(error))),
+                    None => result_tx.send(Ok(())),
+                }
+            }
+        }
+    }
+}
+
+struct SyncFetchRequest<K> {
+    keys: Vec<K>,
+    result_tx: FetchResultSender,
+}
+
+impl<K> SyncFetchRequest<K>
+where
+    K: Clone + std::hash::Hash + Eq,
+{
+    /// Add this request's keys to a pending batch, tracking the index of this
+    /// request for each key.
+    fn add_to_batch(
+        self,
+        pending_keys: &mut OrderedMap<K, Vec<usize>>,
+        result_txs: &mut Vec<FetchResultSender>,
+    ) {
+        let request_index = result_txs.len();
+        result_txs.push(self.result_tx);
+        for key in self.keys {
+            pending_keys.get_or_default(key).push(request_index);
+        }
+    }
+}
+
+/// The result of a fetch request, which the caller waits on until the
+/// worker thread sets it.
+#[derive(Default)]
+struct FetchResult {
+    result: Mutex<Option<Result<(), LoadError>>>,
+    ready: Condvar,
+}
+
+impl FetchResult {
+    fn set(&self, result: Result<(), LoadError>) {
+        let mut current_result = lock(&self.result);
+        if current_result.is_none() {
+            *current_result = Some(result);
+            self.ready.notify_all();
+        }
+    }
+
+    fn wait(&self) -> Result<(), LoadError> {
+        let result = lock(&self.result);
+        let mut result = self
+            .ready
+            .wait_while(result, |result| result.is_none())
+            .unwrap_or_else(|error| error.into_inner());
+        result
+            .take()
+            .expect("fetch result is missing after waiting")
+    }
+}
+
+/// Used by the worker thread to set the result of a fetch request.
+struct FetchResultSender(Arc<FetchResult>);
+
+impl FetchResultSender {
+    fn send(self, result: Result<(), LoadError>) {
+        self.0.set(result);
+    }
+}
+
+impl Drop for FetchResultSender {
+    fn drop(&mut self) {
+        // Don't leave the caller waiting forever if the worker thread
+        // stopped (e.g. if the fetcher panicked) before sending a result
+        self.0.set(Err(LoadError::SendError));
+    }
+}
@@ -0,0 +1,459 @@
+//! Test doubles for code that uses [`BatchFetcher`](crate::BatchFetcher)
+//! and [`BatchExecutor`](crate::BatchExecutor). Requires the `testing`
+//! feature.
+//!
+//! - [`RecordingFetcher`] and [`RecordingExecutor`] wrap a real fetcher or
+//!   executor and count how often it was called, so tests can check that
+//!   keys were batched or cached.
+//! - [`MockFetcher`] returns canned values, and can be told to fail for
+//!   certain keys.
+//! - [`FailingFetcher`] and [`FailingExecutor`] wrap a real fetcher or
+//!   executor and fail the next few calls, so tests can check how errors
+//!   are handled.
+//!
+//! Cloning any of these returns a handle that shares the same counts and
+//! state, so a clone can be passed to a `BatchFetcher` or `BatchExecutor`
+//! while the test keeps the original to inspect it.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ultra_batch::BatchFetcher;
+//! # use ultra_batch::testing::{MockFetcher, RecordingFetcher};
+//! # #[tokio::main] async fn main() -> anyhow::Result<()> {
+//! let fetcher = RecordingFetcher::new(MockFetcher::new([(1, "Alice"), (2, "Bob")]));
+//! let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+//!
+//! let names = batch_fetcher.load_many(&[1, 2]).await?;
+//! assert_eq!(names, ["Alice", "Bob"]);
+//! batch_fetcher.load(1).await?;
+//!
+//! assert_eq!(fetcher.total_calls(), 1);
+//! assert_eq!(fetcher.calls_for_key(&1), 1);
+//! # Ok(()) }
+//! ```
+
+use crate::cache::lock;
+use crate::{Cache, Executor, Fetcher};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A [`Fetcher`] that wraps another fetcher and records how many times it
+/// was called, both in total and for each key.
+pub struct RecordingFetcher<F>
+where
+    F: Fetcher,
+{
+    fetcher: Arc<F>,
+    total_calls: Arc<AtomicUsize>,
+    calls_per_key: Arc<Mutex<HashMap<F::Key, usize>>>,
+}
+
+impl<F> RecordingFetcher<F>
+where
+    F: Fetcher,
+{
+    /// Wrap `fetcher`, starting with no recorded calls.
+    pub fn new(fetcher: F) -> Self {
+        RecordingFetcher {
+            fetcher: Arc::new(fetcher),
+            total_calls: Default::default(),
+            calls_per_key: Default::default(),
+        }
+    }
+
+    /// Get a reference to the wrapped [`Fetcher`].
+    pub fn get_ref(&self) -> &F {
+        &self.fetcher
+    }
+
+    /// The number of batches fetched.
+    pub fn total_calls(&self) -> usize {
+        self.total_calls.load(Ordering::SeqCst)
+    }
+
+    /// The number of batches fetched that included `key`.
+    pub fn calls_for_key(&self, key: &F::Key) -> usize {
+        lock(&self.calls_per_key)
+            .get(key)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+impl<F> Clone for RecordingFetcher<F>
+where
+    F: Fetcher,
+{
+    fn clone(&self) -> Self {
+        RecordingFetcher {
+            fetcher: self.fetcher.clone(),
+            total_calls: self.total_calls.clone(),
+            calls_per_key: self.calls_per_key.clone(),
+        }
+    }
+}
+
+impl<F> Fetcher for RecordingFetcher<F>
+where
+    F: Fetcher + Send + Sync,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        self.total_calls.fetch_add(1, Ordering::SeqCst);
+        {
+            let mut calls_per_key = lock(&self.calls_per_key);
+            for key in keys {
+                *calls_per_key.entry(key.clone()).or_default() += 1;
+            }
+        }
+
+        self.fetcher.fetch(keys, values).await
+    }
+}
+
+/// An [`Executor`] that wraps another executor and records how many times
+/// it was called, and how many values it executed.
+pub struct RecordingExecutor<E> {
+    executor: Arc<E>,
+    total_calls: Arc<AtomicUsize>,
+    total_values: Arc<AtomicUsize>,
+}
+
+impl<E> RecordingExecutor<E>
+where
+    E: Executor,
+{
+    /// Wrap `executor`, starting with no recorded calls.
+    pub fn new(executor: E) -> Self {
+        RecordingExecutor {
+            executor: Arc::new(executor),
+            total_calls: Default::default(),
+            total_values: Default::default(),
+        }
+    }
+
+    /// Get a reference to the wrapped [`Executor`].
+    pub fn get_ref(&self) -> &E {
+        &self.executor
+    }
+
+    /// The number of batches executed.
+    pub fn total_calls(&self) -> usize {
+        self.total_calls.load(Ordering::SeqCst)
+    }
+
+    /// The number of values executed across all batches.
+    pub fn total_values(&self) -> usize {
+        self.total_values.load(Ordering::SeqCst)
+    }
+}
+
+impl<E> Clone for RecordingExecutor<E> {
+    fn clone(&self) -> Self {
+        RecordingExecutor {
+            executor: self.executor.clone(),
+            total_calls: self.total_calls.clone(),
+            total_values: self.total_values.clone(),
+        }
+    }
+}
+
+impl<E> Executor for RecordingExecutor<E>
+where
+    E: Executor + Send + Sync,
+{
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+
+    async fn execute(&self, values: Vec<Self::Value>) -> Result<Vec<Self::Result>, Self::Error> {
+        self.total_calls.fetch_add(1, Ordering::SeqCst);
+        self.total_values.fetch_add(values.len(), Ordering::SeqCst);
+        self.executor.execute(values).await
+    }
+}
+
+/// The error returned by [`MockFetcher`], [`FailingFetcher`], and
+/// [`FailingExecutor`] when a failure was requested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockError {
+    message: String,
+}
+
+impl MockError {
+    /// Create a new `MockError` with the given message.
+    pub fn new(message: impl Into<String>) -> Self {
+        MockError {
+            message: message.into(),
+        }
+    }
+
+    /// Get the error message.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Display for MockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for MockError {}
+
+/// A [`Fetcher`] that returns canned values. Keys without a value are
+/// reported as not found, and batches that include a key passed to
+/// [`fail_on`](MockFetcher::fail_on) fail with a [`MockError`].
+///
+/// Values can be changed while a test runs. Note that a
+/// [`BatchFetcher`](crate::BatchFetcher) caches values it already loaded.
+pub struct MockFetcher<K, V> {
+    inner: Arc<Mutex<MockFetcherInner<K, V>>>,
+}
+
+struct MockFetcherInner<K, V> {
+    values: HashMap<K, V>,
+    failing_keys: HashSet<K>,
+}
+
+impl<K, V> MockFetcher<K, V>
+where
+    K: Hash + Eq,
+{
+    /// Create a new `MockFetcher` that returns the given values.
+    pub fn new(values: impl IntoIterator<Item = (K, V)>) -> Self {
+        MockFetcher {
+            inner: Arc::new(Mutex::new(MockFetcherInner {
+                values: values.into_iter().collect(),
+                failing_keys: HashSet::new(),
+            })),
+        }
+    }
+
+    /// Set the value returned for `key`, replacing any previous value.
+    pub fn set_value(&self, key: K, value: V) {
+        lock(&self.inner).values.insert(key, value);
+    }
+
+    /// Stop returning a value for `key`, so it's reported as not found.
+    pub fn remove_value(&self, key: &K) {
+        lock(&self.inner).values.remove(key);
+    }
+
+    /// Fail any batch that includes `key`, until
+    /// [`clear_failures`](MockFetcher::clear_failures) is called.
+    pub fn fail_on(&self, key: K) {
+        lock(&self.inner).failing_keys.insert(key);
+    }
+
+    /// Stop failing batches for keys passed to
+    /// [`fail_on`](MockFetcher::fail_on).
+    pub fn clear_failures(&self) {
+        lock(&self.inner).failing_keys.clear();
+    }
+}
+
+impl<K, V> Clone for MockFetcher<K, V> {
+    fn clone(&self) -> Self {
+        MockFetcher {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, V> Fetcher for MockFetcher<K, V>
+where
+    K: Clone + Hash + Eq + Send + Sync + std::fmt::Debug,
+    V: Clone + Send + Sync,
+{
+    type Key = K;
+    type Value = V;
+    type Error = MockError;
+
+    async fn fetch(&self, keys: &[K], values: &mut Cache<'_, K, V>) -> Result<(), Self::Error> {
+        let inner = lock(&self.inner);
+        if let Some(key) = keys.iter().find(|key| inner.failing_keys.contains(key)) {
+            return Err(MockError::new(format!("failed to fetch key {key:?}")));
+        }
+
+        for key in keys {
+            if let Some(value) = inner.values.get(key) {
+                values.insert(key.clone(), value.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Tracks how many upcoming calls should fail, shared by
+/// [`FailingFetcher`] and [`FailingExecutor`].
+#[derive(Clone, Default)]
+struct FailureCounter {
+    remaining: Arc<AtomicUsize>,
+}
+
+impl FailureCounter {
+    fn fail_next(&self, calls: usize) {
+        self.remaining.store(calls, Ordering::SeqCst);
+    }
+
+    fn should_fail(&self) -> bool {
+        self.remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| {
+                remaining.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+/// A [`Fetcher`] that wraps another fetcher, failing the next few calls
+/// with an error built by a closure instead of calling the wrapped fetcher.
+/// Calls succeed until [`fail_next`](FailingFetcher::fail_next) is called.
+pub struct FailingFetcher<F>
+where
+    F: Fetcher,
+{
+    fetcher: Arc<F>,
+    make_error: Arc<dyn Fn() -> F::Error + Send + Sync>,
+    failures: FailureCounter,
+}
+
+impl<F> FailingFetcher<F>
+where
+    F: Fetcher,
+{
+    /// Wrap `fetcher`, using `make_error` to build the error for each
+    /// failed call.
+    pub fn new(fetcher: F, make_error: impl Fn() -> F::Error + Send + Sync + 'static) -> Self {
+        FailingFetcher {
+            fetcher: Arc::new(fetcher),
+            make_error: Arc::new(make_error),
+            failures: FailureCounter::default(),
+        }
+    }
+
+    /// Get a reference to the wrapped [`Fetcher`].
+    pub fn get_ref(&self) -> &F {
+        &self.fetcher
+    }
+
+    /// Fail the next `calls` batches, replacing any previous count.
+    pub fn fail_next(&self, calls: usize) {
+        self.failures.fail_next(calls);
+    }
+}
+
+impl<F> Clone for FailingFetcher<F>
+where
+    F: Fetcher,
+{
+    fn clone(&self) -> Self {
+        FailingFetcher {
+            fetcher: self.fetcher.clone(),
+            make_error: self.make_error.clone(),
+            failures: self.failures.clone(),
+        }
+    }
+}
+
+impl<F> Fetcher for FailingFetcher<F>
+where
+    F: Fetcher + Send + Sync,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        if self.failures.should_fail() {
+            return Err((self.make_error)());
+        }
+
+        self.fetcher.fetch(keys, values).await
+    }
+}
+
+/// An [`Executor`] that wraps another executor, failing the next few calls
+/// with an error built by a closure instead of calling the wrapped
+/// executor. Calls succeed until [`fail_next`](FailingExecutor::fail_next)
+/// is called.
+pub struct FailingExecutor<E>
+where
+    E: Executor,
+{
+    executor: Arc<E>,
+    make_error: Arc<dyn Fn() -> E::Error + Send + Sync>,
+    failures: FailureCounter,
+}
+
+impl<E> FailingExecutor<E>
+where
+    E: Executor,
+{
+    /// Wrap `executor`, using `make_error` to build the error for each
+    /// failed call.
+    pub fn new(executor: E, make_error: impl Fn() -> E::Error + Send + Sync + 'static) -> Self {
+        FailingExecutor {
+            executor: Arc::new(executor),
+            make_error: Arc::new(make_error),
+            failures: FailureCounter::default(),
+        }
+    }
+
+    /// Get a reference to the wrapped [`Executor`].
+    pub fn get_ref(&self) -> &E {
+        &self.executor
+    }
+
+    /// Fail the next `calls` batches, replacing any previous count.
+    pub fn fail_next(&self, calls: usize) {
+        self.failures.fail_next(calls);
+    }
+}
+
+impl<E> Clone for FailingExecutor<E>
+where
+    E: Executor,
+{
+    fn clone(&self) -> Self {
+        FailingExecutor {
+            executor: self.executor.clone(),
+            make_error: self.make_error.clone(),
+            failures: self.failures.clone(),
+        }
+    }
+}
+
+impl<E> Executor for FailingExecutor<E>
+where
+    E: Executor + Send + Sync,
+{
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+
+    async fn execute(&self, values: Vec<Self::Value>) -> Result<Vec<Self::Result>, Self::Error> {
+        if self.failures.should_fail() {
+            return Err((self.make_error)());
+        }
+
+        self.executor.execute(values).await
+    }
+}
@@ -0,0 +1,121 @@
+//! A [`Fetcher`] for batching calls to a gRPC service using a
+//! [`tonic`](::tonic) client. Requires the `tonic` feature.
+
+use crate::{Cache, Fetcher};
+use std::future::Future;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+/// A [`Fetcher`] that calls a gRPC method that takes a request with a
+/// repeated key field, and returns a response containing a value for each
+/// key. This lets calls to another service be batched the same way as
+/// database queries.
+///
+/// For each batch, `call_fn` is called with a clone of the client and the
+/// keys for the batch, and should send the request. Then, `response_fn`
+/// is called with the response message, and should return each key and its
+/// value. Any keys missing from the response will be marked as "not found".
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::BatchFetcher;
+/// # use ultra_batch::tonic::TonicFetcher;
+/// # #[derive(Clone)] struct UserServiceClient;
+/// # struct GetUsersRequest { ids: Vec<u64> }
+/// # struct GetUsersResponse { users: Vec<User> }
+/// # #[derive(Clone)] struct User { id: u64, name: String }
+/// # impl UserServiceClient {
+/// #     async fn get_users(&mut self, request: GetUsersRequest) -> Result<tonic::Response<GetUsersResponse>, tonic::Status> {
+/// #         let users = request.ids.into_iter().map(|id| User { id, name: format!("User {id}") }).collect();
+/// #         Ok(tonic::Response::new(GetUsersResponse { users }))
+/// #     }
+/// # }
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// # let client = UserServiceClient;
+/// let user_fetcher = TonicFetcher::new(
+///     client,
+///     |mut client: UserServiceClient, ids| async move {
+///         client.get_users(GetUsersRequest { ids }).await
+///     },
+///     |response: GetUsersResponse| response.users.into_iter().map(|user| (user.id, user)),
+/// );
+/// let batch_fetcher = BatchFetcher::build(user_fetcher).finish();
+///
+/// let user = batch_fetcher.load(1).await?;
+/// assert_eq!(user.name, "User 1");
+/// # Ok(()) }
+/// ```
+pub struct TonicFetcher<C, CallFn, ResponseFn, K> {
+    client: C,
+    call_fn: CallFn,
+    response_fn: ResponseFn,
+    _marker: PhantomData<fn(K)>,
+}
+
+impl<C, CallFn, ResponseFn, K, Fut, Resp, I, V> TonicFetcher<C, CallFn, ResponseFn, K>
+where
+    CallFn: Fn(C, Vec<K>) -> Fut,
+    Fut: Future<Output = Result<::tonic::Response<Resp>, ::tonic::Status>>,
+    ResponseFn: Fn(Resp) -> I,
+    I: IntoIterator<Item = (K, V)>,
+{
+    /// Create a new `TonicFetcher` that uses `call_fn` to send a request
+    /// with `client` for each batch, and uses `response_fn` to get the
+    /// values from each response.
+    pub fn new(client: C, call_fn: CallFn, response_fn: ResponseFn) -> Self {
+        TonicFetcher {
+            client,
+            call_fn,
+            response_fn,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<C, CallFn, ResponseFn, K> Clone for TonicFetcher<C, CallFn, ResponseFn, K>
+where
+    C: Clone,
+    CallFn: Clone,
+    ResponseFn: Clone,
+{
+    fn clone(&self) -> Self {
+        TonicFetcher {
+            client: self.client.clone(),
+            call_fn: self.call_fn.clone(),
+            response_fn: self.response_fn.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<C, CallFn, ResponseFn, K, Fut, Resp, I, V> Fetcher for TonicFetcher<C, CallFn, ResponseFn, K>
+where
+    C: Clone + Sync,
+    CallFn: Fn(C, Vec<K>) -> Fut + Sync,
+    Fut: Future<Output = Result<::tonic::Response<Resp>, ::tonic::Status>> + Send,
+    ResponseFn: Fn(Resp) -> I + Sync,
+    I: IntoIterator<Item = (K, V)>,
+    K: Clone + Hash + Eq + Send + Sync,
+    V: Clone + Send + Sync,
+{
+    type Key = K;
+    type Value = V;
+    type Error = ::tonic::Status;
+
+    fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let response = (self.call_fn)(self.client.clone(), keys.to_vec());
+        async move {
+            let response = response.await?.into_inner();
+            for (key, value) in (self.response_fn)(response) {
+                values.insert(key, value);
+            }
+
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! [`tower::Service`](tower_service::Service) implementations for
+//! [`BatchFetcher`] and [`BatchExecutor`]. Requires the `tower` feature.
+//!
+//! A [`BatchFetcher`] is a service that takes a key and returns the loaded
+//! value, and a [`BatchExecutor`] is a service that takes a value and returns
+//! its result. Both services are always ready, and calling them works the
+//! same as calling [`BatchFetcher::load`] or [`BatchExecutor::execute`], so
+//! they can be used with existing middleware such as timeouts.
+//!
+//! # Examples
+//!
+//! ```
+//! # use ultra_batch::{BatchFetcher, Fetcher, Cache};
+//! # use tower_service::Service;
+//! # struct FetchUserNames;
+//! # impl Fetcher for FetchUserNames {
+//! #     type Key = u64;
+//! #     type Value = String;
+//! #     type Error = anyhow::Error;
+//! #     async fn fetch(&self, keys: &[u64], values: &mut Cache<'_, u64, String>) -> anyhow::Result<()> {
+//! #         for key in keys {
+//! #             values.insert(*key, format!("User {key}"));
+//! #         }
+//! #         Ok(())
+//! #     }
+//! # }
+//! # #[tokio::main] async fn main() -> anyhow::Result<()> {
+//! let mut service = BatchFetcher::build(FetchUserNames).finish();
+//!
+//! std::future::poll_fn(|cx| service.poll_ready(cx)).await?;
+//! let user_name = service.call(1).await?;
+//! assert_eq!(user_name, "User 1");
+//! # Ok(()) }
+//! ```
+
+use crate::{BatchExecutor, BatchFetcher, ExecuteError, Fetcher, LoadError, TryExecutor};
+use futures_util::future::BoxFuture;
+use std::task::{Context, Poll};
+use tower_service::Service;
+
+impl<F> Service<F::Key> for BatchFetcher<F>
+where
+    F: Fetcher + Send + Sync + 'static,
+{
+    type Response = F::Value;
+    type Error = LoadError;
+    type Future = BoxFuture<'static, Result<F::Value, LoadError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, key: F::Key) -> Self::Future {
+        let batch_fetcher = self.clone();
+        Box::pin(async move { batch_fetcher.load(key).await })
+    }
+}
+
+impl<E> Service<E::Value> for BatchExecutor<E>
+where
+    E: TryExecutor + Send + Sync + 'static,
+{
+    type Response = Option<E::Result>;
+    type Error = ExecuteError;
+    type Future = BoxFuture<'static, Result<Option<E::Result>, ExecuteError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, value: E::Value) -> Self::Future {
+        let batch_executor = self.clone();
+        Box::pin(async move { batch_executor.execute(value).await })
+    }
+}
@@ -0,0 +1,190 @@
+use crate::batch_context::execute_with_context;
+use crate::{BatchContext, ContextExecutor, Executor};
+use std::fmt::Display;
+use std::future::Future;
+
+/// A trait for executing a batch of values inside a transaction, such as a
+/// database transaction. Wrap a `TransactionalExecutor` with [`Transactional`]
+/// to use it with a [`BatchExecutor`](crate::BatchExecutor), which will
+/// execute each batch in its own transaction.
+///
+/// Each batch goes through a begin/execute/commit lifecycle. If executing the
+/// batch fails, the transaction is rolled back. If beginning, executing, or
+/// committing the transaction fails, then every caller waiting on the batch
+/// receives an [`ExecuteError::ExecutorError`](crate::ExecuteError::ExecutorError),
+/// so either all of the values in the batch succeed or none of them do.
+///
+/// # Examples
+///
+/// ```
+/// # use ultra_batch::{BatchExecutor, Transactional, TransactionalExecutor};
+/// # struct User;
+/// # struct DbConnection;
+/// # struct DbTransaction;
+/// # impl DbConnection {
+/// #     async fn begin(&self) -> anyhow::Result<DbTransaction> { Ok(DbTransaction) }
+/// # }
+/// # impl DbTransaction {
+/// #     async fn insert_users(&mut self, users: &[User]) -> anyhow::Result<Vec<u64>> { Ok(vec![]) }
+/// #     async fn commit(self) -> anyhow::Result<()> { Ok(()) }
+/// #     async fn rollback(self) -> anyhow::Result<()> { Ok(()) }
+/// # }
+/// struct UserInserter {
+///     db_conn: DbConnection,
+/// }
+///
+/// impl TransactionalExecutor for UserInserter {
+///     type Value = User;
+///     type Result = u64;
+///     type Error = anyhow::Error;
+///     type Transaction = DbTransaction;
+///
+///     async fn begin(&self) -> anyhow::Result<DbTransaction> {
+///         self.db_conn.begin().await
+///     }
+///
+///     async fn execute(
+///         &self,
+///         transaction: &mut DbTransaction,
+///         values: Vec<User>,
+///     ) -> anyhow::Result<Vec<u64>> {
+///         transaction.insert_users(&values).await
+///     }
+///
+///     async fn commit(&self, transaction: DbTransaction) -> anyhow::Result<()> {
+///         transaction.commit().await
+///     }
+///
+///     async fn rollback(&self, transaction: DbTransaction) -> anyhow::Result<()> {
+///         transaction.rollback().await
+///     }
+/// }
+///
+/// # #[tokio::main] async fn main() -> anyhow::Result<()> {
+/// # let db_conn = DbConnection;
+/// let batch_inserter = BatchExecutor::build(Transactional::new(UserInserter { db_conn })).finish();
+/// # Ok(())
+/// # }
+/// ```
+pub trait TransactionalExecutor {
+    /// The input value provided by the caller to do something.
+    type Value: Send;
+
+    /// The output value returned by the executor back to the caller for each
+    /// input value.
+    type Result: Send;
+
+    /// The error indicating that executing a batch failed.
+    type Error: Display + Send;
+
+    /// The transaction used while executing a single batch.
+    type Transaction: Send;
+
+    /// Start a new transaction for a batch.
+    fn begin(&self) -> impl Future<Output = Result<Self::Transaction, Self::Error>> + Send;
+
+    /// Execute the operation for each value in the batch within the given
+    /// transaction. The returned results follow the same rules as
+    /// [`Executor::execute`].
+    fn execute(
+        &self,
+        transaction: &mut Self::Transaction,
+        values: Vec<Self::Value>,
+    ) -> impl Future<Output = Result<Vec<Self::Result>, Self::Error>> + Send;
+
+    /// Commit the transaction after the batch was executed successfully.
+    fn commit(
+        &self,
+        transaction: Self::Transaction,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Roll back the transaction after executing the batch failed. Errors
+    /// from rolling back are logged, and callers will receive the original
+    /// error from [`execute`](TransactionalExecutor::execute).
+    fn rollback(
+        &self,
+        transaction: Self::Transaction,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// Adapts a [`TransactionalExecutor`] into an [`Executor`], running each
+/// call to [`Executor::execute`] in its own transaction.
+///
+/// `Transactional` is built on [`BatchContext`]: it's a [`BatchContext`]
+/// whose context is a transaction, which is committed if the batch
+/// succeeded and rolled back otherwise, and a [`ContextExecutor`] that
+/// executes each batch within that transaction. Each call to the
+/// [`Executor`] is a separate transaction, in the same way that
+/// [`WithContext`](crate::WithContext) opens a separate context for each
+/// call.
+#[derive(Debug, Clone)]
+pub struct Transactional<E> {
+    executor: E,
+}
+
+impl<E> Transactional<E>
+where
+    E: TransactionalExecutor,
+{
+    /// Wrap a [`TransactionalExecutor`] so it can be used with a
+    /// [`BatchExecutor`](crate::BatchExecutor).
+    pub fn new(executor: E) -> Self {
+        Transactional { executor }
+    }
+
+    /// Get a reference to the wrapped [`TransactionalExecutor`].
+    pub fn get_ref(&self) -> &E {
+        &self.executor
+    }
+}
+
+impl<E> Executor for Transactional<E>
+where
+    E: TransactionalExecutor + Sync,
+{
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+
+    async fn execute(&self, values: Vec<Self::Value>) -> Result<Vec<Self::Result>, Self::Error> {
+        execute_with_context(self, self, values).await
+    }
+}
+
+impl<E> BatchContext for Transactional<E>
+where
+    E: TransactionalExecutor + Sync,
+{
+    type Context = E::Transaction;
+    type Error = E::Error;
+
+    async fn open(&self) -> Result<Self::Context, Self::Error> {
+        self.executor.begin().await
+    }
+
+    async fn finish(&self, transaction: Self::Context, succeeded: bool) -> Result<(), Self::Error> {
+        if succeeded {
+            self.executor.commit(transaction).await
+        } else {
+            self.executor.rollback(transaction).await
+        }
+    }
+}
+
+impl<E> ContextExecutor for Transactional<E>
+where
+    E: TransactionalExecutor + Sync,
+{
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+    type Context = E::Transaction;
+
+    async fn execute(
+        &self,
+        transaction: &mut Self::Context,
+        values: Vec<Self::Value>,
+    ) -> Result<Vec<Self::Result>, Self::Error> {
+        self.executor.execute(transaction, values).await
+    }
+}
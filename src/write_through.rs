@@ -0,0 +1,87 @@
+use crate::{BatchFetcher, ExecutorLayer, LocalFetcher, TryExecutor};
+use std::sync::Arc;
+
+/// A [`TryExecutor`] that inserts each successful result into a
+/// [`BatchFetcher`]'s cache, so loading the written value afterwards
+/// doesn't need to fetch it again. Created with [`BatchExecutorBuilder::write_through`](crate::BatchExecutorBuilder::write_through).
+///
+/// Note that results are cached after the batch is executed, so a batch
+/// that was already fetching the same key (from before the write) may
+/// still finish afterwards and replace the written value with the stale
+/// value it fetched. This is the same race as with [`BatchFetcher::invalidate`].
+pub struct WriteThrough<E, F>
+where
+    E: TryExecutor,
+    F: LocalFetcher,
+{
+    executor: E,
+    batch_fetcher: BatchFetcher<F>,
+    cache_entry: CacheEntryFn<E::Result, F::Key, F::Value>,
+}
+
+type CacheEntryFn<R, K, V> = Arc<dyn Fn(&R) -> (K, V) + Send + Sync>;
+
+impl<E, F> WriteThrough<E, F>
+where
+    E: TryExecutor,
+    F: LocalFetcher,
+{
+    /// Get a reference to the wrapped executor.
+    pub fn get_ref(&self) -> &E {
+        &self.executor
+    }
+
+    /// Get a reference to the [`BatchFetcher`] whose cache is updated.
+    pub fn batch_fetcher(&self) -> &BatchFetcher<F> {
+        &self.batch_fetcher
+    }
+}
+
+impl<E, F> TryExecutor for WriteThrough<E, F>
+where
+    E: TryExecutor + Sync,
+    F: LocalFetcher + 'static,
+{
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+
+    async fn try_execute(
+        &self,
+        values: Vec<Self::Value>,
+    ) -> Result<Vec<Result<Self::Result, Self::Error>>, Self::Error> {
+        let results = self.executor.try_execute(values).await?;
+
+        for result in results.iter().flatten() {
+            let (key, value) = (self.cache_entry)(result);
+            self.batch_fetcher.insert_cached(key, value);
+        }
+
+        Ok(results)
+    }
+}
+
+/// The [`ExecutorLayer`] used by [`BatchExecutorBuilder::write_through`](crate::BatchExecutorBuilder::write_through).
+pub(crate) struct WriteThroughLayer<R, F>
+where
+    F: LocalFetcher,
+{
+    pub(crate) batch_fetcher: BatchFetcher<F>,
+    pub(crate) cache_entry: CacheEntryFn<R, F::Key, F::Value>,
+}
+
+impl<E, F> ExecutorLayer<E> for WriteThroughLayer<E::Result, F>
+where
+    E: TryExecutor,
+    F: LocalFetcher,
+{
+    type Executor = WriteThrough<E, F>;
+
+    fn layer(&self, executor: E) -> WriteThrough<E, F> {
+        WriteThrough {
+            executor,
+            batch_fetcher: self.batch_fetcher.clone(),
+            cache_entry: self.cache_entry.clone(),
+        }
+    }
+}
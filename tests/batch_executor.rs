@@ -1,6 +1,13 @@
+use futures_util::StreamExt;
+use std::collections::HashMap;
 use std::sync::{atomic::AtomicUsize, Arc, RwLock};
 
-use ultra_batch::{BatchExecutor, ExecuteError, Executor};
+use ultra_batch::testing::{RecordingExecutor, RecordingFetcher};
+use ultra_batch::{
+    BatchContext, BatchExecutor, BatchScheduler, ContextExecutor, ExecuteError, Executor,
+    ExecutorLayer, FnExecutor, GroupedExecutor, Keyed, KeyedExecutor, PendingBatch, Schedule,
+    Transactional, TransactionalExecutor, TryExecutor,
+};
 
 mod db;
 mod stubs;
@@ -110,6 +117,28 @@ async fn test_execute_many_ordering() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_execute_many_zipped() -> anyhow::Result<()> {
+    // Stops returning results after the first zero
+    let batch_executor = BatchExecutor::from_fn(|values: Vec<u64>| async move {
+        let results: Vec<u64> = values
+            .into_iter()
+            .take_while(|value| *value != 0)
+            .map(|value| value * 2)
+            .collect();
+        anyhow::Ok(results)
+    })
+    .finish();
+
+    let results = batch_executor.execute_many_zipped(vec![1, 2, 0, 3]).await?;
+    assert_eq!(results, [(1, Some(2)), (2, Some(4)), (0, None), (3, None)]);
+
+    let results = batch_executor.execute_many_zipped(vec![]).await?;
+    assert_eq!(results, []);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_execute_big_batch() -> anyhow::Result<()> {
     let db = db::Database::fake();
@@ -117,7 +146,7 @@ async fn test_execute_big_batch() -> anyhow::Result<()> {
 
     let inserts: Vec<_> = (0..1000).map(|_| db::User::fake()).collect();
 
-    let executor = stubs::ObserveExecutor::new(db::InsertUsers { db: db.clone() });
+    let executor = RecordingExecutor::new(db::InsertUsers { db: db.clone() });
     let batch_executor = BatchExecutor::build(executor.clone())
         .eager_batch_size(Some(50))
         .finish();
@@ -130,12 +159,515 @@ async fn test_execute_big_batch() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_execute_max_batch_size() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let db = Arc::new(RwLock::new(db));
+
+    let inserts: Vec<_> = (0..250).map(|_| db::User::fake()).collect();
+    let insert_ids: Vec<_> = inserts.iter().map(|user| user.id).collect();
+
+    let executor = RecordingExecutor::new(db::InsertUsers { db: db.clone() });
+    let batch_executor = BatchExecutor::build(executor.clone())
+        .max_batch_size(Some(100))
+        .finish();
+    let results = batch_executor.execute_many(inserts).await?;
+    let result_ids: Vec<_> = results.into_iter().flatten().collect();
+
+    assert_eq!(result_ids, insert_ids);
+    assert_eq!(executor.total_calls(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_max_batch_size_error() -> anyhow::Result<()> {
+    // Executor that fails any batch containing a zero
+    struct FailOnZeroExecutor;
+
+    impl Executor for FailOnZeroExecutor {
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+
+        async fn execute(&self, values: Vec<u64>) -> Result<Vec<u64>, Self::Error> {
+            anyhow::ensure!(!values.contains(&0), "uh oh");
+            Ok(values)
+        }
+    }
+
+    let batch_executor = BatchExecutor::build(FailOnZeroExecutor)
+        .max_batch_size(Some(2))
+        .finish();
+
+    let (first, second, third) = tokio::join!(
+        batch_executor.execute_many(vec![1, 2]),
+        batch_executor.execute_many(vec![0, 3]),
+        batch_executor.execute_many(vec![4, 5]),
+    );
+    assert_eq!(first?, [1, 2]);
+    assert!(matches!(second, Err(ExecuteError::ExecutorError(_))));
+    assert_eq!(third?, [4, 5]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_max_concurrent_chunks() -> anyhow::Result<()> {
+    // Executor that tracks how many calls are running at once. Earlier
+    // chunks take longer, so they finish out of order
+    #[derive(Clone, Default)]
+    struct ConcurrencyExecutor {
+        running: Arc<AtomicUsize>,
+        max_running: Arc<AtomicUsize>,
+    }
+
+    impl Executor for ConcurrencyExecutor {
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+
+        async fn execute(&self, values: Vec<u64>) -> Result<Vec<u64>, Self::Error> {
+            let running = self
+                .running
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+                + 1;
+            self.max_running
+                .fetch_max(running, std::sync::atomic::Ordering::SeqCst);
+
+            let delay = 50 - values[0];
+            tokio::time::sleep(tokio::time::Duration::from_millis(delay)).await;
+
+            self.running
+                .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(values.into_iter().map(|value| value * 2).collect())
+        }
+    }
+
+    let executor = ConcurrencyExecutor::default();
+    let batch_executor = BatchExecutor::build(executor.clone())
+        .max_batch_size(Some(4))
+        .max_concurrent_chunks(3)
+        .finish();
+
+    let results = batch_executor.execute_many((0..40).collect()).await?;
+    assert_eq!(results, (0..40).map(|value| value * 2).collect::<Vec<_>>());
+    assert_eq!(
+        executor
+            .max_running
+            .load(std::sync::atomic::Ordering::SeqCst),
+        3
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_max_concurrent_batches() -> anyhow::Result<()> {
+    // Executor that waits to be notified before executing the value 0
+    struct GatedExecutor {
+        gate: Arc<tokio::sync::Notify>,
+    }
+
+    impl Executor for GatedExecutor {
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+
+        async fn execute(&self, values: Vec<u64>) -> Result<Vec<u64>, Self::Error> {
+            if values.contains(&0) {
+                self.gate.notified().await;
+            }
+
+            Ok(values)
+        }
+    }
+
+    let gate = Arc::new(tokio::sync::Notify::new());
+    let batch_executor = BatchExecutor::build(GatedExecutor { gate: gate.clone() })
+        .max_concurrent_batches(2)
+        .finish();
+
+    let slow_task = tokio::spawn({
+        let batch_executor = batch_executor.clone();
+        async move { batch_executor.execute(0).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    // The slow batch is still in-flight, but another batch can be executed
+    let result = tokio::time::timeout(
+        tokio::time::Duration::from_secs(1),
+        batch_executor.execute(1),
+    )
+    .await??;
+    assert_eq!(result, Some(1));
+    assert!(!slow_task.is_finished());
+
+    gate.notify_one();
+    assert_eq!(slow_task.await??, Some(0));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_max_pending_values() -> anyhow::Result<()> {
+    // Executor that waits to be notified before executing the value 0
+    struct GatedExecutor {
+        gate: Arc<tokio::sync::Notify>,
+    }
+
+    impl Executor for GatedExecutor {
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+
+        async fn execute(&self, values: Vec<u64>) -> Result<Vec<u64>, Self::Error> {
+            if values.contains(&0) {
+                self.gate.notified().await;
+            }
+
+            Ok(values)
+        }
+    }
+
+    let gate = Arc::new(tokio::sync::Notify::new());
+    let batch_executor = BatchExecutor::build(GatedExecutor { gate: gate.clone() })
+        .max_concurrent_batches(2)
+        .max_pending_values(Some(2))
+        .finish();
+
+    let slow_task = tokio::spawn({
+        let batch_executor = batch_executor.clone();
+        async move { batch_executor.execute_many(vec![0, 1]).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    // The limit is reached, so another value waits for the slow batch
+    let mut waiting_task = tokio::spawn({
+        let batch_executor = batch_executor.clone();
+        async move { batch_executor.execute(2).await }
+    });
+    let result =
+        tokio::time::timeout(tokio::time::Duration::from_millis(100), &mut waiting_task).await;
+    assert!(result.is_err());
+
+    gate.notify_one();
+    assert_eq!(slow_task.await??, vec![0, 1]);
+    assert_eq!(waiting_task.await??, Some(2));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_flush() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let db = Arc::new(RwLock::new(db));
+
+    let executor = RecordingExecutor::new(db::InsertUsers { db: db.clone() });
+    let batch_executor = BatchExecutor::build(executor.clone())
+        .delay_duration(tokio::time::Duration::from_secs(60))
+        .eager_batch_size(None)
+        .finish();
+
+    // Flushing with nothing queued shouldn't affect the next batch
+    batch_executor.flush().await;
+
+    let batch_task = tokio::spawn({
+        let batch_executor = batch_executor.clone();
+        let inserts: Vec<_> = (0..10).map(|_| db::User::fake()).collect();
+        async move { batch_executor.execute_many(inserts).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    assert_eq!(executor.total_calls(), 0);
+
+    batch_executor.flush().await;
+    let results = tokio::time::timeout(tokio::time::Duration::from_secs(1), batch_task).await???;
+    assert_eq!(results.len(), 10);
+    assert_eq!(executor.total_calls(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_warns_on_slow_batches() -> anyhow::Result<()> {
+    let traces = stubs::RecordTraces::default();
+    let _guard = tracing::subscriber::set_default(traces.clone());
+
+    let batch_executor = BatchExecutor::from_fn(|values: Vec<u64>| async move {
+        if values.contains(&0) {
+            tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+        }
+        anyhow::Ok(values)
+    })
+    .label("slow-executor")
+    .warn_if_slower_than(tokio::time::Duration::from_millis(20))
+    .finish();
+
+    batch_executor.execute_many(vec![1, 2]).await?;
+    assert!(traces
+        .events("batch took longer than the slow batch threshold")
+        .is_empty());
+
+    batch_executor.execute_many(vec![0, 3]).await?;
+    let warnings = traces.events("batch took longer than the slow batch threshold");
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0]["batch_executor"], "slow-executor");
+    assert_eq!(warnings[0]["num_values"], "2");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_metrics() -> anyhow::Result<()> {
+    let metrics = stubs::RecordMetrics::default();
+    let batch_executor = BatchExecutor::from_fn(|values: Vec<u64>| async move {
+        if values.contains(&0) {
+            anyhow::bail!("can't execute 0");
+        }
+        let results: Vec<u64> = values.into_iter().map(|value| value * 2).collect();
+        anyhow::Ok(results)
+    })
+    .max_batch_size(Some(2))
+    .metrics(metrics.clone())
+    .finish();
+
+    // The second batch has values from both requests
+    let (many_results, result) = tokio::join!(
+        batch_executor.execute_many(vec![1, 2, 3]),
+        batch_executor.execute(4),
+    );
+    assert_eq!(many_results?, vec![2, 4, 6]);
+    assert_eq!(result?, Some(8));
+    assert_eq!(metrics.dispatched(), vec![(2, 1), (2, 2)]);
+    assert_eq!(metrics.completed(), vec![(2, Ok(())), (2, Ok(()))]);
+    assert_eq!(metrics.queue_depths(), vec![3, 4, 0]);
+
+    assert!(batch_executor.execute(0).await.is_err());
+    assert_eq!(
+        metrics.completed().last(),
+        Some(&(1, Err("can't execute 0".to_string())))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_max_wait_duration() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let db = Arc::new(RwLock::new(db));
+
+    let executor = RecordingExecutor::new(db::InsertUsers { db: db.clone() });
+    let batch_executor = BatchExecutor::build(executor.clone())
+        .delay_duration(tokio::time::Duration::from_millis(100))
+        .max_wait_duration(Some(tokio::time::Duration::from_millis(150)))
+        .eager_batch_size(None)
+        .finish();
+
+    // Values trickle in faster than the delay, which would otherwise keep
+    // the first batch waiting until the last value
+    let started_at = tokio::time::Instant::now();
+    let first_task = tokio::spawn({
+        let batch_executor = batch_executor.clone();
+        async move {
+            batch_executor.execute(db::User::fake()).await?;
+            anyhow::Ok(started_at.elapsed())
+        }
+    });
+    for _ in 0..15 {
+        tokio::time::sleep(tokio::time::Duration::from_millis(30)).await;
+        tokio::spawn({
+            let batch_executor = batch_executor.clone();
+            async move { batch_executor.execute(db::User::fake()).await }
+        });
+    }
+
+    let first_elapsed = first_task.await??;
+    assert!(first_elapsed < tokio::time::Duration::from_millis(400));
+    assert!(executor.total_calls() >= 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_submit() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let db = Arc::new(RwLock::new(db));
+
+    let executor = RecordingExecutor::new(db::InsertUsers { db: db.clone() });
+    let batch_executor = BatchExecutor::build(executor.clone())
+        .delay_duration(tokio::time::Duration::from_secs(60))
+        .eager_batch_size(None)
+        .finish();
+
+    // Submitting returns once each value is queued, without waiting for
+    // the batch to be executed
+    let inserts: Vec<_> = (0..10).map(|_| db::User::fake()).collect();
+    for insert in &inserts {
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(1),
+            batch_executor.submit(insert.clone()),
+        )
+        .await??;
+    }
+    assert_eq!(executor.total_calls(), 0);
+
+    batch_executor.shutdown().await;
+    assert_eq!(executor.total_calls(), 1);
+    let db = db.read().unwrap();
+    assert!(inserts.iter().all(|user| db.users.contains_key(&user.id)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_submit_then_drop() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let db = Arc::new(RwLock::new(db));
+
+    let executor = RecordingExecutor::new(db::InsertUsers { db: db.clone() });
+    let batch_executor = BatchExecutor::build(executor.clone())
+        .delay_duration(tokio::time::Duration::from_secs(60))
+        .eager_batch_size(None)
+        .finish();
+
+    let inserts: Vec<_> = (0..10).map(|_| db::User::fake()).collect();
+    for insert in &inserts {
+        batch_executor.submit(insert.clone()).await?;
+    }
+
+    // Dropping the executor still executes the values that were submitted
+    drop(batch_executor);
+    tokio::time::timeout(tokio::time::Duration::from_secs(1), async {
+        while executor.total_calls() == 0 {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    assert_eq!(executor.total_calls(), 1);
+    let db = db.read().unwrap();
+    assert!(inserts.iter().all(|user| db.users.contains_key(&user.id)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_submit_max_pending_values() -> anyhow::Result<()> {
+    // Executor that waits to be notified before executing the value 0
+    struct GatedExecutor {
+        gate: Arc<tokio::sync::Notify>,
+    }
+
+    impl Executor for GatedExecutor {
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+
+        async fn execute(&self, values: Vec<u64>) -> Result<Vec<u64>, Self::Error> {
+            if values.contains(&0) {
+                self.gate.notified().await;
+            }
+
+            Ok(values)
+        }
+    }
+
+    let gate = Arc::new(tokio::sync::Notify::new());
+    let batch_executor = BatchExecutor::build(GatedExecutor { gate: gate.clone() })
+        .eager_batch_size(Some(2))
+        .max_concurrent_batches(2)
+        .max_pending_values(Some(2))
+        .finish();
+
+    batch_executor.submit(0).await?;
+    batch_executor.submit(1).await?;
+
+    // The submitted values are still pending while their batch runs, so
+    // another value waits for room
+    let mut waiting_task = tokio::spawn({
+        let batch_executor = batch_executor.clone();
+        async move { batch_executor.submit(2).await }
+    });
+    let result =
+        tokio::time::timeout(tokio::time::Duration::from_millis(100), &mut waiting_task).await;
+    assert!(result.is_err());
+
+    gate.notify_one();
+    tokio::time::timeout(tokio::time::Duration::from_secs(1), waiting_task).await???;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_shutdown() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let db = Arc::new(RwLock::new(db));
+
+    let executor = RecordingExecutor::new(db::InsertUsers { db: db.clone() });
+    let batch_executor = BatchExecutor::build(executor.clone())
+        .delay_duration(tokio::time::Duration::from_secs(60))
+        .eager_batch_size(None)
+        .finish();
+
+    let inserts: Vec<_> = (0..10).map(|_| db::User::fake()).collect();
+    let batch_task = tokio::spawn({
+        let batch_executor = batch_executor.clone();
+        let inserts = inserts.clone();
+        async move { batch_executor.execute_many(inserts).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    assert_eq!(executor.total_calls(), 0);
+
+    // Shutting down executes the queued values before returning
+    tokio::time::timeout(
+        tokio::time::Duration::from_secs(1),
+        batch_executor.shutdown(),
+    )
+    .await?;
+    assert_eq!(executor.total_calls(), 1);
+    {
+        let db = db.read().unwrap();
+        assert!(inserts.iter().all(|user| db.users.contains_key(&user.id)));
+    }
+    let results = batch_task.await??;
+    assert_eq!(results.len(), 10);
+
+    let result = batch_executor.execute(db::User::fake()).await;
+    assert!(matches!(result, Err(ExecuteError::SendError)));
+    assert_eq!(executor.total_calls(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_stream() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let db = Arc::new(RwLock::new(db));
+
+    let inserts: Vec<_> = (0..250).map(|_| db::User::fake()).collect();
+    let insert_ids: Vec<_> = inserts.iter().map(|user| Some(Some(user.id))).collect();
+
+    let executor = RecordingExecutor::new(db::InsertUsers { db: db.clone() });
+    let batch_executor = BatchExecutor::build(executor.clone())
+        .eager_batch_size(Some(100))
+        .finish();
+    let results: Vec<_> = batch_executor
+        .execute_stream(futures_util::stream::iter(inserts), 100)
+        .collect()
+        .await;
+    let result_ids = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+
+    assert_eq!(result_ids, insert_ids);
+    assert_eq!(executor.total_calls(), 3);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_execute_small_awaited_batches() -> anyhow::Result<()> {
     let db = db::Database::fake();
     let db = Arc::new(RwLock::new(db));
 
-    let executor = stubs::ObserveExecutor::new(db::InsertUsers { db: db.clone() });
+    let executor = RecordingExecutor::new(db::InsertUsers { db: db.clone() });
     let batch_executor = BatchExecutor::build(executor.clone())
         .eager_batch_size(Some(50))
         .finish();
@@ -162,7 +694,7 @@ async fn test_execute_merged_batches() -> anyhow::Result<()> {
     let db = db::Database::fake();
     let db = Arc::new(RwLock::new(db));
 
-    let executor = stubs::ObserveExecutor::new(db::InsertUsers { db: db.clone() });
+    let executor = RecordingExecutor::new(db::InsertUsers { db: db.clone() });
     let batch_executor = BatchExecutor::build(executor.clone())
         .eager_batch_size(Some(50))
         .finish();
@@ -215,7 +747,7 @@ async fn test_execute_merged_batches_returning_none() -> anyhow::Result<()> {
     let db = db::Database::fake();
     let db = Arc::new(RwLock::new(db));
 
-    let executor = stubs::ObserveExecutor::new(stubs::ExecutorReturnsEmpty(db::InsertUsers {
+    let executor = RecordingExecutor::new(stubs::ExecutorReturnsEmpty(db::InsertUsers {
         db: db.clone(),
     }));
     let batch_executor = BatchExecutor::build(executor.clone())
@@ -285,7 +817,7 @@ async fn test_execute_merged_batches_returning_error() -> anyhow::Result<()> {
         }
     }
 
-    let executor = stubs::ObserveExecutor::new(ErrorExecutor);
+    let executor = RecordingExecutor::new(ErrorExecutor);
     let batch_executor = BatchExecutor::build(executor.clone())
         .eager_batch_size(Some(50))
         .finish();
@@ -334,3 +866,834 @@ async fn test_execute_merged_batches_returning_error() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_execute_custom_scheduler() -> anyhow::Result<()> {
+    // Scheduler that waits for the next tick until 20 values are queued
+    struct TwentyValueScheduler;
+
+    impl BatchScheduler for TwentyValueScheduler {
+        fn schedule(&self, batch: &PendingBatch) -> Schedule {
+            if batch.len >= 20 {
+                Schedule::DispatchNow
+            } else {
+                Schedule::WaitForNextTick
+            }
+        }
+    }
+
+    let db = db::Database::fake();
+    let db = Arc::new(RwLock::new(db));
+
+    let executor = RecordingExecutor::new(db::InsertUsers { db: db.clone() });
+    let batch_executor = BatchExecutor::build(executor.clone())
+        .scheduler(TwentyValueScheduler)
+        .finish();
+
+    let inserts: Vec<_> = (0..30).map(|_| db::User::fake()).collect();
+    let (first, second, third) = tokio::join!(
+        batch_executor.execute_many(inserts[0..10].to_vec()),
+        batch_executor.execute_many(inserts[10..20].to_vec()),
+        batch_executor.execute_many(inserts[20..30].to_vec()),
+    );
+    assert_eq!(first?.len(), 10);
+    assert_eq!(second?.len(), 10);
+    assert_eq!(third?.len(), 10);
+
+    // The first two requests fill up a batch, and the last request is
+    // dispatched on its own once nothing else is queued
+    assert_eq!(executor.total_calls(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_deduplicate_values() -> anyhow::Result<()> {
+    // Executor that doubles each value and records the values it was given
+    #[derive(Default)]
+    struct DoubleExecutor {
+        executed_values: Arc<RwLock<Vec<u64>>>,
+    }
+
+    impl Executor for DoubleExecutor {
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+
+        async fn execute(&self, values: Vec<u64>) -> Result<Vec<u64>, Self::Error> {
+            self.executed_values
+                .write()
+                .unwrap()
+                .extend(values.iter().copied());
+            Ok(values.into_iter().map(|value| value * 2).collect())
+        }
+    }
+
+    let executor = DoubleExecutor::default();
+    let executed_values = executor.executed_values.clone();
+    let batch_executor = BatchExecutor::build(executor).deduplicate_values().finish();
+
+    let (first, second) = tokio::join!(
+        batch_executor.execute_many(vec![1, 2, 1, 3]),
+        batch_executor.execute_many(vec![3, 4, 2]),
+    );
+    assert_eq!(first?, [2, 4, 2, 6]);
+    assert_eq!(second?, [6, 8, 4]);
+
+    let executed_values = executed_values.read().unwrap();
+    assert_eq!(*executed_values, [1, 2, 3, 4]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_try_execute_item_errors() -> anyhow::Result<()> {
+    // Executor that fails each zero value, but succeeds for everything else
+    struct FailZeroesExecutor;
+
+    impl TryExecutor for FailZeroesExecutor {
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+
+        async fn try_execute(
+            &self,
+            values: Vec<u64>,
+        ) -> Result<Vec<Result<u64, Self::Error>>, Self::Error> {
+            let results = values
+                .into_iter()
+                .map(|value| {
+                    anyhow::ensure!(value != 0, "uh oh");
+                    Ok(value)
+                })
+                .collect();
+            Ok(results)
+        }
+    }
+
+    let batch_executor = BatchExecutor::build(FailZeroesExecutor).finish();
+
+    let (first, second, third) = tokio::join!(
+        batch_executor.execute(1),
+        batch_executor.execute_many(vec![2, 0, 3]),
+        batch_executor.execute_many(vec![4, 5]),
+    );
+    assert_eq!(first?, Some(1));
+    assert!(matches!(second, Err(ExecuteError::ExecutorError(_))));
+    assert_eq!(third?, [4, 5]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_transactional() -> anyhow::Result<()> {
+    // Executor that records each step of the transaction lifecycle, and
+    // fails any batch containing a zero
+    #[derive(Default)]
+    struct RecordingExecutor {
+        events: Arc<RwLock<Vec<&'static str>>>,
+    }
+
+    impl TransactionalExecutor for RecordingExecutor {
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+        type Transaction = ();
+
+        async fn begin(&self) -> anyhow::Result<()> {
+            self.events.write().unwrap().push("begin");
+            Ok(())
+        }
+
+        async fn execute(
+            &self,
+            _transaction: &mut (),
+            values: Vec<u64>,
+        ) -> anyhow::Result<Vec<u64>> {
+            anyhow::ensure!(!values.contains(&0), "uh oh");
+            Ok(values)
+        }
+
+        async fn commit(&self, _transaction: ()) -> anyhow::Result<()> {
+            self.events.write().unwrap().push("commit");
+            Ok(())
+        }
+
+        async fn rollback(&self, _transaction: ()) -> anyhow::Result<()> {
+            self.events.write().unwrap().push("rollback");
+            Ok(())
+        }
+    }
+
+    let executor = RecordingExecutor::default();
+    let events = executor.events.clone();
+    let batch_executor = BatchExecutor::build(Transactional::new(executor)).finish();
+
+    let (first, second) = tokio::join!(
+        batch_executor.execute_many(vec![1, 2]),
+        batch_executor.execute_many(vec![3, 4]),
+    );
+    assert_eq!(first?, [1, 2]);
+    assert_eq!(second?, [3, 4]);
+
+    // Every caller fails if any value in the batch fails
+    let (first, second) = tokio::join!(
+        batch_executor.execute_many(vec![1, 2]),
+        batch_executor.execute_many(vec![0, 3]),
+    );
+    assert!(matches!(first, Err(ExecuteError::ExecutorError(_))));
+    assert!(matches!(second, Err(ExecuteError::ExecutorError(_))));
+
+    let events = events.read().unwrap();
+    assert_eq!(*events, ["begin", "commit", "begin", "rollback"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_with_context() -> anyhow::Result<()> {
+    // Context that numbers each batch, and records when each one is
+    // opened and finished
+    #[derive(Default)]
+    struct NumberBatches {
+        events: Arc<RwLock<Vec<(&'static str, u64)>>>,
+        next_batch: AtomicUsize,
+    }
+
+    impl BatchContext for NumberBatches {
+        type Context = u64;
+        type Error = anyhow::Error;
+
+        async fn open(&self) -> anyhow::Result<u64> {
+            let batch = self
+                .next_batch
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst) as u64;
+            self.events.write().unwrap().push(("open", batch));
+            Ok(batch)
+        }
+
+        async fn finish(&self, batch: u64, succeeded: bool) -> anyhow::Result<()> {
+            let event = if succeeded { "succeeded" } else { "failed" };
+            self.events.write().unwrap().push((event, batch));
+            Ok(())
+        }
+    }
+
+    // Executor that tags each value with its batch number, and fails any
+    // batch containing a zero
+    struct TagValues;
+
+    impl ContextExecutor for TagValues {
+        type Value = u64;
+        type Result = (u64, u64);
+        type Error = anyhow::Error;
+        type Context = u64;
+
+        async fn execute(
+            &self,
+            batch: &mut u64,
+            values: Vec<u64>,
+        ) -> anyhow::Result<Vec<(u64, u64)>> {
+            anyhow::ensure!(!values.contains(&0), "uh oh");
+            Ok(values.into_iter().map(|value| (*batch, value)).collect())
+        }
+    }
+
+    let context = NumberBatches::default();
+    let events = context.events.clone();
+    let batch_executor = BatchExecutor::build_with_context(TagValues, context).finish();
+
+    let (first, second) = tokio::join!(
+        batch_executor.execute_many(vec![1, 2]),
+        batch_executor.execute_many(vec![3]),
+    );
+    assert_eq!(first?, [(0, 1), (0, 2)]);
+    assert_eq!(second?, [(0, 3)]);
+
+    let result = batch_executor.execute(0).await;
+    assert!(matches!(result, Err(ExecuteError::ExecutorError(_))));
+
+    let events = events.read().unwrap();
+    assert_eq!(
+        *events,
+        [("open", 0), ("succeeded", 0), ("open", 1), ("failed", 1)]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_grouped() -> anyhow::Result<()> {
+    // Executor that records the groups it received, and sums each group
+    #[derive(Clone, Default)]
+    struct SumGroups {
+        batches: Arc<RwLock<Vec<Vec<Vec<u64>>>>>,
+    }
+
+    impl GroupedExecutor for SumGroups {
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+
+        async fn execute(&self, groups: Vec<Vec<u64>>) -> anyhow::Result<Vec<Vec<u64>>> {
+            self.batches.write().unwrap().push(groups.clone());
+            Ok(groups
+                .into_iter()
+                .map(|group| vec![group.iter().sum()])
+                .collect())
+        }
+    }
+
+    let executor = SumGroups::default();
+    let batch_executor = BatchExecutor::build_grouped(executor.clone()).finish();
+
+    let (first, second) = tokio::join!(
+        batch_executor.execute_group(vec![1, 2]),
+        batch_executor.execute_group(vec![3, 4, 5]),
+    );
+    assert_eq!(first?, [3]);
+    assert_eq!(second?, [12]);
+
+    let batches = executor.batches.read().unwrap();
+    assert_eq!(*batches, [vec![vec![1, 2], vec![3, 4, 5]]]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_layers() -> anyhow::Result<()> {
+    // Fails any value that isn't allowed, without passing it to the inner
+    // executor
+    struct Validate<E> {
+        executor: E,
+        allowed: fn(&u64) -> bool,
+    }
+
+    impl<E> TryExecutor for Validate<E>
+    where
+        E: TryExecutor<Value = u64, Error = anyhow::Error> + Sync,
+    {
+        type Value = u64;
+        type Result = E::Result;
+        type Error = anyhow::Error;
+
+        async fn try_execute(
+            &self,
+            values: Vec<u64>,
+        ) -> anyhow::Result<Vec<anyhow::Result<E::Result>>> {
+            let (allowed, rejected): (Vec<_>, Vec<_>) =
+                values.iter().partition(|value| (self.allowed)(value));
+            let mut results = self.executor.try_execute(allowed).await?.into_iter();
+            Ok(values
+                .iter()
+                .map(|value| {
+                    if rejected.contains(value) {
+                        Err(anyhow::anyhow!("{value} is not allowed"))
+                    } else {
+                        results.next().unwrap()
+                    }
+                })
+                .collect())
+        }
+    }
+
+    struct ValidateLayer(fn(&u64) -> bool);
+
+    impl<E> ExecutorLayer<E> for ValidateLayer {
+        type Executor = Validate<E>;
+
+        fn layer(&self, executor: E) -> Validate<E> {
+            Validate {
+                executor,
+                allowed: self.0,
+            }
+        }
+    }
+
+    // Only allowed values should reach the inner executor
+    let executor = RecordingExecutor::new(FnExecutor::new(|values: Vec<u64>| async move {
+        anyhow::ensure!(values.iter().all(|value| *value != 0 && *value < 100));
+        anyhow::Ok(
+            values
+                .into_iter()
+                .map(|value| value * 2)
+                .collect::<Vec<_>>(),
+        )
+    }));
+    let batch_executor = BatchExecutor::build(executor.clone())
+        .layer(ValidateLayer(|value| *value != 0))
+        .layer(ValidateLayer(|value| *value < 100))
+        .finish();
+
+    let (small, zero, big) = tokio::join!(
+        batch_executor.execute_many(vec![1, 2]),
+        batch_executor.execute(0),
+        batch_executor.execute(100),
+    );
+    assert_eq!(small?, [2, 4]);
+    assert!(matches!(zero, Err(ExecuteError::ExecutorError(error)) if error == "0 is not allowed"));
+    assert!(
+        matches!(big, Err(ExecuteError::ExecutorError(error)) if error == "100 is not allowed")
+    );
+    assert_eq!(executor.total_calls(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_memoize_results() -> anyhow::Result<()> {
+    // Fails the batch for any value over 100
+    let executor = RecordingExecutor::new(FnExecutor::new(|values: Vec<u64>| async move {
+        anyhow::ensure!(values.iter().all(|value| *value <= 100), "uh oh");
+        anyhow::Ok(
+            values
+                .into_iter()
+                .map(|value| value * 2)
+                .collect::<Vec<_>>(),
+        )
+    }));
+    let batch_executor = BatchExecutor::build(executor.clone())
+        .memoize_results(|value| *value % 10, tokio::time::Duration::from_millis(100))
+        .finish();
+
+    assert_eq!(batch_executor.execute_many(vec![1, 2]).await?, [2, 4]);
+    assert_eq!(executor.total_calls(), 1);
+
+    // Values with the same key reuse the remembered result
+    assert_eq!(batch_executor.execute_many(vec![2, 11]).await?, [4, 2]);
+    assert_eq!(executor.total_calls(), 1);
+
+    // Only values without a remembered result are executed
+    assert_eq!(batch_executor.execute_many(vec![1, 3]).await?, [2, 6]);
+    assert_eq!(executor.total_calls(), 2);
+
+    // Errors aren't remembered
+    let result = batch_executor.execute(104).await;
+    assert!(matches!(result, Err(ExecuteError::ExecutorError(_))));
+    assert_eq!(batch_executor.execute(4).await?, Some(8));
+    assert_eq!(executor.total_calls(), 4);
+
+    // Results are executed again once they expire
+    tokio::time::sleep(tokio::time::Duration::from_millis(150)).await;
+    assert_eq!(batch_executor.execute(1).await?, Some(2));
+    assert_eq!(executor.total_calls(), 5);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_memoize_results_dedups_batch() -> anyhow::Result<()> {
+    // Fails each value of 0 on its own, and counts every value it sees
+    struct FailZeroesExecutor {
+        num_values: Arc<AtomicUsize>,
+    }
+
+    impl TryExecutor for FailZeroesExecutor {
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+
+        async fn try_execute(
+            &self,
+            values: Vec<u64>,
+        ) -> Result<Vec<Result<u64, Self::Error>>, Self::Error> {
+            self.num_values
+                .fetch_add(values.len(), std::sync::atomic::Ordering::SeqCst);
+            let results = values
+                .into_iter()
+                .map(|value| {
+                    anyhow::ensure!(value != 0, "uh oh");
+                    Ok(value * 2)
+                })
+                .collect();
+            Ok(results)
+        }
+    }
+
+    let num_values = Arc::new(AtomicUsize::new(0));
+    let batch_executor = BatchExecutor::build(FailZeroesExecutor {
+        num_values: num_values.clone(),
+    })
+    .memoize_results(|value| *value % 10, tokio::time::Duration::from_secs(60))
+    .finish();
+
+    // Values with the same key in one batch are only executed once
+    assert_eq!(
+        batch_executor.execute_many(vec![5, 15, 5, 6]).await?,
+        [10, 10, 10, 12]
+    );
+    assert_eq!(num_values.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+    // Each duplicate of a failed value still gets its own error
+    let (first, second) = tokio::join!(batch_executor.execute(0), batch_executor.execute(0));
+    assert!(matches!(first, Err(ExecuteError::ExecutorError(_))));
+    assert!(matches!(second, Err(ExecuteError::ExecutorError(_))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_write_through() -> anyhow::Result<()> {
+    let db = Arc::new(RwLock::new(db::Database::fake()));
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers { db: db.clone() });
+    let batch_fetcher = ultra_batch::BatchFetcher::build(fetcher.clone()).finish();
+
+    // Returns each user that was inserted
+    let batch_inserter = BatchExecutor::build(FnExecutor::new(move |users: Vec<db::User>| {
+        let db = db.clone();
+        async move {
+            let mut db = db.write().unwrap();
+            for user in &users {
+                db.users.insert(user.id, user.clone());
+            }
+            anyhow::Ok(users)
+        }
+    }))
+    .write_through(batch_fetcher.clone(), |user| (user.id, user.clone()))
+    .finish();
+
+    let new_users = vec![db::User::fake(), db::User::fake()];
+    batch_inserter.execute_many(new_users.clone()).await?;
+
+    // Inserted users are loaded from the cache without fetching them
+    let user_ids: Vec<_> = new_users.iter().map(|user| user.id).collect();
+    assert_eq!(batch_fetcher.load_many(&user_ids).await?, new_users);
+    assert_eq!(fetcher.total_calls(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_keyed() -> anyhow::Result<()> {
+    // Executor that doubles each value, but skips odd values and returns
+    // results in reverse order
+    struct DoubleEvenExecutor;
+
+    impl KeyedExecutor for DoubleEvenExecutor {
+        type Key = u64;
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+
+        fn key(value: &u64) -> u64 {
+            *value
+        }
+
+        async fn execute(&self, values: Vec<u64>) -> anyhow::Result<HashMap<u64, u64>> {
+            let results = values
+                .into_iter()
+                .rev()
+                .filter(|value| value % 2 == 0)
+                .map(|value| (value, value * 2))
+                .collect();
+            Ok(results)
+        }
+    }
+
+    let batch_executor = BatchExecutor::build(Keyed::new(DoubleEvenExecutor)).finish();
+
+    let (first, second) = tokio::join!(
+        batch_executor.execute_many(vec![1, 2, 3, 4]),
+        batch_executor.execute_many(vec![4, 6]),
+    );
+    assert_eq!(first?, [None, Some(4), None, Some(8)]);
+    assert_eq!(second?, [Some(8), Some(12)]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_prepare_values() -> anyhow::Result<()> {
+    // Executor that doubles each value and records the values it was given
+    #[derive(Default)]
+    struct DoubleExecutor {
+        executed_values: Arc<RwLock<Vec<u64>>>,
+    }
+
+    impl Executor for DoubleExecutor {
+        type Value = u64;
+        type Result = u64;
+        type Error = anyhow::Error;
+
+        async fn execute(&self, values: Vec<u64>) -> Result<Vec<u64>, Self::Error> {
+            self.executed_values
+                .write()
+                .unwrap()
+                .extend(values.iter().copied());
+            Ok(values.into_iter().map(|value| value * 2).collect())
+        }
+    }
+
+    let executor = DoubleExecutor::default();
+    let executed_values = executor.executed_values.clone();
+    let batch_executor = BatchExecutor::build(executor)
+        .prepare_values(|values| {
+            // Sort values, then merge values that are within 1 of each other
+            values.sort_by_key(|value| *value);
+            values.coalesce(|previous, next| {
+                if next - *previous <= 1 {
+                    Ok(())
+                } else {
+                    Err(next)
+                }
+            });
+        })
+        .finish();
+
+    let (first, second) = tokio::join!(
+        batch_executor.execute_many(vec![10, 3, 20]),
+        batch_executor.execute_many(vec![4, 1, 11]),
+    );
+    assert_eq!(first?, [20, 6, 40]);
+    assert_eq!(second?, [6, 2, 20]);
+
+    let executed_values = executed_values.read().unwrap();
+    assert_eq!(*executed_values, [1, 3, 10, 20]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_from_fn() -> anyhow::Result<()> {
+    let batch_executor = BatchExecutor::from_fn(|values: Vec<u64>| async move {
+        let results: Vec<u64> = values.into_iter().map(|value| value * 2).collect();
+        anyhow::Ok(results)
+    })
+    .finish();
+
+    let results = batch_executor.execute_many(vec![1, 2, 3]).await?;
+    assert_eq!(results, vec![2, 4, 6]);
+
+    let result = batch_executor.execute(10).await?;
+    assert_eq!(result, Some(20));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_with_dropped_driver() -> anyhow::Result<()> {
+    let executor = RecordingExecutor::new(FnExecutor::new(|values: Vec<u64>| async move {
+        anyhow::Ok(values.into_iter().map(|value| value * 2).collect())
+    }));
+    let (batch_executor, driver) = BatchExecutor::build(executor.clone()).finish_with_driver();
+    drop(driver);
+
+    // The execute task can't be restarted without the driver, so executing
+    // fails instead of spawning a task in the background
+    assert!(matches!(
+        batch_executor.execute(1).await,
+        Err(ExecuteError::SendError)
+    ));
+    let metrics = tokio::runtime::Handle::current().metrics();
+    assert_eq!(metrics.num_alive_tasks(), 0);
+    assert_eq!(executor.total_calls(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_execute_with_driver() -> anyhow::Result<()> {
+    // Built outside of any runtime, since no tasks get spawned
+    let (batch_executor, driver) = BatchExecutor::from_fn(|values: Vec<u64>| async move {
+        let results: Vec<u64> = values.into_iter().map(|value| value * 2).collect();
+        anyhow::Ok(results)
+    })
+    .finish_with_driver();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    let (results, ()) = runtime.block_on(async move {
+        tokio::join!(
+            async move {
+                let results = tokio::try_join!(
+                    batch_executor.execute_many(vec![1, 2]),
+                    batch_executor.execute(3),
+                );
+                drop(batch_executor);
+                results
+            },
+            driver,
+        )
+    });
+
+    let (many_results, result) = results?;
+    assert_eq!(many_results, vec![2, 4]);
+    assert_eq!(result, Some(6));
+
+    Ok(())
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn test_blocking_execute() -> anyhow::Result<()> {
+    let batch_executor = BatchExecutor::from_fn(|values: Vec<u64>| async move {
+        let results: Vec<u64> = values.into_iter().map(|value| value * 2).collect();
+        anyhow::Ok(results)
+    })
+    .finish();
+
+    assert_eq!(batch_executor.blocking_execute(1)?, Some(2));
+    assert_eq!(
+        batch_executor.blocking_execute_many(vec![2, 3])?,
+        vec![4, 6]
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "opentelemetry")]
+#[tokio::test]
+async fn test_execute_propagates_otel_context() -> anyhow::Result<()> {
+    use opentelemetry::context::FutureExt as _;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct RequestId(u64);
+
+    let batch_contexts = Arc::new(RwLock::new(vec![]));
+    let batch_executor = BatchExecutor::from_fn({
+        let batch_contexts = batch_contexts.clone();
+        move |values: Vec<u64>| {
+            let request_id = opentelemetry::Context::current()
+                .get::<RequestId>()
+                .copied();
+            batch_contexts.write().unwrap().push(request_id);
+            async move { anyhow::Ok(values) }
+        }
+    })
+    .finish();
+
+    // The batch runs with the context of the first caller waiting on it
+    let (first, second) = tokio::join!(
+        batch_executor
+            .execute(1)
+            .with_context(opentelemetry::Context::current_with_value(RequestId(1))),
+        batch_executor
+            .execute(2)
+            .with_context(opentelemetry::Context::current_with_value(RequestId(2))),
+    );
+    first?;
+    second?;
+
+    assert_eq!(*batch_contexts.read().unwrap(), vec![Some(RequestId(1))]);
+
+    Ok(())
+}
+
+#[test]
+fn test_execute_restarts_stopped_execute_task() -> anyhow::Result<()> {
+    let batch_executor_builder = BatchExecutor::from_fn(|values: Vec<u64>| async move {
+        let results: Vec<u64> = values.into_iter().map(|value| value * 2).collect();
+        anyhow::Ok(results)
+    });
+
+    // The execute task gets spawned onto the first runtime, and stops when
+    // that runtime is dropped
+    let first_runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    let batch_executor = first_runtime.block_on(async { batch_executor_builder.finish() });
+    assert_eq!(first_runtime.block_on(batch_executor.execute(1))?, Some(2));
+    drop(first_runtime);
+
+    // A new execute task is started on the second runtime
+    let second_runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    assert_eq!(second_runtime.block_on(batch_executor.execute(2))?, Some(4));
+
+    Ok(())
+}
+
+#[test]
+fn test_execute_finish_on_restarts_execute_task_on_default_runtime() -> anyhow::Result<()> {
+    let first_runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_time()
+        .build()?;
+
+    // Executor that never finishes executing the value 0
+    let executor = RecordingExecutor::new(FnExecutor::new(|values: Vec<u64>| async move {
+        if values.contains(&0) {
+            std::future::pending::<()>().await;
+        }
+        let results: Vec<u64> = values.into_iter().map(|value| value * 2).collect();
+        anyhow::Ok(results)
+    }));
+    let batch_executor = BatchExecutor::build(executor.clone()).finish_on(first_runtime.handle());
+
+    let second_runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    let waiting_execute = second_runtime.spawn({
+        let batch_executor = batch_executor.clone();
+        async move { batch_executor.execute(0).await }
+    });
+    second_runtime.block_on(async {
+        while executor.total_calls() == 0 {
+            tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+        }
+    });
+
+    // Calls waiting on the stopped execute task fail instead of hanging
+    drop(first_runtime);
+    second_runtime.block_on(async move {
+        assert!(matches!(
+            waiting_execute.await?,
+            Err(ExecuteError::SendError)
+        ));
+
+        // The first runtime is gone, so a new execute task is started with
+        // the default spawner. Only `finish_on` falls back like this, since
+        // it was given a runtime handle rather than a custom spawner
+        assert_eq!(batch_executor.execute(1).await?, Some(2));
+        assert_eq!(batch_executor.execute_many(vec![2, 3]).await?, [4, 6]);
+
+        anyhow::Ok(())
+    })
+}
+
+#[test]
+fn test_execute_finish_on() -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_time()
+        .build()?;
+
+    let batch_executor = BatchExecutor::from_fn(|values: Vec<u64>| async move {
+        let results: Vec<u64> = values.into_iter().map(|value| value * 2).collect();
+        anyhow::Ok(results)
+    })
+    .finish_on(runtime.handle());
+
+    let results = runtime.block_on(batch_executor.execute_many(vec![1, 2, 3]))?;
+    assert_eq!(results, vec![2, 4, 6]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_execute_testing_executors() -> anyhow::Result<()> {
+    use ultra_batch::testing::FailingExecutor;
+
+    let failing_executor = FailingExecutor::new(
+        FnExecutor::new(|values: Vec<u64>| async move {
+            anyhow::Ok(values.into_iter().map(|value| value * 2).collect())
+        }),
+        || anyhow::anyhow!("down"),
+    );
+    let executor = RecordingExecutor::new(failing_executor.clone());
+    let batch_executor = BatchExecutor::build(executor.clone()).finish();
+
+    assert_eq!(batch_executor.execute_many(vec![1, 2, 3]).await?, [2, 4, 6]);
+
+    failing_executor.fail_next(1);
+    assert!(batch_executor.execute(4).await.is_err());
+    assert_eq!(batch_executor.execute(4).await?, Some(8));
+
+    assert_eq!(executor.total_calls(), 3);
+    assert_eq!(executor.total_values(), 5);
+
+    Ok(())
+}
@@ -1,6 +1,15 @@
+use futures_util::future::BoxFuture;
+use futures_util::StreamExt;
 use std::sync::{Arc, RwLock};
 
-use ultra_batch::{BatchFetcher, Cache, Fetcher, LoadError};
+use ultra_batch::testing::RecordingFetcher;
+use ultra_batch::{
+    AdaptiveBatchScheduler, BatchFetcher, BatchFetcherStats, BatchInfo, BatchScheduler,
+    BlockingFetcher, Cache, CacheCodec, CacheEntry, CacheEvent, ExclusiveFetcher, Fetcher,
+    FinishedBatch, LoadError, LoaderFactory, LoaderRegistry, LocalFetcher, ManyToManyFetcher,
+    MapFetcher, PairsFetcher, PendingBatch, RemoteCache, RequestContextFetcher, Schedule,
+    SharedCache, SharedFetcher, Spawner, Timer,
+};
 
 mod db;
 mod stubs;
@@ -54,12 +63,192 @@ async fn test_load_many_ordering() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_load_many_repeated_keys() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+
+    let users: Vec<_> = db.users.values().take(20).cloned().collect();
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+
+    // A few keys, with repeats
+    let expected_users = vec![
+        users[0].clone(),
+        users[1].clone(),
+        users[0].clone(),
+        users[0].clone(),
+    ];
+    let user_ids: Vec<_> = expected_users.iter().map(|user| user.id).collect();
+    let actual_users = batch_fetcher.load_many(&user_ids).await?;
+    assert_eq!(actual_users, expected_users);
+    assert_eq!(fetcher.calls_for_key(&users[0].id), 1);
+
+    // Enough keys that repeats are found with a `HashMap`
+    let expected_users: Vec<_> = users.iter().chain(users.iter().rev()).cloned().collect();
+    let user_ids: Vec<_> = expected_users.iter().map(|user| user.id).collect();
+    let actual_users = batch_fetcher.load_many(&user_ids).await?;
+    assert_eq!(actual_users, expected_users);
+    for user in &users {
+        assert_eq!(fetcher.calls_for_key(&user.id), 1);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_many_key_order() -> anyhow::Result<()> {
+    let batches = Arc::new(RwLock::new(vec![]));
+    let batch_fetcher = BatchFetcher::from_fn({
+        let batches = batches.clone();
+        move |ids: Vec<u64>| {
+            batches.write().unwrap().push(ids.clone());
+            async move {
+                anyhow::Ok(
+                    ids.into_iter()
+                        .map(|id| (id, id))
+                        .collect::<std::collections::HashMap<_, _>>(),
+                )
+            }
+        }
+    })
+    .finish();
+
+    // Keys are passed to the fetcher in the order they were first queued
+    let keys = [50, 3, 97, 1, 3, 28, 64, 50, 12, 7];
+    assert_eq!(batch_fetcher.load_many(&keys).await?, keys);
+    assert_eq!(
+        *batches.read().unwrap(),
+        vec![vec![50, 3, 97, 1, 28, 64, 12, 7]]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_many_iterator() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+
+    let expected_users: Vec<_> = db.users.values().take(5).cloned().collect();
+
+    let batch_fetcher = BatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .finish();
+    let actual_users = batch_fetcher
+        .load_many(expected_users.iter().map(|user| user.id))
+        .await?;
+    assert_eq!(actual_users, expected_users);
+
+    let actual_users = batch_fetcher
+        .load_many(expected_users.iter().map(|user| &user.id))
+        .await?;
+    assert_eq!(actual_users, expected_users);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_many_arc_keys() -> anyhow::Result<()> {
+    let batches = Arc::new(RwLock::new(vec![]));
+    let batch_fetcher = BatchFetcher::from_fn({
+        let batches = batches.clone();
+        move |keys: Vec<Arc<str>>| {
+            batches.write().unwrap().push(keys.clone());
+            async move {
+                anyhow::Ok(
+                    keys.into_iter()
+                        .map(|key| {
+                            let len = key.len();
+                            (key, len)
+                        })
+                        .collect::<std::collections::HashMap<_, _>>(),
+                )
+            }
+        }
+    })
+    .finish();
+
+    // Borrowed strings are converted into `Arc<str>` keys
+    assert_eq!(batch_fetcher.load_many(["a", "bb"]).await?, vec![1, 2]);
+
+    // Owned keys are passed to the fetcher without copying the string
+    let key: Arc<str> = "c".repeat(1024).into();
+    assert_eq!(batch_fetcher.load(key.clone()).await?, 1024);
+    let batches = batches.read().unwrap();
+    assert_eq!(batches.len(), 2);
+    assert!(Arc::ptr_eq(&batches[1][0], &key));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_shared_values() -> anyhow::Result<()> {
+    // A value that can't be cloned
+    #[derive(Debug, PartialEq, Eq)]
+    struct Buffer(Vec<u8>);
+
+    struct FetchBuffers;
+
+    impl SharedFetcher for FetchBuffers {
+        type Key = u8;
+        type Value = Buffer;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u8],
+            values: &mut Cache<'_, u8, Arc<Buffer>>,
+        ) -> anyhow::Result<()> {
+            for &key in keys {
+                values.insert_shared(key, Buffer(vec![key; 1024]));
+            }
+            Ok(())
+        }
+    }
+
+    let batch_fetcher = BatchFetcher::build_shared(FetchBuffers).finish();
+
+    // Every load shares the same value instead of copying it
+    let first = batch_fetcher.load(1).await?;
+    let second = batch_fetcher.load(1).await?;
+    assert_eq!(*first, Buffer(vec![1; 1024]));
+    assert!(Arc::ptr_eq(&first, &second));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_many_map() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+
+    let expected_users: Vec<_> = db.users.values().take(5).cloned().collect();
+    let missing_id = uuid::Uuid::new_v4();
+    let mut user_ids: Vec<_> = expected_users.iter().map(|user| user.id).collect();
+    user_ids.push(missing_id);
+
+    let batch_fetcher = BatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .finish();
+    let actual_users = batch_fetcher.load_many_map(&user_ids).await?;
+
+    assert_eq!(actual_users.len(), 5);
+    assert!(!actual_users.contains_key(&missing_id));
+    for expected_user in expected_users {
+        assert_eq!(actual_users[&expected_user.id], expected_user);
+    }
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_load_fetching() -> anyhow::Result<()> {
     let db = db::Database::fake();
     let user_ids: Vec<_> = db.users.keys().copied().collect();
 
-    let fetcher = stubs::ObserveFetcher::new(db::FetchUsers {
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
         db: Arc::new(RwLock::new(db)),
     });
     let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
@@ -93,7 +282,7 @@ async fn test_load_caching() -> anyhow::Result<()> {
     let db = db::Database::fake();
     let user_ids: Vec<_> = db.users.keys().copied().collect();
 
-    let fetcher = stubs::ObserveFetcher::new(db::FetchUsers {
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
         db: Arc::new(RwLock::new(db)),
     });
     let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
@@ -130,12 +319,46 @@ async fn test_load_caching() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_load_read_optimized_cache() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().take(100).collect();
+    let missing_id = uuid::Uuid::new_v4();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .read_optimized_cache()
+        .finish();
+
+    let batch = batch_fetcher.load_many(&user_ids).await?;
+    assert_eq!(batch.len(), 100);
+    assert_eq!(fetcher.total_calls(), 1);
+    assert_eq!(batch_fetcher.cached_len(), 100);
+
+    let missing = batch_fetcher.load(missing_id).await;
+    assert!(matches!(missing, Err(LoadError::NotFound)));
+    assert_eq!(fetcher.total_calls(), 2);
+    assert_eq!(batch_fetcher.cached_len(), 101);
+
+    // Loading the same keys again only hits the cache
+    let cached = batch_fetcher.load_many(&user_ids).await?;
+    assert_eq!(cached, batch);
+    let missing = batch_fetcher.load(missing_id).await;
+    assert!(matches!(missing, Err(LoadError::NotFound)));
+    assert_eq!(batch_fetcher.load_borrowed(&user_ids[0]).await?, batch[0]);
+    assert_eq!(fetcher.total_calls(), 2);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_load_batching() -> anyhow::Result<()> {
     let db = db::Database::fake();
     let user_ids: Vec<_> = db.users.keys().copied().collect();
 
-    let fetcher = stubs::ObserveFetcher::new(db::FetchUsers {
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
         db: Arc::new(RwLock::new(db)),
     });
     let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
@@ -180,7 +403,7 @@ async fn test_load_eager_batch_size() -> anyhow::Result<()> {
     let db = db::Database::fake();
     let user_ids: Vec<_> = db.users.keys().copied().collect();
 
-    let fetcher = stubs::ObserveFetcher::new(db::FetchUsers {
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
         db: Arc::new(RwLock::new(db)),
     });
     let batch_fetcher = BatchFetcher::build(fetcher.clone())
@@ -241,7 +464,7 @@ async fn test_load_no_eager_batch_size() -> anyhow::Result<()> {
     let db = db::Database::fake();
     let user_ids: Vec<_> = db.users.keys().copied().collect();
 
-    let fetcher = stubs::ObserveFetcher::new(db::FetchUsers {
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
         db: Arc::new(RwLock::new(db)),
     });
     let batch_fetcher = BatchFetcher::build(fetcher.clone())
@@ -272,39 +495,109 @@ async fn test_load_no_eager_batch_size() -> anyhow::Result<()> {
 }
 
 #[tokio::test]
-async fn test_batch_delay() -> anyhow::Result<()> {
+async fn test_load_max_batch_size() -> anyhow::Result<()> {
     let db = db::Database::fake();
     let user_ids: Vec<_> = db.users.keys().copied().collect();
 
-    let fetcher = stubs::ObserveFetcher::new(db::FetchUsers {
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
         db: Arc::new(RwLock::new(db)),
     });
     let batch_fetcher = BatchFetcher::build(fetcher.clone())
-        .delay_duration(tokio::time::Duration::from_millis(10))
-        .eager_batch_size(None)
+        .max_batch_size(Some(100))
         .finish();
 
-    // Batch run if we exceed the delay duration
-    let batch_task = tokio::spawn({
-        let batch_fetcher = batch_fetcher.clone();
-        let user_id = user_ids[0];
-        async move { batch_fetcher.load(user_id).await }
-    });
-    assert_eq!(fetcher.total_calls(), 0);
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-    assert_eq!(fetcher.total_calls(), 1);
-    batch_task.await??;
-    assert_eq!(fetcher.total_calls(), 1);
+    // A batch larger than the max batch size should be split up
+    let batch = batch_fetcher.load_many(&user_ids[0..250]).await?;
+    assert_eq!(batch.len(), 250);
+    assert_eq!(fetcher.total_calls(), 3);
+    for user_id in &user_ids[0..250] {
+        assert_eq!(fetcher.calls_for_key(user_id), 1);
+    }
+
+    // A batch at the max batch size should not be split
+    let batch = batch_fetcher.load_many(&user_ids[250..350]).await?;
+    assert_eq!(batch.len(), 100);
+    assert_eq!(fetcher.total_calls(), 4);
 
     Ok(())
 }
 
 #[tokio::test]
-async fn test_insert_extra_keys() -> Result<(), anyhow::Error> {
-    // Fetcher that returns the input value, and also always inserts the value 1
-    struct OneFetcher;
+async fn test_load_many_overlapping_split_batches() -> anyhow::Result<()> {
+    let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+        anyhow::Ok(
+            ids.into_iter()
+                .filter(|id| id % 5 != 0)
+                .map(|id| (id, id * 10))
+                .collect::<std::collections::HashMap<_, _>>(),
+        )
+    })
+    .max_batch_size(Some(3))
+    .finish();
 
-    impl Fetcher for OneFetcher {
+    // Both loads share keys that are spread across several batches, and
+    // each gets its values back in its own order
+    let (forward, reverse) = tokio::join!(
+        batch_fetcher.load_many_map(1..=12),
+        batch_fetcher.load_many((6..=9).rev()),
+    );
+    let forward = forward?;
+    assert_eq!(forward.len(), 10);
+    assert!(forward.iter().all(|(id, value)| *value == id * 10));
+    assert_eq!(reverse?, vec![90, 80, 70, 60]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_shard_by() -> anyhow::Result<()> {
+    let batches = Arc::new(RwLock::new(vec![]));
+    let batch_fetcher = BatchFetcher::from_fn({
+        let batches = batches.clone();
+        move |ids: Vec<u64>| {
+            batches.write().unwrap().push(ids.clone());
+            async move {
+                anyhow::Ok(
+                    ids.into_iter()
+                        .map(|id| (id, id))
+                        .collect::<std::collections::HashMap<_, _>>(),
+                )
+            }
+        }
+    })
+    .shard_by(|id| id % 3)
+    .max_batch_size(Some(2))
+    .max_concurrent_batches(4)
+    .finish();
+
+    let ids: Vec<u64> = (0..9).collect();
+    assert_eq!(batch_fetcher.load_many(&ids).await?, ids);
+
+    // Each shard is fetched separately, and is still split by the max
+    // batch size
+    let mut batches = batches.read().unwrap().clone();
+    batches.sort();
+    assert_eq!(
+        batches,
+        vec![
+            vec![0, 3],
+            vec![1, 4],
+            vec![2, 5],
+            vec![6],
+            vec![7],
+            vec![8]
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_max_batch_size_error() -> anyhow::Result<()> {
+    // Fetcher that fails any batch containing an odd key
+    struct EvenFetcher;
+
+    impl Fetcher for EvenFetcher {
         type Key = u64;
         type Value = u64;
         type Error = anyhow::Error;
@@ -314,7 +607,10 @@ async fn test_insert_extra_keys() -> Result<(), anyhow::Error> {
             keys: &[u64],
             values: &mut Cache<'_, u64, u64>,
         ) -> Result<(), Self::Error> {
-            values.insert(1, 1);
+            if keys.iter().any(|key| key % 2 != 0) {
+                return Err(anyhow::anyhow!("odd keys"));
+            }
+
             for key in keys {
                 values.insert(*key, *key);
             }
@@ -323,42 +619,70 @@ async fn test_insert_extra_keys() -> Result<(), anyhow::Error> {
         }
     }
 
-    let fetcher = stubs::ObserveFetcher::new(OneFetcher);
-    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+    let fetcher = RecordingFetcher::new(EvenFetcher);
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .max_batch_size(Some(1))
+        .finish();
 
-    let batch = batch_fetcher.load_many(&[2, 3, 4]).await?;
-    assert_eq!(batch, vec![2, 3, 4]);
-    assert_eq!(fetcher.total_calls(), 1);
-    assert_eq!(fetcher.calls_for_key(&1), 0);
-    assert_eq!(fetcher.calls_for_key(&2), 1);
-    assert_eq!(fetcher.calls_for_key(&3), 1);
-    assert_eq!(fetcher.calls_for_key(&4), 1);
+    // Each key is fetched separately, so only the load for the odd key fails
+    let (even_result, odd_result) = tokio::join!(batch_fetcher.load(2), batch_fetcher.load(3));
+    assert_eq!(even_result?, 2);
+    assert!(matches!(odd_result, Err(LoadError::FetchError(msg)) if &*msg == "odd keys"));
+    assert_eq!(fetcher.total_calls(), 2);
 
-    let batch = batch_fetcher.load(1).await?;
-    assert_eq!(batch, 1);
-    assert_eq!(fetcher.total_calls(), 1);
-    assert_eq!(fetcher.calls_for_key(&1), 0);
-    assert_eq!(fetcher.calls_for_key(&2), 1);
-    assert_eq!(fetcher.calls_for_key(&3), 1);
-    assert_eq!(fetcher.calls_for_key(&4), 1);
+    // A load spanning multiple batches fails if any of its batches fail
+    let batch_result = batch_fetcher.load_many(&[4, 5, 6]).await;
+    assert!(matches!(batch_result, Err(LoadError::FetchError(_))));
+    assert_eq!(batch_fetcher.load_many(&[4, 6]).await?, vec![4, 6]);
+    assert_eq!(fetcher.total_calls(), 5);
 
-    let batch = batch_fetcher.load_many(&[1, 2, 3]).await?;
-    assert_eq!(batch, vec![1, 2, 3]);
-    assert_eq!(fetcher.total_calls(), 1);
-    assert_eq!(fetcher.calls_for_key(&1), 0);
-    assert_eq!(fetcher.calls_for_key(&2), 1);
-    assert_eq!(fetcher.calls_for_key(&3), 1);
-    assert_eq!(fetcher.calls_for_key(&4), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_error_shared_between_waiters() -> anyhow::Result<()> {
+    let batch_fetcher = BatchFetcher::from_fn(|_: Vec<u64>| async move {
+        Err::<std::collections::HashMap<u64, u64>, _>(anyhow::anyhow!("database is down"))
+    })
+    .finish();
+
+    let (result_1, result_2) = tokio::join!(batch_fetcher.load(1), batch_fetcher.load(2));
+    match (result_1, result_2) {
+        (Err(LoadError::FetchError(error_1)), Err(LoadError::FetchError(error_2))) => {
+            assert_eq!(&*error_1, "database is down");
+            assert!(Arc::ptr_eq(&error_1, &error_2));
+        }
+        results => panic!("expected both loads to fail, got {results:?}"),
+    }
 
     Ok(())
 }
 
 #[tokio::test]
-async fn test_keys_not_returned() -> Result<(), anyhow::Error> {
-    // Fetcher that only returns values for even keys (odd keys are ignored)
-    struct EvenFetcher;
+async fn test_load_fetcher_panic() -> anyhow::Result<()> {
+    let batch_fetcher = BatchFetcher::from_fn(|_: Vec<u64>| async move {
+        if true {
+            panic!("fetcher panicked");
+        }
+        anyhow::Ok(std::collections::HashMap::<u64, u64>::new())
+    })
+    .finish();
 
-    impl Fetcher for EvenFetcher {
+    // The batch hangs up on its callers instead of reporting a result
+    let result = batch_fetcher.load(1).await;
+    assert!(matches!(result, Err(LoadError::SendError)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_max_concurrent_batches() -> anyhow::Result<()> {
+    // Fetcher that waits to be notified before fetching the key 0
+    struct GatedFetcher {
+        gate: Arc<tokio::sync::Notify>,
+    }
+
+    impl Fetcher for GatedFetcher {
         type Key = u64;
         type Value = u64;
         type Error = anyhow::Error;
@@ -368,58 +692,49 @@ async fn test_keys_not_returned() -> Result<(), anyhow::Error> {
             keys: &[u64],
             values: &mut Cache<'_, u64, u64>,
         ) -> Result<(), Self::Error> {
+            if keys.contains(&0) {
+                self.gate.notified().await;
+            }
+
             for key in keys {
-                if key % 2 == 0 {
-                    values.insert(*key, *key);
-                }
+                values.insert(*key, *key);
             }
 
             Ok(())
         }
     }
 
-    let fetcher = stubs::ObserveFetcher::new(EvenFetcher);
-    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
-
-    let batch = batch_fetcher.load_many(&[2, 4, 6]).await?;
-    assert_eq!(batch, vec![2, 4, 6]);
-    assert_eq!(fetcher.total_calls(), 1);
-    assert_eq!(fetcher.calls_for_key(&2), 1);
-    assert_eq!(fetcher.calls_for_key(&4), 1);
-    assert_eq!(fetcher.calls_for_key(&6), 1);
+    let gate = Arc::new(tokio::sync::Notify::new());
+    let batch_fetcher = BatchFetcher::build(GatedFetcher { gate: gate.clone() })
+        .max_concurrent_batches(2)
+        .finish();
 
-    let batch_result = batch_fetcher.load_many(&[2, 8, 10, 11]).await;
-    assert!(matches!(batch_result, Err(LoadError::NotFound)));
-    assert_eq!(fetcher.total_calls(), 2);
-    assert_eq!(fetcher.calls_for_key(&2), 1);
-    assert_eq!(fetcher.calls_for_key(&8), 1);
-    assert_eq!(fetcher.calls_for_key(&10), 1);
-    assert_eq!(fetcher.calls_for_key(&11), 1);
+    let slow_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        async move { batch_fetcher.load(0).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
 
-    let batch = batch_fetcher.load_many(&[2, 4, 6, 8, 10]).await?;
-    assert_eq!(batch, vec![2, 4, 6, 8, 10]);
-    assert_eq!(fetcher.total_calls(), 2);
-    assert_eq!(fetcher.calls_for_key(&2), 1);
-    assert_eq!(fetcher.calls_for_key(&4), 1);
-    assert_eq!(fetcher.calls_for_key(&6), 1);
-    assert_eq!(fetcher.calls_for_key(&8), 1);
-    assert_eq!(fetcher.calls_for_key(&10), 1);
-    assert_eq!(fetcher.calls_for_key(&11), 1);
+    // The slow batch is still in-flight, but another batch can be fetched
+    let value =
+        tokio::time::timeout(tokio::time::Duration::from_secs(1), batch_fetcher.load(1)).await??;
+    assert_eq!(value, 1);
+    assert!(!slow_task.is_finished());
 
-    let batch_result = batch_fetcher.load_many(&[11, 12]).await;
-    assert!(matches!(batch_result, Err(LoadError::NotFound)));
-    assert_eq!(fetcher.calls_for_key(&11), 1); // "Not found" status should be cached
-    assert_eq!(fetcher.calls_for_key(&12), 1);
+    gate.notify_one();
+    assert_eq!(slow_task.await??, 0);
 
     Ok(())
 }
 
 #[tokio::test]
-async fn test_fetch_error_before_inserting() -> Result<(), anyhow::Error> {
-    // Fetcher that first validates no odd keys are present, then stores even keys
-    struct EvenFetcher;
+async fn test_load_joins_in_flight_batch() -> anyhow::Result<()> {
+    // Fetcher that waits to be notified before fetching the key 0
+    struct GatedFetcher {
+        gate: Arc<tokio::sync::Notify>,
+    }
 
-    impl Fetcher for EvenFetcher {
+    impl Fetcher for GatedFetcher {
         type Key = u64;
         type Value = u64;
         type Error = anyhow::Error;
@@ -429,25 +744,273 @@ async fn test_fetch_error_before_inserting() -> Result<(), anyhow::Error> {
             keys: &[u64],
             values: &mut Cache<'_, u64, u64>,
         ) -> Result<(), Self::Error> {
-            let (even_keys, mut odd_keys): (Vec<u64>, Vec<u64>) =
-                keys.iter().partition(|&&key| key % 2 == 0);
-
-            // Sort odd keys so we return consistent error messages
-            odd_keys.sort_unstable();
-            if !odd_keys.is_empty() {
-                return Err(anyhow::anyhow!("odd keys: {:?}", odd_keys));
+            if keys.contains(&0) {
+                self.gate.notified().await;
             }
 
-            for key in even_keys {
-                values.insert(key, key);
+            for key in keys {
+                values.insert(*key, *key);
             }
 
             Ok(())
         }
     }
 
-    let fetcher = stubs::ObserveFetcher::new(EvenFetcher);
-    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+    let gate = Arc::new(tokio::sync::Notify::new());
+    let fetcher = RecordingFetcher::new(GatedFetcher { gate: gate.clone() });
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .max_concurrent_batches(2)
+        .finish();
+
+    let slow_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        async move { batch_fetcher.load(0).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    // Key 0 is still in flight, so only key 1 is fetched in the next batch
+    let joined_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        async move { batch_fetcher.load_many(&[1, 0]).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    assert_eq!(fetcher.total_calls(), 2);
+    assert!(!joined_task.is_finished());
+
+    gate.notify_one();
+    assert_eq!(slow_task.await??, 0);
+    assert_eq!(joined_task.await??, vec![1, 0]);
+    assert_eq!(fetcher.calls_for_key(&0), 1);
+    assert_eq!(fetcher.calls_for_key(&1), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_one_concurrent_batch() -> anyhow::Result<()> {
+    struct SlowFetcher;
+
+    impl Fetcher for SlowFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, u64>,
+        ) -> Result<(), Self::Error> {
+            if keys.contains(&0) {
+                tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+            }
+
+            for key in keys {
+                values.insert(*key, *key);
+            }
+
+            Ok(())
+        }
+    }
+
+    let batch_fetcher = BatchFetcher::build(SlowFetcher).finish();
+
+    let slow_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        async move { batch_fetcher.load(0).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+
+    // By default, the next batch waits for the slow batch to finish
+    let result = tokio::time::timeout(
+        tokio::time::Duration::from_millis(100),
+        batch_fetcher.load(1),
+    )
+    .await;
+    assert!(result.is_err());
+
+    assert_eq!(slow_task.await??, 0);
+    assert_eq!(batch_fetcher.load(1).await?, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_batch_delay() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .delay_duration(tokio::time::Duration::from_millis(10))
+        .eager_batch_size(None)
+        .finish();
+
+    // Batch run if we exceed the delay duration
+    let batch_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        let user_id = user_ids[0];
+        async move { batch_fetcher.load(user_id).await }
+    });
+    assert_eq!(fetcher.total_calls(), 0);
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    assert_eq!(fetcher.total_calls(), 1);
+    batch_task.await??;
+    assert_eq!(fetcher.total_calls(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_insert_extra_keys() -> Result<(), anyhow::Error> {
+    // Fetcher that returns the input value, and also always inserts the value 1
+    struct OneFetcher;
+
+    impl Fetcher for OneFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, u64>,
+        ) -> Result<(), Self::Error> {
+            values.insert(1, 1);
+            for key in keys {
+                values.insert(*key, *key);
+            }
+
+            Ok(())
+        }
+    }
+
+    let fetcher = RecordingFetcher::new(OneFetcher);
+    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+
+    let batch = batch_fetcher.load_many(&[2, 3, 4]).await?;
+    assert_eq!(batch, vec![2, 3, 4]);
+    assert_eq!(fetcher.total_calls(), 1);
+    assert_eq!(fetcher.calls_for_key(&1), 0);
+    assert_eq!(fetcher.calls_for_key(&2), 1);
+    assert_eq!(fetcher.calls_for_key(&3), 1);
+    assert_eq!(fetcher.calls_for_key(&4), 1);
+
+    let batch = batch_fetcher.load(1).await?;
+    assert_eq!(batch, 1);
+    assert_eq!(fetcher.total_calls(), 1);
+    assert_eq!(fetcher.calls_for_key(&1), 0);
+    assert_eq!(fetcher.calls_for_key(&2), 1);
+    assert_eq!(fetcher.calls_for_key(&3), 1);
+    assert_eq!(fetcher.calls_for_key(&4), 1);
+
+    let batch = batch_fetcher.load_many(&[1, 2, 3]).await?;
+    assert_eq!(batch, vec![1, 2, 3]);
+    assert_eq!(fetcher.total_calls(), 1);
+    assert_eq!(fetcher.calls_for_key(&1), 0);
+    assert_eq!(fetcher.calls_for_key(&2), 1);
+    assert_eq!(fetcher.calls_for_key(&3), 1);
+    assert_eq!(fetcher.calls_for_key(&4), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keys_not_returned() -> Result<(), anyhow::Error> {
+    // Fetcher that only returns values for even keys (odd keys are ignored)
+    struct EvenFetcher;
+
+    impl Fetcher for EvenFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, u64>,
+        ) -> Result<(), Self::Error> {
+            for key in keys {
+                if key % 2 == 0 {
+                    values.insert(*key, *key);
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    let fetcher = RecordingFetcher::new(EvenFetcher);
+    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+
+    let batch = batch_fetcher.load_many(&[2, 4, 6]).await?;
+    assert_eq!(batch, vec![2, 4, 6]);
+    assert_eq!(fetcher.total_calls(), 1);
+    assert_eq!(fetcher.calls_for_key(&2), 1);
+    assert_eq!(fetcher.calls_for_key(&4), 1);
+    assert_eq!(fetcher.calls_for_key(&6), 1);
+
+    let batch_result = batch_fetcher.load_many(&[2, 8, 10, 11]).await;
+    assert!(matches!(batch_result, Err(LoadError::NotFound)));
+    assert_eq!(fetcher.total_calls(), 2);
+    assert_eq!(fetcher.calls_for_key(&2), 1);
+    assert_eq!(fetcher.calls_for_key(&8), 1);
+    assert_eq!(fetcher.calls_for_key(&10), 1);
+    assert_eq!(fetcher.calls_for_key(&11), 1);
+
+    let batch = batch_fetcher.load_many(&[2, 4, 6, 8, 10]).await?;
+    assert_eq!(batch, vec![2, 4, 6, 8, 10]);
+    assert_eq!(fetcher.total_calls(), 2);
+    assert_eq!(fetcher.calls_for_key(&2), 1);
+    assert_eq!(fetcher.calls_for_key(&4), 1);
+    assert_eq!(fetcher.calls_for_key(&6), 1);
+    assert_eq!(fetcher.calls_for_key(&8), 1);
+    assert_eq!(fetcher.calls_for_key(&10), 1);
+    assert_eq!(fetcher.calls_for_key(&11), 1);
+
+    let batch_result = batch_fetcher.load_many(&[11, 12]).await;
+    assert!(matches!(batch_result, Err(LoadError::NotFound)));
+    assert_eq!(fetcher.calls_for_key(&11), 1); // "Not found" status should be cached
+    assert_eq!(fetcher.calls_for_key(&12), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_error_before_inserting() -> Result<(), anyhow::Error> {
+    // Fetcher that first validates no odd keys are present, then stores even keys
+    struct EvenFetcher;
+
+    impl Fetcher for EvenFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, u64>,
+        ) -> Result<(), Self::Error> {
+            let (even_keys, mut odd_keys): (Vec<u64>, Vec<u64>) =
+                keys.iter().partition(|&&key| key % 2 == 0);
+
+            // Sort odd keys so we return consistent error messages
+            odd_keys.sort_unstable();
+            if !odd_keys.is_empty() {
+                return Err(anyhow::anyhow!("odd keys: {:?}", odd_keys));
+            }
+
+            for key in even_keys {
+                values.insert(key, key);
+            }
+
+            Ok(())
+        }
+    }
+
+    let fetcher = RecordingFetcher::new(EvenFetcher);
+    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
 
     let batch = batch_fetcher.load_many(&[2, 4, 6]).await?;
     assert_eq!(batch, vec![2, 4, 6]);
@@ -457,7 +1020,9 @@ async fn test_fetch_error_before_inserting() -> Result<(), anyhow::Error> {
     assert_eq!(fetcher.calls_for_key(&6), 1);
 
     let batch_result = batch_fetcher.load_many(&[2, 8, 10, 11, 13]).await;
-    assert!(matches!(batch_result, Err(LoadError::FetchError(msg)) if msg == "odd keys: [11, 13]"));
+    assert!(
+        matches!(batch_result, Err(LoadError::FetchError(msg)) if &*msg == "odd keys: [11, 13]")
+    );
     assert_eq!(fetcher.total_calls(), 2);
     assert_eq!(fetcher.calls_for_key(&2), 1);
     assert_eq!(fetcher.calls_for_key(&8), 1);
@@ -476,7 +1041,7 @@ async fn test_fetch_error_before_inserting() -> Result<(), anyhow::Error> {
     assert_eq!(fetcher.calls_for_key(&11), 1);
 
     let batch_result = batch_fetcher.load_many(&[11, 12]).await;
-    assert!(matches!(batch_result, Err(LoadError::FetchError(msg)) if msg == "odd keys: [11]"));
+    assert!(matches!(batch_result, Err(LoadError::FetchError(msg)) if &*msg == "odd keys: [11]"));
     assert_eq!(fetcher.calls_for_key(&11), 2); // Previously errored out, so it should be retried
     assert_eq!(fetcher.calls_for_key(&12), 1);
 
@@ -515,7 +1080,7 @@ async fn test_fetch_error_after_inserting() -> Result<(), anyhow::Error> {
         }
     }
 
-    let fetcher = stubs::ObserveFetcher::new(EvenFetcher);
+    let fetcher = RecordingFetcher::new(EvenFetcher);
     let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
 
     let batch = batch_fetcher.load_many(&[2, 4, 6]).await?;
@@ -526,7 +1091,9 @@ async fn test_fetch_error_after_inserting() -> Result<(), anyhow::Error> {
     assert_eq!(fetcher.calls_for_key(&6), 1);
 
     let batch_result = batch_fetcher.load_many(&[2, 8, 10, 11, 13]).await;
-    assert!(matches!(batch_result, Err(LoadError::FetchError(msg)) if msg == "odd keys: [11, 13]"));
+    assert!(
+        matches!(batch_result, Err(LoadError::FetchError(msg)) if &*msg == "odd keys: [11, 13]")
+    );
     assert_eq!(fetcher.total_calls(), 2);
     assert_eq!(fetcher.calls_for_key(&2), 1);
     assert_eq!(fetcher.calls_for_key(&8), 1);
@@ -545,9 +1112,2514 @@ async fn test_fetch_error_after_inserting() -> Result<(), anyhow::Error> {
     assert_eq!(fetcher.calls_for_key(&11), 1);
 
     let batch_result = batch_fetcher.load_many(&[11, 12]).await;
-    assert!(matches!(batch_result, Err(LoadError::FetchError(msg)) if msg == "odd keys: [11]"));
+    assert!(matches!(batch_result, Err(LoadError::FetchError(msg)) if &*msg == "odd keys: [11]"));
     assert_eq!(fetcher.calls_for_key(&11), 2); // Previously errored out, so it should be retried
     assert_eq!(fetcher.calls_for_key(&12), 1);
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_flush() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .delay_duration(tokio::time::Duration::from_secs(60))
+        .eager_batch_size(None)
+        .finish();
+
+    // Flushing with nothing queued shouldn't affect the next batch
+    batch_fetcher.flush().await;
+
+    let batch_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        let user_ids = user_ids[0..10].to_vec();
+        async move { batch_fetcher.load_many(&user_ids).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    assert_eq!(fetcher.total_calls(), 0);
+
+    batch_fetcher.flush().await;
+    let batch = tokio::time::timeout(tokio::time::Duration::from_secs(1), batch_task).await???;
+    assert_eq!(batch.len(), 10);
+    assert_eq!(fetcher.total_calls(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .delay_duration(tokio::time::Duration::from_secs(60))
+        .eager_batch_size(None)
+        .finish();
+
+    let batch_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        let user_ids = user_ids[0..10].to_vec();
+        async move { batch_fetcher.load_many(&user_ids).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    assert_eq!(fetcher.total_calls(), 0);
+
+    // Shutting down dispatches the queued keys and waits for them
+    tokio::time::timeout(
+        tokio::time::Duration::from_secs(1),
+        batch_fetcher.shutdown(),
+    )
+    .await?;
+    assert_eq!(fetcher.total_calls(), 1);
+    let batch = batch_task.await??;
+    assert_eq!(batch.len(), 10);
+
+    // Cached keys can still be loaded, but new keys can't
+    let user = batch_fetcher.load(user_ids[0]).await?;
+    assert_eq!(user.id, user_ids[0]);
+    assert!(matches!(
+        batch_fetcher.load(user_ids[10]).await,
+        Err(LoadError::SendError)
+    ));
+    assert_eq!(fetcher.total_calls(), 1);
+
+    // Shutting down again does nothing
+    batch_fetcher.shutdown().await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drop_aborts_fetch_task() -> anyhow::Result<()> {
+    struct PendingFetcher;
+
+    impl Fetcher for PendingFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            _keys: &[u64],
+            _values: &mut Cache<'_, u64, u64>,
+        ) -> Result<(), Self::Error> {
+            std::future::pending().await
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct TrackingSpawner {
+        finished: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    struct FinishGuard(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl Drop for FinishGuard {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    impl Spawner for TrackingSpawner {
+        fn spawn(&self, task: BoxFuture<'static, ()>) {
+            let guard = FinishGuard(self.finished.clone());
+            tokio::spawn(async move {
+                let _guard = guard;
+                task.await;
+            });
+        }
+    }
+
+    let spawner = TrackingSpawner::default();
+    let batch_fetcher = BatchFetcher::build(PendingFetcher)
+        .max_concurrent_batches(1)
+        .spawner(spawner.clone())
+        .finish();
+
+    // Start a batch that never finishes, then give up on it
+    let load_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        async move { batch_fetcher.load(1).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    load_task.abort();
+    let _ = load_task.await;
+
+    // The fetch task is waiting on the in-flight batch, but dropping the
+    // last `BatchFetcher` should still stop it
+    drop(batch_fetcher);
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    assert_eq!(
+        spawner.finished.load(std::sync::atomic::Ordering::SeqCst),
+        1
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dispatch_on_next_tick() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .delay_duration(tokio::time::Duration::from_secs(60))
+        .eager_batch_size(None)
+        .dispatch_on_next_tick()
+        .finish();
+
+    // Loads from the same tick should be batched without waiting for the delay
+    let loads = tokio::time::timeout(tokio::time::Duration::from_secs(1), async {
+        tokio::join!(
+            batch_fetcher.load(user_ids[0]),
+            batch_fetcher.load(user_ids[1]),
+            batch_fetcher.load_many(&user_ids[2..10]),
+        )
+    })
+    .await?;
+    assert_eq!(loads.0?.id, user_ids[0]);
+    assert_eq!(loads.1?.id, user_ids[1]);
+    assert_eq!(loads.2?.len(), 8);
+    assert_eq!(fetcher.total_calls(), 1);
+
+    let batch = batch_fetcher.load_many(&user_ids[10..20]).await?;
+    assert_eq!(batch.len(), 10);
+    assert_eq!(fetcher.total_calls(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_custom_scheduler() -> anyhow::Result<()> {
+    // Scheduler that waits (practically) forever until two requests are queued
+    struct TwoRequestScheduler;
+
+    impl BatchScheduler for TwoRequestScheduler {
+        fn schedule(&self, batch: &PendingBatch) -> Schedule {
+            if batch.num_requests >= 2 {
+                Schedule::DispatchNow
+            } else {
+                Schedule::WaitFor(tokio::time::Duration::from_secs(60))
+            }
+        }
+    }
+
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .scheduler(TwoRequestScheduler)
+        .finish();
+
+    let first_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        let user_id = user_ids[0];
+        async move { batch_fetcher.load(user_id).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    assert_eq!(fetcher.total_calls(), 0);
+
+    let second_user = tokio::time::timeout(
+        tokio::time::Duration::from_secs(1),
+        batch_fetcher.load(user_ids[1]),
+    )
+    .await??;
+    assert_eq!(second_user.id, user_ids[1]);
+    assert_eq!(first_task.await??.id, user_ids[0]);
+    assert_eq!(fetcher.total_calls(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_adaptive_scheduler() -> anyhow::Result<()> {
+    // Fetcher that takes 40ms for each batch
+    struct SlowFetcher;
+
+    impl Fetcher for SlowFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, u64>,
+        ) -> Result<(), Self::Error> {
+            tokio::time::sleep(tokio::time::Duration::from_millis(40)).await;
+            for key in keys {
+                values.insert(*key, *key);
+            }
+
+            Ok(())
+        }
+    }
+
+    let scheduler = Arc::new(AdaptiveBatchScheduler::new(
+        tokio::time::Duration::from_millis(1),
+        tokio::time::Duration::from_millis(100),
+    ));
+    let batch_fetcher = BatchFetcher::build(SlowFetcher)
+        .scheduler(scheduler.clone())
+        .finish();
+
+    assert_eq!(
+        scheduler.current_delay(),
+        tokio::time::Duration::from_millis(100)
+    );
+    assert_eq!(scheduler.current_eager_batch_size(), None);
+
+    for key in 0..5 {
+        assert_eq!(batch_fetcher.load(key).await?, key);
+    }
+
+    // The delay should shrink toward half of the batch duration
+    let delay = scheduler.current_delay();
+    assert!(delay > tokio::time::Duration::from_millis(15));
+    assert!(delay < tokio::time::Duration::from_millis(100));
+    assert!(scheduler.current_eager_batch_size().is_some());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_adaptive_scheduler_batches_sparse_loads() -> anyhow::Result<()> {
+    // Fetcher that takes 40ms for each batch
+    struct SlowFetcher;
+
+    impl Fetcher for SlowFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, u64>,
+        ) -> Result<(), Self::Error> {
+            tokio::time::sleep(tokio::time::Duration::from_millis(40)).await;
+            for key in keys {
+                values.insert(*key, *key);
+            }
+
+            Ok(())
+        }
+    }
+
+    let fetcher = RecordingFetcher::new(SlowFetcher);
+    let scheduler = Arc::new(AdaptiveBatchScheduler::new(
+        tokio::time::Duration::from_millis(1),
+        tokio::time::Duration::from_millis(100),
+    ));
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .scheduler(scheduler.clone())
+        .finish();
+
+    // Each batch only ever sees one key, so fewer than 2 keys are
+    // expected per batch. The eager batch size shouldn't drop to 1, which
+    // would dispatch each key on its own
+    for key in 0..3 {
+        assert_eq!(batch_fetcher.load(key).await?, key);
+        assert!(scheduler.current_eager_batch_size() >= Some(2));
+        tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(fetcher.total_calls(), 3);
+
+    // Two keys arriving within the delay should still share a batch
+    let (first, second) = tokio::join!(batch_fetcher.load(10), async {
+        tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+        batch_fetcher.load(11).await
+    });
+    assert_eq!(first?, 10);
+    assert_eq!(second?, 11);
+    assert_eq!(fetcher.total_calls(), 4);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cancelled_loads_are_not_fetched() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .delay_duration(tokio::time::Duration::from_millis(50))
+        .finish();
+
+    // A dropped load should not trigger a fetch
+    let result = tokio::time::timeout(
+        tokio::time::Duration::from_millis(10),
+        batch_fetcher.load(user_ids[0]),
+    )
+    .await;
+    assert!(result.is_err());
+    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    assert_eq!(fetcher.total_calls(), 0);
+
+    // Only keys with a remaining caller should be fetched
+    let cancelled_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        let user_ids = user_ids[0..2].to_vec();
+        async move { batch_fetcher.load_many(&user_ids).await }
+    });
+    let task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        let user_ids = user_ids[1..3].to_vec();
+        async move { batch_fetcher.load_many(&user_ids).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+    cancelled_task.abort();
+
+    let batch = task.await??;
+    assert_eq!(batch.len(), 2);
+    assert_eq!(fetcher.total_calls(), 1);
+    assert_eq!(fetcher.calls_for_key(&user_ids[0]), 0);
+    assert_eq!(fetcher.calls_for_key(&user_ids[1]), 1);
+    assert_eq!(fetcher.calls_for_key(&user_ids[2]), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_stream() -> anyhow::Result<()> {
+    // Fetcher that waits to be notified before fetching the key 0
+    struct GatedFetcher {
+        gate: Arc<tokio::sync::Notify>,
+    }
+
+    impl Fetcher for GatedFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, u64>,
+        ) -> Result<(), Self::Error> {
+            if keys.contains(&0) {
+                self.gate.notified().await;
+            }
+
+            for key in keys {
+                if *key != 3 {
+                    values.insert(*key, *key);
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    let gate = Arc::new(tokio::sync::Notify::new());
+    let batch_fetcher = BatchFetcher::build(GatedFetcher { gate: gate.clone() })
+        .max_batch_size(Some(1))
+        .max_concurrent_batches(4)
+        .finish();
+
+    let mut values = batch_fetcher.load_stream(&[0, 1, 2, 3]);
+
+    // The other keys finish while the batch with key 0 is still in-flight
+    let mut first_values = vec![];
+    for _ in 0..3 {
+        let value =
+            tokio::time::timeout(tokio::time::Duration::from_secs(1), values.next()).await?;
+        first_values.push(value.unwrap());
+    }
+    first_values.sort_by_key(|(key, _)| *key);
+
+    assert!(matches!(
+        &first_values[..],
+        [(1, Ok(1)), (2, Ok(2)), (3, Err(LoadError::NotFound))]
+    ));
+
+    gate.notify_one();
+    assert!(matches!(values.next().await, Some((0, Ok(0)))));
+    assert!(values.next().await.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_borrowed() -> anyhow::Result<()> {
+    // Fetcher that returns the length of each key, except for empty keys
+    struct LengthFetcher;
+
+    impl Fetcher for LengthFetcher {
+        type Key = String;
+        type Value = usize;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[String],
+            values: &mut Cache<'_, String, usize>,
+        ) -> Result<(), Self::Error> {
+            for key in keys.iter().filter(|key| !key.is_empty()) {
+                values.insert(key.clone(), key.len());
+            }
+
+            Ok(())
+        }
+    }
+
+    let fetcher = RecordingFetcher::new(LengthFetcher);
+    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+
+    assert_eq!(batch_fetcher.load_borrowed("a").await?, 1);
+    assert_eq!(
+        batch_fetcher.load_many_borrowed(["a", "bb", "a"]).await?,
+        [1, 2, 1]
+    );
+    assert!(matches!(
+        batch_fetcher.load_borrowed("").await,
+        Err(LoadError::NotFound)
+    ));
+    assert_eq!(fetcher.total_calls(), 3);
+
+    // Cached values don't need to be fetched again
+    assert_eq!(batch_fetcher.load_many_borrowed(["bb", "a"]).await?, [2, 1]);
+    assert!(matches!(
+        batch_fetcher.load_borrowed("").await,
+        Err(LoadError::NotFound)
+    ));
+    assert_eq!(fetcher.total_calls(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_introspection() -> anyhow::Result<()> {
+    // Fetcher that waits to be notified before fetching
+    struct GatedFetcher {
+        gate: Arc<tokio::sync::Notify>,
+    }
+
+    impl Fetcher for GatedFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, u64>,
+        ) -> Result<(), Self::Error> {
+            self.gate.notified().await;
+
+            for key in keys.iter().filter(|key| **key != 0) {
+                values.insert(*key, *key);
+            }
+
+            Ok(())
+        }
+    }
+
+    let gate = Arc::new(tokio::sync::Notify::new());
+    let batch_fetcher = BatchFetcher::build(GatedFetcher { gate: gate.clone() })
+        .delay_duration(tokio::time::Duration::from_secs(60))
+        .eager_batch_size(None)
+        .finish();
+
+    let batch_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        async move { batch_fetcher.load_many_map(&[0, 1, 2]).await }
+    });
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    assert_eq!(batch_fetcher.pending_keys_len(), 3);
+    assert_eq!(batch_fetcher.in_flight_batches(), 0);
+    assert_eq!(batch_fetcher.cached_len(), 0);
+
+    batch_fetcher.flush().await;
+    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    assert_eq!(batch_fetcher.pending_keys_len(), 0);
+    assert_eq!(batch_fetcher.in_flight_batches(), 1);
+
+    gate.notify_one();
+    let values = batch_task.await??;
+    assert_eq!(values.len(), 2);
+    assert_eq!(batch_fetcher.in_flight_batches(), 0);
+
+    // Keys that were not found are still cached
+    assert_eq!(batch_fetcher.cached_len(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_or_else() -> anyhow::Result<()> {
+    // Fetcher that only has even keys
+    struct EvenFetcher;
+
+    impl Fetcher for EvenFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, u64>,
+        ) -> Result<(), Self::Error> {
+            for key in keys.iter().filter(|key| *key % 2 == 0) {
+                values.insert(*key, *key);
+            }
+
+            Ok(())
+        }
+    }
+
+    let fetcher = RecordingFetcher::new(EvenFetcher);
+    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+
+    assert_eq!(batch_fetcher.load_or_else(2, || async { 100 }).await?, 2);
+    assert_eq!(batch_fetcher.load_or_else(3, || async { 100 }).await?, 100);
+    assert_eq!(fetcher.total_calls(), 2);
+
+    // The fallback value is cached
+    assert_eq!(batch_fetcher.load(3).await?, 100);
+    assert_eq!(batch_fetcher.load_or_else(3, || async { 200 }).await?, 100);
+    assert_eq!(fetcher.total_calls(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_from_fn() -> anyhow::Result<()> {
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let batch_fetcher = BatchFetcher::from_fn({
+        let calls = calls.clone();
+        move |keys: Vec<u64>| {
+            calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                let values: std::collections::HashMap<_, _> = keys
+                    .into_iter()
+                    .filter(|key| key % 2 == 0)
+                    .map(|key| (key, key * 10))
+                    .collect();
+                anyhow::Ok(values)
+            }
+        }
+    })
+    .finish();
+
+    let values = batch_fetcher.load_many(&[2, 4]).await?;
+    assert_eq!(values, vec![20, 40]);
+    assert!(matches!(
+        batch_fetcher.load(3).await,
+        Err(LoadError::NotFound)
+    ));
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_map_fetcher() -> anyhow::Result<()> {
+    // Fetcher that returns a map with only the even keys
+    struct EvenFetcher;
+
+    impl MapFetcher for EvenFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch_map(
+            &self,
+            keys: &[u64],
+        ) -> anyhow::Result<std::collections::HashMap<u64, u64>> {
+            Ok(keys
+                .iter()
+                .filter(|&&key| key % 2 == 0)
+                .map(|&key| (key, key * 10))
+                .collect())
+        }
+    }
+
+    let batch_fetcher = BatchFetcher::build_map(EvenFetcher).finish();
+
+    let values = batch_fetcher.load_many(&[2, 4]).await?;
+    assert_eq!(values, vec![20, 40]);
+    assert!(matches!(
+        batch_fetcher.load(3).await,
+        Err(LoadError::NotFound)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_pairs_fetcher() -> anyhow::Result<()> {
+    // Fetcher that returns pairs for only the even keys
+    struct EvenFetcher;
+
+    impl PairsFetcher for EvenFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch_pairs(&self, keys: &[u64]) -> anyhow::Result<Vec<(u64, u64)>> {
+            Ok(keys
+                .iter()
+                .filter(|&&key| key % 2 == 0)
+                .map(|&key| (key, key * 10))
+                .collect())
+        }
+    }
+
+    let batch_fetcher = BatchFetcher::build_pairs(EvenFetcher).finish();
+
+    let values = batch_fetcher.load_many(&[2, 4]).await?;
+    assert_eq!(values, vec![20, 40]);
+    assert!(matches!(
+        batch_fetcher.load(3).await,
+        Err(LoadError::NotFound)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_batch_info() -> anyhow::Result<()> {
+    // Fetcher that records the info for each batch
+    #[derive(Clone, Default)]
+    struct RecordBatchInfo {
+        batches: Arc<RwLock<Vec<BatchInfo>>>,
+    }
+
+    impl Fetcher for RecordBatchInfo {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, u64>,
+        ) -> anyhow::Result<()> {
+            let batch_info = values.batch_info().expect("no batch info").clone();
+            self.batches.write().unwrap().push(batch_info);
+            for &key in keys {
+                values.insert(key, key);
+            }
+            Ok(())
+        }
+    }
+
+    let fetcher = RecordBatchInfo::default();
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .label("record-batch-info")
+        .finish();
+
+    let (first, second) = tokio::join!(batch_fetcher.load(1), batch_fetcher.load_many(&[2, 3]));
+    first?;
+    second?;
+    batch_fetcher.load(4).await?;
+
+    let batches = fetcher.batches.read().unwrap();
+    let batches: Vec<_> = batches
+        .iter()
+        .map(|batch| (batch.batch_id, &*batch.label, batch.num_waiters))
+        .collect();
+    assert_eq!(
+        batches,
+        [(0, "record-batch-info", 2), (1, "record-batch-info", 1)]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_with_request_context() -> anyhow::Result<()> {
+    // Fetcher that records the keys fetched for each context, and tags
+    // each value with its context
+    #[derive(Clone, Default)]
+    struct TenantFetcher {
+        calls: Arc<RwLock<Vec<String>>>,
+    }
+
+    impl RequestContextFetcher for TenantFetcher {
+        type Context = &'static str;
+        type Key = u64;
+        type Value = String;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            tenant: &&'static str,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, String>,
+        ) -> anyhow::Result<()> {
+            let mut sorted_keys = keys.to_vec();
+            sorted_keys.sort();
+            self.calls
+                .write()
+                .unwrap()
+                .push(format!("{tenant}: {sorted_keys:?}"));
+            for &key in keys {
+                values.insert(key, format!("{tenant}/{key}"));
+            }
+            Ok(())
+        }
+    }
+
+    let fetcher = TenantFetcher::default();
+    let batch_fetcher = BatchFetcher::build_with_request_context(fetcher.clone()).finish();
+
+    let (first, second, third) = tokio::join!(
+        batch_fetcher.load_with_context("a", 1),
+        batch_fetcher.load_many_with_context("b", [1, 2]),
+        batch_fetcher.load_with_context("a", 3),
+    );
+    assert_eq!(first?, "a/1");
+    assert_eq!(second?, ["b/1", "b/2"]);
+    assert_eq!(third?, "a/3");
+
+    // Tenants never share a batch
+    let mut calls = fetcher.calls.read().unwrap().clone();
+    calls.sort();
+    assert_eq!(calls, ["a: [1, 3]", "b: [1, 2]"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_exclusive_fetcher() -> anyhow::Result<()> {
+    // Fetcher that numbers each batch using state that isn't `Sync`, and
+    // tracks how many batches are running at once
+    struct NumberBatches {
+        next_batch: std::cell::Cell<u64>,
+        running: Arc<std::sync::atomic::AtomicUsize>,
+        max_running: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl ExclusiveFetcher for NumberBatches {
+        type Key = u64;
+        type Value = (u64, u64);
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &mut self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, (u64, u64)>,
+        ) -> anyhow::Result<()> {
+            let running = self
+                .running
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+                + 1;
+            self.max_running
+                .fetch_max(running, std::sync::atomic::Ordering::SeqCst);
+
+            let batch = self.next_batch.get();
+            self.next_batch.set(batch + 1);
+            tokio::time::sleep(tokio::time::Duration::from_millis(20)).await;
+            for &key in keys {
+                values.insert(key, (batch, key));
+            }
+
+            self.running
+                .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    let max_running = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let fetcher = NumberBatches {
+        next_batch: std::cell::Cell::new(0),
+        running: Arc::default(),
+        max_running: max_running.clone(),
+    };
+
+    // Each key is its own batch, but batches still run one at a time
+    let batch_fetcher = BatchFetcher::build_exclusive(fetcher)
+        .shard_by(|key| *key)
+        .max_concurrent_batches(4)
+        .finish();
+
+    let mut values = batch_fetcher.load_many(&[1, 2, 3]).await?;
+    values.sort();
+    let batches: Vec<_> = values.iter().map(|(batch, _)| *batch).collect();
+    assert_eq!(batches, [0, 1, 2]);
+    assert_eq!(max_running.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_lifecycle_hooks() -> anyhow::Result<()> {
+    // Fetcher that records each lifecycle hook along with each fetch
+    #[derive(Clone, Default)]
+    struct RecordHooks {
+        events: Arc<RwLock<Vec<String>>>,
+    }
+
+    impl Fetcher for RecordHooks {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[u64],
+            values: &mut Cache<'_, u64, u64>,
+        ) -> anyhow::Result<()> {
+            self.events
+                .write()
+                .unwrap()
+                .push(format!("fetch {}", keys.len()));
+            anyhow::ensure!(!keys.contains(&0), "uh oh");
+            for &key in keys {
+                values.insert(key, key);
+            }
+            Ok(())
+        }
+
+        fn on_batch_queued(&self, batch: &PendingBatch) {
+            self.events
+                .write()
+                .unwrap()
+                .push(format!("queued {}", batch.len));
+        }
+
+        fn on_batch_dispatching(&self, batch: &BatchInfo) {
+            self.events
+                .write()
+                .unwrap()
+                .push(format!("dispatching {}", batch.batch_id));
+        }
+
+        fn on_batch_complete(&self, batch: &FinishedBatch) {
+            self.events.write().unwrap().push(format!(
+                "complete {} {}",
+                batch.size,
+                batch.result.is_ok()
+            ));
+        }
+    }
+
+    let fetcher = RecordHooks::default();
+    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+
+    batch_fetcher.load_many(&[1, 2]).await?;
+    let result = batch_fetcher.load(0).await;
+    assert!(matches!(result, Err(LoadError::FetchError(_))));
+
+    let events = fetcher.events.read().unwrap();
+    assert_eq!(
+        *events,
+        [
+            "queued 2",
+            "dispatching 0",
+            "fetch 2",
+            "complete 2 true",
+            "queued 1",
+            "dispatching 1",
+            "fetch 1",
+            "complete 1 false",
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_validate_keys() -> anyhow::Result<()> {
+    let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let batch_fetcher = BatchFetcher::from_fn({
+        let calls = calls.clone();
+        move |keys: Vec<u64>| {
+            calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                let values: std::collections::HashMap<_, _> =
+                    keys.into_iter().map(|key| (key, key * 10)).collect();
+                anyhow::Ok(values)
+            }
+        }
+    })
+    .validate_keys(|key| {
+        if *key == 0 {
+            Err("key must be non-zero")
+        } else {
+            Ok(())
+        }
+    })
+    .finish();
+
+    // Loads with an invalid key fail without being fetched
+    let result = batch_fetcher.load_many(&[1, 0]).await;
+    assert!(matches!(result, Err(LoadError::InvalidKey(msg)) if &*msg == "key must be non-zero"));
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+    // Loads with only valid keys aren't affected
+    let values = batch_fetcher.load_many(&[1, 2]).await?;
+    assert_eq!(values, vec![10, 20]);
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_shared_fetcher() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().take(2).collect();
+
+    let fetcher = Arc::new(RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    }));
+    let batch_fetcher_1 = BatchFetcher::build(fetcher.clone()).finish();
+    let batch_fetcher_2 = BatchFetcher::build(fetcher.clone()).finish();
+
+    let user_1 = batch_fetcher_1.load(user_ids[0]).await?;
+    let user_2 = batch_fetcher_2.load(user_ids[1]).await?;
+    assert_eq!(user_1.id, user_ids[0]);
+    assert_eq!(user_2.id, user_ids[1]);
+    assert_eq!(fetcher.total_calls(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_many_to_many() -> anyhow::Result<()> {
+    let association_calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let value_calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    // Each user belongs to the groups with IDs up to their own ID
+    let user_group_ids = BatchFetcher::from_fn({
+        let association_calls = association_calls.clone();
+        move |user_ids: Vec<u64>| {
+            association_calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                let group_ids: std::collections::HashMap<_, _> = user_ids
+                    .into_iter()
+                    .filter(|user_id| *user_id != 0)
+                    .map(|user_id| (user_id, (1..=user_id).collect::<Vec<_>>()))
+                    .collect();
+                anyhow::Ok(group_ids)
+            }
+        }
+    })
+    .finish();
+
+    // Only odd groups exist
+    let groups = BatchFetcher::from_fn({
+        let value_calls = value_calls.clone();
+        move |group_ids: Vec<u64>| {
+            value_calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                let groups: std::collections::HashMap<_, _> = group_ids
+                    .into_iter()
+                    .filter(|group_id| group_id % 2 == 1)
+                    .map(|group_id| (group_id, format!("Group {group_id}")))
+                    .collect();
+                anyhow::Ok(groups)
+            }
+        }
+    })
+    .finish();
+
+    let user_groups = BatchFetcher::build(ManyToManyFetcher::new(user_group_ids, groups)).finish();
+
+    let (user_1_groups, user_3_groups) =
+        tokio::try_join!(user_groups.load(1), user_groups.load(3))?;
+    assert_eq!(user_1_groups, vec!["Group 1"]);
+    assert_eq!(user_3_groups, vec!["Group 1", "Group 3"]);
+    assert_eq!(
+        association_calls.load(std::sync::atomic::Ordering::SeqCst),
+        1
+    );
+    assert_eq!(value_calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    let user_0_groups = user_groups.load(0).await;
+    assert!(matches!(user_0_groups, Err(LoadError::NotFound)));
+
+    Ok(())
+}
+
+ultra_batch::batch_key! {
+    struct TenantKey {
+        tenant_id: u64,
+        id: u64,
+    }
+}
+
+#[tokio::test]
+async fn test_load_composite_key() -> anyhow::Result<()> {
+    let batch_fetcher = BatchFetcher::from_fn(|keys: Vec<TenantKey>| async move {
+        let values: std::collections::HashMap<_, _> = keys
+            .into_iter()
+            .map(|key| {
+                let value = (key.tenant_id * 100) + key.id;
+                (key, value)
+            })
+            .collect();
+        anyhow::Ok(values)
+    })
+    .finish();
+
+    let keys: Vec<TenantKey> = vec![(2, 1).into(), (1, 2).into(), (1, 1).into()];
+    let values = batch_fetcher.load_many(&keys).await?;
+    assert_eq!(values, vec![201, 102, 101]);
+
+    // Keys are sorted by tenant, then by ID
+    let mut sorted_keys = keys.clone();
+    sorted_keys.sort();
+    let sorted_keys: Vec<(u64, u64)> = sorted_keys.into_iter().map(Into::into).collect();
+    assert_eq!(sorted_keys, vec![(1, 1), (1, 2), (2, 1)]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_loader_registry() -> anyhow::Result<()> {
+    let db = Arc::new(RwLock::new(db::Database::fake()));
+    let user_id = *db.read().unwrap().users.keys().next().unwrap();
+
+    let mut registry = LoaderRegistry::new();
+    assert!(registry.get::<db::FetchUsers>().is_none());
+
+    let previous = registry.insert(BatchFetcher::build(db::FetchUsers { db: db.clone() }).finish());
+    assert!(previous.is_none());
+    assert_eq!(registry.len(), 1);
+
+    let user = registry
+        .get::<db::FetchUsers>()
+        .unwrap()
+        .load(user_id)
+        .await?;
+    assert_eq!(user.id, user_id);
+
+    let previous = registry.insert(BatchFetcher::build(db::FetchUsers { db }).finish());
+    assert!(previous.is_some());
+    assert_eq!(registry.len(), 1);
+
+    assert!(registry.remove::<db::FetchUsers>().is_some());
+    assert!(registry.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_loader_factory() -> anyhow::Result<()> {
+    let db = Arc::new(RwLock::new(db::Database::fake()));
+    let user_ids: Vec<_> = db.read().unwrap().users.keys().copied().collect();
+
+    let factory = LoaderFactory::new().eager_batch_size(Some(2)).fetcher({
+        let db = db.clone();
+        move || db::FetchUsers { db: db.clone() }
+    });
+
+    // Each registry gets its own fetcher with its own cache
+    let loaders_1 = factory.create();
+    let loaders_2 = factory.create();
+    let user_loader_1 = loaders_1.get::<db::FetchUsers>().unwrap();
+    let user_loader_2 = loaders_2.get::<db::FetchUsers>().unwrap();
+
+    user_loader_1.load(user_ids[0]).await?;
+    assert_eq!(user_loader_1.cached_len(), 1);
+    assert_eq!(user_loader_2.cached_len(), 0);
+
+    // Options from the factory are used for each fetcher
+    let (user_1, user_2) = tokio::time::timeout(
+        tokio::time::Duration::from_millis(5),
+        futures_util::future::try_join(
+            user_loader_2.load(user_ids[0]),
+            user_loader_2.load(user_ids[1]),
+        ),
+    )
+    .await??;
+    assert_eq!(user_1.id, user_ids[0]);
+    assert_eq!(user_2.id, user_ids[1]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_then_load_with() -> anyhow::Result<()> {
+    let db = Arc::new(RwLock::new(db::Database::fake()));
+    let posts: Vec<_> = db.read().unwrap().posts.values().cloned().collect();
+
+    let post_fetcher = RecordingFetcher::new(db::FetchPosts { db: db.clone() });
+    let user_fetcher = RecordingFetcher::new(db::FetchUsers { db: db.clone() });
+    let batch_fetcher = BatchFetcher::build(
+        post_fetcher
+            .clone()
+            .then_load_with(user_fetcher.clone(), |post| post.user_id),
+    )
+    .finish();
+
+    let post_ids: Vec<_> = posts.iter().map(|post| post.id).collect();
+    let authors = batch_fetcher.load_many(&post_ids).await?;
+    let author_ids: Vec<_> = authors.iter().map(|author| author.id).collect();
+    let expected_author_ids: Vec<_> = posts.iter().map(|post| post.user_id).collect();
+    assert_eq!(author_ids, expected_author_ids);
+    assert_eq!(post_fetcher.total_calls(), 1);
+    assert_eq!(user_fetcher.total_calls(), 1);
+
+    let missing = batch_fetcher.load(uuid::Uuid::new_v4()).await;
+    assert!(matches!(missing, Err(LoadError::NotFound)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_map_value() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let users: Vec<_> = db.users.values().cloned().collect();
+
+    let fetcher = db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    };
+    let batch_fetcher = BatchFetcher::build(fetcher.map_value(|user: db::User| user.name)).finish();
+
+    let user_ids: Vec<_> = users.iter().map(|user| user.id).collect();
+    let names = batch_fetcher.load_many(&user_ids).await?;
+    let expected_names: Vec<_> = users.iter().map(|user| user.name.clone()).collect();
+    assert_eq!(names, expected_names);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_contramap_key() -> anyhow::Result<()> {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    struct UserId(uuid::Uuid);
+
+    let db = db::Database::fake();
+    let user_id = *db.users.keys().next().unwrap();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher =
+        BatchFetcher::build(fetcher.clone().contramap_key(|user_id: &UserId| user_id.0)).finish();
+
+    let user = batch_fetcher.load(UserId(user_id)).await?;
+    assert_eq!(user.id, user_id);
+
+    let missing = batch_fetcher.load(UserId(uuid::Uuid::new_v4())).await;
+    assert!(matches!(missing, Err(LoadError::NotFound)));
+    assert_eq!(fetcher.total_calls(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_with_fallback() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    // The primary fetcher only has the first user
+    let mut primary_db = db::Database::fake();
+    primary_db.users = db
+        .users
+        .iter()
+        .filter(|(id, _)| **id == user_ids[0])
+        .map(|(id, user)| (*id, user.clone()))
+        .collect();
+
+    let primary = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(primary_db)),
+    });
+    let fallback = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher =
+        BatchFetcher::build(primary.clone().with_fallback(fallback.clone())).finish();
+
+    let user = batch_fetcher.load(user_ids[0]).await?;
+    assert_eq!(user.id, user_ids[0]);
+    assert_eq!(primary.total_calls(), 1);
+    assert_eq!(fallback.total_calls(), 0);
+
+    let users = batch_fetcher.load_many(&user_ids).await?;
+    let loaded_ids: Vec<_> = users.iter().map(|user| user.id).collect();
+    assert_eq!(loaded_ids, user_ids);
+    assert_eq!(primary.total_calls(), 2);
+    assert_eq!(fallback.total_calls(), 1);
+    assert_eq!(fallback.calls_for_key(&user_ids[0]), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_blocking() -> anyhow::Result<()> {
+    let batch_fetcher = BatchFetcher::build(BlockingFetcher::new(|keys: Vec<u64>| {
+        let values: std::collections::HashMap<_, _> = keys
+            .into_iter()
+            .filter(|key| key % 2 == 0)
+            .map(|key| (key, key * 10))
+            .collect();
+        anyhow::Ok(values)
+    }))
+    .finish();
+
+    let values = batch_fetcher.load_many(&[2, 4]).await?;
+    assert_eq!(values, vec![20, 40]);
+    assert!(matches!(
+        batch_fetcher.load(3).await,
+        Err(LoadError::NotFound)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_links_caller_spans() -> anyhow::Result<()> {
+    let spans = stubs::RecordTraces::default();
+    let _guard = tracing::subscriber::set_default(spans.clone());
+
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().take(3).collect();
+    let batch_fetcher = BatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .finish();
+
+    // Both callers wait on the same batch, so it links to both of them
+    let (user, users) = tokio::join!(
+        batch_fetcher.load(user_ids[0]),
+        batch_fetcher.load_many(&user_ids[1..]),
+    );
+    user?;
+    users?;
+
+    let mut follows_from = spans.follows_from();
+    follows_from.sort();
+    assert_eq!(
+        follows_from,
+        vec![("fetch_batch", "load"), ("fetch_batch", "load_many")]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_traces_cache_lookups() -> anyhow::Result<()> {
+    let traces = stubs::RecordTraces::default();
+    let _guard = tracing::subscriber::set_default(traces.clone());
+
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().take(3).collect();
+    let batch_fetcher = BatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .finish();
+
+    let missing_id = uuid::Uuid::new_v4();
+    batch_fetcher.load_many(&user_ids[..2]).await?;
+    assert!(batch_fetcher.load(missing_id).await.is_err());
+    let _ = batch_fetcher
+        .load_many(&[user_ids[0], user_ids[1], user_ids[2], missing_id])
+        .await;
+
+    let lookups: Vec<_> = traces
+        .events("looked up keys in cache")
+        .into_iter()
+        .map(|event| {
+            (
+                event["num_cache_hits"].clone(),
+                event["num_cache_negative_hits"].clone(),
+                event["num_cache_misses"].clone(),
+            )
+        })
+        .collect();
+    let lookup = |hits: &str, negative_hits: &str, misses: &str| {
+        (
+            hits.to_string(),
+            negative_hits.to_string(),
+            misses.to_string(),
+        )
+    };
+    assert_eq!(
+        lookups,
+        vec![
+            lookup("0", "0", "2"),
+            lookup("0", "0", "1"),
+            lookup("2", "1", "1"),
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_stats() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().take(6).collect();
+    let batch_fetcher = BatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .delay_duration(tokio::time::Duration::from_millis(10))
+    .finish();
+
+    assert_eq!(batch_fetcher.stats(), BatchFetcherStats::default());
+    assert_eq!(batch_fetcher.stats().average_batch_size(), 0.0);
+
+    // Overlapping keys in the same batch are only fetched once
+    let (first, second) = tokio::join!(
+        batch_fetcher.load_many(&user_ids[..3]),
+        batch_fetcher.load_many(&user_ids[1..4]),
+    );
+    first?;
+    second?;
+
+    // Cached keys aren't fetched again
+    batch_fetcher.load_many(&user_ids[2..6]).await?;
+    batch_fetcher.load_borrowed(&user_ids[0]).await?;
+
+    let stats = batch_fetcher.stats();
+    assert_eq!(stats.num_batches, 2);
+    assert_eq!(stats.num_keys_requested, 11);
+    assert_eq!(stats.num_keys_fetched, 6);
+    assert_eq!(stats.average_batch_size(), 3.0);
+    assert!(stats.total_wait_duration >= tokio::time::Duration::from_millis(20));
+    assert_eq!(stats.average_wait_duration(), stats.total_wait_duration / 2);
+
+    Ok(())
+}
+
+#[cfg(feature = "opentelemetry")]
+#[tokio::test]
+async fn test_load_propagates_otel_context() -> anyhow::Result<()> {
+    use opentelemetry::context::FutureExt as _;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct RequestId(u64);
+
+    let batch_contexts = Arc::new(RwLock::new(vec![]));
+    let batch_fetcher = BatchFetcher::from_fn({
+        let batch_contexts = batch_contexts.clone();
+        move |ids: Vec<u64>| {
+            let request_id = opentelemetry::Context::current()
+                .get::<RequestId>()
+                .copied();
+            batch_contexts.write().unwrap().push(request_id);
+            async move {
+                anyhow::Ok(
+                    ids.into_iter()
+                        .map(|id| (id, id))
+                        .collect::<std::collections::HashMap<_, _>>(),
+                )
+            }
+        }
+    })
+    .finish();
+
+    // The batch runs with the context of the first caller waiting on it
+    let (first, second) = tokio::join!(
+        batch_fetcher
+            .load(1)
+            .with_context(opentelemetry::Context::current_with_value(RequestId(1))),
+        batch_fetcher
+            .load(2)
+            .with_context(opentelemetry::Context::current_with_value(RequestId(2))),
+    );
+    first?;
+    second?;
+
+    assert_eq!(*batch_contexts.read().unwrap(), vec![Some(RequestId(1))]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_warns_on_slow_batches() -> anyhow::Result<()> {
+    let traces = stubs::RecordTraces::default();
+    let _guard = tracing::subscriber::set_default(traces.clone());
+
+    let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+        if ids.contains(&0) {
+            tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+        }
+        anyhow::Ok(
+            ids.into_iter()
+                .map(|id| (id, id))
+                .collect::<std::collections::HashMap<_, _>>(),
+        )
+    })
+    .label("slow-fetcher")
+    .warn_if_slower_than(tokio::time::Duration::from_millis(20))
+    .finish();
+
+    batch_fetcher.load_many(&[1, 2]).await?;
+    assert!(traces
+        .events("batch took longer than the slow batch threshold")
+        .is_empty());
+
+    batch_fetcher.load_many(&[0, 3, 4]).await?;
+    let warnings = traces.events("batch took longer than the slow batch threshold");
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0]["batch_fetcher"], "slow-fetcher");
+    assert_eq!(warnings[0]["num_keys"], "3");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_inspect() -> anyhow::Result<()> {
+    let fetch_permits = Arc::new(tokio::sync::Semaphore::new(0));
+    let batch_fetcher = BatchFetcher::from_fn({
+        let fetch_permits = fetch_permits.clone();
+        move |ids: Vec<u64>| {
+            let fetch_permits = fetch_permits.clone();
+            async move {
+                let _permit = fetch_permits.acquire().await?;
+                anyhow::Ok(
+                    ids.into_iter()
+                        .map(|id| (id, id))
+                        .collect::<std::collections::HashMap<_, _>>(),
+                )
+            }
+        }
+    })
+    .label("inspected")
+    .delay_duration(tokio::time::Duration::from_secs(60))
+    .eager_batch_size(None)
+    .max_concurrent_batches(2)
+    .finish();
+
+    // Start a batch that stays in flight until a permit is added
+    let first = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        async move { batch_fetcher.load_many(&[1, 2]).await }
+    });
+    while batch_fetcher.inspect().in_flight_batches.is_empty() {
+        batch_fetcher.flush().await;
+        tokio::task::yield_now().await;
+    }
+
+    // Queue up more keys without dispatching them
+    let second = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        async move { batch_fetcher.load_many(&[3, 4]).await }
+    });
+    let third = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        async move { batch_fetcher.load(3).await }
+    });
+    while batch_fetcher.inspect().num_queued_waiters < 2 {
+        tokio::task::yield_now().await;
+    }
+
+    let state = batch_fetcher.inspect_with_keys();
+    assert_eq!(state.label, "inspected");
+    assert_eq!(state.num_queued_keys, 2);
+    assert_eq!(state.num_queued_waiters, 2);
+    let mut queued_keys = state.queued_keys.unwrap();
+    queued_keys.sort();
+    assert_eq!(queued_keys, vec![3, 4]);
+    assert_eq!(state.in_flight_batches.len(), 1);
+    assert_eq!(state.in_flight_batches[0].num_keys, 2);
+    assert_eq!(state.in_flight_batches[0].num_waiters, 1);
+    assert_eq!(state.num_cached, 0);
+    assert!(batch_fetcher.inspect().queued_keys.is_none());
+
+    fetch_permits.add_permits(2);
+    batch_fetcher.flush().await;
+    assert_eq!(first.await??, vec![1, 2]);
+    assert_eq!(second.await??, vec![3, 4]);
+    assert_eq!(third.await??, 3);
+
+    let state = batch_fetcher.inspect();
+    assert_eq!(state.num_queued_keys, 0);
+    assert!(state.in_flight_batches.is_empty());
+    assert_eq!(state.num_cached, 4);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_metrics() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().take(10).collect();
+
+    let metrics = stubs::RecordMetrics::default();
+    let batch_fetcher = BatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .max_batch_size(Some(4))
+    .metrics(metrics.clone())
+    .finish();
+
+    batch_fetcher.load_many(&user_ids).await?;
+
+    let mut dispatched = metrics.dispatched();
+    dispatched.sort();
+    assert_eq!(dispatched, vec![(2, 1), (4, 1), (4, 1)]);
+    let mut completed = metrics.completed();
+    completed.sort();
+    assert_eq!(completed, vec![(2, Ok(())), (4, Ok(())), (4, Ok(()))]);
+    assert_eq!(metrics.cache_hits(), 0);
+    assert_eq!(metrics.cache_misses(), 10);
+    assert_eq!(metrics.queue_depths(), vec![10, 0]);
+
+    // Loading the same keys again only hits the cache
+    batch_fetcher.load_many(&user_ids).await?;
+    batch_fetcher.load_borrowed(&user_ids[0]).await?;
+    assert_eq!(metrics.dispatched().len(), 3);
+    assert_eq!(metrics.cache_hits(), 11);
+    assert_eq!(metrics.cache_misses(), 10);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_custom_spawner_and_timer() -> anyhow::Result<()> {
+    #[derive(Clone, Default)]
+    struct CountingRuntime {
+        spawns: Arc<std::sync::atomic::AtomicUsize>,
+        sleeps: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Spawner for CountingRuntime {
+        fn spawn(&self, task: BoxFuture<'static, ()>) {
+            self.spawns
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::spawn(task);
+        }
+    }
+
+    impl Timer for CountingRuntime {
+        fn sleep(&self, duration: std::time::Duration) -> BoxFuture<'static, ()> {
+            self.sleeps
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Box::pin(tokio::time::sleep(duration))
+        }
+    }
+
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().take(2).collect();
+
+    let runtime = CountingRuntime::default();
+    let batch_fetcher = BatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .spawner(runtime.clone())
+    .timer(runtime.clone())
+    .finish();
+
+    let users = batch_fetcher.load_many(&user_ids).await?;
+    assert_eq!(users.len(), 2);
+
+    // One task for the `BatchFetcher` itself, plus one for the batch
+    assert_eq!(runtime.spawns.load(std::sync::atomic::Ordering::SeqCst), 2);
+    assert_eq!(runtime.sleeps.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_reuses_delay_timer() -> anyhow::Result<()> {
+    #[derive(Clone, Default)]
+    struct CountingTimer {
+        sleeps: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl Timer for CountingTimer {
+        fn sleep(&self, duration: std::time::Duration) -> BoxFuture<'static, ()> {
+            self.sleeps
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Box::pin(tokio::time::sleep(duration))
+        }
+    }
+
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().take(10).collect();
+
+    let timer = CountingTimer::default();
+    let batch_fetcher = BatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .scheduler(AdaptiveBatchScheduler::new(
+        tokio::time::Duration::from_millis(50),
+        tokio::time::Duration::from_millis(50),
+    ))
+    .timer(timer.clone())
+    .finish();
+
+    let users =
+        futures_util::future::join_all(user_ids.iter().map(|id| batch_fetcher.load(*id))).await;
+    assert!(users.iter().all(|user| user.is_ok()));
+
+    // Every load waits on the same deadline, so only one sleep is needed
+    assert_eq!(timer.sleeps.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_names_fetch_task() -> anyhow::Result<()> {
+    #[derive(Clone, Default)]
+    struct NamingSpawner {
+        names: Arc<RwLock<Vec<String>>>,
+    }
+
+    impl Spawner for NamingSpawner {
+        fn spawn(&self, task: BoxFuture<'static, ()>) {
+            tokio::spawn(task);
+        }
+
+        fn spawn_named(&self, name: &str, task: BoxFuture<'static, ()>) {
+            self.names.write().unwrap().push(name.to_string());
+            tokio::spawn(task);
+        }
+    }
+
+    let traces = stubs::RecordTraces::default();
+    let _guard = tracing::subscriber::set_default(traces.clone());
+
+    let spawner = NamingSpawner::default();
+    let batch_fetcher = BatchFetcher::from_fn(|ids: Vec<u64>| async move {
+        anyhow::Ok(
+            ids.into_iter()
+                .map(|id| (id, id))
+                .collect::<std::collections::HashMap<_, _>>(),
+        )
+    })
+    .label("users")
+    .spawner(spawner.clone())
+    .finish();
+    batch_fetcher.load(1).await?;
+
+    // Only the long-running fetch task is named
+    assert_eq!(
+        *spawner.names.read().unwrap(),
+        vec!["ultra-batch fetcher users".to_string()]
+    );
+    assert!(traces.span_names().contains(&"fetch_task"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_with_driver() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+    let expected_ids = user_ids.clone();
+
+    let (batch_fetcher, driver) = BatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .finish_with_driver();
+
+    // The driver completes once the `BatchFetcher` is dropped
+    let (users, ()) = tokio::join!(
+        async move {
+            let users = batch_fetcher.load_many(&user_ids).await;
+            drop(batch_fetcher);
+            users
+        },
+        driver,
+    );
+
+    let loaded_ids: Vec<_> = users?.iter().map(|user| user.id).collect();
+    assert_eq!(loaded_ids, expected_ids);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_with_dropped_driver() -> anyhow::Result<()> {
+    let fetcher = RecordingFetcher::new(ultra_batch::FnFetcher::new(|keys: Vec<u64>| async move {
+        let values: std::collections::HashMap<_, _> =
+            keys.into_iter().map(|key| (key, key * 2)).collect();
+        anyhow::Ok(values)
+    }));
+    let (batch_fetcher, driver) = BatchFetcher::build(fetcher.clone()).finish_with_driver();
+    drop(driver);
+
+    // The fetch task can't be restarted without the driver, so loads fail
+    // instead of spawning a task in the background
+    assert!(matches!(
+        batch_fetcher.load(1).await,
+        Err(LoadError::SendError)
+    ));
+    let metrics = tokio::runtime::Handle::current().metrics();
+    assert_eq!(metrics.num_alive_tasks(), 0);
+    assert_eq!(fetcher.total_calls(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_load_finish_outside_runtime() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let expected_user = db.users.values().next().unwrap().clone();
+
+    // No runtime is running yet, so the `BatchFetcher` falls back to
+    // spawning its tasks on a background runtime
+    let batch_fetcher = BatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .finish();
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    let actual_user = runtime.block_on(batch_fetcher.load(expected_user.id))?;
+
+    assert_eq!(actual_user, expected_user);
+    Ok(())
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn test_blocking_load() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+
+    let user = batch_fetcher.blocking_load(user_ids[0])?;
+    assert_eq!(user.id, user_ids[0]);
+
+    let users = batch_fetcher.blocking_load_many(&user_ids)?;
+    let loaded_ids: Vec<_> = users.iter().map(|user| user.id).collect();
+    assert_eq!(loaded_ids, user_ids);
+    assert_eq!(fetcher.total_calls(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_load_restarts_stopped_fetch_task() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+
+    // The fetch task gets spawned onto the first runtime, and stops when that
+    // runtime is dropped
+    let first_runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    let batch_fetcher = first_runtime.block_on(async {
+        let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+        batch_fetcher.load(user_ids[0]).await.map(|_| batch_fetcher)
+    })?;
+    drop(first_runtime);
+    assert_eq!(fetcher.total_calls(), 1);
+
+    // A new fetch task is started on the second runtime, using the same cache
+    let second_runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    let users = second_runtime.block_on(batch_fetcher.load_many(&user_ids[0..2]))?;
+    let loaded_ids: Vec<_> = users.iter().map(|user| user.id).collect();
+    assert_eq!(loaded_ids, user_ids[0..2]);
+    assert_eq!(fetcher.total_calls(), 2);
+    assert_eq!(fetcher.calls_for_key(&user_ids[0]), 1);
+
+    // The fetch task isn't restarted after shutting down
+    second_runtime.block_on(batch_fetcher.shutdown());
+    assert!(matches!(
+        second_runtime.block_on(batch_fetcher.load(user_ids[2])),
+        Err(LoadError::SendError)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_load_finish_on_restarts_fetch_task_on_default_runtime() -> anyhow::Result<()> {
+    let first_runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_time()
+        .build()?;
+
+    // Fetcher that never finishes fetching key 0
+    let fetcher = RecordingFetcher::new(ultra_batch::FnFetcher::new(|keys: Vec<u64>| async move {
+        if keys.contains(&0) {
+            std::future::pending::<()>().await;
+        }
+        let values: std::collections::HashMap<_, _> =
+            keys.into_iter().map(|key| (key, key * 2)).collect();
+        anyhow::Ok(values)
+    }));
+    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish_on(first_runtime.handle());
+
+    let second_runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()?;
+    let waiting_load = second_runtime.spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        async move { batch_fetcher.load(0).await }
+    });
+    second_runtime.block_on(async {
+        while fetcher.total_calls() == 0 {
+            tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+        }
+    });
+
+    // Loads waiting on the stopped fetch task fail instead of hanging
+    drop(first_runtime);
+    second_runtime.block_on(async move {
+        assert!(matches!(waiting_load.await?, Err(LoadError::SendError)));
+
+        // The first runtime is gone, so a new fetch task is started with the
+        // default spawner. Only `finish_on` falls back like this, since it
+        // was given a runtime handle rather than a custom spawner
+        assert_eq!(batch_fetcher.load(1).await?, 2);
+        assert_eq!(batch_fetcher.load_many(&[2, 3]).await?, [4, 6]);
+
+        anyhow::Ok(())
+    })
+}
+
+#[tokio::test]
+async fn test_load_local_fetcher() -> anyhow::Result<()> {
+    struct LocalFetchUsers {
+        // `Rc` makes the fetcher `!Send`
+        users: std::rc::Rc<std::collections::HashMap<uuid::Uuid, db::User>>,
+    }
+
+    impl LocalFetcher for LocalFetchUsers {
+        type Key = uuid::Uuid;
+        type Value = db::User;
+        type Error = anyhow::Error;
+
+        async fn fetch(
+            &self,
+            keys: &[Self::Key],
+            values: &mut Cache<'_, Self::Key, Self::Value>,
+        ) -> Result<(), Self::Error> {
+            tokio::task::yield_now().await;
+            for key in keys {
+                if let Some(user) = self.users.get(key) {
+                    values.insert(*key, user.clone());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+    let expected_ids = user_ids.clone();
+    let users = std::rc::Rc::new(db.users);
+
+    let local_set = tokio::task::LocalSet::new();
+    let loaded_ids = local_set
+        .run_until(async move {
+            let batch_fetcher = BatchFetcher::build(LocalFetchUsers { users }).finish_local();
+
+            // The `BatchFetcher` itself can still be sent to other tasks
+            let loaded_users = tokio::spawn({
+                let batch_fetcher = batch_fetcher.clone();
+                async move { batch_fetcher.load_many(&user_ids).await }
+            })
+            .await??;
+
+            assert!(matches!(
+                batch_fetcher.load(uuid::Uuid::new_v4()).await,
+                Err(LoadError::NotFound)
+            ));
+
+            anyhow::Ok(
+                loaded_users
+                    .into_iter()
+                    .map(|user| user.id)
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .await?;
+
+    assert_eq!(loaded_ids, expected_ids);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_invalidate() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+
+    batch_fetcher.load_many(&user_ids).await?;
+    assert_eq!(fetcher.total_calls(), 1);
+
+    assert!(batch_fetcher.invalidate(&user_ids[0]));
+    assert!(!batch_fetcher.invalidate(&user_ids[0]));
+
+    // Only the invalidated key is fetched again
+    batch_fetcher.load_many(&user_ids).await?;
+    assert_eq!(fetcher.total_calls(), 2);
+    assert_eq!(fetcher.calls_for_key(&user_ids[0]), 2);
+    assert_eq!(fetcher.calls_for_key(&user_ids[1]), 1);
+
+    // Invalidate keys from a stream of events
+    let (invalidate_tx, invalidate_rx) = tokio::sync::mpsc::unbounded_channel();
+    let invalidated_keys = futures_util::stream::unfold(invalidate_rx, |mut rx| async move {
+        let key = rx.recv().await?;
+        Some((key, rx))
+    });
+    let invalidate_task = tokio::spawn({
+        let batch_fetcher = batch_fetcher.clone();
+        async move { batch_fetcher.invalidate_from(invalidated_keys).await }
+    });
+
+    invalidate_tx.send(user_ids[1])?;
+    invalidate_tx.send(user_ids[2])?;
+    drop(invalidate_tx);
+    invalidate_task.await?;
+
+    batch_fetcher.load_many(&user_ids).await?;
+    assert_eq!(fetcher.total_calls(), 3);
+    assert_eq!(fetcher.calls_for_key(&user_ids[0]), 2);
+    assert_eq!(fetcher.calls_for_key(&user_ids[1]), 2);
+    assert_eq!(fetcher.calls_for_key(&user_ids[2]), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_shared_cache() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let shared_users = SharedCache::new(tokio::time::Duration::from_millis(100));
+
+    let first_request =
+        BatchFetcher::build(fetcher.clone().with_shared_cache(shared_users.clone())).finish();
+    first_request.load_many(&user_ids[..2]).await?;
+    assert_eq!(fetcher.total_calls(), 1);
+    assert_eq!(shared_users.len(), 2);
+
+    // Only keys missing from the shared cache are fetched
+    let second_request =
+        BatchFetcher::build(fetcher.clone().with_shared_cache(shared_users.clone())).finish();
+    second_request.load_many(&user_ids).await?;
+    assert_eq!(fetcher.total_calls(), 2);
+    assert_eq!(fetcher.calls_for_key(&user_ids[0]), 1);
+    assert_eq!(fetcher.calls_for_key(&user_ids[2]), 1);
+
+    // Invalidated keys are fetched again
+    assert!(shared_users.invalidate(&user_ids[0]));
+    let third_request =
+        BatchFetcher::build(fetcher.clone().with_shared_cache(shared_users.clone())).finish();
+    third_request.load_many(&user_ids).await?;
+    assert_eq!(fetcher.total_calls(), 3);
+    assert_eq!(fetcher.calls_for_key(&user_ids[0]), 2);
+    assert_eq!(fetcher.calls_for_key(&user_ids[1]), 1);
+
+    // Values are fetched again once they expire
+    tokio::time::sleep(tokio::time::Duration::from_millis(150)).await;
+    let fourth_request =
+        BatchFetcher::build(fetcher.clone().with_shared_cache(shared_users.clone())).finish();
+    fourth_request.load(user_ids[1]).await?;
+    assert_eq!(fetcher.calls_for_key(&user_ids[1]), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_cache_snapshot() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let users: Vec<_> = db.users.values().cloned().collect();
+    let db = Arc::new(RwLock::new(db));
+
+    for read_optimized in [false, true] {
+        let mut builder = BatchFetcher::build(db::FetchUsers { db: db.clone() });
+        if read_optimized {
+            builder = builder.read_optimized_cache();
+        }
+        let batch_fetcher = builder.finish();
+        assert!(batch_fetcher.cache_snapshot().is_empty());
+
+        let missing_id = uuid::Uuid::new_v4();
+        batch_fetcher.load_many(&[users[0].id, users[1].id]).await?;
+        let _ = batch_fetcher.load(missing_id).await;
+
+        let snapshot = batch_fetcher.cache_snapshot();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot.num_loaded(), 2);
+        assert_eq!(snapshot.num_not_found(), 1);
+        assert_eq!(
+            snapshot.get(&users[0].id),
+            Some(&CacheEntry::Loaded(users[0].clone()))
+        );
+        assert_eq!(snapshot.get(&missing_id), Some(&CacheEntry::NotFound));
+        assert_eq!(snapshot.get(&users[2].id), None);
+
+        // Later loads don't change the snapshot
+        batch_fetcher.load(users[2].id).await?;
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(batch_fetcher.cache_snapshot().len(), 4);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_cache_events() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let users: Vec<_> = db.users.values().cloned().collect();
+
+    let batch_fetcher = BatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .finish();
+
+    // Changes before subscribing aren't sent
+    batch_fetcher.load(users[0].id).await?;
+
+    let mut cache_events = Box::pin(batch_fetcher.subscribe_cache_events());
+
+    let missing_id = uuid::Uuid::new_v4();
+    batch_fetcher.load(users[1].id).await?;
+    let _ = batch_fetcher.load(missing_id).await;
+    assert!(batch_fetcher.invalidate(&users[1].id));
+
+    // Loading cached keys doesn't change the cache
+    batch_fetcher.load(users[0].id).await?;
+    let _ = batch_fetcher.load(missing_id).await;
+    drop(batch_fetcher);
+
+    let cache_events: Vec<_> = cache_events.by_ref().collect().await;
+    assert_eq!(cache_events.len(), 3);
+    assert!(matches!(
+        &cache_events[0],
+        CacheEvent::Inserted { key, value } if *key == users[1].id && *value == users[1]
+    ));
+    assert!(matches!(
+        &cache_events[1],
+        CacheEvent::NotFound { key } if *key == missing_id
+    ));
+    assert!(matches!(
+        &cache_events[2],
+        CacheEvent::Invalidated { key } if *key == users[1].id
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_cache_hasher() -> anyhow::Result<()> {
+    // Hashes every key to the same value, so every key collides
+    #[derive(Clone, Default)]
+    struct CollidingHasher {
+        num_hashers: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl std::hash::BuildHasher for CollidingHasher {
+        type Hasher = CollidingHasherState;
+
+        fn build_hasher(&self) -> CollidingHasherState {
+            self.num_hashers
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            CollidingHasherState
+        }
+    }
+
+    struct CollidingHasherState;
+
+    impl std::hash::Hasher for CollidingHasherState {
+        fn finish(&self) -> u64 {
+            0
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher = RecordingFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let hasher = CollidingHasher::default();
+    let batch_fetcher = BatchFetcher::build(fetcher.clone())
+        .cache_hasher(hasher.clone())
+        .finish();
+
+    let users = batch_fetcher.load_many(&user_ids).await?;
+    let loaded_ids: Vec<_> = users.iter().map(|user| user.id).collect();
+    assert_eq!(loaded_ids, user_ids);
+    assert!(hasher.num_hashers.load(std::sync::atomic::Ordering::SeqCst) > 0);
+
+    // Keys with the same hash are still cached separately
+    let missing_id = uuid::Uuid::new_v4();
+    assert!(matches!(
+        batch_fetcher.load(missing_id).await,
+        Err(LoadError::NotFound)
+    ));
+    assert_eq!(batch_fetcher.cached_len(), user_ids.len() + 1);
+    assert_eq!(batch_fetcher.cache_snapshot().num_not_found(), 1);
+
+    let users = batch_fetcher.load_many(&user_ids).await?;
+    assert_eq!(users.len(), user_ids.len());
+    assert_eq!(fetcher.total_calls(), 2);
+
+    assert!(batch_fetcher.invalidate(&user_ids[1]));
+    assert!(!batch_fetcher.invalidate(&user_ids[1]));
+    batch_fetcher.load_many(&user_ids).await?;
+    assert_eq!(fetcher.total_calls(), 3);
+    assert_eq!(fetcher.calls_for_key(&user_ids[0]), 1);
+    assert_eq!(fetcher.calls_for_key(&user_ids[1]), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_remote_cache() -> anyhow::Result<()> {
+    #[derive(Clone, Default)]
+    struct InMemoryCache {
+        entries: Arc<std::sync::Mutex<std::collections::HashMap<u64, Vec<u8>>>>,
+    }
+
+    impl RemoteCache<u64> for InMemoryCache {
+        type Error = anyhow::Error;
+
+        async fn get_many(&self, keys: &[u64]) -> anyhow::Result<Vec<Option<Vec<u8>>>> {
+            let entries = self.entries.lock().unwrap();
+            Ok(keys.iter().map(|key| entries.get(key).cloned()).collect())
+        }
+
+        async fn set_many(&self, new_entries: Vec<(u64, Vec<u8>)>) -> anyhow::Result<()> {
+            self.entries.lock().unwrap().extend(new_entries);
+            Ok(())
+        }
+    }
+
+    struct Utf8Codec;
+
+    impl CacheCodec<String> for Utf8Codec {
+        type Error = std::string::FromUtf8Error;
+
+        fn encode(&self, value: &String) -> Result<Vec<u8>, Self::Error> {
+            Ok(value.as_bytes().to_vec())
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<String, Self::Error> {
+            String::from_utf8(bytes.to_vec())
+        }
+    }
+
+    let fetcher = RecordingFetcher::new(ultra_batch::FnFetcher::new(|ids: Vec<u64>| async move {
+        anyhow::Ok(
+            ids.into_iter()
+                .filter(|id| *id < 10)
+                .map(|id| (id, format!("User {id}")))
+                .collect::<std::collections::HashMap<_, _>>(),
+        )
+    }));
+    let remote_cache = InMemoryCache::default();
+
+    let first_request = BatchFetcher::build(
+        fetcher
+            .clone()
+            .with_remote_cache(remote_cache.clone(), Utf8Codec),
+    )
+    .finish();
+    assert_eq!(
+        first_request.load_many(&[1, 2]).await?,
+        ["User 1", "User 2"]
+    );
+    assert_eq!(remote_cache.entries.lock().unwrap()[&1], b"User 1");
+
+    // Values that can't be decoded are fetched again
+    remote_cache
+        .entries
+        .lock()
+        .unwrap()
+        .insert(3, vec![0xff, 0xff]);
+
+    let second_request = BatchFetcher::build(
+        fetcher
+            .clone()
+            .with_remote_cache(remote_cache.clone(), Utf8Codec),
+    )
+    .finish();
+    assert_eq!(
+        second_request.load_many(&[1, 2, 3]).await?,
+        ["User 1", "User 2", "User 3"]
+    );
+    assert_eq!(fetcher.total_calls(), 2);
+    assert_eq!(fetcher.calls_for_key(&1), 1);
+    assert_eq!(fetcher.calls_for_key(&3), 1);
+    assert_eq!(remote_cache.entries.lock().unwrap()[&3], b"User 3");
+
+    // Keys that aren't found aren't cached remotely
+    assert!(matches!(
+        second_request.load(20).await,
+        Err(LoadError::NotFound)
+    ));
+    assert!(!remote_cache.entries.lock().unwrap().contains_key(&20));
+
+    Ok(())
+}
+
+#[cfg(feature = "disk-cache")]
+#[tokio::test]
+async fn test_load_disk_cache() -> anyhow::Result<()> {
+    use ultra_batch::disk_cache::DiskCache;
+
+    struct Utf8Codec;
+
+    impl CacheCodec<String> for Utf8Codec {
+        type Error = std::string::FromUtf8Error;
+
+        fn encode(&self, value: &String) -> Result<Vec<u8>, Self::Error> {
+            Ok(value.as_bytes().to_vec())
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<String, Self::Error> {
+            String::from_utf8(bytes.to_vec())
+        }
+    }
+
+    let fetcher = RecordingFetcher::new(ultra_batch::FnFetcher::new(|ids: Vec<u64>| async move {
+        anyhow::Ok(
+            ids.into_iter()
+                .map(|id| (id, format!("User {id}")))
+                .collect::<std::collections::HashMap<_, _>>(),
+        )
+    }));
+    let cache_dir = std::env::temp_dir().join(format!("ultra-batch-test-{}", uuid::Uuid::new_v4()));
+    let ttl = tokio::time::Duration::from_millis(500);
+
+    let first_run = BatchFetcher::build(
+        fetcher
+            .clone()
+            .with_remote_cache(DiskCache::new(&cache_dir, ttl), Utf8Codec),
+    )
+    .finish();
+    assert_eq!(first_run.load_many(&[1, 2]).await?, ["User 1", "User 2"]);
+    assert_eq!(fetcher.total_calls(), 1);
+
+    // A new cache using the same directory starts with the stored values
+    let second_run = BatchFetcher::build(
+        fetcher
+            .clone()
+            .with_remote_cache(DiskCache::new(&cache_dir, ttl), Utf8Codec),
+    )
+    .finish();
+    assert_eq!(
+        second_run.load_many(&[1, 2, 3]).await?,
+        ["User 1", "User 2", "User 3"]
+    );
+    assert_eq!(fetcher.total_calls(), 2);
+    assert_eq!(fetcher.calls_for_key(&1), 1);
+    assert_eq!(fetcher.calls_for_key(&3), 1);
+
+    // Values are fetched again once they expire
+    tokio::time::sleep(ttl).await;
+    let third_run = BatchFetcher::build(
+        fetcher
+            .clone()
+            .with_remote_cache(DiskCache::new(&cache_dir, ttl), Utf8Codec),
+    )
+    .finish();
+    assert_eq!(third_run.load(1).await?, "User 1");
+    assert_eq!(fetcher.calls_for_key(&1), 2);
+
+    std::fs::remove_dir_all(&cache_dir)?;
+    Ok(())
+}
+
+#[cfg(feature = "disk-cache")]
+#[tokio::test]
+async fn test_load_disk_cache_long_keys() -> anyhow::Result<()> {
+    use ultra_batch::disk_cache::DiskCache;
+
+    struct Utf8Codec;
+
+    impl CacheCodec<String> for Utf8Codec {
+        type Error = std::string::FromUtf8Error;
+
+        fn encode(&self, value: &String) -> Result<Vec<u8>, Self::Error> {
+            Ok(value.as_bytes().to_vec())
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<String, Self::Error> {
+            String::from_utf8(bytes.to_vec())
+        }
+    }
+
+    let fetcher = RecordingFetcher::new(ultra_batch::FnFetcher::new(
+        |keys: Vec<String>| async move {
+            anyhow::Ok(
+                keys.into_iter()
+                    .map(|key| (key.clone(), key.len().to_string()))
+                    .collect::<std::collections::HashMap<_, _>>(),
+            )
+        },
+    ));
+    let cache_dir = std::env::temp_dir().join(format!("ultra-batch-test-{}", uuid::Uuid::new_v4()));
+    let ttl = tokio::time::Duration::from_secs(60);
+
+    // Keys that are too long to use as file names
+    let keys = vec!["a".repeat(1000), "b".repeat(2000)];
+    let first_run = BatchFetcher::build(
+        fetcher
+            .clone()
+            .with_remote_cache(DiskCache::new(&cache_dir, ttl), Utf8Codec),
+    )
+    .finish();
+    assert_eq!(first_run.load_many(&keys).await?, ["1000", "2000"]);
+
+    let second_run = BatchFetcher::build(
+        fetcher
+            .clone()
+            .with_remote_cache(DiskCache::new(&cache_dir, ttl), Utf8Codec),
+    )
+    .finish();
+    assert_eq!(second_run.load_many(&keys).await?, ["1000", "2000"]);
+    assert_eq!(fetcher.total_calls(), 1);
+
+    std::fs::remove_dir_all(&cache_dir)?;
+    Ok(())
+}
+
+#[cfg(feature = "disk-cache")]
+#[tokio::test]
+async fn test_load_disk_cache_entry_error() -> anyhow::Result<()> {
+    use ultra_batch::disk_cache::DiskCache;
+
+    struct Utf8Codec;
+
+    impl CacheCodec<String> for Utf8Codec {
+        type Error = std::string::FromUtf8Error;
+
+        fn encode(&self, value: &String) -> Result<Vec<u8>, Self::Error> {
+            Ok(value.as_bytes().to_vec())
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<String, Self::Error> {
+            String::from_utf8(bytes.to_vec())
+        }
+    }
+
+    let fetcher = RecordingFetcher::new(ultra_batch::FnFetcher::new(|ids: Vec<u64>| async move {
+        anyhow::Ok(
+            ids.into_iter()
+                .map(|id| (id, format!("User {id}")))
+                .collect::<std::collections::HashMap<_, _>>(),
+        )
+    }));
+    let cache_dir = std::env::temp_dir().join(format!("ultra-batch-test-{}", uuid::Uuid::new_v4()));
+    let ttl = tokio::time::Duration::from_secs(60);
+
+    // A directory in place of the entry for key 2 can't be read or replaced
+    let disk_cache = DiskCache::new(&cache_dir, ttl);
+    std::fs::create_dir_all(disk_cache.entry_path(&2))?;
+
+    let first_run =
+        BatchFetcher::build(fetcher.clone().with_remote_cache(disk_cache, Utf8Codec)).finish();
+    assert_eq!(first_run.load_many(&[1, 2]).await?, ["User 1", "User 2"]);
+
+    // The other key was still stored, and the failed write didn't leave a
+    // temporary file behind
+    let second_run = BatchFetcher::build(
+        fetcher
+            .clone()
+            .with_remote_cache(DiskCache::new(&cache_dir, ttl), Utf8Codec),
+    )
+    .finish();
+    assert_eq!(second_run.load_many(&[1, 2]).await?, ["User 1", "User 2"]);
+    assert_eq!(fetcher.calls_for_key(&1), 1);
+    assert_eq!(fetcher.calls_for_key(&2), 2);
+    assert_eq!(std::fs::read_dir(&cache_dir)?.count(), 2);
+
+    std::fs::remove_dir_all(&cache_dir)?;
+    Ok(())
+}
+
+#[cfg(feature = "boxed")]
+#[tokio::test]
+async fn test_load_boxed_fetcher() -> anyhow::Result<()> {
+    use ultra_batch::BoxedFetcher;
+
+    struct BoxedFetchUsers(db::FetchUsers);
+
+    impl BoxedFetcher for BoxedFetchUsers {
+        type Key = uuid::Uuid;
+        type Value = db::User;
+        type Error = anyhow::Error;
+
+        fn fetch<'a>(
+            &'a self,
+            keys: &'a [uuid::Uuid],
+            values: &'a mut Cache<'_, uuid::Uuid, db::User>,
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(Fetcher::fetch(&self.0, keys, values))
+        }
+    }
+
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().collect();
+
+    let fetcher: Arc<dyn BoxedFetcher<Key = uuid::Uuid, Value = db::User, Error = anyhow::Error>> =
+        Arc::new(BoxedFetchUsers(db::FetchUsers {
+            db: Arc::new(RwLock::new(db)),
+        }));
+    let batch_fetcher = BatchFetcher::build_boxed(fetcher).finish();
+
+    let users = batch_fetcher.load_many(&user_ids).await?;
+    let loaded_ids: Vec<_> = users.iter().map(|user| user.id).collect();
+    assert_eq!(loaded_ids, user_ids);
+
+    assert!(matches!(
+        batch_fetcher.load(uuid::Uuid::new_v4()).await,
+        Err(LoadError::NotFound)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_testing_fetchers() -> anyhow::Result<()> {
+    use ultra_batch::testing::{FailingFetcher, MockError, MockFetcher};
+
+    let mock_fetcher = MockFetcher::new([(1, "Alice"), (2, "Bob")]);
+    let failing_fetcher = FailingFetcher::new(mock_fetcher.clone(), || MockError::new("down"));
+    let fetcher = RecordingFetcher::new(failing_fetcher.clone());
+    let batch_fetcher = BatchFetcher::build(fetcher.clone()).finish();
+
+    assert_eq!(batch_fetcher.load_many(&[1, 2]).await?, ["Alice", "Bob"]);
+    assert!(batch_fetcher.load(3).await.is_err());
+    assert_eq!(fetcher.total_calls(), 2);
+    assert_eq!(fetcher.calls_for_key(&1), 1);
+
+    // Batches with a failing key fail, until the failures are cleared
+    mock_fetcher.set_value(4, "Carol");
+    mock_fetcher.fail_on(4);
+    assert!(batch_fetcher.load(4).await.is_err());
+    mock_fetcher.clear_failures();
+    assert_eq!(batch_fetcher.load(4).await?, "Carol");
+
+    // Injected failures only apply to the next calls
+    failing_fetcher.fail_next(1);
+    assert!(batch_fetcher.load(5).await.is_err());
+    mock_fetcher.set_value(5, "Dave");
+    assert_eq!(batch_fetcher.load(5).await?, "Dave");
+    assert_eq!(fetcher.calls_for_key(&5), 2);
+
+    Ok(())
+}
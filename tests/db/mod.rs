@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     sync::{Arc, RwLock},
 };
-use ultra_batch::{Cache, Executor, Fetcher};
+use ultra_batch::{Cache, Executor, Fetcher, SyncFetcher};
 use uuid::Uuid;
 
 pub struct Database {
@@ -121,6 +121,54 @@ impl Fetcher for FetchUsers {
     }
 }
 
+impl SyncFetcher for FetchUsers {
+    type Key = Uuid;
+    type Value = User;
+    type Error = anyhow::Error;
+
+    fn fetch(&self, keys: &[Uuid], values: &mut Cache<'_, Uuid, User>) -> Result<(), Self::Error> {
+        let db = self
+            .db
+            .read()
+            .map_err(|_| anyhow::anyhow!("failed to lock database"))?;
+        for key in keys {
+            if let Some(user) = db.users.get(key) {
+                values.insert(*key, user.clone())
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct FetchPosts {
+    pub db: Arc<RwLock<Database>>,
+}
+
+impl Fetcher for FetchPosts {
+    type Key = Uuid;
+    type Value = Post;
+    type Error = anyhow::Error;
+
+    async fn fetch(
+        &self,
+        keys: &[Uuid],
+        values: &mut Cache<'_, Uuid, Post>,
+    ) -> Result<(), Self::Error> {
+        let db = self
+            .db
+            .read()
+            .map_err(|_| anyhow::anyhow!("failed to lock database"))?;
+        for key in keys {
+            if let Some(post) = db.posts.get(key) {
+                values.insert(*key, post.clone())
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub struct InsertUsers {
     pub db: Arc<RwLock<Database>>,
 }
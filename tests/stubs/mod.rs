@@ -2,7 +2,9 @@
 
 use std::collections::HashMap;
 use std::sync::{atomic, Arc, RwLock};
-use ultra_batch::{Cache, Executor, Fetcher};
+use ultra_batch::{
+    BatchMetrics, CacheAccess, DispatchedBatch, Executor, FinishedBatch, QueueDepth,
+};
 
 #[derive(Debug, Default, Clone)]
 pub struct Counter {
@@ -23,137 +25,179 @@ impl Counter {
     }
 }
 
-pub struct ObserveFetcher<F>
+/// Wraps an `Executor`, overriding the return value to always return an empty
+/// `Vec`.
+#[derive(Clone)]
+pub struct ExecutorReturnsEmpty<E>(pub E);
+
+impl<E> Executor for ExecutorReturnsEmpty<E>
 where
-    F: Fetcher,
+    E: Executor + Sync,
 {
-    fetcher: Arc<F>,
-    total_calls: Counter,
-    calls_per_key: Arc<RwLock<HashMap<F::Key, Counter>>>,
+    type Value = E::Value;
+    type Result = E::Result;
+    type Error = E::Error;
+
+    async fn execute(&self, values: Vec<Self::Value>) -> Result<Vec<Self::Result>, Self::Error> {
+        self.0.execute(values).await?;
+        Ok(vec![])
+    }
 }
 
-impl<F> ObserveFetcher<F>
-where
-    F: Fetcher,
-{
-    pub fn new(fetcher: F) -> Self {
-        ObserveFetcher {
-            fetcher: Arc::new(fetcher),
-            total_calls: Counter::new(),
-            calls_per_key: Default::default(),
-        }
+/// The size and result of a completed batch.
+pub type CompletedBatchResult = (usize, Result<(), String>);
+
+/// Records every event passed to `BatchMetrics`.
+#[derive(Debug, Default, Clone)]
+pub struct RecordMetrics {
+    dispatched: Arc<RwLock<Vec<(usize, usize)>>>,
+    completed: Arc<RwLock<Vec<CompletedBatchResult>>>,
+    cache_hits: Counter,
+    cache_misses: Counter,
+    queue_depths: Arc<RwLock<Vec<usize>>>,
+}
+
+impl RecordMetrics {
+    /// The size and number of waiters for each dispatched batch.
+    pub fn dispatched(&self) -> Vec<(usize, usize)> {
+        self.dispatched.read().unwrap().clone()
+    }
+
+    /// The size and result of each completed batch.
+    pub fn completed(&self) -> Vec<CompletedBatchResult> {
+        self.completed.read().unwrap().clone()
+    }
+
+    pub fn cache_hits(&self) -> usize {
+        self.cache_hits.count()
     }
 
-    pub fn total_calls(&self) -> usize {
-        self.total_calls.count()
+    pub fn cache_misses(&self) -> usize {
+        self.cache_misses.count()
     }
 
-    pub fn calls_for_key(&self, key: &F::Key) -> usize {
-        let calls_per_key = self.calls_per_key.read().unwrap();
-        calls_per_key
-            .get(key)
-            .map(|count| count.count())
-            .unwrap_or_default()
+    /// Each reported queue depth, in order.
+    pub fn queue_depths(&self) -> Vec<usize> {
+        self.queue_depths.read().unwrap().clone()
     }
 }
 
-impl<F> Clone for ObserveFetcher<F>
-where
-    F: Fetcher,
-{
-    fn clone(&self) -> Self {
-        ObserveFetcher {
-            fetcher: self.fetcher.clone(),
-            total_calls: self.total_calls.clone(),
-            calls_per_key: self.calls_per_key.clone(),
+impl BatchMetrics for RecordMetrics {
+    fn on_batch_dispatched(&self, batch: &DispatchedBatch) {
+        let mut dispatched = self.dispatched.write().unwrap();
+        dispatched.push((batch.size, batch.waiters));
+    }
+
+    fn on_batch_completed(&self, batch: &FinishedBatch) {
+        let mut completed = self.completed.write().unwrap();
+        completed.push((batch.size, batch.result.map_err(ToString::to_string)));
+    }
+
+    fn on_cache_hit(&self, access: &CacheAccess) {
+        for _ in 0..access.num_keys {
+            self.cache_hits.inc();
         }
     }
-}
 
-impl<F> Fetcher for ObserveFetcher<F>
-where
-    F: Fetcher + Send + Sync,
-{
-    type Key = F::Key;
-    type Value = F::Value;
-    type Error = F::Error;
-
-    async fn fetch(
-        &self,
-        keys: &[Self::Key],
-        values: &mut Cache<'_, Self::Key, Self::Value>,
-    ) -> Result<(), Self::Error> {
-        {
-            self.total_calls.inc();
-            let mut calls_per_key = self.calls_per_key.write().unwrap();
-            for key in keys {
-                calls_per_key.entry(key.clone()).or_default().inc();
-            }
+    fn on_cache_miss(&self, access: &CacheAccess) {
+        for _ in 0..access.num_keys {
+            self.cache_misses.inc();
         }
+    }
 
-        self.fetcher.fetch(keys, values).await
+    fn on_queue_depth(&self, queue: &QueueDepth) {
+        let mut queue_depths = self.queue_depths.write().unwrap();
+        queue_depths.push(queue.depth);
     }
 }
 
-/// Wraps an `Executor`, overriding the return value to always return an empty
-/// `Vec`.
-#[derive(Clone)]
-pub struct ExecutorReturnsEmpty<E>(pub E);
+/// A tracing subscriber that records the name of each span, which spans
+/// follow from which, and the fields of each event. Only meant to be used
+/// from a single thread.
+#[derive(Debug, Default, Clone)]
+pub struct RecordTraces {
+    spans: Arc<RwLock<Vec<&'static tracing::Metadata<'static>>>>,
+    follows_from: Arc<RwLock<Vec<(&'static str, &'static str)>>>,
+    entered: Arc<RwLock<Vec<tracing::span::Id>>>,
+    events: Arc<RwLock<Vec<HashMap<&'static str, String>>>>,
+}
 
-impl<E> Executor for ExecutorReturnsEmpty<E>
-where
-    E: Executor + Sync,
-{
-    type Value = E::Value;
-    type Result = E::Result;
-    type Error = E::Error;
+impl RecordTraces {
+    /// The name of each span, in the order they were created.
+    pub fn span_names(&self) -> Vec<&'static str> {
+        let spans = self.spans.read().unwrap();
+        spans.iter().map(|metadata| metadata.name()).collect()
+    }
 
-    async fn execute(&self, values: Vec<Self::Value>) -> Result<Vec<Self::Result>, Self::Error> {
-        self.0.execute(values).await?;
-        Ok(vec![])
+    /// The names of the spans linked with `follows_from`, as `(span, cause)`.
+    pub fn follows_from(&self) -> Vec<(&'static str, &'static str)> {
+        self.follows_from.read().unwrap().clone()
     }
-}
 
-pub struct ObserveExecutor<E> {
-    executor: Arc<E>,
-    total_calls: Counter,
+    /// The fields of each event with the given message.
+    pub fn events(&self, message: &str) -> Vec<HashMap<&'static str, String>> {
+        let events = self.events.read().unwrap();
+        events
+            .iter()
+            .filter(|event| event.get("message").map(String::as_str) == Some(message))
+            .cloned()
+            .collect()
+    }
+
+    fn span_metadata(&self, id: &tracing::span::Id) -> &'static tracing::Metadata<'static> {
+        let spans = self.spans.read().unwrap();
+        spans[id.into_u64() as usize - 1]
+    }
 }
 
-impl<E> ObserveExecutor<E>
-where
-    E: Executor,
-{
-    pub fn new(executor: E) -> Self {
-        ObserveExecutor {
-            executor: Arc::new(executor),
-            total_calls: Counter::new(),
-        }
+impl tracing::Subscriber for RecordTraces {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
     }
 
-    pub fn total_calls(&self) -> usize {
-        self.total_calls.count()
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut spans = self.spans.write().unwrap();
+        spans.push(span.metadata());
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, span: &tracing::span::Id, follows: &tracing::span::Id) {
+        let link = (
+            self.span_metadata(span).name(),
+            self.span_metadata(follows).name(),
+        );
+        self.follows_from.write().unwrap().push(link);
     }
-}
 
-impl<E> Clone for ObserveExecutor<E> {
-    fn clone(&self) -> Self {
-        ObserveExecutor {
-            executor: self.executor.clone(),
-            total_calls: self.total_calls.clone(),
+    fn event(&self, event: &tracing::Event<'_>) {
+        struct RecordFields(HashMap<&'static str, String>);
+
+        impl tracing::field::Visit for RecordFields {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                self.0.insert(field.name(), format!("{value:?}"));
+            }
         }
+
+        let mut fields = RecordFields(HashMap::new());
+        event.record(&mut fields);
+        self.events.write().unwrap().push(fields.0);
     }
-}
 
-impl<E> Executor for ObserveExecutor<E>
-where
-    E: Executor + Send + Sync,
-{
-    type Value = E::Value;
-    type Result = E::Result;
-    type Error = E::Error;
+    fn enter(&self, span: &tracing::span::Id) {
+        self.entered.write().unwrap().push(span.clone());
+    }
 
-    async fn execute(&self, values: Vec<Self::Value>) -> Result<Vec<Self::Result>, Self::Error> {
-        self.total_calls.inc();
-        self.executor.execute(values).await
+    fn exit(&self, _span: &tracing::span::Id) {
+        self.entered.write().unwrap().pop();
+    }
+
+    fn current_span(&self) -> tracing_core::span::Current {
+        let entered = self.entered.read().unwrap();
+        match entered.last() {
+            Some(span) => tracing_core::span::Current::new(span.clone(), self.span_metadata(span)),
+            None => tracing_core::span::Current::none(),
+        }
     }
 }
@@ -0,0 +1,180 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use ultra_batch::{Cache, LoadError, SyncBatchFetcher, SyncFetcher};
+
+mod db;
+
+struct ObserveSyncFetcher<F> {
+    fetcher: F,
+    total_calls: Arc<AtomicUsize>,
+}
+
+impl<F> ObserveSyncFetcher<F> {
+    fn new(fetcher: F) -> (Self, Arc<AtomicUsize>) {
+        let total_calls = Arc::new(AtomicUsize::new(0));
+        let fetcher = ObserveSyncFetcher {
+            fetcher,
+            total_calls: total_calls.clone(),
+        };
+        (fetcher, total_calls)
+    }
+}
+
+impl<F> SyncFetcher for ObserveSyncFetcher<F>
+where
+    F: SyncFetcher,
+{
+    type Key = F::Key;
+    type Value = F::Value;
+    type Error = F::Error;
+
+    fn fetch(
+        &self,
+        keys: &[Self::Key],
+        values: &mut Cache<'_, Self::Key, Self::Value>,
+    ) -> Result<(), Self::Error> {
+        self.total_calls.fetch_add(1, Ordering::SeqCst);
+        self.fetcher.fetch(keys, values)
+    }
+}
+
+#[test]
+fn test_sync_load() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let expected_user = db.users.values().next().unwrap().clone();
+
+    let batch_fetcher = SyncBatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .finish();
+
+    let actual_user = batch_fetcher.load(expected_user.id)?;
+    assert_eq!(actual_user, expected_user);
+
+    Ok(())
+}
+
+#[test]
+fn test_sync_load_many() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().take(10).collect();
+
+    let (fetcher, total_calls) = ObserveSyncFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = SyncBatchFetcher::build(fetcher).finish();
+
+    let users = batch_fetcher.load_many(&user_ids)?;
+    let loaded_ids: Vec<_> = users.iter().map(|user| user.id).collect();
+    assert_eq!(loaded_ids, user_ids);
+    assert_eq!(total_calls.load(Ordering::SeqCst), 1);
+
+    // Loading the same keys again uses the cache
+    batch_fetcher.load_many(&user_ids)?;
+    assert_eq!(total_calls.load(Ordering::SeqCst), 1);
+    assert_eq!(batch_fetcher.cached_len(), 10);
+
+    Ok(())
+}
+
+#[test]
+fn test_sync_load_not_found() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let existing_id = *db.users.keys().next().unwrap();
+    let missing_id = uuid::Uuid::new_v4();
+
+    let batch_fetcher = SyncBatchFetcher::build(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    })
+    .finish();
+
+    assert!(matches!(
+        batch_fetcher.load(missing_id),
+        Err(LoadError::NotFound)
+    ));
+    assert!(matches!(
+        batch_fetcher.load_many([existing_id, missing_id]),
+        Err(LoadError::NotFound)
+    ));
+    assert_eq!(batch_fetcher.load(existing_id)?.id, existing_id);
+
+    Ok(())
+}
+
+#[test]
+fn test_sync_load_from_threads() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().take(20).collect();
+
+    let (fetcher, total_calls) = ObserveSyncFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = SyncBatchFetcher::build(fetcher)
+        .delay_duration(Duration::from_millis(200))
+        .eager_batch_size(None)
+        .finish();
+
+    let loaded_ids = std::thread::scope(|scope| {
+        let threads: Vec<_> = user_ids
+            .iter()
+            .map(|&user_id| {
+                let batch_fetcher = batch_fetcher.clone();
+                scope.spawn(move || batch_fetcher.load(user_id).map(|user| user.id))
+            })
+            .collect();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+
+    assert_eq!(loaded_ids, user_ids);
+    assert_eq!(total_calls.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_sync_load_max_batch_size() -> anyhow::Result<()> {
+    let db = db::Database::fake();
+    let user_ids: Vec<_> = db.users.keys().copied().take(10).collect();
+
+    let (fetcher, total_calls) = ObserveSyncFetcher::new(db::FetchUsers {
+        db: Arc::new(RwLock::new(db)),
+    });
+    let batch_fetcher = SyncBatchFetcher::build(fetcher)
+        .max_batch_size(Some(3))
+        .finish();
+
+    let users = batch_fetcher.load_many(&user_ids)?;
+    assert_eq!(users.len(), 10);
+    assert_eq!(total_calls.load(Ordering::SeqCst), 4);
+
+    Ok(())
+}
+
+#[test]
+fn test_sync_load_error() -> anyhow::Result<()> {
+    struct FailingFetcher;
+
+    impl SyncFetcher for FailingFetcher {
+        type Key = u64;
+        type Value = u64;
+        type Error = anyhow::Error;
+
+        fn fetch(&self, _keys: &[u64], _values: &mut Cache<'_, u64, u64>) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("database is down"))
+        }
+    }
+
+    let batch_fetcher = SyncBatchFetcher::build(FailingFetcher).finish();
+
+    match batch_fetcher.load(1) {
+        Err(LoadError::FetchError(error)) => assert_eq!(&*error, "database is down"),
+        result => panic!("expected a fetch error, got {result:?}"),
+    }
+
+    Ok(())
+}